@@ -0,0 +1,25 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use telecom::balancer::{self, BalancerConfig};
+use telecom::{BalancerType, RoutingContext};
+
+fn bench_balancer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("balancer");
+    for &carriers in &[3, 30] {
+        let context = RoutingContext {
+            weights: (1..=carriers as u32).collect(),
+            scores: (0..carriers).map(|i| Some(i as f32)).collect(),
+            ..RoutingContext::default()
+        };
+        for &kind in &[BalancerType::RoundRobin, BalancerType::Best] {
+            let balancer = balancer::build(&BalancerConfig::new(kind), Some(42));
+            let id = BenchmarkId::new(format!("{:?}", kind), carriers);
+            group.bench_with_input(id, &context, |b, context| {
+                b.iter(|| balancer.next_idx(carriers, context))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_balancer);
+criterion_main!(benches);
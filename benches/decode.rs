@@ -0,0 +1,72 @@
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use serde::de::DeserializeOwned;
+use telecom::codec::Format;
+use telecom::http::HttpConfig;
+use telecom::VerificationRequest;
+
+// body of a verification request with every optional field a client commonly sends
+fn body(i: u64) -> serde_json::Value {
+    serde_json::json!({
+        "number": format!("+1415{:07}", i % 10_000_000),
+        "time": 1781000000000_i64,
+        "callback_url": format!("https://app.example.com/verifications/{}/done", i),
+        "locale": "pt-BR",
+        "metadata": {
+            "user_id": format!("user-{}", i),
+            "session": "3f0c9a1e8b7d4c2a9e6f1b0d5c8a7e3f",
+            "device": { "os": "android", "version": "14", "model": "Pixel 8" },
+            "tags": ["signup", "mobile", "referral"],
+        },
+    })
+}
+
+// bench_payload compares Format::Json, which decodes with simd-json when built with
+// --features simd-json, to serde_json on payload
+fn bench_payload<T: DeserializeOwned>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    name: &str,
+    payload: &[u8],
+) {
+    group.throughput(Throughput::Bytes(payload.len() as u64));
+    group.bench_function(format!("{}/serde_json", name), |b| {
+        b.iter(|| serde_json::from_slice::<T>(payload).unwrap())
+    });
+    group.bench_function(format!("{}/format", name), |b| {
+        b.iter(|| Format::Json.decode::<T>(payload).unwrap())
+    });
+}
+
+// large_body is body(i) with its metadata padded up to just under the body limit, the largest
+// request the server accepts
+fn large_body(i: u64) -> Vec<u8> {
+    let limit = HttpConfig::default().max_body_bytes;
+    let mut body = body(i);
+    let mut notes = Vec::new();
+    while serde_json::to_vec(&body).unwrap().len() < limit - 512 {
+        notes.push(format!(
+            "note {} about the user's device and session",
+            notes.len()
+        ));
+        body["metadata"]["notes"] = notes.clone().into();
+    }
+    serde_json::to_vec(&body).unwrap()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let minimal = serde_json::to_vec(&serde_json::json!({
+        "number": "+14155550100",
+        "time": 1781000000000_i64,
+    }))
+    .unwrap();
+    let single = serde_json::to_vec(&body(1)).unwrap();
+    let large = large_body(1);
+    let mut group = c.benchmark_group("decode");
+    bench_payload::<VerificationRequest>(&mut group, "minimal", &minimal);
+    bench_payload::<VerificationRequest>(&mut group, "single", &single);
+    bench_payload::<VerificationRequest>(&mut group, "large", &large);
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
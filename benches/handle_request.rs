@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use telecom::provider::{MockTelecomProvider, TelecomProvider};
+use telecom::repo::VerificationKeeper;
+use telecom::{BalancerType, VerificationRequest, VerificationServer};
+
+// server routes to three mock carriers that always reach the number, so every request walks the
+// whole path down to storing its attempt
+fn server() -> VerificationServer {
+    let carriers = (1..=3)
+        .map(|i| {
+            let carrier = MockTelecomProvider::new(format!("carrier_{}", i), 100, 100)
+                .unwrap()
+                .with_seed(i);
+            Box::new(carrier) as Box<dyn TelecomProvider>
+        })
+        .collect();
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    VerificationServer::new(BalancerType::RoundRobin, carriers, Box::new(keeper)).with_seed(42)
+}
+
+fn request(i: u64) -> VerificationRequest {
+    serde_json::from_value(serde_json::json!({
+        "number": format!("+1415{:07}", i % 10_000_000),
+        "time": 1781000000000_i64,
+    }))
+    .unwrap()
+}
+
+fn bench_handle_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handle_request");
+    group.throughput(Throughput::Elements(1));
+    let server = server();
+    let mut i = 0;
+    group.bench_function("round_robin", |b| {
+        b.iter_batched(
+            || {
+                i += 1;
+                request(i)
+            },
+            |request| server.handle_request(&request).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_handle_request);
+criterion_main!(benches);
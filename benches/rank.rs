@@ -0,0 +1,65 @@
+use chrono::{Duration, Utc};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::sync::Arc;
+use telecom::repo::{
+    RankQuery, VerificationEntry, VerificationKeeper, VerificationRepo, VerificationStep,
+};
+
+const STEPS: [VerificationStep; 5] = [
+    VerificationStep::FirstSMS,
+    VerificationStep::SecondSMS,
+    VerificationStep::FirstTextToSpeech,
+    VerificationStep::SecondTextToSpeech,
+    VerificationStep::Unreachable,
+];
+
+// keeper holds entries attempts spread over five carriers and every step
+fn keeper(entries: usize) -> VerificationKeeper {
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    let now = Utc::now();
+    let carriers = (0..5)
+        .map(|i| Arc::<str>::from(format!("carrier_{}", i)))
+        .collect::<Vec<_>>();
+    for i in 0..entries {
+        keeper
+            .store_attempt(VerificationEntry {
+                carrier: carriers[i % 5].clone(),
+                // half of them German
+                number: match i % 2 {
+                    0 => format!("+4917{:08}", i),
+                    _ => format!("+1415{:07}", i),
+                },
+                time: now - Duration::seconds(i as i64),
+                step: STEPS[i * 7 % 5],
+                simulated: false,
+                correlation_id: None,
+            })
+            .unwrap();
+    }
+    keeper
+}
+
+fn bench_rank(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rank");
+    for &entries in &[1_000, 10_000, 100_000] {
+        let keeper = keeper(entries);
+        // taken from the running totals
+        group.bench_with_input(BenchmarkId::new("all", entries), &keeper, |b, keeper| {
+            b.iter(|| keeper.get_provider_rank())
+        });
+        // scoped by country, walking every attempt
+        let query = RankQuery {
+            country: Some("DE".to_string()),
+            ..RankQuery::default()
+        };
+        group.bench_with_input(
+            BenchmarkId::new("country", entries),
+            &keeper,
+            |b, keeper| b.iter(|| keeper.get_provider_rank_by(&query)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rank);
+criterion_main!(benches);
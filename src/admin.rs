@@ -0,0 +1,749 @@
+use crate::audit::{self, AuditAction, AuditEntry, AuditQuery};
+use crate::consent::{OptOut, OptOutSource};
+use crate::debug::DebugState;
+use crate::escalation::EscalationConfig;
+use crate::fraud::{FraudConfig, FraudDecision};
+use crate::http::{self, error_response, AppState};
+use crate::logging::{self, LogLevelRequest, LogLevelStatus};
+use crate::middleware::ClientIp;
+use crate::pagination::{Page, PageParams};
+use crate::policy::NumberPolicy;
+use crate::provider::ProviderConfig;
+use crate::pumping::Throttle;
+use crate::receipt::Receipt;
+use crate::reload::ReloadReport;
+use crate::repo::RankingConfig;
+use crate::CarrierStatus;
+use axum::extract::{FromRequestParts, Path, Query, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use utoipa::IntoParams;
+
+// shortest admin token accepted, shorter ones are guessed too easily
+pub const MIN_TOKEN_LEN: usize = 16;
+
+// AdminTokens are the bearer tokens admin requests authenticate with, each one names the
+// operator its requests are audited as
+#[derive(Debug, Default, Clone)]
+pub struct AdminTokens(Vec<(String, String)>);
+
+impl AdminTokens {
+    pub fn new(tokens: &BTreeMap<String, String>) -> Self {
+        Self(
+            tokens
+                .iter()
+                .map(|(operator, token)| (operator.clone(), token.clone()))
+                .collect(),
+        )
+    }
+
+    // operator is who token was issued to, every token is compared in constant time so the
+    // time taken doesn't tell how close a guess was
+    fn operator(&self, token: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .fold(None, |found, (operator, t)| match same(t, token) {
+                true => Some(operator.as_str()),
+                false => found,
+            })
+    }
+}
+
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+// parses an admin token passed as "alice=<token>"
+pub fn parse_admin_token(s: &str) -> Result<(String, String), String> {
+    let (operator, token) = s
+        .split_once('=')
+        .ok_or_else(|| "expected <operator>=<token>".to_string())?;
+    match (operator.trim(), token.trim()) {
+        ("", _) => Err("the operator of an admin token can't be empty".to_string()),
+        (_, t) if t.len() < MIN_TOKEN_LEN => Err(format!(
+            "admin tokens must be at least {} characters",
+            MIN_TOKEN_LEN
+        )),
+        (operator, token) => Ok((operator.to_string(), token.to_string())),
+    }
+}
+
+// Operator is who authenticated an admin request
+#[derive(Debug, Clone)]
+struct Operator(String);
+
+// authenticate refuses admin requests without the bearer token of an operator
+async fn authenticate(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let operator = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(|token| state.admin_tokens.operator(token.trim()))
+        .map(str::to_string);
+    match operator {
+        Some(operator) => {
+            request.extensions_mut().insert(Operator(operator));
+            next.run(request).await
+        }
+        None => {
+            let mut response = error_response(
+                StatusCode::UNAUTHORIZED,
+                "admin requests need the bearer token of an operator",
+            );
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Bearer realm=\"telecom-admin\""),
+            );
+            response
+        }
+    }
+}
+
+// router serves the admin API, every route of it authenticated with state's admin tokens
+pub fn router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/carriers", get(list_carriers).post(add_carrier))
+        .route("/admin/carriers/{name}", delete(remove_carrier))
+        .route("/admin/ranking", get(get_ranking).put(put_ranking))
+        .route("/admin/escalation", get(get_escalation).put(put_escalation))
+        .route("/admin/fraud", get(get_fraud).put(put_fraud))
+        .route(
+            "/admin/number-policy",
+            get(get_number_policy).put(put_number_policy),
+        )
+        .route("/admin/fraud/decisions", get(list_fraud_decisions))
+        .route("/admin/fraud/throttles", get(list_throttles))
+        .route("/admin/fraud/throttles/{prefix}", delete(lift_throttle))
+        .route("/admin/receipts", get(list_receipts))
+        .route("/admin/opt-outs", get(list_opt_outs))
+        .route(
+            "/admin/opt-outs/{number}",
+            put(put_opt_out).delete(delete_opt_out),
+        )
+        .route("/admin/reload", post(post_reload))
+        .route("/admin/log-level", get(get_log_level).put(put_log_level))
+        .route("/admin/audit", get(list_audit))
+        .route("/debug/state", get(get_debug_state))
+        .merge(http::operational_routes())
+        // only matched routes, unknown paths are still answered with 404
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            authenticate,
+        ))
+}
+
+// Actor is who made an admin request, as recorded in the audit log: the operator whose token
+// authenticated it and the client address
+pub(crate) struct Actor(String);
+
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let name = parts.extensions.get::<Operator>().map(|o| o.0.as_str());
+        let client = parts.extensions.get::<ClientIp>().and_then(|c| c.0);
+        Ok(Actor(audit::actor(name, client)))
+    }
+}
+
+// value renders what an admin action replaced or put in place for the audit log
+fn value<T: serde::Serialize>(value: &T) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+// -------------------------
+// LIST CARRIERS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/carriers",
+    responses((status = 200, description = "registered carriers", body = Vec<CarrierStatus>))
+)]
+pub(crate) async fn list_carriers(State(state): State<AppState>) -> Response {
+    Json(state.server.list_carriers()).into_response()
+}
+
+// -------------------------
+// REGISTER CARRIER
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/admin/carriers",
+    request_body = ProviderConfig,
+    responses(
+        (status = 201, description = "carrier registered", body = CarrierStatus),
+        (status = 400, description = "invalid provider configuration"),
+        (status = 409, description = "a carrier with that name is already registered"),
+    )
+)]
+pub(crate) async fn add_carrier(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Json(config): Json<ProviderConfig>,
+) -> Response {
+    let server = &state.server;
+    // resolving its secrets may call the secrets backend, keep it off the async workers
+    let built = {
+        let server = server.clone();
+        tokio::task::spawn_blocking(move || (server.build_carrier(&config), config)).await
+    };
+    let (carrier, config) = match built {
+        Ok((Ok(c), config)) => (c, config),
+        Ok((Err(e), _)) => return error_response(StatusCode::BAD_REQUEST, e),
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    if let Err(e) = server.add_carrier(carrier) {
+        return error_response(StatusCode::CONFLICT, e);
+    }
+    if !config.enabled {
+        server.drain_carrier(&config.name);
+    }
+    let status = server
+        .list_carriers()
+        .into_iter()
+        .find(|c| c.name == config.name);
+    server.audit(
+        &actor,
+        AuditAction::CarrierAdded,
+        &config.name,
+        None,
+        value(&status),
+    );
+    (StatusCode::CREATED, Json(status)).into_response()
+}
+
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct RemoveParams {
+    // keep the carrier registered for callbacks and only stop routing attempts to it
+    #[serde(default)]
+    drain: bool,
+}
+
+// -------------------------
+// REMOVE OR DRAIN CARRIER
+// -------------------------
+#[utoipa::path(
+    delete,
+    path = "/admin/carriers/{name}",
+    params(("name" = String, Path, description = "carrier name"), RemoveParams),
+    responses(
+        (status = 204, description = "carrier removed or draining"),
+        (status = 404, description = "carrier not found"),
+    )
+)]
+pub(crate) async fn remove_carrier(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Path(name): Path<String>,
+    Query(params): Query<RemoveParams>,
+) -> Response {
+    let server = &state.server;
+    let status = |server: &crate::VerificationServer| {
+        server.list_carriers().into_iter().find(|c| c.name == name)
+    };
+    let before = status(server);
+    let (found, action) = match params.drain {
+        true => (server.drain_carrier(&name), AuditAction::CarrierDrained),
+        false => (server.remove_carrier(&name), AuditAction::CarrierRemoved),
+    };
+    match found {
+        true => {
+            let after = status(server).and_then(|s| value(&s));
+            server.audit(&actor, action, &name, value(&before), after);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        false => error_response(
+            StatusCode::NOT_FOUND,
+            format!("carrier not found: {}", name),
+        ),
+    }
+}
+
+// -------------------------
+// GET RANKING CONFIG
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/ranking",
+    responses((status = 200, description = "active ranking configuration", body = RankingConfig))
+)]
+pub(crate) async fn get_ranking(State(state): State<AppState>) -> Response {
+    Json(state.server.get_ranking_config()).into_response()
+}
+
+// -------------------------
+// UPDATE RANKING CONFIG
+// -------------------------
+#[utoipa::path(
+    put,
+    path = "/admin/ranking",
+    request_body = RankingConfig,
+    responses(
+        (status = 200, description = "ranking configuration updated", body = RankingConfig),
+        (status = 422, description = "ranking configuration failed validation"),
+    )
+)]
+pub(crate) async fn put_ranking(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Json(config): Json<RankingConfig>,
+) -> Response {
+    let server = &state.server;
+    let before = server.get_ranking_config();
+    match server.set_ranking_config(config) {
+        Ok(()) => {
+            let after = server.get_ranking_config();
+            server.audit(
+                &actor,
+                AuditAction::RankingUpdated,
+                "ranking",
+                value(&before),
+                value(&after),
+            );
+            Json(after).into_response()
+        }
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+// -------------------------
+// GET ESCALATION CONFIG
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/escalation",
+    responses((status = 200, description = "active escalation ladders", body = EscalationConfig))
+)]
+pub(crate) async fn get_escalation(State(state): State<AppState>) -> Response {
+    Json(state.server.get_escalation_config()).into_response()
+}
+
+// -------------------------
+// UPDATE ESCALATION CONFIG
+// -------------------------
+#[utoipa::path(
+    put,
+    path = "/admin/escalation",
+    request_body = EscalationConfig,
+    responses(
+        (status = 200, description = "escalation ladders updated", body = EscalationConfig),
+        (status = 422, description = "escalation ladders failed validation"),
+    )
+)]
+pub(crate) async fn put_escalation(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Json(config): Json<EscalationConfig>,
+) -> Response {
+    let server = &state.server;
+    let before = server.get_escalation_config();
+    match server.set_escalation_config(config) {
+        Ok(()) => {
+            let after = server.get_escalation_config();
+            server.audit(
+                &actor,
+                AuditAction::EscalationUpdated,
+                "escalation",
+                value(&before),
+                value(&after),
+            );
+            Json(after).into_response()
+        }
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+// -------------------------
+// GET FRAUD CONFIG
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/fraud",
+    responses((status = 200, description = "active velocity limits", body = FraudConfig))
+)]
+pub(crate) async fn get_fraud(State(state): State<AppState>) -> Response {
+    Json(state.server.get_fraud_config()).into_response()
+}
+
+// -------------------------
+// UPDATE FRAUD CONFIG
+// -------------------------
+#[utoipa::path(
+    put,
+    path = "/admin/fraud",
+    request_body = FraudConfig,
+    responses(
+        (status = 200, description = "velocity limits updated", body = FraudConfig),
+        (status = 422, description = "velocity limits failed validation"),
+    )
+)]
+pub(crate) async fn put_fraud(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Json(config): Json<FraudConfig>,
+) -> Response {
+    let server = &state.server;
+    let before = server.get_fraud_config();
+    match server.set_fraud_config(config) {
+        Ok(()) => {
+            let after = server.get_fraud_config();
+            server.audit(
+                &actor,
+                AuditAction::FraudUpdated,
+                "fraud",
+                value(&before),
+                value(&after),
+            );
+            Json(after).into_response()
+        }
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+// -------------------------
+// LIST FRAUD DECISIONS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/fraud/decisions",
+    params(PageParams),
+    responses(
+        (status = 200, description = "fraud decisions, oldest first", body = Page<FraudDecision>),
+        (status = 400, description = "invalid cursor"),
+    )
+)]
+pub(crate) async fn list_fraud_decisions(
+    State(state): State<AppState>,
+    Query(page): Query<PageParams>,
+) -> Response {
+    match state.server.list_fraud_decisions(&page) {
+        Ok(p) => Json(p).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+// -------------------------
+// LIST THROTTLED PREFIXES
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/fraud/throttles",
+    responses((status = 200, description = "prefixes throttled for traffic pumping", body = Vec<Throttle>))
+)]
+pub(crate) async fn list_throttles(State(state): State<AppState>) -> Response {
+    Json(state.server.throttles()).into_response()
+}
+
+// -------------------------
+// LIFT THROTTLE
+// -------------------------
+#[utoipa::path(
+    delete,
+    path = "/admin/fraud/throttles/{prefix}",
+    params(("prefix" = String, Path, description = "throttled prefix, e.g. +155555501")),
+    responses(
+        (status = 204, description = "throttle lifted"),
+        (status = 404, description = "prefix is not throttled"),
+    )
+)]
+pub(crate) async fn lift_throttle(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Path(prefix): Path<String>,
+) -> Response {
+    let server = &state.server;
+    let before = server.throttles().into_iter().find(|t| t.prefix == prefix);
+    match server.lift_throttle(&prefix) {
+        true => {
+            server.audit(
+                &actor,
+                AuditAction::ThrottleLifted,
+                &prefix,
+                value(&before),
+                None,
+            );
+            StatusCode::NO_CONTENT.into_response()
+        }
+        false => error_response(
+            StatusCode::NOT_FOUND,
+            format!("prefix is not throttled: {}", prefix),
+        ),
+    }
+}
+
+// -------------------------
+// LIST RECEIPTS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/receipts",
+    params(PageParams),
+    responses(
+        (status = 200, description = "verification receipts in chain order", body = Page<Receipt>),
+        (status = 400, description = "invalid cursor"),
+    )
+)]
+pub(crate) async fn list_receipts(
+    State(state): State<AppState>,
+    Query(page): Query<PageParams>,
+) -> Response {
+    match state.server.list_receipts(&page) {
+        Ok(p) => Json(p).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+// -------------------------
+// LIST OPT-OUTS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/opt-outs",
+    params(PageParams),
+    responses(
+        (status = 200, description = "numbers opted out of messages, ordered by number", body = Page<OptOut>),
+        (status = 400, description = "invalid cursor"),
+    )
+)]
+pub(crate) async fn list_opt_outs(
+    State(state): State<AppState>,
+    Query(page): Query<PageParams>,
+) -> Response {
+    match state.server.list_opt_outs(&page) {
+        Ok(p) => Json(p).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+// -------------------------
+// OPT OUT NUMBER
+// -------------------------
+#[utoipa::path(
+    put,
+    path = "/admin/opt-outs/{number}",
+    params(("number" = String, Path, description = "number to send no more messages to")),
+    responses(
+        (status = 200, description = "number opted out", body = OptOut),
+        (status = 422, description = "invalid number"),
+    )
+)]
+pub(crate) async fn put_opt_out(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Path(number): Path<String>,
+) -> Response {
+    let server = &state.server;
+    match server.opt_out(&number, OptOutSource::Admin) {
+        Ok(o) => {
+            server.audit(&actor, AuditAction::OptOutAdded, &o.number, None, value(&o));
+            Json(o).into_response()
+        }
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+// -------------------------
+// OPT NUMBER BACK IN
+// -------------------------
+#[utoipa::path(
+    delete,
+    path = "/admin/opt-outs/{number}",
+    params(("number" = String, Path, description = "opted out number")),
+    responses(
+        (status = 204, description = "number opted back in"),
+        (status = 404, description = "number is not opted out"),
+        (status = 422, description = "invalid number"),
+    )
+)]
+pub(crate) async fn delete_opt_out(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Path(number): Path<String>,
+) -> Response {
+    let server = &state.server;
+    match server.opt_in(&number) {
+        Ok(Some(o)) => {
+            server.audit(
+                &actor,
+                AuditAction::OptOutRemoved,
+                &o.number,
+                value(&o),
+                None,
+            );
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(None) => error_response(
+            StatusCode::NOT_FOUND,
+            format!("number is not opted out: {}", number),
+        ),
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+// -------------------------
+// GET NUMBER POLICY
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/number-policy",
+    responses((status = 200, description = "active allow and deny lists", body = NumberPolicy))
+)]
+pub(crate) async fn get_number_policy(State(state): State<AppState>) -> Response {
+    Json(state.server.get_number_policy()).into_response()
+}
+
+// -------------------------
+// UPDATE NUMBER POLICY
+// -------------------------
+#[utoipa::path(
+    put,
+    path = "/admin/number-policy",
+    request_body = NumberPolicy,
+    responses(
+        (status = 200, description = "allow and deny lists updated", body = NumberPolicy),
+        (status = 422, description = "invalid country code or prefix"),
+    )
+)]
+pub(crate) async fn put_number_policy(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Json(policy): Json<NumberPolicy>,
+) -> Response {
+    let server = &state.server;
+    let before = server.get_number_policy();
+    match server.set_number_policy(policy) {
+        Ok(()) => {
+            let after = server.get_number_policy();
+            server.audit(
+                &actor,
+                AuditAction::NumberPolicyUpdated,
+                "number_policy",
+                value(&before),
+                value(&after),
+            );
+            Json(after).into_response()
+        }
+        Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+    }
+}
+
+// -------------------------
+// RELOAD CONFIG
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    responses(
+        (status = 200, description = "changed settings applied", body = ReloadReport),
+        (status = 422, description = "config could not be loaded or is invalid, nothing was applied"),
+    )
+)]
+pub(crate) async fn post_reload(State(state): State<AppState>, Actor(actor): Actor) -> Response {
+    let server = state.server.clone();
+    // the config file is read synchronously, keep it off the async workers
+    match tokio::task::spawn_blocking(move || server.reload_config(&actor)).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+// -------------------------
+// GET LOG LEVEL
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/log-level",
+    responses(
+        (status = 200, description = "active log level and the configured one", body = LogLevelStatus),
+        (status = 503, description = "logging wasn't set up by this process"),
+    )
+)]
+pub(crate) async fn get_log_level() -> Response {
+    match logging::log_level() {
+        Some(status) => Json(status).into_response(),
+        None => error_response(StatusCode::SERVICE_UNAVAILABLE, "logging is not set up"),
+    }
+}
+
+// -------------------------
+// CHANGE LOG LEVEL
+// -------------------------
+#[utoipa::path(
+    put,
+    path = "/admin/log-level",
+    request_body = LogLevelRequest,
+    responses(
+        (status = 200, description = "log level changed", body = LogLevelStatus),
+        (status = 422, description = "invalid level or duration"),
+        (status = 503, description = "logging wasn't set up by this process"),
+    )
+)]
+pub(crate) async fn put_log_level(
+    State(state): State<AppState>,
+    Actor(actor): Actor,
+    Json(request): Json<LogLevelRequest>,
+) -> Response {
+    let before = logging::log_level();
+    match logging::set_log_level(&request) {
+        Some(Ok(after)) => {
+            state.server.audit(
+                &actor,
+                AuditAction::LogLevelChanged,
+                "log_level",
+                value(&before),
+                value(&after),
+            );
+            Json(after).into_response()
+        }
+        Some(Err(e)) => error_response(StatusCode::UNPROCESSABLE_ENTITY, e),
+        None => error_response(StatusCode::SERVICE_UNAVAILABLE, "logging is not set up"),
+    }
+}
+
+// -------------------------
+// LIST AUDIT LOG
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    params(PageParams, AuditQuery),
+    responses(
+        (status = 200, description = "admin actions and issued tokens, oldest first", body = Page<AuditEntry>),
+        (status = 400, description = "invalid cursor or query"),
+    )
+)]
+pub(crate) async fn list_audit(
+    State(state): State<AppState>,
+    Query(page): Query<PageParams>,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    match state.server.list_audit(&page, &query) {
+        Ok(p) => Json(p).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+// -------------------------
+// DUMP DEBUG STATE
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/debug/state",
+    responses((status = 200, description = "snapshot of the running server for troubleshooting", body = DebugState))
+)]
+pub(crate) async fn get_debug_state(State(state): State<AppState>) -> Response {
+    Json(state.server.debug_state()).into_response()
+}
@@ -0,0 +1,347 @@
+use crate::webhook;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// AlertConfig is when carriers are reported degraded and recovered, carriers are judged by the
+// share of their verifications within the window that reached the number
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertConfig {
+    pub window_secs: u64,
+    // verifications a carrier needs within the window before it is judged
+    pub min_attempts: usize,
+    // carriers delivering a smaller share are degraded, carriers aren't judged when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_success_rate: Option<f64>,
+    // share a degraded carrier has to deliver again to recover, min_success_rate when omitted, a
+    // higher one keeps a carrier hovering around the threshold from alerting on every attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recover_success_rate: Option<f64>,
+    pub webhooks: Vec<AlertWebhook>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 300,
+            min_attempts: 20,
+            min_success_rate: None,
+            recover_success_rate: None,
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+impl AlertConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.window_secs == 0 || self.min_attempts == 0 {
+            return Err(anyhow!(
+                "alerting window_secs and min_attempts must be greater than 0"
+            ));
+        }
+        for rate in self
+            .min_success_rate
+            .iter()
+            .chain(&self.recover_success_rate)
+        {
+            if !(0.0..=1.0).contains(rate) {
+                return Err(anyhow!("alerting success rates must be between 0 and 1"));
+            }
+        }
+        match (self.min_success_rate, self.recover_success_rate) {
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "alerting recover_success_rate requires min_success_rate"
+                ))
+            }
+            (Some(min), Some(recover)) if recover < min => {
+                return Err(anyhow!(
+                    "alerting recover_success_rate must be at least min_success_rate"
+                ))
+            }
+            _ => (),
+        }
+        for hook in &self.webhooks {
+            webhook::parse_callback_url(&hook.url)?;
+            match (hook.format, &hook.routing_key) {
+                (AlertFormat::Pagerduty, None) => {
+                    return Err(anyhow!("pagerduty alert webhooks require a routing_key"))
+                }
+                (AlertFormat::Json | AlertFormat::Slack, Some(_)) => {
+                    return Err(anyhow!(
+                        "routing_key only applies to pagerduty alert webhooks"
+                    ))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.window_secs as i64)
+    }
+}
+
+// AlertFormat is the body alerts are posted with
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertFormat {
+    // the CarrierAlert itself
+    #[default]
+    Json,
+    // an incoming webhook message, {"text": ...}
+    Slack,
+    // an Events API v2 event triggered on degradation and resolved on recovery
+    Pagerduty,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AlertWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub format: AlertFormat,
+    // the integration key of the PagerDuty service alerts are raised on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_key: Option<String>,
+}
+
+impl AlertWebhook {
+    // payload renders alert in the format of the webhook
+    pub fn payload(&self, alert: &CarrierAlert) -> Value {
+        match self.format {
+            AlertFormat::Json => json!(alert),
+            AlertFormat::Slack => json!({ "text": alert.to_string() }),
+            AlertFormat::Pagerduty => json!({
+                "routing_key": self.routing_key,
+                "event_action": match alert.kind {
+                    AlertKind::Degraded => "trigger",
+                    AlertKind::Recovered => "resolve",
+                },
+                // the recovery resolves the incident its degradation raised
+                "dedup_key": format!("telecom/carrier/{}", alert.carrier),
+                "payload": {
+                    "summary": alert.to_string(),
+                    "source": "telecom",
+                    "severity": "error",
+                    "timestamp": alert.time.to_rfc3339(),
+                    "custom_details": alert,
+                },
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Degraded,
+    Recovered,
+}
+
+impl AlertKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertKind::Degraded => "degraded",
+            AlertKind::Recovered => "recovered",
+        }
+    }
+}
+
+// CarrierAlert reports a carrier moving in or out of degradation
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct CarrierAlert {
+    pub carrier: String,
+    pub kind: AlertKind,
+    // share of the verifications within the window that reached the number
+    pub success_rate: f64,
+    pub attempts: usize,
+    pub window_secs: u64,
+    pub time: DateTime<Utc>,
+}
+
+impl std::fmt::Display for CarrierAlert {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "carrier {} {}, {:.0}% of {} verifications delivered in the last {}s",
+            self.carrier,
+            self.kind.as_str(),
+            self.success_rate * 100.0,
+            self.attempts,
+            self.window_secs
+        )
+    }
+}
+
+// CarrierMonitor keeps the recent verification outcomes of each carrier and which carriers are
+// degraded
+#[derive(Debug, Default)]
+pub struct CarrierMonitor {
+    outcomes: HashMap<String, VecDeque<(DateTime<Utc>, bool)>>,
+    degraded: HashSet<String>,
+}
+
+impl CarrierMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // record adds a verification of carrier at now, returning an alert when it moved the carrier
+    // in or out of degradation
+    pub fn record(
+        &mut self,
+        config: &AlertConfig,
+        carrier: &str,
+        delivered: bool,
+        now: DateTime<Utc>,
+    ) -> Option<CarrierAlert> {
+        let outcomes = self.outcomes.entry(carrier.to_string()).or_default();
+        outcomes.push_back((now, delivered));
+        let since = now - config.window();
+        while outcomes.front().is_some_and(|(t, _)| *t <= since) {
+            outcomes.pop_front();
+        }
+        let min = config.min_success_rate?;
+        let attempts = outcomes.len();
+        if attempts < config.min_attempts {
+            return None;
+        }
+        let success_rate = outcomes.iter().filter(|(_, d)| *d).count() as f64 / attempts as f64;
+        let recover = config.recover_success_rate.unwrap_or(min);
+        let kind = match self.degraded.contains(carrier) {
+            false if success_rate < min => {
+                self.degraded.insert(carrier.to_string());
+                AlertKind::Degraded
+            }
+            true if success_rate >= recover => {
+                self.degraded.remove(carrier);
+                AlertKind::Recovered
+            }
+            _ => return None,
+        };
+        Some(CarrierAlert {
+            carrier: carrier.to_string(),
+            kind,
+            success_rate,
+            attempts,
+            window_secs: config.window_secs,
+            time: now,
+        })
+    }
+
+    pub fn is_degraded(&self, carrier: &str) -> bool {
+        self.degraded.contains(carrier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monitor() {
+        let config = AlertConfig {
+            min_attempts: 4,
+            min_success_rate: Some(0.5),
+            recover_success_rate: Some(0.75),
+            ..AlertConfig::default()
+        };
+        let now = Utc::now();
+        let mut monitor = CarrierMonitor::new();
+        let mut alerts = Vec::new();
+        let mut outcomes = vec![false; 3];
+        outcomes.extend(vec![true; 9]);
+        for delivered in outcomes {
+            alerts.extend(monitor.record(&config, "carrier_1", delivered, now));
+        }
+        // judged once it reached min_attempts, recovered once 9 of 12 were delivered
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].kind, AlertKind::Degraded);
+        assert_eq!(alerts[0].success_rate, 0.25);
+        assert_eq!(alerts[1].kind, AlertKind::Recovered);
+        assert_eq!(alerts[1].attempts, 12);
+        assert_eq!(
+            alerts[0].to_string(),
+            "carrier carrier_1 degraded, 25% of 4 verifications delivered in the last 300s"
+        );
+
+        // verifications outside the window no longer count
+        let later = now + config.window();
+        for _ in 0..3 {
+            assert!(monitor.record(&config, "carrier_1", false, later).is_none());
+        }
+        assert!(monitor.record(&config, "carrier_1", false, later).is_some());
+        assert!(monitor.is_degraded("carrier_1"));
+        assert!(!monitor.is_degraded("carrier_2"));
+
+        let disabled = AlertConfig::default();
+        for _ in 0..30 {
+            assert!(monitor.record(&disabled, "carrier_2", false, now).is_none());
+        }
+    }
+
+    #[test]
+    fn test_payloads() {
+        let alert = CarrierAlert {
+            carrier: "carrier_2".to_string(),
+            kind: AlertKind::Recovered,
+            success_rate: 0.9,
+            attempts: 20,
+            window_secs: 300,
+            time: Utc::now(),
+        };
+        let hook = |format, routing_key: Option<&str>| AlertWebhook {
+            url: "https://alerts.example.com/hook".to_string(),
+            format,
+            routing_key: routing_key.map(str::to_string),
+        };
+        assert_eq!(
+            hook(AlertFormat::Json, None).payload(&alert)["kind"],
+            "recovered"
+        );
+        assert_eq!(
+            hook(AlertFormat::Slack, None).payload(&alert)["text"],
+            alert.to_string()
+        );
+        let event = hook(AlertFormat::Pagerduty, Some("key")).payload(&alert);
+        assert_eq!(event["event_action"], "resolve");
+        assert_eq!(event["dedup_key"], "telecom/carrier/carrier_2");
+
+        let valid = AlertConfig {
+            min_success_rate: Some(0.5),
+            webhooks: vec![hook(AlertFormat::Pagerduty, Some("key"))],
+            ..AlertConfig::default()
+        };
+        assert!(valid.validate().is_ok());
+        let invalid = vec![
+            AlertConfig {
+                webhooks: vec![hook(AlertFormat::Pagerduty, None)],
+                ..valid.clone()
+            },
+            AlertConfig {
+                webhooks: vec![hook(AlertFormat::Slack, Some("key"))],
+                ..valid.clone()
+            },
+            AlertConfig {
+                recover_success_rate: Some(0.4),
+                ..valid.clone()
+            },
+            AlertConfig {
+                min_success_rate: Some(1.5),
+                ..valid.clone()
+            },
+            AlertConfig {
+                window_secs: 0,
+                ..valid
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::pagination::{encode_cursor, Page};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use utoipa::{IntoParams, ToSchema};
+
+// actor describes who made an admin request, e.g. "alice (10.0.0.5)"
+pub fn actor(name: Option<&str>, client: Option<IpAddr>) -> String {
+    let address = client.map_or("unix socket".to_string(), |ip| ip.to_string());
+    match name.map(str::trim).filter(|n| !n.is_empty()) {
+        Some(name) => format!("{} ({})", name, address),
+        None => address,
+    }
+}
+
+// AuditAction is a change made through the admin API or a token issued by the server
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CarrierAdded,
+    CarrierRemoved,
+    CarrierDrained,
+    RankingUpdated,
+    EscalationUpdated,
+    FraudUpdated,
+    NumberPolicyUpdated,
+    ThrottleLifted,
+    OptOutAdded,
+    OptOutRemoved,
+    ConfigReloaded,
+    LogLevelChanged,
+    TokenIssued,
+}
+
+// AuditEntry records one action, before and after are the values it replaced and put in place,
+// omitted for what didn't exist before or doesn't exist after
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct AuditEntry {
+    // position in the log, starting at 0
+    pub sequence: u64,
+    pub time: DateTime<Utc>,
+    pub actor: String,
+    pub action: AuditAction,
+    // what was acted on, e.g. a carrier name, a number or the attempt_id of a token
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub before: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub after: Option<Value>,
+}
+
+// AuditQuery narrows down the entries listed, every given field must match
+#[derive(Deserialize, IntoParams, Debug, Default, Clone)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.action.is_none_or(|a| a == entry.action)
+            && self.actor.as_ref().is_none_or(|a| *a == entry.actor)
+            && self.target.as_ref().is_none_or(|t| *t == entry.target)
+    }
+}
+
+// AuditLog keeps entries in the order they were recorded, they are never changed. Stores with a
+// bound evict the oldest ones
+pub trait AuditLog: Send + Sync {
+    // append stores entry as the next in the log, its sequence is assigned here
+    fn append(&mut self, entry: AuditEntry) -> u64;
+    // list returns the entries matching query starting at sequence position, the cursor of the
+    // page continues after the last entry looked at
+    fn list(&self, position: u64, limit: usize, query: &AuditQuery) -> Page<AuditEntry>;
+    // entries evicted so far to stay within the store's bound
+    fn evicted(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemoryAuditLog {
+    entries: VecDeque<AuditEntry>,
+    // entries evicted so far, the sequence of the oldest one kept
+    evicted: u64,
+    max_entries: usize,
+}
+
+impl Default for InMemoryAuditLog {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            evicted: 0,
+            max_entries: usize::MAX,
+        }
+    }
+}
+
+impl InMemoryAuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_max_entries keeps at most max entries, evicting the oldest ones beyond it
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self
+    }
+}
+
+impl AuditLog for InMemoryAuditLog {
+    fn append(&mut self, mut entry: AuditEntry) -> u64 {
+        let sequence = self.evicted + self.entries.len() as u64;
+        entry.sequence = sequence;
+        self.entries.push_back(entry);
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+            self.evicted += 1;
+        }
+        sequence
+    }
+
+    // pages start at the oldest entry kept once the one at position was evicted
+    fn list(&self, position: u64, limit: usize, query: &AuditQuery) -> Page<AuditEntry> {
+        let mut items = Vec::new();
+        let mut next = position.max(self.evicted);
+        for entry in self.entries.iter().skip((next - self.evicted) as usize) {
+            if items.len() == limit {
+                break;
+            }
+            next += 1;
+            if query.matches(entry) {
+                items.push(entry.clone());
+            }
+        }
+        let next_cursor = match next < self.evicted + self.entries.len() as u64 {
+            true => Some(encode_cursor(next)),
+            false => None,
+        };
+        Page { items, next_cursor }
+    }
+
+    fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(action: AuditAction, target: &str) -> AuditEntry {
+        AuditEntry {
+            sequence: 0,
+            time: Utc::now(),
+            actor: actor(Some("alice"), Some("10.0.0.5".parse().unwrap())),
+            action,
+            target: target.to_string(),
+            before: None,
+            after: Some(serde_json::json!({ "weight": 2 })),
+        }
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut log = InMemoryAuditLog::new();
+        for i in 0..5 {
+            log.append(entry(AuditAction::CarrierAdded, &format!("carrier_{}", i)));
+        }
+        log.append(entry(AuditAction::TokenIssued, "attempt"));
+        assert_eq!(
+            log.append(entry(AuditAction::CarrierRemoved, "carrier_0")),
+            6
+        );
+
+        let page = log.list(0, 4, &AuditQuery::default());
+        assert_eq!(page.items.len(), 4);
+        assert_eq!(page.items[3].sequence, 3);
+        assert_eq!(page.items[0].actor, "alice (10.0.0.5)");
+
+        let query = AuditQuery {
+            target: Some("carrier_0".to_string()),
+            ..AuditQuery::default()
+        };
+        let page = log.list(0, 2, &query);
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.items[1].action, AuditAction::CarrierRemoved);
+        assert_eq!(page.next_cursor, None);
+
+        let query = AuditQuery {
+            action: Some(AuditAction::CarrierAdded),
+            ..AuditQuery::default()
+        };
+        let page = log.list(3, 2, &query);
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.next_cursor, Some(encode_cursor(5)));
+        assert!(log.list(5, 2, &query).items.is_empty());
+
+        // the oldest entries are evicted beyond max_entries, keeping their sequences
+        let mut bounded = InMemoryAuditLog::new().with_max_entries(2);
+        for i in 0..5 {
+            bounded.append(entry(AuditAction::CarrierAdded, &format!("carrier_{}", i)));
+        }
+        assert_eq!(bounded.evicted(), 3);
+        let page = bounded.list(0, 1, &AuditQuery::default());
+        assert_eq!(page.items[0].sequence, 3);
+        assert_eq!(page.next_cursor, Some(encode_cursor(4)));
+        assert_eq!(bounded.list(4, 2, &AuditQuery::default()).items.len(), 1);
+
+        assert_eq!(actor(None, None), "unix socket");
+        assert_eq!(actor(Some(" "), "::1".parse().ok()), "::1");
+    }
+}
@@ -0,0 +1,282 @@
+use crate::rng::SharedRng;
+use crate::{Balancer, BalancerType, RoundRobinBalancer, RoutingContext};
+use anyhow::{anyhow, Error};
+use rand::Rng;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+// share of attempts the best balancer routes to a random carrier unless configured
+pub const DEFAULT_EPSILON: f64 = 0.1;
+
+// BalancerConfig is the balancer attempts are routed with along with its parameters. The config
+// file takes it as a table with the balancer's type, or as just the type
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BalancerConfig {
+    #[serde(rename = "type")]
+    pub kind: BalancerType,
+    // best only, share of attempts routed to a random carrier rather than the best ranked one so
+    // every carrier keeps being ranked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epsilon: Option<f64>,
+    // carriers an attempt is tried through in turn before it is left to the background retries
+    #[serde(default = "default_failover_depth")]
+    pub failover_depth: usize,
+}
+
+fn default_failover_depth() -> usize {
+    1
+}
+
+impl BalancerConfig {
+    pub fn new(kind: BalancerType) -> Self {
+        Self {
+            kind,
+            epsilon: None,
+            failover_depth: default_failover_depth(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        match (self.kind, self.epsilon) {
+            (BalancerType::RoundRobin, Some(_)) => {
+                return Err(anyhow!("epsilon only applies to the best balancer"))
+            }
+            (_, Some(epsilon)) if !(0.0..=1.0).contains(&epsilon) => {
+                return Err(anyhow!("epsilon must be between 0 and 1"))
+            }
+            _ => (),
+        }
+        if self.failover_depth == 0 {
+            return Err(anyhow!("failover_depth must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+// deserialize_setting reads a balancer setting given as a table or as just its type
+pub fn deserialize_setting<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<BalancerConfig>, D::Error> {
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(serde_json::Value::String(kind)) => BalancerType::from_str(&kind)
+            .map(|kind| Some(BalancerConfig::new(kind)))
+            .map_err(D::Error::custom),
+        Some(table) => BalancerConfig::deserialize(table)
+            .map(Some)
+            .map_err(D::Error::custom),
+    }
+}
+
+// Exclusion is why a carrier was left out of a routing decision, in the order carriers are checked
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Exclusion {
+    // drained through the admin API
+    Draining,
+    // already tried for the attempt before failing over
+    FailedOver,
+    // can't send codes of the requested length or alphabet
+    CodeFormat,
+    // doesn't serve the number's country
+    Country,
+    // spent its error budget, see slo::SloConfig
+    ErrorBudget,
+}
+
+impl Exclusion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exclusion::Draining => "draining",
+            Exclusion::FailedOver => "failed_over",
+            Exclusion::CodeFormat => "code_format",
+            Exclusion::Country => "country",
+            Exclusion::ErrorBudget => "error_budget",
+        }
+    }
+
+    // error is why no carrier could be routed to when this was the last check any carrier failed
+    pub fn error(self) -> &'static str {
+        match self {
+            Exclusion::Draining | Exclusion::FailedOver => "no carriers found",
+            Exclusion::CodeFormat => "no carriers support the requested code format",
+            Exclusion::Country => "no carriers serve numbers of this country",
+            // carriers out of budget are only left out while others can take the attempt
+            Exclusion::ErrorBudget => "no carriers within their error budget",
+        }
+    }
+}
+
+// describe_exclusions lists the carriers left out of a routing decision and why, e.g.
+// "carrier_1=draining,carrier_3=country"
+pub fn describe_exclusions(left_out: &[(Arc<str>, Exclusion)]) -> String {
+    left_out
+        .iter()
+        .map(|(carrier, exclusion)| format!("{}={}", carrier, exclusion.as_str()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// BalancerState is where a running balancer is at, for troubleshooting
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct BalancerState {
+    #[serde(rename = "type")]
+    pub kind: BalancerType,
+    // round robin only, the turn the next attempt takes counting from the first, each carrier
+    // taking as many turns in a row as its weight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_turn: Option<usize>,
+    // best only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub epsilon: Option<f64>,
+}
+
+impl BalancerState {
+    pub fn new(kind: BalancerType) -> Self {
+        Self {
+            kind,
+            next_turn: None,
+            epsilon: None,
+        }
+    }
+}
+
+// build returns the balancer config describes, the best balancer's draws are seeded with seed
+pub fn build(config: &BalancerConfig, seed: Option<u64>) -> Box<dyn Balancer> {
+    match config.kind {
+        BalancerType::RoundRobin => Box::new(RoundRobinBalancer::new()),
+        BalancerType::Best => Box::new(BestBalancer {
+            epsilon: config.epsilon.unwrap_or(DEFAULT_EPSILON),
+            rng: seed.map_or_else(SharedRng::default, |s| SharedRng::seeded_for(s, "balancer")),
+        }),
+    }
+}
+
+// BestBalancer routes to the best ranked carrier, exploring a random one for epsilon of the
+// attempts. Carriers that weren't ranked yet are tried first
+#[derive(Debug)]
+pub struct BestBalancer {
+    epsilon: f64,
+    rng: SharedRng,
+}
+
+impl Balancer for BestBalancer {
+    fn ranked(&self) -> bool {
+        true
+    }
+
+    fn next_idx(&self, carrier_len: usize, context: &RoutingContext) -> usize {
+        // directly connected carriers are ranked among themselves
+        let candidates: Vec<usize> = match context.direct.is_empty() {
+            true => (0..carrier_len).collect(),
+            false => context.direct.clone(),
+        };
+        if self.rng.with(|r| r.gen_bool(self.epsilon)) {
+            return candidates[self.rng.with(|r| r.gen_range(0, candidates.len()))];
+        }
+        candidates
+            .into_iter()
+            .min_by(|a, b| match (context.score(*a), context.score(*b)) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            })
+            .unwrap_or(0)
+    }
+
+    fn state(&self) -> BalancerState {
+        BalancerState {
+            epsilon: Some(self.epsilon),
+            ..BalancerState::new(BalancerType::Best)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balancer_config() {
+        let config: crate::config::Config = toml::from_str("balancer = \"rr\"").unwrap();
+        assert_eq!(
+            config.balancer,
+            Some(BalancerConfig::new(BalancerType::RoundRobin))
+        );
+        let config: crate::config::Config =
+            toml::from_str("[balancer]\ntype = \"best\"\nepsilon = 0.2\nfailover_depth = 2")
+                .unwrap();
+        let balancer = config.balancer.unwrap();
+        assert_eq!(balancer.epsilon, Some(0.2));
+        assert!(balancer.validate().is_ok());
+
+        let invalid = vec![
+            BalancerConfig {
+                epsilon: Some(0.2),
+                ..BalancerConfig::new(BalancerType::RoundRobin)
+            },
+            BalancerConfig {
+                epsilon: Some(1.5),
+                ..BalancerConfig::new(BalancerType::Best)
+            },
+            BalancerConfig {
+                failover_depth: 0,
+                ..BalancerConfig::new(BalancerType::Best)
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_best_balancer() {
+        let config = BalancerConfig {
+            epsilon: Some(0.0),
+            ..BalancerConfig::new(BalancerType::Best)
+        };
+        let balancer = build(&config, Some(1));
+        let mut context = RoutingContext {
+            scores: vec![Some(2.5), Some(1.2), Some(3.0)],
+            ..RoutingContext::default()
+        };
+        assert_eq!(balancer.next_idx(3, &context), 1);
+        // unranked carriers first
+        context.scores[2] = None;
+        assert_eq!(balancer.next_idx(3, &context), 2);
+        context.direct = vec![0];
+        assert_eq!(balancer.next_idx(3, &context), 0);
+
+        // always exploring still picks among the candidates
+        let balancer = build(
+            &BalancerConfig {
+                epsilon: Some(1.0),
+                ..config
+            },
+            Some(1),
+        );
+        context.direct = vec![0, 2];
+        for _ in 0..20 {
+            assert_ne!(balancer.next_idx(3, &context), 1);
+        }
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let left_out = vec![
+            ("carrier_1".into(), Exclusion::Country),
+            ("carrier_2".into(), Exclusion::Draining),
+            ("carrier_3".into(), Exclusion::CodeFormat),
+        ];
+        assert_eq!(
+            describe_exclusions(&left_out),
+            "carrier_1=country,carrier_2=draining,carrier_3=code_format"
+        );
+        // a carrier that got past the code format check reports the country as the problem
+        let last = left_out.iter().map(|(_, e)| *e).max().unwrap();
+        assert_eq!(last.error(), "no carriers serve numbers of this country");
+        assert_eq!(Exclusion::FailedOver.error(), "no carriers found");
+    }
+}
@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Error};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Runtime;
+use tokio::sync::{Semaphore, TryAcquireError};
+
+// carrier calls in flight at once unless max_provider_calls says otherwise, each holds a thread
+// of the carrier pool while it runs
+pub const DEFAULT_MAX_CALLS: usize = 256;
+
+// requests waiting for a carrier call unless max_queued_provider_calls says otherwise
+pub const DEFAULT_MAX_QUEUED: usize = 1024;
+
+// seconds requests turned away from a full queue are told to wait before retrying, calls are
+// usually done within a carrier's ladder
+pub const RETRY_AFTER_SECS: i64 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CallError {
+    // every call is in flight and max_queued requests are already waiting for one
+    Saturated,
+    // the call panicked or the runtime is shutting down
+    Failed(String),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Saturated => write!(f, "too many requests waiting for carriers"),
+            CallError::Failed(e) => write!(f, "carrier call failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+// CarrierCalls runs the work that calls carriers on a blocking pool of its own, carriers are
+// called synchronously. Carriers that stall take its threads rather than those of the server's
+// runtime, so health checks, rankings and reloads are still answered. At most max_calls of it is
+// in flight at once, the rest waits for its turn without holding a thread. Requests wait in a
+// queue of at most max_queued and are turned away once it is full, background work always waits
+#[derive(Debug, Clone)]
+pub struct CarrierCalls {
+    pool: Arc<Pool>,
+    permits: Arc<Semaphore>,
+    max_calls: usize,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl Default for CarrierCalls {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CALLS, DEFAULT_MAX_QUEUED).expect("default carrier calls")
+    }
+}
+
+// Pool is the runtime carrier calls run on the blocking threads of, built by the first call so
+// calls replaced before serving never start one. Its threads are started as calls need them and
+// stopped once idle
+#[derive(Debug)]
+struct Pool {
+    max_calls: usize,
+    runtime: OnceLock<Runtime>,
+}
+
+impl Pool {
+    fn new(max_calls: usize) -> Self {
+        Self {
+            max_calls,
+            runtime: OnceLock::new(),
+        }
+    }
+
+    // spawn calls f on one of the pool's threads, awaited from any runtime
+    fn spawn<T, F>(&self, f: F) -> tokio::task::JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let runtime = self.runtime.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .max_blocking_threads(self.max_calls)
+                .thread_name("telecom-carrier")
+                .enable_all()
+                .build()
+                .expect("carrier pool runtime")
+        });
+        runtime.spawn_blocking(f)
+    }
+}
+
+impl Drop for Pool {
+    // calls still running finish on their own, the pool may be dropped from async code
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+// Queued is a request's place in the queue, given up once it gets its call or is dropped
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl CarrierCalls {
+    pub fn new(max_calls: usize, max_queued: usize) -> Result<Self, Error> {
+        validate(max_calls)?;
+        Ok(Self {
+            pool: Arc::new(Pool::new(max_calls)),
+            permits: Arc::new(Semaphore::new(max_calls)),
+            max_calls,
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued,
+        })
+    }
+
+    // admit calls f on the pool like run, unless every call is taken and the queue
+    // is full
+    pub async fn admit<T, F>(&self, f: F) -> Result<T, CallError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(e @ TryAcquireError::Closed) => return Err(CallError::Failed(e.to_string())),
+            Err(TryAcquireError::NoPermits) => {
+                let ahead = self.queued.fetch_add(1, Ordering::SeqCst);
+                let _queued = Queued(&self.queued);
+                if ahead >= self.max_queued {
+                    return Err(CallError::Saturated);
+                }
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| CallError::Failed(e.to_string()))?
+            }
+        };
+        self.pool
+            .spawn(move || {
+                let _permit = permit;
+                f()
+            })
+            .await
+            .map_err(|e| CallError::Failed(e.to_string()))
+    }
+
+    // run calls f on the pool once a call is free, from an async task
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let ran = self
+            .pool
+            .spawn(move || {
+                let _permit = permit;
+                f()
+            })
+            .await?;
+        Ok(ran)
+    }
+
+    // try_spawn starts f on the pool without waiting for it if a call is free, from code already
+    // running on it, and hands f back otherwise
+    pub fn try_spawn<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => return Err(f),
+        };
+        self.pool.spawn(move || {
+            let _permit = permit;
+            f()
+        });
+        Ok(())
+    }
+
+    pub fn max_calls(&self) -> usize {
+        self.max_calls
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_calls - self.permits.available_permits()
+    }
+
+    // queued tells the requests waiting for a call
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+pub fn validate(max_calls: usize) -> Result<(), Error> {
+    match max_calls {
+        0 => Err(anyhow!("max_provider_calls must be greater than 0")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_bounded() {
+        assert!(CarrierCalls::new(0, 0).is_err());
+        let calls = CarrierCalls::new(2, DEFAULT_MAX_QUEUED).unwrap();
+        // the pool's runtime is only built by the first call
+        assert!(calls.pool.runtime.get().is_none());
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let ran = runtime.block_on(async {
+            let tasks = (0..6)
+                .map(|i| {
+                    let (calls, running, peak) = (calls.clone(), running.clone(), peak.clone());
+                    tokio::spawn(async move {
+                        calls
+                            .run(move || {
+                                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                                peak.fetch_max(now, Ordering::SeqCst);
+                                std::thread::sleep(Duration::from_millis(20));
+                                running.fetch_sub(1, Ordering::SeqCst);
+                                i
+                            })
+                            .await
+                            .unwrap()
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut ran = Vec::new();
+            for task in tasks {
+                ran.push(task.await.unwrap());
+            }
+            ran
+        });
+        assert_eq!(ran, vec![0, 1, 2, 3, 4, 5]);
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert_eq!(calls.in_flight(), 0);
+
+        // with the call taken and a request waiting, the next one is turned away
+        let calls = CarrierCalls::new(1, 1).unwrap();
+        let admitted = runtime.block_on(async {
+            let slow = |calls: CarrierCalls| {
+                tokio::spawn(async move {
+                    calls
+                        .admit(|| std::thread::sleep(Duration::from_millis(50)))
+                        .await
+                })
+            };
+            let running = slow(calls.clone());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let waiting = slow(calls.clone());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(calls.queued(), 1);
+            let rejected = calls.admit(|| ()).await;
+            (
+                running.await.unwrap(),
+                waiting.await.unwrap(),
+                rejected,
+                // background work waits however long the queue is
+                calls.run(|| ()).await.is_ok(),
+            )
+        });
+        assert_eq!(admitted, (Ok(()), Ok(()), Err(CallError::Saturated), true));
+        assert_eq!(calls.queued(), 0);
+
+        // a stalled carrier holds the pool's thread, not the server's blocking pool
+        let (thread, answered) = runtime.block_on(async {
+            let stalled = calls.clone();
+            let stall = tokio::spawn(async move {
+                stalled
+                    .run(|| std::thread::sleep(Duration::from_millis(100)))
+                    .await
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let answered = tokio::task::spawn_blocking(|| ()).await.is_ok();
+            assert_eq!(calls.in_flight(), 1);
+            stall.await.unwrap().unwrap();
+            let thread = calls
+                .run(|| std::thread::current().name().map(str::to_string))
+                .await
+                .unwrap();
+            (thread, answered)
+        });
+        assert_eq!(thread.as_deref(), Some("telecom-carrier"));
+        assert!(answered);
+    }
+}
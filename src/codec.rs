@@ -0,0 +1,368 @@
+use crate::http::error_response;
+use anyhow::Error;
+use axum::body::{Body, BodyDataStream, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::error::Category;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+pub const JSON: &str = "application/json";
+pub const MSGPACK: &str = "application/msgpack";
+// alias still sent by most msgpack client libraries
+pub const MSGPACK_LEGACY: &str = "application/x-msgpack";
+
+// DecodeError is why a request body couldn't be decoded, bodies aren't echoed back since they
+// hold the number
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+    // the body isn't well formed JSON or msgpack, e.g. it was cut short
+    Malformed(String),
+    // the body is well formed but isn't the request expected, e.g. a field is missing
+    Invalid(String),
+    // the body couldn't be read to its end, e.g. it exceeded max_body_bytes
+    Unreadable(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(e) => write!(f, "malformed request body: {}", e),
+            DecodeError::Invalid(e) => write!(f, "invalid request: {}", e),
+            DecodeError::Unreadable(e) => write!(f, "request body could not be read: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            Category::Data => DecodeError::Invalid(e.to_string()),
+            Category::Io | Category::Syntax | Category::Eof => {
+                DecodeError::Malformed(e.to_string())
+            }
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DecodeError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        use rmp_serde::decode::Error as E;
+        match e {
+            E::TypeMismatch(_) | E::OutOfRange | E::LengthMismatch(_) | E::Syntax(_) => {
+                DecodeError::Invalid(e.to_string())
+            }
+            _ => DecodeError::Malformed(e.to_string()),
+        }
+    }
+}
+
+// Format is a serialization the verification endpoints can read and write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    MsgPack,
+}
+
+impl Format {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let media_type = media_type.split(';').next()?.trim();
+        if media_type.eq_ignore_ascii_case(JSON) {
+            Some(Format::Json)
+        } else if media_type.eq_ignore_ascii_case(MSGPACK)
+            || media_type.eq_ignore_ascii_case(MSGPACK_LEGACY)
+        {
+            Some(Format::MsgPack)
+        } else {
+            None
+        }
+    }
+
+    // from_content_type is the format of a request body, None when it isn't one we read
+    pub fn from_content_type(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Self::from_media_type)
+    }
+
+    // from_accept picks the response format preferred by the Accept header, JSON unless
+    // msgpack is weighted above it
+    pub fn from_accept(headers: &HeaderMap) -> Self {
+        let mut best = (Format::Json, 0.0);
+        for accepted in headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let format = match Self::from_media_type(accepted) {
+                Some(f) => f,
+                None => continue,
+            };
+            // ties go to JSON
+            let q = quality(accepted);
+            let preferred = match format {
+                Format::Json => q >= best.1,
+                Format::MsgPack => q > best.1,
+            };
+            if preferred {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => JSON,
+            Format::MsgPack => MSGPACK,
+        }
+    }
+
+    // decode_body parses body as its chunks arrive rather than collecting it first. Bodies that
+    // arrive in a single chunk, most of them, are decoded in place, longer ones are handed chunk
+    // by chunk to a decoder on a blocking thread so they are never copied into one buffer
+    pub async fn decode_body<T>(self, body: Body) -> Result<T, DecodeError>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let unreadable = |e: axum::Error| DecodeError::Unreadable(e.to_string());
+        let mut chunks = body.into_data_stream();
+        let first = match chunks.next().await {
+            Some(chunk) => chunk.map_err(unreadable)?,
+            None => Bytes::new(),
+        };
+        let second = match chunks.next().await {
+            Some(chunk) => chunk.map_err(unreadable)?,
+            None => return self.decode(&first),
+        };
+
+        let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        let decoding = tokio::task::spawn_blocking(move || {
+            let mut reader = ChunkReader {
+                receiver,
+                chunk: Bytes::new(),
+                failed: None,
+            };
+            let decoded = self.decode_reader(BufReader::new(&mut reader));
+            match reader.failed {
+                Some(e) => Err(DecodeError::Unreadable(e)),
+                None => decoded,
+            }
+        });
+        forward(sender, [first, second], chunks).await;
+        decoding
+            .await
+            .map_err(|e| DecodeError::Unreadable(e.to_string()))?
+    }
+
+    fn decode_reader<T: DeserializeOwned>(self, reader: impl Read) -> Result<T, DecodeError> {
+        Ok(match self {
+            Format::Json => serde_json::from_reader(reader)?,
+            Format::MsgPack => rmp_serde::from_read(reader)?,
+        })
+    }
+
+    // decode parses a body that arrived whole
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, DecodeError> {
+        Ok(match self {
+            Format::Json => decode_json(body)?,
+            Format::MsgPack => rmp_serde::from_slice(body)?,
+        })
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            Format::Json => serde_json::to_vec(value)?,
+            // maps keyed by field name keep tagged enums and optional fields compatible with JSON
+            Format::MsgPack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    // respond renders value in this format with a 200 status
+    pub fn respond<T: Serialize>(self, value: &T) -> Response {
+        match self.encode(value) {
+            Ok(body) => (
+                StatusCode::OK,
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(self.content_type()),
+                )],
+                body,
+            )
+                .into_response(),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    }
+}
+
+// decode_json parses body with simd-json, which parses in place so it works on a copy. Bodies it
+// rejects are decoded again with serde_json, so errors read the same with or without the feature
+#[cfg(feature = "simd-json")]
+fn decode_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, DecodeError> {
+    let mut parsed = body.to_vec();
+    match simd_json::serde::from_slice(&mut parsed) {
+        Ok(value) => Ok(value),
+        Err(_) => Ok(serde_json::from_slice(body)?),
+    }
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn decode_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, DecodeError> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+// forward sends the chunks of a body to its decoder, until the body ends or fails or the decoder
+// stops receiving, done or giving up on the body
+async fn forward(
+    sender: mpsc::Sender<Result<Bytes, String>>,
+    read: [Bytes; 2],
+    mut chunks: BodyDataStream,
+) {
+    for chunk in read {
+        if sender.send(Ok(chunk)).await.is_err() {
+            return;
+        }
+    }
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| e.to_string());
+        let failed = chunk.is_err();
+        if sender.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// chunks of a body decode_body holds while the decoder catches up
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+// ChunkReader reads the chunks of a body as decode_body receives them, blocking the thread in
+// between, failed is why the body stopped short of its end
+struct ChunkReader {
+    receiver: mpsc::Receiver<Result<Bytes, String>>,
+    chunk: Bytes,
+    failed: Option<String>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => {
+                    self.failed = Some(e);
+                    return Err(io::Error::other("request body stopped short"));
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk = self.chunk.slice(n..);
+        Ok(n)
+    }
+}
+
+// quality returns the q parameter of a media range, 1 when absent
+fn quality(media_range: &str) -> f32 {
+    media_range
+        .split(';')
+        .skip(1)
+        .filter_map(|p| p.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VerificationRequest;
+
+    fn accept(value: &'static str) -> Format {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        Format::from_accept(&headers)
+    }
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(Format::from_accept(&HeaderMap::new()), Format::Json);
+        assert_eq!(accept("*/*"), Format::Json);
+        assert_eq!(accept("application/msgpack"), Format::MsgPack);
+        assert_eq!(accept("application/x-msgpack"), Format::MsgPack);
+        assert_eq!(
+            accept("application/json, application/msgpack"),
+            Format::Json
+        );
+        assert_eq!(
+            accept("application/json;q=0.5, application/msgpack"),
+            Format::MsgPack
+        );
+        assert_eq!(accept("application/msgpack;q=0"), Format::Json);
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let request = serde_json::from_str::<VerificationRequest>(
+            r#"{"number": "555", "time": 1600000000000}"#,
+        )
+        .unwrap();
+        let encoded = Format::MsgPack.encode(&request).unwrap();
+        let decoded = Format::MsgPack
+            .decode::<VerificationRequest>(&encoded)
+            .unwrap();
+        assert_eq!(decoded.number, "555");
+        assert_eq!(decoded.time, request.time);
+        assert_eq!(decoded.callback_url, None);
+
+        let invalid = Format::Json.decode::<VerificationRequest>(br#"{"number": "555"}"#);
+        assert!(
+            matches!(invalid, Err(DecodeError::Invalid(_))),
+            "{:?}",
+            invalid
+        );
+        let cut = Format::Json.decode::<VerificationRequest>(br#"{"number": "555", "ti"#);
+        assert!(matches!(cut, Err(DecodeError::Malformed(_))), "{:?}", cut);
+        let cut = Format::MsgPack.decode::<VerificationRequest>(&encoded[..encoded.len() - 2]);
+        assert!(matches!(cut, Err(DecodeError::Malformed(_))), "{:?}", cut);
+    }
+
+    #[tokio::test]
+    async fn test_decode_body() {
+        let chunked = |chunks: Vec<Result<&'static str, io::Error>>| {
+            let chunks = chunks
+                .into_iter()
+                .map(|c| c.map(|c| Bytes::from_static(c.as_bytes())));
+            Body::from_stream(tokio_stream::iter(chunks))
+        };
+        let body = chunked(vec![
+            Ok(r#"{"number": "5"#),
+            Ok(r#"55", "time""#),
+            Ok(": 1600000000000}"),
+        ]);
+        let decoded = Format::Json
+            .decode_body::<VerificationRequest>(body)
+            .await
+            .unwrap();
+        assert_eq!(decoded.number, "555");
+
+        let body = chunked(vec![Ok(r#"{"number": "5"#), Ok(r#"55", "ti"#)]);
+        let cut = Format::Json.decode_body::<VerificationRequest>(body).await;
+        assert!(matches!(cut, Err(DecodeError::Malformed(_))), "{:?}", cut);
+        let failed = io::Error::other("connection reset");
+        let body = chunked(vec![Ok(r#"{"number": "5"#), Ok(r#"55", "ti"#), Err(failed)]);
+        let failed = Format::Json.decode_body::<VerificationRequest>(body).await;
+        assert!(
+            matches!(failed, Err(DecodeError::Unreadable(_))),
+            "{:?}",
+            failed
+        );
+    }
+}
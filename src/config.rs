@@ -0,0 +1,1087 @@
+use crate::admin;
+use crate::alerting::AlertConfig;
+use crate::balancer::{self, BalancerConfig};
+use crate::calls;
+use crate::fraud::FraudConfig;
+use crate::health::HealthConfig;
+use crate::http::HttpConfig;
+use crate::logging::{self, LogFileConfig, LogFormat};
+use crate::memory::MemoryConfig;
+use crate::metrics::MetricsConfig;
+use crate::policy::NumberPolicy;
+use crate::provider::{build_with_credentials, Credentials, ProviderConfig, ProviderKind};
+use crate::proxy::ProxyConfig;
+use crate::repo::RankingConfig;
+use crate::reporting::ReportingConfig;
+use crate::secrets::{self, SecretsConfig};
+use crate::slo::SloConfig;
+use crate::{BalancerType, ServeCommand};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+// environment variables named TELECOM__ followed by a setting override it
+pub const ENV_PREFIX: &str = "TELECOM__";
+
+// Config is the structured form of the server settings, loaded from the TOML or YAML file passed
+// as --config. TELECOM__ environment variables take precedence over it and flags given on the
+// command line over both
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: String,
+    // address the HTTP and gRPC listeners bind to
+    pub bind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix_socket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grpc_port: Option<String>,
+    // the admin API is served on this port or unix socket instead of alongside the verification
+    // API when set, on admin_bind or else bind
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_port: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_bind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_unix_socket: Option<String>,
+    // also serves the admin API on the verification API's listener, which only happens when
+    // asked for since that listener usually faces clients
+    pub admin_on_public: bool,
+    // bearer tokens admin requests authenticate with, keyed by the operator each one is audited
+    // as, every admin request is refused without them
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub admin_tokens: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+    pub max_concurrency: usize,
+    // requests, retries and checks calling carriers at once, see calls::CarrierCalls
+    pub max_provider_calls: usize,
+    // requests waiting for a carrier call before further ones are turned away
+    pub max_queued_provider_calls: usize,
+    pub max_body_bytes: usize,
+    // limits on slow clients, see http::HttpConfig
+    pub read_timeout_secs: u64,
+    pub write_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+    // requests slower than this are logged with a warning, never when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_request_ms: Option<u64>,
+    // share of requests whose redacted payloads are logged at debug level
+    pub payload_sample_rate: f64,
+    // a balancer's type or a table of it and its parameters, see balancer::BalancerConfig
+    #[serde(
+        deserialize_with = "balancer::deserialize_setting",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub balancer: Option<BalancerConfig>,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    // a rotated file logs are written to instead of stdout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<LogFileConfig>,
+    // seeds mock carriers, codes and attempt ids for reproducible runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // stores simulated attempts without carriers sending messages, see --dry-run
+    pub dry_run: bool,
+    // carriers built by provider::build_provider, the built-in mock carriers when omitted
+    pub carriers: Vec<ProviderConfig>,
+    pub repo: RepoConfig,
+    // backend ${secret:NAME} credentials references are resolved from
+    pub secrets: SecretsConfig,
+    // step weights and window attempts are ranked with, the built-in weights when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ranking: Option<RankingConfig>,
+    // velocity limits, as set at runtime through /admin/fraud
+    pub fraud: FraudConfig,
+    pub number_policy: NumberPolicy,
+    // peers trusted to forward the client address velocity limits and logs use
+    pub proxies: ProxyConfig,
+    pub metrics: MetricsConfig,
+    // when carriers are reported degraded and where to, as applied on reload
+    pub alerting: AlertConfig,
+    // error budgets tracked for carriers, as applied on reload
+    pub slo: SloConfig,
+    // how GET /health/score weighs the instance, as applied on reload
+    pub health: HealthConfig,
+    // bounds on the attempts, decisions, recent verifications, velocity counts, audit entries,
+    // receipts and opt-outs kept in memory, see memory::MemoryConfig
+    pub memory: MemoryConfig,
+    // where errors nobody expected and panics are reported to
+    pub reporting: ReportingConfig,
+    // named sets of settings merged over the others by --profile, a profile may name another
+    // one it inherits from
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Value>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: "5000".to_string(),
+            bind: "localhost".to_string(),
+            unix_socket: None,
+            grpc_port: None,
+            admin_port: None,
+            admin_bind: None,
+            admin_unix_socket: None,
+            admin_on_public: false,
+            admin_tokens: BTreeMap::new(),
+            workers: None,
+            max_concurrency: 1024,
+            max_provider_calls: calls::DEFAULT_MAX_CALLS,
+            max_queued_provider_calls: calls::DEFAULT_MAX_QUEUED,
+            max_body_bytes: 64 * 1024,
+            read_timeout_secs: 30,
+            write_timeout_secs: 30,
+            idle_timeout_secs: 60,
+            slow_request_ms: None,
+            payload_sample_rate: 0.0,
+            balancer: None,
+            log_level: "info".to_string(),
+            log_format: LogFormat::default(),
+            log_file: None,
+            seed: None,
+            dry_run: false,
+            carriers: vec![
+                ProviderConfig::mock("carrier_1", 60, 50),
+                ProviderConfig::mock("carrier_2", 50, 60),
+                ProviderConfig::mock("carrier_3", 10, 100),
+            ],
+            repo: RepoConfig::default(),
+            secrets: SecretsConfig::default(),
+            ranking: None,
+            fraud: FraudConfig::default(),
+            number_policy: NumberPolicy::default(),
+            proxies: ProxyConfig::default(),
+            metrics: MetricsConfig::default(),
+            alerting: AlertConfig::default(),
+            slo: SloConfig::default(),
+            health: HealthConfig::default(),
+            memory: MemoryConfig::default(),
+            reporting: ReportingConfig::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    pub backend: RepoBackend,
+    // the file of the file backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    // records the file backend buffers before writing them together, 1 writes each one as it is
+    // stored
+    pub flush_records: usize,
+    // buffered records are written at least this often, and when the server stops
+    pub flush_interval_ms: u64,
+}
+
+impl Default for RepoConfig {
+    fn default() -> Self {
+        Self {
+            backend: RepoBackend::default(),
+            path: None,
+            flush_records: 1,
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+impl RepoConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.flush_records == 0 || self.flush_interval_ms == 0 {
+            return Err(anyhow!(
+                "repo flush_records and flush_interval_ms must be greater than 0"
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+}
+
+// RepoBackend is where verification attempts and decisions are stored
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RepoBackend {
+    // kept in process and lost on restart
+    #[default]
+    Memory,
+    // kept in process and appended to a JSON lines file they are loaded from on start
+    File,
+}
+
+impl FromStr for RepoBackend {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "file" => Ok(Self::File),
+            _ => Err(anyhow!("invalid repo backend {}, use memory or file", s)),
+        }
+    }
+}
+
+impl Config {
+    // from_file parses path as TOML or YAML depending on its extension
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read config file {}: {}", path.display(), e))?;
+        let parsed = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(Error::from),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(Error::from),
+            _ => {
+                return Err(anyhow!(
+                    "config file {} must end in .toml, .yaml or .yml",
+                    path.display()
+                ))
+            }
+        };
+        parsed.map_err(|e| anyhow!("invalid config file {}: {}", path.display(), e))
+    }
+
+    // load reads the --config file of args, if any, and applies the environment variables and then
+    // the flags given on top of it
+    pub fn load(args: &ServeCommand) -> Result<Self, Error> {
+        let mut config = Self::read(args.config.as_deref(), args.profile.as_deref())?;
+        config.apply(args);
+        Ok(config)
+    }
+
+    // read parses the file at path, if any, merges the named profile over it and applies the
+    // environment variables on top
+    pub fn read(path: Option<&str>, profile: Option<&str>) -> Result<Self, Error> {
+        let mut config = match (path, profile) {
+            (Some(path), Some(profile)) => Self::from_file(path)?.with_profile(profile)?,
+            (Some(path), None) => Self::from_file(path)?,
+            (None, Some(_)) => return Err(anyhow!("--profile requires --config")),
+            (None, None) => Self::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    // with_profile returns the settings of the named profile, and of the ones it inherits from,
+    // merged over the others. Tables are merged key by key while lists replace the inherited ones
+    pub fn with_profile(&self, name: &str) -> Result<Self, Error> {
+        let mut chain: Vec<&str> = Vec::new();
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if chain.contains(&name) {
+                return Err(anyhow!("profile {} inherits from itself", name));
+            }
+            let profile = self.profiles.get(name).ok_or_else(|| {
+                let known = self.profiles.keys().cloned().collect::<Vec<_>>();
+                anyhow!(
+                    "unknown profile {}, profiles are {}",
+                    name,
+                    known.join(", ")
+                )
+            })?;
+            if !profile.is_object() {
+                return Err(anyhow!("profile {} must be a table of settings", name));
+            }
+            next = match profile.get("inherits") {
+                Some(Value::String(parent)) => Some(parent.as_str()),
+                Some(_) => return Err(anyhow!("inherits of profile {} must be a name", name)),
+                None => None,
+            };
+            chain.push(name);
+        }
+        let base = Self {
+            profiles: BTreeMap::new(),
+            ..self.clone()
+        };
+        let mut tree = serde_json::to_value(base)?;
+        for name in chain.iter().rev() {
+            let mut overrides = self.profiles[*name].clone();
+            if let Value::Object(fields) = &mut overrides {
+                fields.remove("inherits");
+                if fields.contains_key("profiles") {
+                    return Err(anyhow!("profile {} can't define profiles", name));
+                }
+            }
+            merge(&mut tree, overrides);
+        }
+        serde_json::from_value(tree).map_err(|e| anyhow!("invalid profile {}: {}", name, e))
+    }
+
+    // apply_env overrides the settings named by the TELECOM__ variables of vars, nested keys and
+    // list indices are separated by double underscores, e.g. TELECOM__FRAUD__MAX_PER_NUMBER=5 or
+    // TELECOM__CARRIERS__0__WEIGHT=2. Values are read as YAML, so lists can be given as [RU, BY]
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> Result<(), Error> {
+        let mut vars = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect::<Vec<_>>();
+        // the environment is unordered, apply variables in a stable order
+        vars.sort();
+        for (name, raw) in vars {
+            let path = name[ENV_PREFIX.len()..].to_ascii_lowercase();
+            let value = serde_yaml::from_str(&raw).unwrap_or_else(|_| Value::String(raw.clone()));
+            // values such as port = 6000 read as numbers are kept as strings where a string is due
+            *self = self
+                .with_value(&path, value)
+                .or_else(|e| self.with_value(&path, Value::String(raw)).map_err(|_| e))
+                .map_err(|e| anyhow!("invalid {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    // with_value returns the config with the setting at the double underscore separated path
+    // replaced by value
+    fn with_value(&self, path: &str, value: Value) -> Result<Self, Error> {
+        let mut tree = serde_json::to_value(self)?;
+        let mut node = &mut tree;
+        for key in path.split("__") {
+            // a setting that is unset is filled in
+            if node.is_null() {
+                *node = Value::Object(Default::default());
+            }
+            node = match node {
+                Value::Object(fields) => fields.entry(key).or_insert(Value::Null),
+                Value::Array(items) => match key.parse::<usize>() {
+                    Ok(i) if i < items.len() => &mut items[i],
+                    _ => return Err(anyhow!("no list item {}", key)),
+                },
+                _ => return Err(anyhow!("{} is not a setting", key)),
+            };
+        }
+        *node = value;
+        Ok(serde_json::from_value(tree)?)
+    }
+
+    // apply overrides the settings that args were given flags for
+    pub fn apply(&mut self, args: &ServeCommand) {
+        if let Some(port) = &args.port {
+            self.port = port.clone();
+        }
+        if let Some(bind) = &args.bind {
+            self.bind = bind.clone();
+        }
+        if args.unix_socket.is_some() {
+            self.unix_socket = args.unix_socket.clone();
+        }
+        if args.grpc_port.is_some() {
+            self.grpc_port = args.grpc_port.clone();
+        }
+        if args.admin_port.is_some() {
+            self.admin_port = args.admin_port.clone();
+        }
+        if args.admin_bind.is_some() {
+            self.admin_bind = args.admin_bind.clone();
+        }
+        if args.admin_unix_socket.is_some() {
+            self.admin_unix_socket = args.admin_unix_socket.clone();
+        }
+        if args.admin_on_public {
+            self.admin_on_public = true;
+        }
+        for (operator, token) in &args.admin_token {
+            self.admin_tokens.insert(operator.clone(), token.clone());
+        }
+        if args.workers.is_some() {
+            self.workers = args.workers;
+        }
+        if let Some(max) = args.max_concurrency {
+            self.max_concurrency = max;
+        }
+        if let Some(max) = args.max_provider_calls {
+            self.max_provider_calls = max;
+        }
+        if let Some(max) = args.max_queued_provider_calls {
+            self.max_queued_provider_calls = max;
+        }
+        if let Some(max) = args.max_body_bytes {
+            self.max_body_bytes = max;
+        }
+        if let Some(secs) = args.read_timeout_secs {
+            self.read_timeout_secs = secs;
+        }
+        if let Some(secs) = args.write_timeout_secs {
+            self.write_timeout_secs = secs;
+        }
+        if let Some(secs) = args.idle_timeout_secs {
+            self.idle_timeout_secs = secs;
+        }
+        if args.slow_request_ms.is_some() {
+            self.slow_request_ms = args.slow_request_ms;
+        }
+        if let Some(rate) = args.payload_sample_rate {
+            self.payload_sample_rate = rate;
+        }
+        if let Some(kind) = args.balancer {
+            self.set_balancer(kind);
+        }
+        if let Some(level) = &args.log_level {
+            self.log_level = level.clone();
+        }
+        if let Some(format) = args.log_format {
+            self.log_format = format;
+        }
+        if let Some(weights) = args.step_weights {
+            self.ranking
+                .get_or_insert_with(RankingConfig::default)
+                .step_weights = weights;
+        }
+        if args.seed.is_some() {
+            self.seed = args.seed;
+        }
+        if args.dry_run {
+            self.dry_run = true;
+        }
+        if args.print_messages {
+            for carrier in &mut self.carriers {
+                let ProviderKind::Mock { print_messages, .. } = &mut carrier.kind;
+                *print_messages = true;
+            }
+        }
+        if let Some(voip) = args.voip_numbers {
+            self.fraud.voip = voip;
+        }
+        if !args.risk_tier.is_empty() {
+            self.fraud.risk_tiers = args.risk_tier.clone();
+        }
+        // list flags replace the list of the config file rather than adding to it
+        let policy = &mut self.number_policy;
+        for (flag, list) in [
+            (&args.allow_country, &mut policy.allow_countries),
+            (&args.deny_country, &mut policy.deny_countries),
+            (&args.allow_prefix, &mut policy.allow_prefixes),
+            (&args.deny_prefix, &mut policy.deny_prefixes),
+        ] {
+            if !flag.is_empty() {
+                *list = flag.iter().cloned().collect();
+            }
+        }
+        if !args.trusted_proxy.is_empty() {
+            self.proxies.trusted = args.trusted_proxy.clone();
+        }
+        if !args.forwarded_header.is_empty() {
+            self.proxies.headers = args.forwarded_header.clone();
+        }
+    }
+
+    // check returns every problem serve would fail to start with, its carriers would hit once
+    // they use their credentials or that leaves routing rules without effect
+    pub fn check(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.http().validate() {
+            problems.push(Problem::new("", e));
+        }
+        if let Err(e) = crate::http::worker_threads(self.workers) {
+            problems.push(Problem::new("workers", e));
+        }
+        if let Err(e) = calls::validate(self.max_provider_calls) {
+            problems.push(Problem::new("max_provider_calls", e));
+        }
+        problems.extend(self.check_admin_listener());
+        if let Err(e) = logging::parse_filter(&self.log_level) {
+            problems.push(Problem::new("log_level", e));
+        }
+        if let Some(Err(e)) = self.log_file.as_ref().map(LogFileConfig::validate) {
+            problems.push(Problem::new("log_file", e));
+        }
+        let secrets = secrets::build_store(&self.secrets);
+        if let Err(e) = &secrets {
+            problems.push(Problem::new("secrets", e));
+        }
+        let mut names = HashSet::new();
+        for (i, carrier) in self.carriers.iter().enumerate() {
+            let path = format!("carriers[{}]", i);
+            if !names.insert(carrier.name.as_str()) {
+                problems.push(Problem::new(
+                    format!("{}.name", path),
+                    format!("carrier {} is listed twice", carrier.name),
+                ));
+            }
+            // the rest of the carrier is still checked when its secrets don't resolve
+            let credentials = match &secrets {
+                Ok(secrets) => {
+                    Credentials::resolve(carrier, secrets.as_ref()).unwrap_or_else(|(field, e)| {
+                        problems.push(Problem::new(format!("{}.{}", path, field), e));
+                        Credentials::default()
+                    })
+                }
+                Err(_) => Credentials::default(),
+            };
+            if let Err(e) = build_with_credentials(carrier, self.seed, credentials) {
+                problems.push(Problem::new(&path, e));
+            }
+        }
+        if let Some(balancer) = &self.balancer {
+            problems.extend(self.check_balancer(balancer));
+        }
+        if let Err(e) = self.ranking.clone().unwrap_or_default().validate() {
+            problems.push(Problem::new("ranking", e));
+        }
+        match (self.repo.backend, &self.repo.path) {
+            (RepoBackend::File, None) => problems.push(Problem::new(
+                "repo.path",
+                "the file backend requires a path",
+            )),
+            (RepoBackend::Memory, Some(_)) => problems.push(Problem::new(
+                "repo.path",
+                "has no effect with the memory backend",
+            )),
+            _ => (),
+        }
+        if let Err(e) = self.repo.validate() {
+            problems.push(Problem::new("repo", e));
+        }
+        if let Err(e) = self.fraud.validate() {
+            problems.push(Problem::new("fraud", e));
+        }
+        if let Err(e) = self.metrics.validate() {
+            problems.push(Problem::new("metrics", e));
+        }
+        if let Err(e) = self.alerting.validate() {
+            problems.push(Problem::new("alerting", e));
+        }
+        if let Err(e) = self.slo.validate() {
+            problems.push(Problem::new("slo", e));
+        }
+        if let Err(e) = self.health.validate() {
+            problems.push(Problem::new("health", e));
+        }
+        if let Err(e) = self.memory.validate() {
+            problems.push(Problem::new("memory", e));
+        }
+        if let Err(e) = self.reporting.validate() {
+            problems.push(Problem::new("reporting", e));
+        }
+        let mut policy = self.number_policy.clone();
+        match policy.validate() {
+            Ok(()) => problems.extend(self.check_routing(&policy)),
+            Err(e) => problems.push(Problem::new("number_policy", e)),
+        }
+        // profiles are fully checked once selected, their credentials may only exist where
+        // they're used
+        for name in self.profiles.keys() {
+            if let Err(e) = self.with_profile(name) {
+                problems.push(Problem::new(format!("profiles.{}", name), e));
+            }
+        }
+        problems
+    }
+
+    // check_routing returns the entries of the validated policy and the carriers that overlap
+    // such that they never take effect
+    fn check_routing(&self, policy: &NumberPolicy) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for country in policy.allow_countries.intersection(&policy.deny_countries) {
+            problems.push(Problem::new(
+                "number_policy.allow_countries",
+                format!("{} is also denied, deny entries win", country),
+            ));
+        }
+        for prefix in &policy.allow_prefixes {
+            if let Some(deny) = policy.deny_prefixes.iter().find(|d| prefix.starts_with(*d)) {
+                problems.push(Problem::new(
+                    "number_policy.allow_prefixes",
+                    format!("{} is covered by the denied prefix {}", prefix, deny),
+                ));
+            }
+        }
+        // carriers' countries are validated, not normalized, by build_provider
+        let serves = |carrier: &ProviderConfig, country: &str| {
+            carrier
+                .countries
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(country))
+        };
+        for (i, carrier) in self.carriers.iter().enumerate() {
+            let denied = policy.deny_countries.iter();
+            for country in denied.filter(|c| serves(carrier, c)) {
+                problems.push(Problem::new(
+                    format!("carriers[{}].countries", i),
+                    format!("{} is denied by number_policy", country),
+                ));
+            }
+        }
+        let enabled = self
+            .carriers
+            .iter()
+            .filter(|c| c.enabled)
+            .collect::<Vec<_>>();
+        if enabled.is_empty() {
+            problems.push(Problem::new(
+                "carriers",
+                "no carrier is enabled, every verification would fail",
+            ));
+        } else if enabled.iter().all(|c| !c.countries.is_empty()) {
+            for country in &policy.allow_countries {
+                if !enabled.iter().any(|c| serves(c, country)) {
+                    problems.push(Problem::new(
+                        "number_policy.allow_countries",
+                        format!("{} is allowed but no enabled carrier serves it", country),
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    // set_balancer routes with the balancer of kind, keeping the parameters set for it if it is the
+    // configured one
+    pub fn set_balancer(&mut self, kind: BalancerType) {
+        if self.balancer.as_ref().map(|b| b.kind) != Some(kind) {
+            self.balancer = Some(BalancerConfig::new(kind));
+        }
+    }
+
+    pub fn balancer(&self) -> Result<BalancerConfig, Error> {
+        self.balancer.clone().ok_or_else(|| {
+            anyhow!("no balancer configured, pass --balancer or set balancer in the config file")
+        })
+    }
+
+    // address is where the HTTP API listens unless it is served on a unix socket
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    // http is the settings of the HTTP layer
+    pub fn http(&self) -> HttpConfig {
+        HttpConfig {
+            max_body_bytes: self.max_body_bytes,
+            max_concurrency: self.max_concurrency,
+            proxies: self.proxies.clone(),
+            read_timeout: Duration::from_secs(self.read_timeout_secs),
+            write_timeout: Duration::from_secs(self.write_timeout_secs),
+            idle_timeout: Duration::from_secs(self.idle_timeout_secs),
+            slow_request: self.slow_request_ms.map(Duration::from_millis),
+            payload_sample_rate: self.payload_sample_rate,
+        }
+    }
+
+    // admin_address is where the admin API listens when it has a TCP port of its own
+    pub fn admin_address(&self) -> Option<String> {
+        let bind = self.admin_bind.as_ref().unwrap_or(&self.bind);
+        self.admin_port
+            .as_ref()
+            .map(|port| format!("{}:{}", bind, port))
+    }
+
+    // check_balancer reports balancer parameters that are invalid or have no effect with the
+    // carriers configured
+    fn check_balancer(&self, balancer: &BalancerConfig) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if let Err(e) = balancer.validate() {
+            problems.push(Problem::new("balancer", e));
+        }
+        let enabled = self.carriers.iter().filter(|c| c.enabled).count();
+        if !self.carriers.is_empty() && balancer.failover_depth > enabled.max(1) {
+            problems.push(Problem::new(
+                "balancer.failover_depth",
+                format!("only {} carriers are enabled to fail over to", enabled),
+            ));
+        }
+        if balancer.kind != BalancerType::RoundRobin {
+            for (i, carrier) in self.carriers.iter().enumerate() {
+                if carrier.weight != 1 {
+                    problems.push(Problem::new(
+                        format!("carriers[{}].weight", i),
+                        "only the round-robin balancer weighs carriers",
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    // check_admin_listener reports admin listener settings that can't be served
+    pub fn check_admin_listener(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if self.admin_port.is_some() && self.admin_unix_socket.is_some() {
+            problems.push(Problem::new(
+                "admin_unix_socket",
+                "admin_port and admin_unix_socket are mutually exclusive",
+            ));
+        }
+        if self.admin_bind.is_some() && self.admin_port.is_none() {
+            problems.push(Problem::new(
+                "admin_bind",
+                "has no effect without admin_port",
+            ));
+        }
+        let shared = match &self.unix_socket {
+            Some(path) if self.admin_unix_socket.as_ref() == Some(path) => {
+                Some("admin_unix_socket")
+            }
+            None if self.admin_address() == Some(self.address()) => Some("admin_port"),
+            _ => None,
+        };
+        if let Some(path) = shared {
+            problems.push(Problem::new(
+                path,
+                "the admin API must listen apart from the verification API",
+            ));
+        }
+        let own_listener = self.admin_port.is_some() || self.admin_unix_socket.is_some();
+        if self.admin_on_public && own_listener {
+            problems.push(Problem::new(
+                "admin_on_public",
+                "the admin API already has a listener of its own",
+            ));
+        }
+        if (own_listener || self.admin_on_public) && self.admin_tokens.is_empty() {
+            problems.push(Problem::new(
+                "admin_tokens",
+                "the admin API needs at least one token to authenticate operators with",
+            ));
+        }
+        for (operator, token) in &self.admin_tokens {
+            if token.len() < admin::MIN_TOKEN_LEN {
+                problems.push(Problem::new(
+                    format!("admin_tokens.{}", operator),
+                    format!("must be at least {} characters", admin::MIN_TOKEN_LEN),
+                ));
+            }
+        }
+        problems
+    }
+}
+
+// Problem is an invalid setting found by check or a schema::validate, path names it like
+// carriers[1].weight and is empty for problems of the settings as a whole
+#[derive(Debug, PartialEq, Clone)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+    // line of the config file the setting is on, when known
+    pub line: Option<usize>,
+}
+
+impl Problem {
+    pub fn new<P: ToString, M: ToString>(path: P, message: M) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.to_string(),
+            line: None,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+// merge replaces the settings of base that overrides sets, tables are merged recursively
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fraud::FraudAction;
+    use argh::FromArgs;
+
+    const TOML: &str = r#"
+port = "6000"
+balancer = "round-robin"
+
+[[carriers]]
+name = "carrier_1"
+type = "mock"
+chance_sms = 60
+chance_voice = 50
+weight = 2
+countries = ["DE", "AT"]
+
+[ranking]
+step_weights = [1, 2, 4, 8, 16]
+
+[fraud]
+max_per_number = 5
+voip = "flag"
+
+[number_policy]
+deny_countries = ["RU"]
+"#;
+
+    const YAML: &str = r#"
+port: "6000"
+balancer: round-robin
+carriers:
+  - name: carrier_1
+    type: mock
+    chance_sms: 60
+    chance_voice: 50
+    weight: 2
+    countries: [DE, AT]
+ranking:
+  step_weights: [1, 2, 4, 8, 16]
+fraud:
+  max_per_number: 5
+  voip: flag
+number_policy:
+  deny_countries: [RU]
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(TOML).unwrap();
+        assert_eq!(config, serde_yaml::from_str(YAML).unwrap());
+        assert_eq!(config.address(), "localhost:6000");
+        assert_eq!(config.carriers.len(), 1);
+        assert_eq!(config.carriers[0].weight, 2);
+        assert_eq!(config.fraud.max_per_number, Some(5));
+        // omitted settings keep their defaults
+        assert_eq!(config.max_concurrency, 1024);
+        assert_eq!(config.fraud.window_secs, 3600);
+        assert_eq!(Config::default().carriers.len(), 3);
+
+        assert!(toml::from_str::<Config>("prot = \"6000\"").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        let args = ServeCommand::from_args(
+            &["telecom", "serve"],
+            &[
+                "-p",
+                "7000",
+                "--voip-numbers",
+                "reject",
+                "--deny-country",
+                "BY",
+            ],
+        )
+        .unwrap();
+        config.apply(&args);
+        assert_eq!(config.port, "7000");
+        assert_eq!(config.balancer().unwrap().kind, BalancerType::RoundRobin);
+        assert_eq!(config.fraud.voip, FraudAction::Reject);
+        assert_eq!(config.fraud.max_per_number, Some(5));
+        assert_eq!(
+            config.number_policy.deny_countries,
+            vec!["BY".to_string()].into_iter().collect()
+        );
+
+        assert!(Config::load(&args).unwrap().balancer().is_err());
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        let vars = [
+            ("TELECOM__PORT", "6001"),
+            ("TELECOM__GRPC_PORT", "50051"),
+            ("TELECOM__FRAUD__MAX_PER_IP", "20"),
+            ("TELECOM__CARRIERS__0__CREDENTIALS", "env:CARRIER_1_TOKEN"),
+            ("TELECOM__NUMBER_POLICY__DENY_COUNTRIES", "[RU, BY]"),
+            ("TELECOM__RANKING__WINDOW_SECS", "86400"),
+            ("PORT", "80"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        config.apply_env(vars).unwrap();
+        assert_eq!(config.port, "6001");
+        assert_eq!(config.grpc_port.as_deref(), Some("50051"));
+        assert_eq!(config.fraud.max_per_ip, Some(20));
+        assert_eq!(config.fraud.max_per_number, Some(5));
+        assert_eq!(
+            config.carriers[0].credentials.as_deref(),
+            Some("env:CARRIER_1_TOKEN")
+        );
+        assert_eq!(config.number_policy.deny_countries.len(), 2);
+        assert_eq!(config.ranking.unwrap().window_secs, Some(86400));
+
+        // flags take precedence over the environment
+        let args = ServeCommand::from_args(&["telecom", "serve"], &["-p", "7000"]).unwrap();
+        let mut config = Config::default();
+        config
+            .apply_env(vec![("TELECOM__PORT".to_string(), "6001".to_string())])
+            .unwrap();
+        config.apply(&args);
+        assert_eq!(config.port, "7000");
+
+        for (name, value) in [
+            ("TELECOM__PROT", "6000"),
+            ("TELECOM__CARRIERS__9__WEIGHT", "2"),
+            ("TELECOM__PORT__NUMBER", "6000"),
+            ("TELECOM__MAX_CONCURRENCY", "many"),
+        ] {
+            assert!(Config::default()
+                .apply_env(vec![(name.to_string(), value.to_string())])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_with_profile() {
+        let config: Config = toml::from_str(
+            r#"
+balancer = "round-robin"
+
+[fraud]
+max_per_number = 5
+max_per_ip = 20
+
+[profiles.dev]
+balancer = "best"
+fraud = { max_per_number = 100 }
+
+[profiles.prod]
+carriers = [{ name = "carrier_live", type = "mock", chance_sms = 90, chance_voice = 90 }]
+
+[profiles.canary]
+inherits = "prod"
+port = "5001"
+
+[profiles.loop]
+inherits = "loop"
+"#,
+        )
+        .unwrap();
+        let dev = config.with_profile("dev").unwrap();
+        assert_eq!(dev.balancer().unwrap().kind, BalancerType::Best);
+        // tables are merged, settings the profile leaves out are inherited
+        assert_eq!(dev.fraud.max_per_number, Some(100));
+        assert_eq!(dev.fraud.max_per_ip, Some(20));
+        assert_eq!(dev.carriers.len(), 3);
+        assert!(dev.profiles.is_empty());
+
+        let canary = config.with_profile("canary").unwrap();
+        assert_eq!(canary.port, "5001");
+        assert_eq!(canary.balancer().unwrap().kind, BalancerType::RoundRobin);
+        assert_eq!(canary.carriers.len(), 1);
+        assert_eq!(canary.carriers[0].name, "carrier_live");
+
+        assert!(config.with_profile("loop").is_err());
+        assert!(config.with_profile("staging").is_err());
+        assert_eq!(
+            config.check(),
+            vec![Problem::new(
+                "profiles.loop",
+                "profile loop inherits from itself"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(Config::default().check().is_empty());
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        config.carriers.push(config.carriers[0].clone());
+        config.carriers[0].credentials = Some("env:TELECOM_TEST_UNSET_TOKEN".to_string());
+        config.carriers[0].weight = 0;
+        config.max_body_bytes = 0;
+        assert_eq!(config.check().len(), 4);
+
+        // routing rules that never take effect
+        let mut config = Config::default();
+        let mut carrier = ProviderConfig::mock("carrier_1", 50, 50);
+        carrier.countries = ["FR", "de"].iter().map(|c| c.to_string()).collect();
+        config.carriers = vec![carrier];
+        let policy = &mut config.number_policy;
+        policy.allow_countries = ["DE", "GB"].iter().map(|c| c.to_string()).collect();
+        policy.deny_countries = vec!["DE".to_string()].into_iter().collect();
+        let problems = config.check();
+        let paths = problems.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "number_policy.allow_countries",
+                "carriers[0].countries",
+                "number_policy.allow_countries",
+            ]
+        );
+        assert_eq!(
+            problems[2].to_string(),
+            "number_policy.allow_countries: GB is allowed but no enabled carrier serves it"
+        );
+
+        // balancer parameters specific to another balancer or beyond the carriers
+        let mut config: Config = toml::from_str(TOML).unwrap();
+        config.balancer = Some(BalancerConfig {
+            epsilon: Some(0.1),
+            failover_depth: 3,
+            ..BalancerConfig::new(BalancerType::RoundRobin)
+        });
+        let paths = config
+            .check()
+            .into_iter()
+            .map(|p| p.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["balancer", "balancer.failover_depth"]);
+        config.set_balancer(BalancerType::Best);
+        assert_eq!(config.check()[0].path, "carriers[0].weight");
+
+        // the admin API on the verification API's own listener
+        let mut config = Config {
+            admin_port: Some("5000".to_string()),
+            admin_tokens: BTreeMap::from([("alice".to_string(), "a".repeat(16))]),
+            ..Config::default()
+        };
+        assert_eq!(config.check()[0].path, "admin_port");
+        config.admin_bind = Some("10.0.0.1".to_string());
+        assert!(config.check().is_empty());
+        assert_eq!(config.admin_address().as_deref(), Some("10.0.0.1:5000"));
+        config.admin_unix_socket = Some("/run/telecom-admin.sock".to_string());
+        assert_eq!(config.check()[0].path, "admin_unix_socket");
+
+        // the admin API is never served without tokens to authenticate operators with
+        let mut config = Config {
+            admin_on_public: true,
+            ..Config::default()
+        };
+        assert_eq!(config.check()[0].path, "admin_tokens");
+        config
+            .admin_tokens
+            .insert("bob".to_string(), "short".to_string());
+        assert_eq!(config.check()[0].path, "admin_tokens.bob");
+        config.admin_port = Some("5100".to_string());
+        assert_eq!(config.check()[0].path, "admin_on_public");
+
+        let config = Config {
+            idle_timeout_secs: 0,
+            ..Config::default()
+        };
+        assert_eq!(
+            config.check()[0].to_string(),
+            "idle_timeout_secs must be greater than 0"
+        );
+        let config = Config {
+            slow_request_ms: Some(0),
+            ..Config::default()
+        };
+        assert_eq!(config.check()[0].path, "");
+
+        // a file repo without a file
+        let mut config = Config::default();
+        config.repo.backend = RepoBackend::File;
+        assert_eq!(config.check()[0].path, "repo.path");
+        config.repo.path = Some("/var/lib/telecom/history.jsonl".to_string());
+        assert!(config.check().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::pagination::Page;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+// replies that opt a number out of or back into messages, carriers treat these the same way
+const OPT_OUT_KEYWORDS: &[&str] = &["STOP", "STOPALL", "UNSUBSCRIBE", "CANCEL", "END", "QUIT"];
+const OPT_IN_KEYWORDS: &[&str] = &["START", "UNSTOP"];
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Keyword {
+    OptOut,
+    OptIn,
+}
+
+// keyword reads an inbound message, only a message consisting of just the keyword counts so
+// replies merely mentioning one aren't taken as consent changes
+pub fn keyword(body: &str) -> Option<Keyword> {
+    let body = body.trim().to_ascii_uppercase();
+    if OPT_OUT_KEYWORDS.contains(&body.as_str()) {
+        Some(Keyword::OptOut)
+    } else if OPT_IN_KEYWORDS.contains(&body.as_str()) {
+        Some(Keyword::OptIn)
+    } else {
+        None
+    }
+}
+
+// OptOut records that a number must not be sent verification messages
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct OptOut {
+    pub number: String,
+    pub source: OptOutSource,
+    pub opted_out_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OptOutSource {
+    // the number replied with an opt-out keyword to a message of this carrier
+    Carrier { carrier: String },
+    Admin,
+}
+
+// ConsentStore keeps the numbers that opted out, one record per number
+pub trait ConsentStore: Send + Sync {
+    // opt_out replaces any earlier record of the number
+    fn opt_out(&mut self, opt_out: OptOut);
+    fn opt_in(&mut self, number: &str) -> Option<OptOut>;
+    fn get(&self, number: &str) -> Option<&OptOut>;
+    // list returns opted out numbers in order starting at position
+    fn list(&self, position: u64, limit: usize) -> Page<OptOut>;
+    // records evicted so far to stay within the store's bound, those numbers can be sent
+    // messages again
+    fn evicted(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemoryConsentStore {
+    // by number, along with the tick they were recorded at
+    opt_outs: BTreeMap<String, (u64, OptOut)>,
+    // numbers by the tick they opted out at, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    max_opt_outs: usize,
+    evicted: u64,
+}
+
+impl Default for InMemoryConsentStore {
+    fn default() -> Self {
+        Self {
+            opt_outs: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            max_opt_outs: usize::MAX,
+            evicted: 0,
+        }
+    }
+}
+
+impl InMemoryConsentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_max_opt_outs remembers at most max opted out numbers, forgetting the ones that opted
+    // out longest ago beyond it
+    pub fn with_max_opt_outs(mut self, max: usize) -> Self {
+        self.max_opt_outs = max;
+        self
+    }
+}
+
+impl ConsentStore for InMemoryConsentStore {
+    fn opt_out(&mut self, opt_out: OptOut) {
+        self.opt_in(&opt_out.number);
+        self.tick += 1;
+        self.order.insert(self.tick, opt_out.number.clone());
+        self.opt_outs
+            .insert(opt_out.number.clone(), (self.tick, opt_out));
+        while self.opt_outs.len() > self.max_opt_outs {
+            match self.order.pop_first() {
+                Some((_, number)) => {
+                    self.opt_outs.remove(&number);
+                    self.evicted += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn opt_in(&mut self, number: &str) -> Option<OptOut> {
+        let (tick, opt_out) = self.opt_outs.remove(number)?;
+        self.order.remove(&tick);
+        Some(opt_out)
+    }
+
+    fn get(&self, number: &str) -> Option<&OptOut> {
+        self.opt_outs.get(number).map(|(_, o)| o)
+    }
+
+    fn list(&self, position: u64, limit: usize) -> Page<OptOut> {
+        let items = self
+            .opt_outs
+            .values()
+            .skip(position as usize)
+            .take(limit)
+            .map(|(_, o)| o.clone())
+            .collect();
+        Page::new(items, position, limit, self.opt_outs.len() as u64)
+    }
+
+    fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword() {
+        assert_eq!(keyword("STOP"), Some(Keyword::OptOut));
+        assert_eq!(keyword(" unsubscribe\n"), Some(Keyword::OptOut));
+        assert_eq!(keyword("Start"), Some(Keyword::OptIn));
+        assert_eq!(keyword("please stop"), None);
+        assert_eq!(keyword("123456"), None);
+    }
+
+    #[test]
+    fn test_bounded_store() {
+        let opt_out = |number: &str| OptOut {
+            number: number.to_string(),
+            source: OptOutSource::Admin,
+            opted_out_at: Utc::now(),
+        };
+        let mut store = InMemoryConsentStore::new().with_max_opt_outs(2);
+        store.opt_out(opt_out("+15555550100"));
+        store.opt_out(opt_out("+15555550101"));
+        // opting out again counts as the most recent opt-out
+        store.opt_out(opt_out("+15555550100"));
+        store.opt_out(opt_out("+15555550102"));
+        assert!(store.get("+15555550101").is_none());
+        assert!(store.get("+15555550100").is_some());
+        assert_eq!(store.evicted(), 1);
+        // numbers opting back in aren't evictions
+        store.opt_in("+15555550100");
+        store.opt_out(opt_out("+15555550103"));
+        assert_eq!(store.list(0, 10).items.len(), 2);
+        assert_eq!(store.evicted(), 1);
+    }
+}
@@ -0,0 +1,314 @@
+// country calling codes mapped to the ISO 3166-1 alpha-2 code of the country they belong to,
+// shared codes such as +1 and +7 resolve to the largest member country
+const CALLING_CODES: &[(&str, &str)] = &[
+    ("1", "US"),
+    ("7", "RU"),
+    ("20", "EG"),
+    ("27", "ZA"),
+    ("30", "GR"),
+    ("31", "NL"),
+    ("32", "BE"),
+    ("33", "FR"),
+    ("34", "ES"),
+    ("36", "HU"),
+    ("39", "IT"),
+    ("40", "RO"),
+    ("41", "CH"),
+    ("43", "AT"),
+    ("44", "GB"),
+    ("45", "DK"),
+    ("46", "SE"),
+    ("47", "NO"),
+    ("48", "PL"),
+    ("49", "DE"),
+    ("51", "PE"),
+    ("52", "MX"),
+    ("53", "CU"),
+    ("54", "AR"),
+    ("55", "BR"),
+    ("56", "CL"),
+    ("57", "CO"),
+    ("58", "VE"),
+    ("60", "MY"),
+    ("61", "AU"),
+    ("62", "ID"),
+    ("63", "PH"),
+    ("64", "NZ"),
+    ("65", "SG"),
+    ("66", "TH"),
+    ("81", "JP"),
+    ("82", "KR"),
+    ("84", "VN"),
+    ("86", "CN"),
+    ("90", "TR"),
+    ("91", "IN"),
+    ("92", "PK"),
+    ("93", "AF"),
+    ("94", "LK"),
+    ("95", "MM"),
+    ("98", "IR"),
+    ("211", "SS"),
+    ("212", "MA"),
+    ("213", "DZ"),
+    ("216", "TN"),
+    ("218", "LY"),
+    ("220", "GM"),
+    ("221", "SN"),
+    ("222", "MR"),
+    ("223", "ML"),
+    ("224", "GN"),
+    ("225", "CI"),
+    ("226", "BF"),
+    ("227", "NE"),
+    ("228", "TG"),
+    ("229", "BJ"),
+    ("230", "MU"),
+    ("231", "LR"),
+    ("232", "SL"),
+    ("233", "GH"),
+    ("234", "NG"),
+    ("235", "TD"),
+    ("236", "CF"),
+    ("237", "CM"),
+    ("238", "CV"),
+    ("239", "ST"),
+    ("240", "GQ"),
+    ("241", "GA"),
+    ("242", "CG"),
+    ("243", "CD"),
+    ("244", "AO"),
+    ("245", "GW"),
+    ("248", "SC"),
+    ("249", "SD"),
+    ("250", "RW"),
+    ("251", "ET"),
+    ("252", "SO"),
+    ("253", "DJ"),
+    ("254", "KE"),
+    ("255", "TZ"),
+    ("256", "UG"),
+    ("257", "BI"),
+    ("258", "MZ"),
+    ("260", "ZM"),
+    ("261", "MG"),
+    ("262", "RE"),
+    ("263", "ZW"),
+    ("264", "NA"),
+    ("265", "MW"),
+    ("266", "LS"),
+    ("267", "BW"),
+    ("268", "SZ"),
+    ("269", "KM"),
+    ("290", "SH"),
+    ("291", "ER"),
+    ("297", "AW"),
+    ("298", "FO"),
+    ("299", "GL"),
+    ("350", "GI"),
+    ("351", "PT"),
+    ("352", "LU"),
+    ("353", "IE"),
+    ("354", "IS"),
+    ("355", "AL"),
+    ("356", "MT"),
+    ("357", "CY"),
+    ("358", "FI"),
+    ("359", "BG"),
+    ("370", "LT"),
+    ("371", "LV"),
+    ("372", "EE"),
+    ("373", "MD"),
+    ("374", "AM"),
+    ("375", "BY"),
+    ("376", "AD"),
+    ("377", "MC"),
+    ("378", "SM"),
+    ("380", "UA"),
+    ("381", "RS"),
+    ("382", "ME"),
+    ("383", "XK"),
+    ("385", "HR"),
+    ("386", "SI"),
+    ("387", "BA"),
+    ("389", "MK"),
+    ("420", "CZ"),
+    ("421", "SK"),
+    ("423", "LI"),
+    ("500", "FK"),
+    ("501", "BZ"),
+    ("502", "GT"),
+    ("503", "SV"),
+    ("504", "HN"),
+    ("505", "NI"),
+    ("506", "CR"),
+    ("507", "PA"),
+    ("509", "HT"),
+    ("590", "GP"),
+    ("591", "BO"),
+    ("592", "GY"),
+    ("593", "EC"),
+    ("595", "PY"),
+    ("597", "SR"),
+    ("598", "UY"),
+    ("670", "TL"),
+    ("673", "BN"),
+    ("674", "NR"),
+    ("675", "PG"),
+    ("676", "TO"),
+    ("677", "SB"),
+    ("678", "VU"),
+    ("679", "FJ"),
+    ("680", "PW"),
+    ("685", "WS"),
+    ("686", "KI"),
+    ("687", "NC"),
+    ("689", "PF"),
+    ("691", "FM"),
+    ("692", "MH"),
+    ("850", "KP"),
+    ("852", "HK"),
+    ("853", "MO"),
+    ("855", "KH"),
+    ("856", "LA"),
+    ("880", "BD"),
+    ("886", "TW"),
+    ("960", "MV"),
+    ("961", "LB"),
+    ("962", "JO"),
+    ("963", "SY"),
+    ("964", "IQ"),
+    ("965", "KW"),
+    ("966", "SA"),
+    ("967", "YE"),
+    ("968", "OM"),
+    ("970", "PS"),
+    ("971", "AE"),
+    ("972", "IL"),
+    ("973", "BH"),
+    ("974", "QA"),
+    ("975", "BT"),
+    ("976", "MN"),
+    ("977", "NP"),
+    ("992", "TJ"),
+    ("993", "TM"),
+    ("994", "AZ"),
+    ("995", "GE"),
+    ("996", "KG"),
+    ("998", "UZ"),
+];
+
+// calling_code returns the country calling code prefix of an international number, the leading
+// "+" is optional and formatting characters other than digits are ignored
+pub fn calling_code(number: &str) -> Option<&'static str> {
+    let digits = number
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .take(3)
+        .collect::<String>();
+    // calling codes are prefix free, so the first match is the only match
+    CALLING_CODES
+        .iter()
+        .find(|(code, _)| digits.starts_with(code))
+        .map(|(code, _)| *code)
+}
+
+// country_of returns the ISO 3166-1 alpha-2 code of the country an international number belongs to
+pub fn country_of(number: &str) -> Option<&'static str> {
+    let code = calling_code(number)?;
+    CALLING_CODES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, country)| *country)
+}
+
+// region_code returns the calling code of numbers in country, shared codes only resolve for their
+// largest member country
+pub fn region_code(country: &str) -> Option<&'static str> {
+    CALLING_CODES
+        .iter()
+        .find(|(_, c)| c.eq_ignore_ascii_case(country))
+        .map(|(code, _)| *code)
+}
+
+// E.164 numbers have at most 15 digits, calling code included
+const MAX_DIGITS: usize = 15;
+const MIN_DIGITS: usize = 7;
+
+// normalize formats a number as E.164, dropping spaces, dashes, dots and parentheses. Numbers in
+// national format get the calling code of default_region in place of their trunk prefix
+pub fn normalize(number: &str, default_region: Option<&str>) -> Result<String, String> {
+    let number = number.trim();
+    if !number
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c.is_ascii_digit() || " -.()".contains(c) || (c == '+' && i == 0))
+    {
+        return Err(format!("invalid phone number: {}", number));
+    }
+    let digits = number
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>();
+    let international = match (number.starts_with('+'), digits.strip_prefix("00")) {
+        (true, _) => digits,
+        // 00 is the international call prefix across most of the world
+        (false, Some(international)) => international.to_string(),
+        (false, None) => {
+            let code = default_region
+                .and_then(region_code)
+                .ok_or_else(|| format!("number must be in international format: {}", number))?;
+            let national = digits.strip_prefix('0').unwrap_or(&digits);
+            // NANP numbers are dialled with a leading 1 rather than a 0
+            let national = match (code, national.len()) {
+                ("1", 11) => national.strip_prefix('1').unwrap_or(national),
+                _ => national,
+            };
+            format!("{}{}", code, national)
+        }
+    };
+    if !(MIN_DIGITS..=MAX_DIGITS).contains(&international.len()) {
+        return Err(format!("invalid phone number length: {}", number));
+    }
+    Ok(format!("+{}", international))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_country_of() {
+        assert_eq!(country_of("+49 171 2345678"), Some("DE"));
+        assert_eq!(country_of("14155550100"), Some("US"));
+        assert_eq!(country_of("+380441234567"), Some("UA"));
+        assert_eq!(calling_code("+380441234567"), Some("380"));
+        assert_eq!(country_of("+999"), None);
+        assert_eq!(country_of(""), None);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let de = Some("DE");
+        assert_eq!(normalize("0171 2345678", de).unwrap(), "+491712345678");
+        assert_eq!(normalize("+49171 2345678", de).unwrap(), "+491712345678");
+        assert_eq!(
+            normalize("0049 (171) 234-5678", None).unwrap(),
+            "+491712345678"
+        );
+        assert_eq!(
+            normalize("1 (415) 555-0100", Some("us")).unwrap(),
+            "+14155550100"
+        );
+        assert_eq!(
+            normalize("415.555.0100", Some("US")).unwrap(),
+            "+14155550100"
+        );
+
+        assert!(normalize("0171 2345678", None).is_err());
+        assert!(normalize("0171 2345678", Some("XX")).is_err());
+        assert!(normalize("+49 171 CALL-ME", de).is_err());
+        assert!(normalize("49+1712345678", None).is_err());
+        assert!(normalize("+555", None).is_err());
+        assert!(normalize("+4917123456789012", None).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use crate::balancer::BalancerState;
+use crate::fraud::Occupancy;
+use crate::otp::SessionCounts;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+// DebugState is a snapshot of the running server served at GET /debug/state for troubleshooting,
+// its fields may change between releases
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct DebugState {
+    pub balancer: BalancerState,
+    pub failover_depth: usize,
+    pub carriers: Vec<CarrierHealth>,
+    pub sessions: SessionCounts,
+    // attempts waiting for a background retry or for the next ladder step
+    pub pending_retries: usize,
+    pub pending_escalations: usize,
+    // velocity limits by dimension, number, prefix or ip
+    pub velocity: BTreeMap<String, Occupancy>,
+    pub throttled_prefixes: usize,
+    pub repo: RepoStats,
+}
+
+// CarrierHealth is how a registered carrier has fared since the server started
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct CarrierHealth {
+    pub name: String,
+    pub draining: bool,
+    pub weight: u32,
+    // weighted average of the ranked attempts, less is better, omitted until it was ranked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<f32>,
+    pub delivered: u64,
+    pub unreachable: u64,
+    // delivered too few verifications recently, see alerting::AlertConfig
+    pub degraded: bool,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct RepoStats {
+    pub attempts: usize,
+    pub decisions: usize,
+}
@@ -0,0 +1,396 @@
+use crate::country;
+use crate::repo::{Channel, VerificationStep};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+// bounds on a ladder, delays and reply waits are sat out by the scheduler but a code has to
+// outlive them
+pub const MAX_STEPS: usize = 8;
+pub const MAX_DELAY_SECS: u64 = 60;
+pub const MAX_REPLY_WAIT_SECS: u64 = 600;
+
+// EscalationStep is a single delivery attempt of a ladder
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EscalationStep {
+    pub channel: Channel,
+    // seconds waited before attempting this step, the first step of a ladder is attempted right
+    // away
+    #[serde(default)]
+    pub delay_secs: u64,
+    // seconds the user has to submit the code this step delivered before the next step is
+    // attempted anyway, later steps are only attempted when delivery fails when 0
+    #[serde(default)]
+    pub reply_wait_secs: u64,
+}
+
+impl EscalationStep {
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay_secs)
+    }
+
+    pub fn reply_wait(&self) -> Duration {
+        Duration::from_secs(self.reply_wait_secs)
+    }
+}
+
+// Ladder is the sequence of channels a carrier tries until the message is delivered
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Clone)]
+#[serde(transparent)]
+pub struct Ladder {
+    pub steps: Vec<EscalationStep>,
+}
+
+impl Default for Ladder {
+    // SMS twice, then a voice call twice
+    fn default() -> Self {
+        let step = |channel| EscalationStep {
+            channel,
+            delay_secs: 0,
+            reply_wait_secs: 0,
+        };
+        Self {
+            steps: vec![
+                step(Channel::Sms),
+                step(Channel::Sms),
+                step(Channel::Voice),
+                step(Channel::Voice),
+            ],
+        }
+    }
+}
+
+impl Ladder {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.steps.is_empty() || self.steps.len() > MAX_STEPS {
+            return Err(anyhow!(
+                "escalation ladders must have between 1 and {} steps",
+                MAX_STEPS
+            ));
+        }
+        if self.steps.iter().any(|s| s.delay_secs > MAX_DELAY_SECS) {
+            return Err(anyhow!(
+                "escalation step delays can't exceed {} seconds",
+                MAX_DELAY_SECS
+            ));
+        }
+        if self
+            .steps
+            .iter()
+            .any(|s| s.reply_wait_secs > MAX_REPLY_WAIT_SECS)
+        {
+            return Err(anyhow!(
+                "escalation reply waits can't exceed {} seconds",
+                MAX_REPLY_WAIT_SECS
+            ));
+        }
+        Ok(())
+    }
+
+    // stage is the part of the ladder walked at once from step start, a step with a reply wait
+    // on its own or else the steps up to the next one with a reply wait or a delay, along with
+    // the index of the step after it
+    pub fn stage(&self, start: usize) -> (Ladder, usize) {
+        let start = start.min(self.steps.len());
+        let end = match self.steps.get(start) {
+            Some(s) if s.reply_wait_secs > 0 => start + 1,
+            _ => self.steps[start..]
+                .iter()
+                .enumerate()
+                .position(|(i, s)| s.reply_wait_secs > 0 || (i > 0 && s.delay_secs > 0))
+                .map_or(self.steps.len(), |i| start + i),
+        };
+        let steps = self.steps[start..end].to_vec();
+        (Ladder { steps }, end)
+    }
+
+    // escalates reports whether a stage ending before step next waits for a reply before the
+    // steps after it are attempted
+    pub fn escalates(&self, next: usize) -> bool {
+        next < self.steps.len() && next > 0 && self.steps[next - 1].reply_wait_secs > 0
+    }
+
+    // staged_step is the VerificationStep a stage starting at step start delivered on when walked
+    // on its own, the first attempt over a channel in it isn't the first one if earlier stages
+    // used that channel
+    pub fn staged_step(&self, start: usize, step: VerificationStep) -> VerificationStep {
+        let earlier = |channel| self.steps[..start].iter().any(|s| s.channel == channel);
+        match step {
+            VerificationStep::FirstSMS if earlier(Channel::Sms) => VerificationStep::SecondSMS,
+            VerificationStep::FirstTextToSpeech if earlier(Channel::Voice) => {
+                VerificationStep::SecondTextToSpeech
+            }
+            step => step,
+        }
+    }
+
+    // preferring keeps only the steps over the preferred channel, attempting the first of them
+    // right away, a ladder without any gets a single step over it
+    pub fn preferring(&self, preference: ChannelPreference) -> Ladder {
+        let channel = match preference {
+            ChannelPreference::Sms => Channel::Sms,
+            ChannelPreference::Voice => Channel::Voice,
+            ChannelPreference::Auto => return self.clone(),
+        };
+        let mut steps = self
+            .steps
+            .iter()
+            .filter(|s| s.channel == channel)
+            .copied()
+            .collect::<Vec<_>>();
+        match steps.first_mut() {
+            Some(first) => first.delay_secs = 0,
+            None => steps.push(EscalationStep {
+                channel,
+                delay_secs: 0,
+                reply_wait_secs: 0,
+            }),
+        }
+        Ladder { steps }
+    }
+
+    // verification_step is the VerificationStep recorded when step index delivered the message,
+    // the first attempt over a channel counts as its first step and any later one as its second
+    pub fn verification_step(&self, index: usize) -> VerificationStep {
+        let channel = match self.steps.get(index) {
+            Some(s) => s.channel,
+            None => return VerificationStep::Unreachable,
+        };
+        let repeated = self.steps[..index].iter().any(|s| s.channel == channel);
+        match (channel, repeated) {
+            (Channel::Sms, false) => VerificationStep::FirstSMS,
+            (Channel::Sms, true) => VerificationStep::SecondSMS,
+            (Channel::Voice, false) => VerificationStep::FirstTextToSpeech,
+            (Channel::Voice, true) => VerificationStep::SecondTextToSpeech,
+        }
+    }
+}
+
+// ChannelPreference is the channel a client asks a verification to be delivered over
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelPreference {
+    Sms,
+    Voice,
+    // walk the configured ladder
+    #[default]
+    Auto,
+}
+
+impl From<Channel> for ChannelPreference {
+    fn from(channel: Channel) -> Self {
+        match channel {
+            Channel::Sms => Self::Sms,
+            Channel::Voice => Self::Voice,
+        }
+    }
+}
+
+impl FromStr for ChannelPreference {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sms" => Ok(Self::Sms),
+            "voice" => Ok(Self::Voice),
+            "auto" => Ok(Self::Auto),
+            _ => Err(anyhow!("invalid channel: {}", s)),
+        }
+    }
+}
+
+// parses comma separated steps with an optional delay and reply wait, e.g.
+// "sms/60,sms:30,voice:30"
+impl FromStr for Ladder {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        for step in s.split(',').map(str::trim) {
+            let (step, reply_wait) = match step.split_once('/') {
+                Some((step, wait)) => (step, Some(wait)),
+                None => (step, None),
+            };
+            let (channel, delay) = match step.split_once(':') {
+                Some((channel, delay)) => (channel, Some(delay)),
+                None => (step, None),
+            };
+            let channel = match channel.to_ascii_lowercase().as_str() {
+                "sms" => Channel::Sms,
+                "voice" => Channel::Voice,
+                _ => return Err(anyhow!("invalid escalation channel: {}", channel)),
+            };
+            let delay_secs = match delay {
+                Some(d) => d
+                    .parse()
+                    .map_err(|_| anyhow!("invalid escalation delay: {}", d))?,
+                None => 0,
+            };
+            let reply_wait_secs = match reply_wait {
+                Some(w) => w
+                    .parse()
+                    .map_err(|_| anyhow!("invalid escalation reply wait: {}", w))?,
+                None => 0,
+            };
+            steps.push(EscalationStep {
+                channel,
+                delay_secs,
+                reply_wait_secs,
+            });
+        }
+        let ladder = Self { steps };
+        ladder.validate()?;
+        Ok(ladder)
+    }
+}
+
+// EscalationConfig selects the ladder walked for a number
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone, Default)]
+pub struct EscalationConfig {
+    // used for numbers without a country override
+    #[serde(default)]
+    pub default: Ladder,
+    // ladders keyed by the ISO 3166-1 alpha-2 code of the destination country
+    #[serde(default)]
+    pub countries: BTreeMap<String, Ladder>,
+}
+
+impl EscalationConfig {
+    // validate checks every ladder and normalizes country codes to upper case
+    pub fn validate(&mut self) -> Result<(), Error> {
+        self.default.validate()?;
+        let mut countries = BTreeMap::new();
+        for (code, ladder) in std::mem::take(&mut self.countries) {
+            if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(anyhow!("invalid country code: {}", code));
+            }
+            ladder.validate().map_err(|e| anyhow!("{}: {}", code, e))?;
+            countries.insert(code.to_ascii_uppercase(), ladder);
+        }
+        self.countries = countries;
+        Ok(())
+    }
+
+    pub fn ladder_for(&self, number: &str) -> &Ladder {
+        country::country_of(number)
+            .and_then(|c| self.countries.get(c))
+            .unwrap_or(&self.default)
+    }
+}
+
+// parses a country override passed as "DE=voice,sms:30"
+pub fn parse_country_ladder(s: &str) -> Result<(String, Ladder), String> {
+    let (country, ladder) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <country>=<ladder>, got {}", s))?;
+    let ladder = ladder.parse().map_err(|e: Error| e.to_string())?;
+    Ok((country.trim().to_string(), ladder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ladder() {
+        let ladder: Ladder = "sms, voice:30,SMS".parse().unwrap();
+        assert_eq!(ladder.steps.len(), 3);
+        assert_eq!(ladder.steps[1].channel, Channel::Voice);
+        assert_eq!(ladder.steps[1].delay_secs, 30);
+        assert_eq!(ladder.steps[2].channel, Channel::Sms);
+
+        assert!("".parse::<Ladder>().is_err());
+        assert!("fax".parse::<Ladder>().is_err());
+        assert!("sms:soon".parse::<Ladder>().is_err());
+        assert!("sms:61".parse::<Ladder>().is_err());
+        assert!("sms/later".parse::<Ladder>().is_err());
+        assert!("sms/601".parse::<Ladder>().is_err());
+        assert!("sms,sms,sms,sms,sms,sms,sms,sms,sms"
+            .parse::<Ladder>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_verification_step() {
+        let ladder: Ladder = "voice,sms,voice,sms,sms".parse().unwrap();
+        let steps = (0..6)
+            .map(|i| ladder.verification_step(i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                VerificationStep::FirstTextToSpeech,
+                VerificationStep::FirstSMS,
+                VerificationStep::SecondTextToSpeech,
+                VerificationStep::SecondSMS,
+                VerificationStep::SecondSMS,
+                VerificationStep::Unreachable,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stage() {
+        let ladder: Ladder = "sms/60,sms,voice:10,voice/120,sms".parse().unwrap();
+        assert_eq!(ladder.steps[0].reply_wait_secs, 60);
+        assert_eq!(ladder.steps[2].delay_secs, 10);
+        assert_eq!(ladder.stage(0), ("sms/60".parse().unwrap(), 1));
+        assert_eq!(ladder.stage(1), ("sms".parse().unwrap(), 2));
+        assert_eq!(ladder.stage(2), ("voice:10".parse().unwrap(), 3));
+        assert_eq!(ladder.stage(3), ("voice/120".parse().unwrap(), 4));
+        assert_eq!(ladder.stage(4), ("sms".parse().unwrap(), 5));
+        assert!(ladder.stage(5).0.steps.is_empty());
+        assert_eq!(
+            (1..=5).map(|i| ladder.escalates(i)).collect::<Vec<_>>(),
+            vec![true, false, false, true, false]
+        );
+
+        let (stage, _) = ladder.stage(1);
+        assert_eq!(
+            ladder.staged_step(1, stage.verification_step(0)),
+            VerificationStep::SecondSMS
+        );
+        let (stage, _) = ladder.stage(2);
+        assert_eq!(
+            ladder.staged_step(2, stage.verification_step(0)),
+            VerificationStep::FirstTextToSpeech
+        );
+        assert_eq!(
+            ladder.staged_step(3, VerificationStep::FirstTextToSpeech),
+            VerificationStep::SecondTextToSpeech
+        );
+    }
+
+    #[test]
+    fn test_preferring() {
+        let ladder: Ladder = "sms,sms:20,voice:30,voice:30".parse().unwrap();
+        assert_eq!(ladder.preferring(ChannelPreference::Auto), ladder);
+        assert_eq!(
+            ladder.preferring(ChannelPreference::Voice),
+            "voice,voice:30".parse().unwrap()
+        );
+        assert_eq!(
+            ladder.preferring(ChannelPreference::Sms),
+            "sms,sms:20".parse().unwrap()
+        );
+        let sms_only: Ladder = "sms:10".parse().unwrap();
+        assert_eq!(
+            sms_only.preferring(ChannelPreference::Voice),
+            "voice".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ladder_for() {
+        let (country, ladder) = parse_country_ladder("de=voice").unwrap();
+        let mut config = EscalationConfig::default();
+        config.countries.insert(country, ladder.clone());
+        config.validate().unwrap();
+        assert_eq!(config.ladder_for("+491711234567"), &ladder);
+        assert_eq!(config.ladder_for("+14155550100"), &Ladder::default());
+
+        config.countries.insert("DEU".to_string(), ladder);
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::middleware;
+use crate::repo::VerificationStep;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+// number of events buffered per subscriber before slow subscribers start skipping events
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Sent,
+    Delivered,
+    Verified,
+    Failed,
+    // no carrier step delivered the code, another attempt is scheduled
+    Retrying,
+    // the code wasn't submitted before the attempt's validity window ended
+    Expired,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Sent => "sent",
+            EventKind::Delivered => "delivered",
+            EventKind::Verified => "verified",
+            EventKind::Failed => "failed",
+            EventKind::Retrying => "retrying",
+            EventKind::Expired => "expired",
+        }
+    }
+}
+
+// VerificationEvent describes a single lifecycle transition of a verification attempt
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct VerificationEvent {
+    pub kind: EventKind,
+    pub carrier: String,
+    pub number: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<VerificationStep>,
+    pub time: DateTime<Utc>,
+}
+
+impl VerificationEvent {
+    pub fn new(kind: EventKind, carrier: &str, number: &str) -> Self {
+        Self {
+            kind,
+            carrier: carrier.to_string(),
+            number: number.to_string(),
+            step: None,
+            time: Utc::now(),
+        }
+    }
+
+    pub fn with_step(mut self, step: VerificationStep) -> Self {
+        self.step = Some(step);
+        self
+    }
+
+    // masked hides most of the number for operators watching events, clients notified through
+    // their callback_url get the number they asked to verify
+    pub fn masked(mut self) -> Self {
+        self.number = middleware::mask_number(&self.number);
+        self
+    }
+}
+
+// EventBus fans verification events out to every live subscriber, such as the SSE endpoint
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<VerificationEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    // publish drops the event when nobody is subscribed
+    pub fn publish(&self, event: VerificationEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<VerificationEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,162 @@
+use crate::repo::{Record, VerificationRepo};
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::io::{BufRead, Write};
+
+// records read from or written to the repo at a time
+const BATCH: usize = 1000;
+
+// Report counts the records moved by an export or import
+#[derive(Serialize, Debug, Default, PartialEq, Clone, Copy)]
+pub struct Report {
+    pub attempts: u64,
+    pub decisions: u64,
+}
+
+impl Report {
+    pub fn total(&self) -> u64 {
+        self.attempts + self.decisions
+    }
+}
+
+// export writes every attempt and then every decision of repo to out as JSON lines, progress is
+// called after each batch
+pub fn export(
+    repo: &dyn VerificationRepo,
+    out: &mut dyn Write,
+    progress: &mut dyn FnMut(&Report),
+) -> Result<Report, Error> {
+    let mut report = Report::default();
+    let mut position = 0;
+    loop {
+        let page = repo.list_attempts(position, BATCH);
+        for entry in page.items {
+            write_record(out, &Record::Attempt(entry))?;
+            report.attempts += 1;
+            position += 1;
+        }
+        progress(&report);
+        if page.next_cursor.is_none() {
+            break;
+        }
+    }
+    position = 0;
+    loop {
+        let page = repo.list_decisions(position, BATCH);
+        for decision in page.items {
+            write_record(out, &Record::Decision(decision))?;
+            report.decisions += 1;
+            position += 1;
+        }
+        progress(&report);
+        if page.next_cursor.is_none() {
+            break;
+        }
+    }
+    out.flush()?;
+    Ok(report)
+}
+
+fn write_record(out: &mut dyn Write, record: &Record) -> Result<(), Error> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+// import stores the records of an export in repo in the order they were exported, progress is
+// called after every BATCH records. Lines that aren't records fail the import at that line,
+// leaving the records before it stored
+pub fn import(
+    repo: &dyn VerificationRepo,
+    input: &mut dyn BufRead,
+    progress: &mut dyn FnMut(&Report),
+) -> Result<Report, Error> {
+    let mut report = Report::default();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| anyhow!("line {}: {}", i + 1, e))?;
+        match record {
+            Record::Attempt(entry) => {
+                repo.store_attempt(entry)?;
+                report.attempts += 1;
+            }
+            Record::Decision(decision) => {
+                repo.store_decision(decision)?;
+                report.decisions += 1;
+            }
+        }
+        if report.total() % BATCH as u64 == 0 {
+            progress(&report);
+        }
+    }
+    progress(&report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fraud::{FraudAction, FraudDecision};
+    use crate::lookup::LineType;
+    use crate::repo::{VerificationEntry, VerificationKeeper, VerificationStep};
+
+    #[test]
+    fn test_export_import() {
+        let source = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+        for i in 0..2500 {
+            source
+                .store_attempt(VerificationEntry {
+                    carrier: format!("carrier_{}", i % 3).into(),
+                    number: format!("0177{}", i),
+                    time: chrono::Utc::now(),
+                    step: VerificationStep::FirstSMS,
+                    simulated: i % 2 == 0,
+                    correlation_id: None,
+                })
+                .unwrap();
+        }
+        source
+            .store_decision(FraudDecision {
+                number: "01770".to_string(),
+                ip: Some("192.0.2.1".parse().unwrap()),
+                score: 1.2,
+                action: FraudAction::Flag,
+                reasons: vec!["number: 6/5".to_string()],
+                line_type: LineType::Mobile,
+                time: chrono::Utc::now(),
+                correlation_id: None,
+            })
+            .unwrap();
+
+        let mut out = Vec::new();
+        let mut batches = 0;
+        let report = export(&source, &mut out, &mut |_| batches += 1).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                attempts: 2500,
+                decisions: 1
+            }
+        );
+        assert_eq!(batches, 4);
+
+        let target = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+        let imported = import(&target, &mut out.as_slice(), &mut |_| ()).unwrap();
+        assert_eq!(imported, report);
+        let attempts = |repo: &VerificationKeeper| {
+            serde_json::to_value(repo.list_attempts(0, 3000).items).unwrap()
+        };
+        assert_eq!(attempts(&target), attempts(&source));
+        assert_eq!(
+            target.list_decisions(0, 10).items,
+            source.list_decisions(0, 10).items
+        );
+
+        let invalid = b"{\"record\":\"attempt\"}\n";
+        let error = import(&target, &mut &invalid[..], &mut |_| ()).unwrap_err();
+        assert!(error.to_string().starts_with("line 1:"), "{}", error);
+    }
+}
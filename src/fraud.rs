@@ -0,0 +1,506 @@
+use crate::lookup::LineType;
+use crate::memory::LruMap;
+use crate::pumping::PumpingConfig;
+use crate::repo::Channel;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+// bounds the codes a risky attempt can be asked for on top of the first one
+pub const MAX_EXTRA_CODES: u32 = 2;
+
+// FraudConfig limits how many verifications may be requested within window_secs, a request is
+// scored by how close it brings the busiest of its number, client IP and number prefix to its limit
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FraudConfig {
+    pub window_secs: u64,
+    // unlimited when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_number: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_ip: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_prefix: Option<u32>,
+    // leading digits of a number, country code included, grouped into one prefix
+    pub prefix_digits: usize,
+    // requests scoring at least flag_score are recorded as flagged but still sent
+    pub flag_score: f32,
+    // requests scoring above reject_score are rejected before any carrier is contacted
+    pub reject_score: f32,
+    // what happens to requests for numbers looked up as VoIP or virtual
+    pub voip: FraudAction,
+    // change how requests that are allowed but score high are verified, the highest tier scored
+    // applies
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub risk_tiers: Vec<RiskTier>,
+    // throttles prefixes whose traffic looks artificially inflated
+    pub pumping: PumpingConfig,
+}
+
+impl Default for FraudConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 3600,
+            max_per_number: None,
+            max_per_ip: None,
+            max_per_prefix: None,
+            prefix_digits: 6,
+            flag_score: 0.8,
+            reject_score: 1.0,
+            voip: FraudAction::Allow,
+            risk_tiers: Vec::new(),
+            pumping: PumpingConfig::default(),
+        }
+    }
+}
+
+impl FraudConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.window_secs == 0 {
+            return Err(anyhow!("window_secs must be greater than 0"));
+        }
+        if [self.max_per_number, self.max_per_ip, self.max_per_prefix].contains(&Some(0)) {
+            return Err(anyhow!("velocity limits must be greater than 0"));
+        }
+        if self.prefix_digits == 0 {
+            return Err(anyhow!("prefix_digits must be greater than 0"));
+        }
+        if !(self.flag_score > 0.0 && self.flag_score <= self.reject_score) {
+            return Err(anyhow!(
+                "flag_score must be greater than 0 and at most reject_score"
+            ));
+        }
+        for tier in &self.risk_tiers {
+            if tier.min_score <= 0.0 {
+                return Err(anyhow!("risk tier min_score must be greater than 0"));
+            }
+            if tier.extra_codes > MAX_EXTRA_CODES {
+                return Err(anyhow!(
+                    "risk tiers can ask for at most {} extra codes",
+                    MAX_EXTRA_CODES
+                ));
+            }
+        }
+        self.pumping.validate()
+    }
+
+    // risk_tier is the tier a request scoring score falls into, None for low risk ones
+    pub fn risk_tier(&self, score: f32) -> Option<&RiskTier> {
+        self.risk_tiers
+            .iter()
+            .filter(|t| score >= t.min_score)
+            .max_by(|a, b| a.min_score.total_cmp(&b.min_score))
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.window_secs as i64)
+    }
+}
+
+// RiskTier is how requests scoring at least min_score are verified instead of walking the
+// number's ladder with a single code
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RiskTier {
+    pub min_score: f32,
+    // deliver only over this channel, e.g. voice for numbers whose SMS traffic looks pumped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+    // codes to submit after the first one before a token is issued, each sent over the channel
+    // the previous one wasn't
+    #[serde(default)]
+    pub extra_codes: u32,
+}
+
+// parses a tier passed as "0.5=voice", "0.9=voice+1" or "0.7=auto+1"
+impl FromStr for RiskTier {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min_score, tier) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <min score>=<channel>[+<extra codes>], got {}", s))?;
+        let min_score = min_score
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid risk tier score: {}", min_score))?;
+        let (channel, extra_codes) = match tier.split_once('+') {
+            Some((channel, extra)) => (channel, Some(extra)),
+            None => (tier, None),
+        };
+        let channel = match channel.trim() {
+            "auto" => None,
+            "sms" => Some(Channel::Sms),
+            "voice" => Some(Channel::Voice),
+            _ => return Err(anyhow!("invalid risk tier channel: {}", channel)),
+        };
+        let extra_codes = match extra_codes {
+            Some(n) => n
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid risk tier extra codes: {}", n))?,
+            None => 0,
+        };
+        Ok(Self {
+            min_score,
+            channel,
+            extra_codes,
+        })
+    }
+}
+
+// RiskDecision is the tier an attempt was verified under, recorded on its session
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct RiskDecision {
+    pub score: f32,
+    #[serde(flatten)]
+    pub tier: RiskTier,
+}
+
+// actions are ordered by severity
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FraudAction {
+    Allow,
+    Flag,
+    Reject,
+}
+
+impl FromStr for FraudAction {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "flag" => Ok(Self::Flag),
+            "reject" => Ok(Self::Reject),
+            _ => Err(anyhow!("invalid fraud action: {}", s)),
+        }
+    }
+}
+
+// FraudDecision is the outcome of scoring a verification request, stored in the repo
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct FraudDecision {
+    pub number: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub ip: Option<IpAddr>,
+    // requests seen in the window over the limit of the busiest dimension, 1 is at the limit
+    pub score: f32,
+    pub action: FraudAction,
+    // the dimensions at or over flag_score, e.g. "number: 6/5"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<String>,
+    pub line_type: LineType,
+    pub time: DateTime<Utc>,
+    // request id of the API call the decision was made for, see trace::TraceContext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl FraudDecision {
+    // with_line_type escalates the decision for numbers on risky line types according to config
+    pub fn with_line_type(mut self, line_type: LineType, config: &FraudConfig) -> Self {
+        self.line_type = line_type;
+        if line_type == LineType::Voip && config.voip != FraudAction::Allow {
+            self.action = self.action.max(config.voip);
+            self.reasons.push("line type: voip".to_string());
+        }
+        self
+    }
+
+    // with_throttle rejects requests to a prefix throttled for traffic pumping
+    pub fn with_throttle(mut self, prefix: Option<&str>) -> Self {
+        if let Some(prefix) = prefix {
+            self.action = FraudAction::Reject;
+            self.reasons.push(format!("throttled: {}", prefix));
+        }
+        self
+    }
+}
+
+// Occupancy is how close the numbers, addresses or prefixes of one velocity limit are to it
+#[derive(Serialize, ToSchema, Debug, Default, PartialEq, Clone)]
+pub struct Occupancy {
+    // numbers, addresses or prefixes requested within the window
+    pub tracked: usize,
+    pub requests: usize,
+    // requests of the busiest one, rejected past limit
+    pub busiest: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+// VelocityTracker counts recent requests per number, client IP and number prefix
+#[derive(Debug, Default)]
+pub struct VelocityTracker {
+    // numbers, addresses and prefixes by when they were last requested, unbounded unless
+    // with_max_keys bounds them
+    seen: LruMap<VecDeque<DateTime<Utc>>>,
+}
+
+impl VelocityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_max_keys forgets the least recently requested numbers, addresses and prefixes beyond
+    // max, their requests no longer count towards their limits
+    pub fn with_max_keys(mut self, max: usize) -> Self {
+        self.seen = LruMap::new(max);
+        self
+    }
+
+    // evicted tells the numbers, addresses and prefixes forgotten so far to stay within max_keys
+    pub fn evicted(&self) -> u64 {
+        self.seen.evicted()
+    }
+
+    // score records a request for number from ip at now and decides what to do with it
+    pub fn score(
+        &mut self,
+        config: &FraudConfig,
+        number: &str,
+        ip: Option<IpAddr>,
+        now: DateTime<Utc>,
+    ) -> FraudDecision {
+        let since = now - config.window();
+        let prefix = number
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .take(config.prefix_digits)
+            .collect::<String>();
+        let mut dimensions = vec![
+            (
+                "number",
+                format!("number:{}", number),
+                config.max_per_number,
+            ),
+            (
+                "prefix",
+                format!("prefix:{}", prefix),
+                config.max_per_prefix,
+            ),
+        ];
+        if let Some(ip) = ip {
+            dimensions.push(("ip", format!("ip:{}", ip), config.max_per_ip));
+        }
+
+        let mut score = 0.0_f32;
+        let mut reasons = Vec::new();
+        for (name, key, limit) in dimensions {
+            // only the requests of what is scored are pruned here, sweep forgets the others
+            let seen = self.seen.touch(&key, VecDeque::new);
+            prune(seen, since);
+            seen.push_back(now);
+            let limit = match limit {
+                Some(l) => l,
+                None => continue,
+            };
+            let dimension_score = seen.len() as f32 / limit as f32;
+            if dimension_score >= config.flag_score {
+                reasons.push(format!("{}: {}/{}", name, seen.len(), limit));
+            }
+            score = score.max(dimension_score);
+        }
+        // the least recently requested beyond max_keys, the ones just requested last
+        self.seen.evict();
+        let action = match score {
+            s if s > config.reject_score => FraudAction::Reject,
+            s if s >= config.flag_score => FraudAction::Flag,
+            _ => FraudAction::Allow,
+        };
+        FraudDecision {
+            number: number.to_string(),
+            ip,
+            score,
+            action,
+            reasons,
+            line_type: LineType::Unknown,
+            time: now,
+            correlation_id: None,
+        }
+    }
+
+    // occupancy returns how full each velocity limit of config is at now, by dimension
+    pub fn occupancy(
+        &self,
+        config: &FraudConfig,
+        now: DateTime<Utc>,
+    ) -> BTreeMap<String, Occupancy> {
+        let mut occupancy = BTreeMap::new();
+        for (name, limit) in &[
+            ("number", config.max_per_number),
+            ("prefix", config.max_per_prefix),
+            ("ip", config.max_per_ip),
+        ] {
+            occupancy.insert(
+                name.to_string(),
+                Occupancy {
+                    limit: *limit,
+                    ..Occupancy::default()
+                },
+            );
+        }
+        let since = now - config.window();
+        for (key, seen) in self.seen.iter() {
+            let dimension = key.split(':').next().unwrap_or_default();
+            let requests = seen.iter().filter(|t| **t > since).count();
+            if let (Some(o), true) = (occupancy.get_mut(dimension), requests > 0) {
+                o.tracked += 1;
+                o.requests += requests;
+                o.busiest = o.busiest.max(requests);
+            }
+        }
+        occupancy
+    }
+
+    // sweep forgets the numbers, addresses and prefixes that went quiet for the window of
+    // config, run periodically rather than on every request
+    pub fn sweep(&mut self, config: &FraudConfig, now: DateTime<Utc>) {
+        let since = now - config.window();
+        self.seen.retain(|_, seen| {
+            prune(seen, since);
+            !seen.is_empty()
+        });
+    }
+
+    // tracked is how many numbers, addresses and prefixes are counted
+    pub fn tracked(&self) -> usize {
+        self.seen.len()
+    }
+}
+
+// prune drops requests made at or before since
+fn prune(seen: &mut VecDeque<DateTime<Utc>>, since: DateTime<Utc>) {
+    while seen.front().is_some_and(|t| *t <= since) {
+        seen.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let config = FraudConfig {
+            max_per_number: Some(2),
+            max_per_ip: Some(4),
+            ..FraudConfig::default()
+        };
+        let ip = Some("10.0.0.1".parse().unwrap());
+        let now = Utc::now();
+        let mut tracker = VelocityTracker::new();
+
+        let first = tracker.score(&config, "+15555550100", ip, now);
+        assert_eq!(first.action, FraudAction::Allow);
+        assert_eq!(first.score, 0.5);
+
+        let second = tracker.score(&config, "+15555550100", ip, now);
+        assert_eq!(second.action, FraudAction::Flag);
+        assert_eq!(second.reasons, vec!["number: 2/2"]);
+
+        let third = tracker.score(&config, "+15555550100", ip, now);
+        assert_eq!(third.action, FraudAction::Reject);
+
+        // the same address moving on to other numbers trips the IP limit
+        tracker.score(&config, "+15555550101", ip, now);
+        let fifth = tracker.score(&config, "+15555550102", ip, now);
+        assert_eq!(fifth.action, FraudAction::Reject);
+        assert_eq!(fifth.reasons, vec!["ip: 5/4"]);
+        let occupancy = tracker.occupancy(&config, now);
+        assert_eq!(
+            occupancy["number"],
+            Occupancy {
+                tracked: 3,
+                requests: 5,
+                busiest: 3,
+                limit: Some(2),
+            }
+        );
+        assert_eq!(occupancy["ip"].busiest, 5);
+
+        // requests outside the window no longer count
+        let later = now + config.window();
+        let after_window = tracker.score(&config, "+15555550100", ip, later);
+        assert_eq!(after_window.action, FraudAction::Allow);
+        // the ones that went quiet are only forgotten by a sweep
+        assert_eq!(tracker.tracked(), 5);
+        tracker.sweep(&config, later);
+        assert_eq!(tracker.tracked(), 3);
+
+        // the least recently requested are forgotten beyond max_keys, each request counts its
+        // number and prefix
+        let mut bounded = VelocityTracker::new().with_max_keys(3);
+        bounded.score(&config, "+15555550100", None, now);
+        bounded.score(&config, "+49171000000", None, now + Duration::seconds(1));
+        assert_eq!(bounded.evicted(), 1);
+        let again = bounded.score(&config, "+49171000000", None, now + Duration::seconds(2));
+        assert_eq!(again.reasons, vec!["number: 2/2"]);
+    }
+
+    #[test]
+    fn test_line_type() {
+        let config = FraudConfig {
+            voip: FraudAction::Flag,
+            ..FraudConfig::default()
+        };
+        let mut tracker = VelocityTracker::new();
+        let decision = tracker
+            .score(&config, "+15555550100", None, Utc::now())
+            .with_line_type(LineType::Voip, &config);
+        assert_eq!(decision.action, FraudAction::Flag);
+        assert_eq!(decision.reasons, vec!["line type: voip"]);
+
+        let mobile = tracker
+            .score(&config, "+15555550101", None, Utc::now())
+            .with_line_type(LineType::Mobile, &config);
+        assert_eq!(mobile.action, FraudAction::Allow);
+    }
+
+    #[test]
+    fn test_risk_tier() {
+        let mut config = FraudConfig {
+            risk_tiers: vec!["0.9=voice+1".parse().unwrap(), "0.5=voice".parse().unwrap()],
+            ..FraudConfig::default()
+        };
+        config.validate().unwrap();
+        assert_eq!(config.risk_tier(0.4), None);
+        let tier = config.risk_tier(0.6).unwrap();
+        assert_eq!((tier.channel, tier.extra_codes), (Some(Channel::Voice), 0));
+        assert_eq!(config.risk_tier(0.95).unwrap().extra_codes, 1);
+
+        assert!("0.5".parse::<RiskTier>().is_err());
+        assert!("high=voice".parse::<RiskTier>().is_err());
+        assert!("0.5=fax".parse::<RiskTier>().is_err());
+        assert_eq!("0.7=auto+2".parse::<RiskTier>().unwrap().channel, None);
+        config.risk_tiers.push("0.7=auto+3".parse().unwrap());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let config = FraudConfig {
+            max_per_prefix: Some(2),
+            prefix_digits: 4,
+            ..FraudConfig::default()
+        };
+        let now = Utc::now();
+        let mut tracker = VelocityTracker::new();
+        tracker.score(&config, "+1 555 0100", None, now);
+        tracker.score(&config, "+15550101", None, now);
+        assert_eq!(
+            tracker.score(&config, "+15550102", None, now).action,
+            FraudAction::Reject
+        );
+        assert_eq!(
+            tracker.score(&config, "+15560100", None, now).action,
+            FraudAction::Allow
+        );
+    }
+}
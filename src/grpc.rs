@@ -0,0 +1,175 @@
+use crate::calls::CallError;
+use crate::escalation::ChannelPreference;
+use crate::http::SharedServer;
+use crate::middleware;
+use crate::otp::{Alphabet, CheckError};
+use crate::proxy::ProxyConfig;
+use crate::trace::TraceContext;
+use crate::{CheckRequest, VerificationRequest};
+use anyhow::Error;
+use chrono::{TimeZone, Utc};
+use proto::verification_server::{Verification, VerificationServer};
+use proto::*;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+pub mod proto {
+    tonic::include_proto!("telecom.v1");
+}
+
+// GrpcService exposes the same VerificationServer used by the HTTP router over gRPC
+pub struct GrpcService {
+    server: SharedServer,
+    proxies: ProxyConfig,
+}
+
+impl GrpcService {
+    pub fn new(server: SharedServer, proxies: ProxyConfig) -> Self {
+        Self { server, proxies }
+    }
+}
+
+#[tonic::async_trait]
+impl Verification for GrpcService {
+    async fn start_verification(
+        &self,
+        request: Request<StartVerificationRequest>,
+    ) -> Result<Response<StartVerificationResponse>, Status> {
+        // gRPC metadata is carried in HTTP/2 headers, so traceparent and forwarded addresses
+        // arrive the same way
+        let headers = request.metadata().clone().into_headers();
+        let trace = TraceContext::from_headers(&headers)
+            .with_correlation_id(middleware::request_id(&headers));
+        let client = self
+            .proxies
+            .client_ip(request.remote_addr().map(|a| a.ip()), &headers);
+        let request = request.into_inner();
+        let time = Utc
+            .timestamp_millis_opt(request.time)
+            .single()
+            .ok_or_else(|| Status::invalid_argument("time is out of range"))?;
+        let request = VerificationRequest {
+            number: request.number,
+            time,
+            callback_url: request.callback_url,
+            code_length: request.code_length.map(|l| l as usize),
+            code_alphabet: request
+                .code_alphabet
+                .map(|a| a.parse::<Alphabet>())
+                .transpose()
+                .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            locale: request.locale,
+            reuse_recent: request.reuse_recent,
+            reuse_token: request.reuse_token,
+            parallel: request.parallel,
+            metadata: request
+                .metadata
+                .map(|m| serde_json::from_str(&m))
+                .transpose()
+                .map_err(|e| Status::invalid_argument(format!("invalid metadata: {}", e)))?,
+            channel: request
+                .channel
+                .map(|c| c.parse::<ChannelPreference>())
+                .transpose()
+                .map_err(|e| Status::invalid_argument(e.to_string()))?,
+        };
+
+        let server = self.server.clone();
+        let handled = self
+            .server
+            .carrier_calls()
+            .admit(move || server.handle_traced_request(&request, &trace, client))
+            .await
+            .map_err(call_status)?
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(StartVerificationResponse {
+            attempt_id: handled.attempt_id,
+            error: handled.error,
+            retrying: handled.retrying,
+            reused_token: handled.token,
+            reused: handled.reused,
+            in_progress: handled.in_progress,
+            opted_out: handled.opted_out,
+        }))
+    }
+
+    async fn check_code(
+        &self,
+        request: Request<CheckCodeRequest>,
+    ) -> Result<Response<CheckCodeResponse>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let trace = TraceContext::from_headers(&headers)
+            .with_correlation_id(middleware::request_id(&headers));
+        let request = request.into_inner();
+        let request = CheckRequest::new(request.attempt_id, request.code);
+        // a risky attempt's extra code is sent by its check
+        let server = self.server.clone();
+        let checked = self
+            .server
+            .carrier_calls()
+            .admit(move || server.check_traced_code(&request, &trace))
+            .await;
+        let checked = match checked {
+            Ok(checked) => checked,
+            Err(e @ CallError::Saturated) => return Err(call_status(e)),
+            Err(CallError::Failed(e)) => Err(CheckError::Internal(e)),
+        };
+        match checked {
+            Ok(r) => Ok(Response::new(CheckCodeResponse {
+                token: r.token.unwrap_or_default(),
+                next_code: r.next_code.map(|c| c.as_str().to_string()),
+            })),
+            Err(e @ CheckError::NotFound) => Err(Status::not_found(e.to_string())),
+            Err(e @ CheckError::Expired) => Err(Status::failed_precondition(e.to_string())),
+            Err(e @ CheckError::Mismatch { .. }) => Err(Status::permission_denied(e.to_string())),
+            Err(e @ CheckError::Locked { .. }) => Err(Status::resource_exhausted(e.to_string())),
+            Err(e @ CheckError::Internal(_)) => Err(Status::internal(e.to_string())),
+            Err(e @ CheckError::Undelivered) => Err(Status::failed_precondition(e.to_string())),
+            Err(e @ CheckError::TooEarly { .. }) => Err(Status::unavailable(e.to_string())),
+        }
+    }
+
+    async fn get_rank(
+        &self,
+        _request: Request<GetRankRequest>,
+    ) -> Result<Response<GetRankResponse>, Status> {
+        let rank = self
+            .server
+            .get_provider_rank()
+            .rank
+            .into_iter()
+            .map(|(carrier, score)| CarrierRank {
+                carrier: carrier.to_string(),
+                score,
+            })
+            .collect();
+        Ok(Response::new(GetRankResponse { rank }))
+    }
+}
+
+// call_status tells a client that couldn't call carriers how it failed, a full queue is worth
+// retrying
+fn call_status(error: CallError) -> Status {
+    match error {
+        CallError::Saturated => Status::unavailable(error.to_string()),
+        CallError::Failed(e) => Status::internal(e),
+    }
+}
+
+// serve runs the gRPC API on listener until the process exits
+pub async fn serve(
+    listener: std::net::TcpListener,
+    server: SharedServer,
+    proxies: ProxyConfig,
+) -> Result<(), Error> {
+    listener.set_nonblocking(true)?;
+    info!("gRPC listening on {}", listener.local_addr()?);
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    Server::builder()
+        .add_service(VerificationServer::new(GrpcService::new(server, proxies)))
+        .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use utoipa::ToSchema;
+
+// HealthConfig is how the score served at GET /health/score weighs the health of the instance,
+// every component scores between 0 and 1 and the score is their weighted average
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    // repo latency and error rates are judged over the requests within the window
+    pub window_secs: u64,
+    // average time storing an attempt takes before the repo component drops, it halves at
+    // twice the target
+    pub repo_latency_ms: f64,
+    // share of the requests failing with a server error at which the error component reaches 0
+    pub max_error_rate: f64,
+    // pending retries and escalations at which the queue component reaches 0
+    pub max_queue_depth: usize,
+    pub weights: HealthWeights,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 300,
+            repo_latency_ms: 50.0,
+            max_error_rate: 0.05,
+            max_queue_depth: 1000,
+            weights: HealthWeights::default(),
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.window_secs == 0 || self.max_queue_depth == 0 {
+            return Err(anyhow!(
+                "health window_secs and max_queue_depth must be greater than 0"
+            ));
+        }
+        if !self.repo_latency_ms.is_finite() || self.repo_latency_ms <= 0.0 {
+            return Err(anyhow!("health repo_latency_ms must be greater than 0"));
+        }
+        if self.max_error_rate <= 0.0 || self.max_error_rate > 1.0 {
+            return Err(anyhow!("health max_error_rate must be between 0 and 1"));
+        }
+        let weights = self.weights.all();
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            return Err(anyhow!("health weights must not be negative"));
+        }
+        if weights.iter().sum::<f64>() <= 0.0 {
+            return Err(anyhow!("health weights must not all be 0"));
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.window_secs as i64)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthWeights {
+    pub carriers: f64,
+    pub repo_latency: f64,
+    pub error_rate: f64,
+    pub queue_depth: f64,
+}
+
+impl Default for HealthWeights {
+    fn default() -> Self {
+        Self {
+            carriers: 0.4,
+            repo_latency: 0.2,
+            error_rate: 0.3,
+            queue_depth: 0.1,
+        }
+    }
+}
+
+impl HealthWeights {
+    fn all(&self) -> [f64; 4] {
+        [
+            self.carriers,
+            self.repo_latency,
+            self.error_rate,
+            self.queue_depth,
+        ]
+    }
+}
+
+// HealthScore is served at GET /health/score for traffic managers weighing instances against
+// each other, more is healthier
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct HealthScore {
+    // weighted average of the component scores, between 0 and 1
+    pub score: f64,
+    pub components: Vec<HealthComponent>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct HealthComponent {
+    // carriers, repo_latency, error_rate or queue_depth
+    pub name: String,
+    pub score: f64,
+    pub weight: f64,
+    // what was measured: the share of routable carriers, the average milliseconds storing an
+    // attempt took, the share of requests failing or the pending retries and escalations
+    pub value: f64,
+}
+
+// Snapshot is what the score is taken from besides the tracked requests
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Snapshot {
+    pub carriers: usize,
+    // carriers neither draining, degraded nor out of error budget
+    pub routable: usize,
+    pub queue_depth: usize,
+}
+
+// HealthTracker keeps the outcomes of recent requests and how long storing their attempts took
+#[derive(Debug, Default)]
+pub struct HealthTracker {
+    // time of each request and whether it failed with a server error
+    requests: VecDeque<(DateTime<Utc>, bool)>,
+    repo_writes: VecDeque<(DateTime<Utc>, std::time::Duration)>,
+}
+
+impl HealthTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_request(&mut self, config: &HealthConfig, failed: bool, now: DateTime<Utc>) {
+        self.requests.push_back((now, failed));
+        self.expire(config, now);
+    }
+
+    pub fn record_repo_write(
+        &mut self,
+        config: &HealthConfig,
+        took: std::time::Duration,
+        now: DateTime<Utc>,
+    ) {
+        self.repo_writes.push_back((now, took));
+        self.expire(config, now);
+    }
+
+    fn expire(&mut self, config: &HealthConfig, now: DateTime<Utc>) {
+        let since = now - config.window();
+        while self.requests.front().is_some_and(|(t, _)| *t <= since) {
+            self.requests.pop_front();
+        }
+        while self.repo_writes.front().is_some_and(|(t, _)| *t <= since) {
+            self.repo_writes.pop_front();
+        }
+    }
+
+    // score weighs the requests within the window at now and snapshot, an instance without
+    // carriers scores 0 for them and components without requests score 1
+    pub fn score(
+        &self,
+        config: &HealthConfig,
+        snapshot: Snapshot,
+        now: DateTime<Utc>,
+    ) -> HealthScore {
+        let since = now - config.window();
+        let routable = match snapshot.carriers {
+            0 => 0.0,
+            n => snapshot.routable as f64 / n as f64,
+        };
+        let writes = self
+            .repo_writes
+            .iter()
+            .filter(|(t, _)| *t > since)
+            .map(|(_, took)| took.as_secs_f64() * 1e3)
+            .collect::<Vec<_>>();
+        let latency = match writes.len() {
+            0 => 0.0,
+            n => writes.iter().sum::<f64>() / n as f64,
+        };
+        let requests = self.requests.iter().filter(|(t, _)| *t > since);
+        let (total, failed) =
+            requests.fold((0, 0), |(n, f), (_, failed)| (n + 1, f + *failed as u64));
+        let error_rate = match total {
+            0 => 0.0,
+            n => failed as f64 / n as f64,
+        };
+        let weights = &config.weights;
+        let components = vec![
+            component("carriers", routable, weights.carriers, routable),
+            component(
+                "repo_latency",
+                config.repo_latency_ms / latency.max(config.repo_latency_ms),
+                weights.repo_latency,
+                latency,
+            ),
+            component(
+                "error_rate",
+                1.0 - error_rate / config.max_error_rate,
+                weights.error_rate,
+                error_rate,
+            ),
+            component(
+                "queue_depth",
+                1.0 - snapshot.queue_depth as f64 / config.max_queue_depth as f64,
+                weights.queue_depth,
+                snapshot.queue_depth as f64,
+            ),
+        ];
+        let weight = components.iter().map(|c| c.weight).sum::<f64>();
+        let score = components.iter().map(|c| c.score * c.weight).sum::<f64>() / weight;
+        HealthScore {
+            score: round(score),
+            components,
+        }
+    }
+}
+
+fn component(name: &str, score: f64, weight: f64, value: f64) -> HealthComponent {
+    HealthComponent {
+        name: name.to_string(),
+        score: round(score.clamp(0.0, 1.0)),
+        weight,
+        value: round(value),
+    }
+}
+
+// to the thousandth, scores moving in the noise shouldn't shift traffic
+fn round(value: f64) -> f64 {
+    (value * 1e3).round() / 1e3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let config = HealthConfig::default();
+        let now = Utc::now();
+        let mut tracker = HealthTracker::new();
+        let snapshot = Snapshot {
+            carriers: 4,
+            routable: 3,
+            queue_depth: 250,
+        };
+        // an idle instance is only held back by its carriers and queue
+        let idle = tracker.score(&config, snapshot, now);
+        assert_eq!(idle.components[0].score, 0.75);
+        assert_eq!(idle.components[1].score, 1.0);
+        assert_eq!(idle.components[2].score, 1.0);
+        assert_eq!(idle.components[3].score, 0.75);
+        assert_eq!(idle.score, 0.875);
+
+        // 1 in 40 failing spends half the error rate allowed, writes at twice the target halve
+        // the repo component
+        for i in 0..40 {
+            tracker.record_request(&config, i == 0, now);
+            tracker.record_repo_write(&config, std::time::Duration::from_millis(100), now);
+        }
+        let busy = tracker.score(&config, snapshot, now);
+        assert_eq!(busy.components[1].value, 100.0);
+        assert_eq!(busy.components[1].score, 0.5);
+        assert_eq!(busy.components[2].value, 0.025);
+        assert_eq!(busy.components[2].score, 0.5);
+        assert_eq!(busy.score, 0.625);
+
+        // requests outside the window no longer count
+        let later = now + config.window();
+        assert_eq!(tracker.score(&config, snapshot, later), idle);
+
+        let none = Snapshot {
+            carriers: 0,
+            routable: 0,
+            queue_depth: 5000,
+        };
+        let score = tracker.score(&config, none, later);
+        assert_eq!(score.components[0].score, 0.0);
+        assert_eq!(score.components[3].score, 0.0);
+
+        for invalid in &[
+            HealthConfig {
+                max_error_rate: 0.0,
+                ..config.clone()
+            },
+            HealthConfig {
+                weights: HealthWeights {
+                    carriers: 0.0,
+                    repo_latency: 0.0,
+                    error_rate: 0.0,
+                    queue_depth: 0.0,
+                },
+                ..config.clone()
+            },
+            HealthConfig {
+                window_secs: 0,
+                ..config
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
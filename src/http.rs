@@ -0,0 +1,1031 @@
+use crate::admin::{self, AdminTokens};
+use crate::calls::{self, CallError};
+use crate::codec::Format;
+use crate::events::{EventBus, VerificationEvent};
+use crate::health::HealthScore;
+use crate::metrics::{self, Metrics};
+use crate::middleware::{self, ClientIp};
+use crate::openapi::ApiDoc;
+use crate::otp::{CheckError, VerificationStatus};
+use crate::pagination::{Page, PageParams};
+use crate::provider::WebhookError;
+use crate::proxy::ProxyConfig;
+use crate::receipt::Receipt;
+use crate::repo::{Channel, RankQuery, VerificationEntry};
+use crate::reporting;
+use crate::slo::SloReport;
+use crate::tls::TlsConfig;
+use crate::token::{IntrospectResponse, JwkSet};
+use crate::totp::{TotpCheckRequest, TotpEnrollRequest, TotpEnrollResponse, TotpError};
+use crate::trace::TraceContext;
+use crate::uptime::{UptimeQuery, UptimeReport};
+use crate::{
+    CheckRequest, CheckResponse, RankResponse, VerificationRequest, VerificationResponse,
+    VerificationServer,
+};
+use anyhow::{anyhow, Error};
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use chrono::Utc;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use hyper_util::server::conn::auto;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tower::limit::ConcurrencyLimitLayer;
+use tower_http::compression::CompressionLayer;
+use tracing::info;
+use utoipa::{OpenApi, ToSchema};
+
+// SharedServer is the handle to the VerificationServer shared by the HTTP and gRPC layers, the
+// server synchronizes itself so requests are handled concurrently
+pub type SharedServer = Arc<VerificationServer>;
+
+// AppState is cloned into every request handler
+#[derive(Clone)]
+pub struct AppState {
+    pub server: SharedServer,
+    pub events: EventBus,
+    // scraped without going through the server
+    pub metrics: Metrics,
+    // admin requests are refused without one of them
+    pub admin_tokens: Arc<AdminTokens>,
+}
+
+impl AppState {
+    pub fn new(server: SharedServer) -> Self {
+        let (events, metrics) = (server.events().clone(), server.metrics().clone());
+        Self {
+            server,
+            events,
+            metrics,
+            admin_tokens: Arc::new(AdminTokens::default()),
+        }
+    }
+
+    pub fn with_admin_tokens(mut self, tokens: AdminTokens) -> Self {
+        self.admin_tokens = Arc::new(tokens);
+        self
+    }
+}
+
+// HttpConfig tunes the HTTP layer independently of the routes it serves
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub max_body_bytes: usize,
+    pub max_concurrency: usize,
+    pub proxies: ProxyConfig,
+    // time a request body has to arrive in once its headers are read, answered with 408 after
+    pub read_timeout: Duration,
+    // time a request has to be answered in once it is read, answered with 503 after
+    pub write_timeout: Duration,
+    // time a connection may wait for the headers of its next request before it is closed, the
+    // first request's included
+    pub idle_timeout: Duration,
+    // requests taking longer than this to answer are logged with a warning, never when None
+    pub slow_request: Option<Duration>,
+    // share of requests whose payloads are logged at debug level
+    pub payload_sample_rate: f64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 64 * 1024,
+            max_concurrency: 1024,
+            proxies: ProxyConfig::default(),
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            slow_request: None,
+            payload_sample_rate: 0.0,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_body_bytes == 0 {
+            return Err(anyhow!("max_body_bytes must be greater than 0"));
+        }
+        if self.max_concurrency == 0 {
+            return Err(anyhow!("max_concurrency must be greater than 0"));
+        }
+        let timeouts = [
+            ("read_timeout_secs", self.read_timeout),
+            ("write_timeout_secs", self.write_timeout),
+            ("idle_timeout_secs", self.idle_timeout),
+        ];
+        if let Some((name, _)) = timeouts.iter().find(|(_, t)| t.is_zero()) {
+            return Err(anyhow!("{} must be greater than 0", name));
+        }
+        if self.slow_request.is_some_and(|t| t.is_zero()) {
+            return Err(anyhow!("slow_request_ms must be greater than 0"));
+        }
+        if !(0.0..=1.0).contains(&self.payload_sample_rate) {
+            return Err(anyhow!("payload_sample_rate must be between 0 and 1"));
+        }
+        Ok(())
+    }
+
+    // tune sets up the connections of a listener, hyper only enforces the header timeout once
+    // it has a timer
+    fn tune(&self, builder: &mut auto::Builder<TokioExecutor>) {
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(self.idle_timeout);
+    }
+}
+
+// upper bound on worker threads, well beyond the point where more threads stop adding throughput
+pub const MAX_WORKER_THREADS: usize = 256;
+
+// worker_threads validates a configured worker count, defaulting to the available parallelism
+pub fn worker_threads(configured: Option<usize>) -> Result<usize, Error> {
+    match configured {
+        Some(n) if n == 0 || n > MAX_WORKER_THREADS => Err(anyhow!(
+            "workers must be between 1 and {}",
+            MAX_WORKER_THREADS
+        )),
+        Some(n) => Ok(n),
+        None => Ok(std::thread::available_parallelism().map_or(1, |n| n.get())),
+    }
+}
+
+// Routes is which of the APIs a listener serves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Routes {
+    // the verification API along with the admin API, see Config::admin_on_public
+    All,
+    // the verification API, for when the admin API has a listener of its own
+    Public,
+    Admin,
+}
+
+pub fn router(state: AppState, config: &HttpConfig, routes: Routes) -> Router {
+    let router = match routes {
+        Routes::All => public_routes().merge(admin::router(&state)),
+        Routes::Public => public_routes(),
+        Routes::Admin => admin::router(&state),
+    };
+    router
+        .fallback(not_found)
+        // must follow every route it covers, axum keeps filling in the Allow header
+        .method_not_allowed_fallback(method_not_allowed)
+        // inside the timeouts so sampled bodies have already arrived
+        .layer(axum::middleware::from_fn_with_state(
+            (config.payload_sample_rate, config.max_body_bytes),
+            middleware::sample_payloads,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            (
+                config.read_timeout,
+                config.write_timeout,
+                config.max_body_bytes,
+            ),
+            middleware::enforce_timeouts,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            config.max_body_bytes,
+            middleware::enforce_body,
+        ))
+        .layer(DefaultBodyLimit::max(config.max_body_bytes))
+        .layer(ConcurrencyLimitLayer::new(config.max_concurrency))
+        // negotiated through Accept-Encoding, SSE and tiny responses are left uncompressed
+        .layer(CompressionLayer::new().gzip(true).br(true))
+        // outermost so requests rejected by the layers above are logged too
+        .layer(axum::middleware::from_fn_with_state(
+            config.slow_request,
+            middleware::log_requests,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(config.proxies.clone()),
+            middleware::resolve_client,
+        ))
+        .layer(axum::middleware::from_fn(middleware::propagate_trace))
+        .with_state(state)
+}
+
+fn public_routes() -> Router<AppState> {
+    Router::new()
+        .route("/", post(post_verification))
+        .route("/check", post(post_check))
+        .route("/verifications/{attempt_id}", get(get_verification_status))
+        .route("/receipts/{attempt_id}", get(get_receipt))
+        .route("/tokens/revoke", post(post_revoke_token))
+        .route("/tokens/introspect", get(get_introspect_token))
+        .route("/totp/enroll", post(post_totp_enroll))
+        .route("/totp/check", post(post_totp_check))
+        .route("/.well-known/jwks.json", get(get_jwks))
+        .route("/rank", get(get_rank))
+        .route("/rank/uptime", get(get_rank_uptime))
+        .route("/webhooks/{provider_name}", post(post_provider_webhook))
+        .route("/openapi.json", get(get_openapi))
+}
+
+// operational_routes are served along with the admin API and authenticated like it, metrics and
+// attempts tell more about traffic than clients should see
+pub(crate) fn operational_routes() -> Router<AppState> {
+    Router::new()
+        .route("/events", get(get_events))
+        .route("/attempts", get(get_attempts))
+        .route("/metrics", get(get_metrics))
+        .route("/slo", get(get_slo))
+        .route("/health/score", get(get_health_score))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    error: String,
+    // seconds until the request can be retried, also sent as Retry-After
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<i64>,
+}
+
+// error_response renders a JSON error body with the given status
+pub fn error_response<E: ToString>(status: StatusCode, error: E) -> Response {
+    (
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            retry_after_secs: None,
+        }),
+    )
+        .into_response()
+}
+
+// Listener is where the HTTP server accepts connections
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listener {
+    Tcp(String),
+    Unix(PathBuf),
+    // a listening socket passed by systemd, TCP or unix
+    Inherited(RawFd),
+}
+
+// Bound is a socket accepting connections
+#[derive(Debug)]
+pub enum Bound {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // bind starts listening, so the socket accepts connections before they are served
+    pub fn bind(&self) -> Result<Bound, Error> {
+        let bound = match self {
+            Listener::Tcp(address) => Bound::Tcp(TcpListener::bind(address)?),
+            Listener::Unix(path) => {
+                // a socket left behind by a previous run would make the bind fail
+                if fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                Bound::Unix(UnixListener::bind(path)?)
+            }
+            // passed sockets are owned by this process from now on, only unix sockets have
+            // no IP address
+            Listener::Inherited(fd) => {
+                let listener = unsafe { TcpListener::from_raw_fd(*fd) };
+                match listener.local_addr() {
+                    Ok(_) => Bound::Tcp(listener),
+                    Err(_) => {
+                        Bound::Unix(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) })
+                    }
+                }
+            }
+        };
+        match &bound {
+            Bound::Tcp(listener) => listener.set_nonblocking(true)?,
+            Bound::Unix(listener) => listener.set_nonblocking(true)?,
+        }
+        Ok(bound)
+    }
+}
+
+// serve serves app on listener until the process exits, terminating TLS when configured
+pub async fn serve(
+    listener: Bound,
+    app: Router,
+    tls: Option<TlsConfig>,
+    config: &HttpConfig,
+) -> Result<(), Error> {
+    let tls = match tls {
+        Some(tls) => Some(RustlsConfig::from_config(Arc::new(tls.server_config()?))),
+        None => None,
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    match listener {
+        Bound::Tcp(listener) => {
+            info!("Now listening on {}://{}", scheme, listener.local_addr()?);
+            let mut server = axum_server::from_tcp(listener)?;
+            config.tune(server.http_builder());
+            // peer addresses are handed to handlers for velocity scoring
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            match tls {
+                Some(config) => {
+                    server
+                        .acceptor(RustlsAcceptor::new(config))
+                        .serve(service)
+                        .await?
+                }
+                None => server.serve(service).await?,
+            }
+        }
+        Bound::Unix(listener) => {
+            let address = listener.local_addr()?;
+            let path = address.as_pathname().unwrap_or_else(|| "unnamed".as_ref());
+            info!("Now listening on {}+unix://{}", scheme, path.display());
+            let mut server = axum_server::from_unix(listener)?;
+            config.tune(server.http_builder());
+            let service = app.into_make_service();
+            match tls {
+                Some(config) => {
+                    server
+                        .acceptor(RustlsAcceptor::new(config))
+                        .serve(service)
+                        .await?
+                }
+                None => server.serve(service).await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// -------------------------
+// POST VERIFICATION ATTEMPT
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body(content(
+        (VerificationRequest = "application/json"),
+        (VerificationRequest = "application/msgpack"),
+    )),
+    responses(
+        (status = 200, description = "verification attempt handled", content(
+            (VerificationResponse = "application/json"),
+            (VerificationResponse = "application/msgpack"),
+        )),
+        (status = 400, description = "malformed verification request", body = ErrorResponse),
+        (status = 403, description = "the number opted out of messages", content(
+            (VerificationResponse = "application/json"),
+            (VerificationResponse = "application/msgpack"),
+        )),
+        (status = 409, description = "the number already has a verification in progress, named by attempt_id", content(
+            (VerificationResponse = "application/json"),
+            (VerificationResponse = "application/msgpack"),
+        )),
+        (status = 413, description = "request body too large", body = ErrorResponse),
+        (status = 415, description = "request body is neither JSON nor msgpack", body = ErrorResponse),
+        (status = 500, description = "the attempt couldn't be handled", body = ErrorResponse),
+        (status = 503, description = "too many requests waiting for carriers, retry after Retry-After seconds", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn post_verification(
+    State(state): State<AppState>,
+    Extension(trace): Extension<TraceContext>,
+    // unix socket clients not behind a trusted proxy are scored without an address
+    Extension(ClientIp(client)): Extension<ClientIp>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let format = Format::from_accept(&headers);
+    let request = match Format::from_content_type(&headers)
+        .unwrap_or(Format::Json)
+        .decode_body::<VerificationRequest>(body)
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    // provider calls block, run them on the carrier pool instead of an async worker
+    let server = state.server.clone();
+    let handled = state
+        .server
+        .carrier_calls()
+        .admit(move || server.handle_timed_request(&request, &trace, client))
+        .await;
+    let (handled, timings) = match handled {
+        Ok(handled) => handled,
+        Err(e) => return call_error_response(e),
+    };
+    let mut response = match handled {
+        // only rejected when the server is started with --duplicate-requests reject
+        Ok(r) if r.in_progress && r.error.is_some() => {
+            (StatusCode::CONFLICT, format.respond(&r)).into_response()
+        }
+        Ok(r) if r.opted_out => (StatusCode::FORBIDDEN, format.respond(&r)).into_response(),
+        Ok(r) => format.respond(&r),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    // logged along with the request when it was slow
+    response.extensions_mut().insert(timings);
+    response
+}
+
+// -------------------------
+// GET VERIFICATION RECEIPT
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/receipts/{attempt_id}",
+    params(("attempt_id" = String, Path, description = "attempt_id of a completed verification")),
+    responses(
+        (status = 200, description = "hash chained receipt of the verification", body = Receipt),
+        (status = 404, description = "unknown or not yet verified attempt", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn get_receipt(
+    State(state): State<AppState>,
+    Path(attempt_id): Path<String>,
+) -> Response {
+    match state.server.receipt(&attempt_id) {
+        Some(r) => Json(r).into_response(),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("no receipt for attempt: {}", attempt_id),
+        ),
+    }
+}
+
+// -------------------------
+// SUBMIT VERIFICATION CODE
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/check",
+    request_body(content(
+        (CheckRequest = "application/json"),
+        (CheckRequest = "application/msgpack"),
+    )),
+    responses(
+        (status = 200, description = "code accepted, the attempt is verified", content(
+            (CheckResponse = "application/json"),
+            (CheckResponse = "application/msgpack"),
+        )),
+        (status = 202, description = "code accepted, the attempt's risk tier asks for the code just sent over next_code", content(
+            (CheckResponse = "application/json"),
+            (CheckResponse = "application/msgpack"),
+        )),
+        (status = 400, description = "malformed check request", body = ErrorResponse),
+        (status = 401, description = "code does not match the one sent", body = ErrorResponse),
+        (status = 404, description = "unknown or already verified attempt", body = ErrorResponse),
+        (status = 409, description = "no code was delivered yet, or no retry delivered one", body = ErrorResponse),
+        (status = 410, description = "code expired", body = ErrorResponse),
+        (status = 423, description = "too many invalid codes, retry after Retry-After seconds with a new verification", body = ErrorResponse),
+        (status = 429, description = "an invalid code was submitted too recently, retry after Retry-After seconds", body = ErrorResponse),
+        (status = 503, description = "too many requests waiting for carriers, retry after Retry-After seconds", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn post_check(
+    State(state): State<AppState>,
+    Extension(trace): Extension<TraceContext>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let request = match Format::from_content_type(&headers)
+        .unwrap_or(Format::Json)
+        .decode_body::<CheckRequest>(body)
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    // a risky attempt's extra code is sent by its check
+    let server = state.server.clone();
+    let checked = state
+        .server
+        .carrier_calls()
+        .admit(move || server.check_traced_code(&request, &trace))
+        .await;
+    let checked = match checked {
+        Ok(checked) => checked,
+        Err(e @ CallError::Saturated) => return call_error_response(e),
+        Err(CallError::Failed(e)) => Err(CheckError::Internal(e)),
+    };
+    match checked {
+        // risky attempts are only verified once their extra codes are submitted too
+        Ok(r) if r.next_code.is_some() => (
+            StatusCode::ACCEPTED,
+            Format::from_accept(&headers).respond(&r),
+        )
+            .into_response(),
+        Ok(r) => Format::from_accept(&headers).respond(&r),
+        Err(e) => {
+            let status = match e {
+                CheckError::NotFound => StatusCode::NOT_FOUND,
+                CheckError::Expired => StatusCode::GONE,
+                CheckError::Mismatch { .. } => StatusCode::UNAUTHORIZED,
+                CheckError::Locked { .. } => StatusCode::LOCKED,
+                CheckError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                CheckError::Undelivered => StatusCode::CONFLICT,
+                CheckError::TooEarly { .. } => StatusCode::TOO_MANY_REQUESTS,
+            };
+            match e {
+                CheckError::Locked { until } | CheckError::TooEarly { retry_at: until } => {
+                    retry_after_response(status, &e, until)
+                }
+                _ => error_response(status, &e),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RevokeRequest {
+    token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RevokeResponse {
+    revoked: bool,
+}
+
+// -------------------------
+// GET VERIFICATION STATUS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/verifications/{attempt_id}",
+    params(("attempt_id" = String, Path, description = "attempt_id returned by POST /")),
+    responses(
+        (status = 200, description = "state of the attempt", body = VerificationStatus),
+        (status = 404, description = "unknown or expired attempt", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn get_verification_status(
+    State(state): State<AppState>,
+    Path(attempt_id): Path<String>,
+) -> Response {
+    match state.server.verification_status(&attempt_id) {
+        Some(s) => Json(s).into_response(),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("verification attempt not found: {}", attempt_id),
+        ),
+    }
+}
+
+// -------------------------
+// REVOKE VERIFICATION TOKEN
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/tokens/revoke",
+    request_body = RevokeRequest,
+    responses(
+        (status = 200, description = "token revoked until it expires", body = RevokeResponse),
+        (status = 400, description = "not a valid, unexpired token issued by this server", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn post_revoke_token(
+    State(state): State<AppState>,
+    Json(request): Json<RevokeRequest>,
+) -> Response {
+    match state.server.revoke_token(&request.token) {
+        Ok(()) => Json(RevokeResponse { revoked: true }).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, format!("invalid token: {}", e)),
+    }
+}
+
+// -------------------------
+// INTROSPECT VERIFICATION TOKEN
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/tokens/introspect",
+    params(("Authorization" = String, Header, description = "Bearer <token>")),
+    responses(
+        (status = 200, description = "validity and revocation status of the token", body = IntrospectResponse),
+        (status = 401, description = "no bearer token given", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn get_introspect_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    match bearer_token(&headers) {
+        Some(token) => Json(state.server.introspect_token(token)).into_response(),
+        None => error_response(StatusCode::UNAUTHORIZED, "missing bearer token"),
+    }
+}
+
+// retry_after_response is an error clients can retry once until passed, the wait is sent both
+// as Retry-After and in the body
+fn retry_after_response<E: ToString>(
+    status: StatusCode,
+    error: E,
+    until: chrono::DateTime<Utc>,
+) -> Response {
+    // rounded up, retrying on the dot would still be too early
+    let millis = (until - Utc::now()).num_milliseconds().max(0);
+    let retry_after = (millis + 999) / 1000;
+    let mut response = (
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            retry_after_secs: Some(retry_after),
+        }),
+    )
+        .into_response();
+    if let Ok(v) = HeaderValue::from_str(&retry_after.to_string()) {
+        response.headers_mut().insert(header::RETRY_AFTER, v);
+    }
+    response
+}
+
+// call_error_response answers a request that couldn't call carriers, a full queue is retried
+// shortly
+fn call_error_response(error: CallError) -> Response {
+    match error {
+        CallError::Saturated => retry_after_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &error,
+            Utc::now() + chrono::Duration::seconds(calls::RETRY_AFTER_SECS),
+        ),
+        CallError::Failed(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+// tokens are taken from a header rather than the query string so they stay out of access logs
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// -------------------------
+// ENROLL TOTP
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/totp/enroll",
+    params(("Authorization" = String, Header, description = "Bearer <token> of a completed code or TOTP verification")),
+    request_body(content = Option<TotpEnrollRequest>, description = "a code of the current enrollment, required to replace it"),
+    responses(
+        (status = 200, description = "TOTP secret of the verified number, replacing any earlier one", body = TotpEnrollResponse),
+        (status = 400, description = "malformed enrollment request", body = ErrorResponse),
+        (status = 401, description = "missing, invalid, revoked or reused token, or a wrong code", body = ErrorResponse),
+        (status = 409, description = "number is already enrolled and no code was submitted", body = ErrorResponse),
+        (status = 423, description = "too many invalid codes, retry after Retry-After seconds", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn post_totp_enroll(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let token = match bearer_token(&headers) {
+        Some(t) => t,
+        None => return error_response(StatusCode::UNAUTHORIZED, "missing bearer token"),
+    };
+    // first enrollments don't need a body
+    let request = match body.is_empty() {
+        true => TotpEnrollRequest::default(),
+        false => match Format::from_content_type(&headers)
+            .unwrap_or(Format::Json)
+            .decode::<TotpEnrollRequest>(&body)
+        {
+            Ok(r) => r,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+        },
+    };
+    match state.server.enroll_totp(token, &request) {
+        Ok(r) => Json(r).into_response(),
+        Err(e) => totp_error_response(e),
+    }
+}
+
+// -------------------------
+// CHECK TOTP CODE
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/totp/check",
+    request_body = TotpCheckRequest,
+    responses(
+        (status = 200, description = "code accepted, number verified", body = CheckResponse),
+        (status = 400, description = "malformed check request", body = ErrorResponse),
+        (status = 401, description = "code does not match or was already used", body = ErrorResponse),
+        (status = 404, description = "number has no TOTP enrollment", body = ErrorResponse),
+        (status = 423, description = "too many invalid codes, retry after Retry-After seconds", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn post_totp_check(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let request = match Format::from_content_type(&headers)
+        .unwrap_or(Format::Json)
+        .decode::<TotpCheckRequest>(&body)
+    {
+        Ok(r) => r,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+    let checked = state.server.check_totp(&request);
+    match checked {
+        Ok(r) => Format::from_accept(&headers).respond(&r),
+        Err(e) => totp_error_response(e),
+    }
+}
+
+fn totp_error_response(e: TotpError) -> Response {
+    let status = match e {
+        TotpError::NotEnrolled => StatusCode::NOT_FOUND,
+        TotpError::Enrolled => StatusCode::CONFLICT,
+        TotpError::InvalidToken(_) | TotpError::Mismatch { .. } => StatusCode::UNAUTHORIZED,
+        TotpError::Locked { .. } => StatusCode::LOCKED,
+        TotpError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    match e {
+        TotpError::Locked { until } => retry_after_response(status, &e, until),
+        _ => error_response(status, &e),
+    }
+}
+
+// -------------------------
+// PUBLISH TOKEN SIGNING KEYS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses((status = 200, description = "public keys validating RS256 and ES256 tokens, empty for HS256", body = JwkSet))
+)]
+pub(crate) async fn get_jwks(State(state): State<AppState>) -> Response {
+    Json(state.server.tokens().jwks()).into_response()
+}
+
+// -------------------------
+// GET CARRIER RANKINGS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/rank",
+    params(
+        ("window" = Option<u64>, Query, description = "only rank attempts from the last window seconds"),
+        ("country" = Option<String>, Query, description = "ISO 3166-1 alpha-2 destination country"),
+        ("channel" = Option<Channel>, Query, description = "only rank attempts verified over this channel"),
+        ("min_attempts" = Option<usize>, Query, description = "omit carriers with fewer attempts"),
+    ),
+    responses(
+        (status = 200, description = "carrier rankings, less is better", content(
+            (RankResponse = "application/json"),
+            (RankResponse = "application/msgpack"),
+        )),
+        (status = 400, description = "malformed query parameters"),
+    )
+)]
+pub(crate) async fn get_rank(
+    State(state): State<AppState>,
+    Query(query): Query<RankQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let rank = state.server.get_provider_rank_by(&query);
+    Format::from_accept(&headers).respond(&rank)
+}
+
+// -------------------------
+// GET CARRIER UPTIME
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/rank/uptime",
+    params(
+        ("window" = Option<u64>, Query, description = "report over the last window seconds, a day when omitted"),
+    ),
+    responses(
+        (status = 200, description = "share of the window each carrier wasn't degraded for and its incidents", content(
+            (UptimeReport = "application/json"),
+            (UptimeReport = "application/msgpack"),
+        )),
+        (status = 400, description = "window out of range", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn get_rank_uptime(
+    State(state): State<AppState>,
+    Query(query): Query<UptimeQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let report = state.server.uptime_report(&query);
+    match report {
+        Ok(r) => Format::from_accept(&headers).respond(&r),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+// -------------------------
+// GET SLO
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/slo",
+    responses(
+        (status = 200, description = "error budgets of the carriers with an SLO target", content(
+            (SloReport = "application/json"),
+            (SloReport = "application/msgpack"),
+        )),
+    )
+)]
+pub(crate) async fn get_slo(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let report = state.server.slo_report();
+    Format::from_accept(&headers).respond(&report)
+}
+
+// -------------------------
+// GET HEALTH SCORE
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/health/score",
+    responses(
+        (status = 200, description = "health of the instance between 0 and 1, more is healthier, with the score of every component", content(
+            (HealthScore = "application/json"),
+            (HealthScore = "application/msgpack"),
+        )),
+    )
+)]
+pub(crate) async fn get_health_score(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    let score = state.server.health_score();
+    Format::from_accept(&headers).respond(&score)
+}
+
+// -------------------------
+// GET METRICS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "counters, gauges and histograms in the Prometheus text format", body = String, content_type = "text/plain"),
+    )
+)]
+pub(crate) async fn get_metrics(State(state): State<AppState>) -> Response {
+    state.server.record_repo_size();
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
+// -------------------------
+// LIST VERIFICATION ATTEMPTS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/attempts",
+    params(PageParams),
+    responses(
+        (status = 200, description = "stored attempts, oldest first", content(
+            (Page<VerificationEntry> = "application/json"),
+            (Page<VerificationEntry> = "application/msgpack"),
+        )),
+        (status = 400, description = "invalid cursor", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn get_attempts(
+    State(state): State<AppState>,
+    Query(page): Query<PageParams>,
+    headers: HeaderMap,
+) -> Response {
+    let attempts = state.server.list_attempts(&page);
+    match attempts {
+        Ok(mut p) => {
+            for entry in &mut p.items {
+                entry.number = middleware::mask_number(&entry.number);
+            }
+            Format::from_accept(&headers).respond(&p)
+        }
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WebhookResponse {
+    accepted: usize,
+}
+
+// -------------------------
+// INBOUND PROVIDER WEBHOOK
+// -------------------------
+#[utoipa::path(
+    post,
+    path = "/webhooks/{provider_name}",
+    params(("provider_name" = String, Path, description = "name of the carrier sending the callback")),
+    request_body(content = String, description = "provider specific callback payload"),
+    responses(
+        (status = 200, description = "callbacks accepted", body = WebhookResponse),
+        (status = 400, description = "payload could not be parsed", body = ErrorResponse),
+        (status = 401, description = "signature check failed", body = ErrorResponse),
+        (status = 404, description = "unknown provider or provider without webhooks", body = ErrorResponse),
+        (status = 503, description = "too many requests waiting for carriers, retry after Retry-After seconds", body = ErrorResponse),
+    )
+)]
+pub(crate) async fn post_provider_webhook(
+    State(state): State<AppState>,
+    Path(provider_name): Path<String>,
+    Extension(trace): Extension<TraceContext>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    // providers parse their callbacks synchronously, like their other calls
+    let server = state.server.clone();
+    let name = provider_name.clone();
+    let handled = state
+        .server
+        .carrier_calls()
+        .admit(move || {
+            let _scope = reporting::enter(trace.correlation_id);
+            server.handle_provider_webhook(&name, &headers, &body)
+        })
+        .await;
+    let handled = match handled {
+        Ok(handled) => handled,
+        Err(e) => return call_error_response(e),
+    };
+    match handled {
+        Some(Ok(accepted)) => Json(WebhookResponse { accepted }).into_response(),
+        Some(Err(e)) => {
+            let status = match e {
+                WebhookError::Unsupported => StatusCode::NOT_FOUND,
+                WebhookError::Unauthorized => StatusCode::UNAUTHORIZED,
+                WebhookError::Invalid(_) => StatusCode::BAD_REQUEST,
+            };
+            error_response(status, e)
+        }
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("carrier not found: {}", provider_name),
+        ),
+    }
+}
+
+// -------------------------
+// STREAM VERIFICATION EVENTS
+// -------------------------
+#[utoipa::path(
+    get,
+    path = "/events",
+    responses((
+        status = 200,
+        description = "server-sent stream of attempt lifecycle events",
+        body = VerificationEvent,
+        content_type = "text/event-stream"
+    ))
+)]
+pub(crate) async fn get_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // subscribers that fall behind skip the events they missed rather than closing the stream
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(|event| {
+        let event = event.ok()?.masked();
+        Event::default()
+            .event(event.kind.as_str())
+            .json_data(&event)
+            .ok()
+            .map(Ok)
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn get_openapi() -> Response {
+    Json(ApiDoc::openapi()).into_response()
+}
+
+async fn not_found(uri: Uri) -> Response {
+    error_response(
+        StatusCode::NOT_FOUND,
+        format!("no route for {}", uri.path()),
+    )
+}
+
+async fn method_not_allowed(method: Method, uri: Uri) -> Response {
+    error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        format!("method {} not allowed for {}", method, uri.path()),
+    )
+}
@@ -0,0 +1,24 @@
+use std::time::{Duration, Instant};
+
+// RequestTimings is where the time handling a verification request went, the HTTP layer logs
+// it for requests slower than --slow-request-ms. Phases the request was answered before are zero
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestTimings {
+    // the carrier the attempt was delivered through, the last one tried after failing over
+    pub carrier: Option<String>,
+    // normalizing the number and the policy, consent, lockout and duplicate checks
+    pub checks: Duration,
+    // velocity limits, pumping throttles and the line type lookup
+    pub fraud: Duration,
+    // routing and the carrier calls, storing the attempts included
+    pub delivery: Duration,
+    // opening the session and scheduling its retries and escalations
+    pub session: Duration,
+}
+
+// lap returns the time since the start of the current phase and starts the next one
+pub fn lap(phase: &mut Instant) -> Duration {
+    let elapsed = phase.elapsed();
+    *phase = Instant::now();
+    elapsed
+}
@@ -1,34 +1,613 @@
+use crate::admin::parse_admin_token;
+use crate::alerting::{AlertConfig, CarrierAlert, CarrierMonitor};
+use crate::audit::{AuditAction, AuditEntry, AuditLog, AuditQuery, InMemoryAuditLog};
+use crate::balancer::{BalancerConfig, BalancerState, Exclusion};
+use crate::calls::CarrierCalls;
+use crate::config::{Config, RepoBackend};
+use crate::consent::{ConsentStore, InMemoryConsentStore, Keyword, OptOut, OptOutSource};
+use crate::debug::{CarrierHealth, DebugState, RepoStats};
+use crate::escalation::{parse_country_ladder, ChannelPreference, EscalationConfig, Ladder};
+use crate::events::{EventBus, EventKind, VerificationEvent};
+use crate::fraud::{
+    FraudAction, FraudConfig, FraudDecision, RiskDecision, RiskTier, VelocityTracker,
+};
+use crate::health::{HealthConfig, HealthScore, HealthTracker, Snapshot};
+use crate::latency::RequestTimings;
+use crate::loadtest::parse_duration;
+use crate::logging::LogFormat;
+use crate::lookup::{
+    parse_line_type, parse_network, LineType, LineTypeLookup, MobileNetwork, NetworkLookup,
+    PrefixLineTypeLookup, PrefixNetworkLookup,
+};
+use crate::memory::MemoryConfig;
+use crate::metrics::{Metered, Metrics, MetricsConfig};
+use crate::otp::{
+    Alphabet, CheckDelays, CheckError, CodeFormat, DuplicateRequests, InMemoryOtpStore, OtpConfig,
+    OtpSession, OtpStore, RecentVerifications, SessionState, VerificationStatus,
+};
+use crate::pagination::{Page, PageParams};
+use crate::policy::NumberPolicy;
 use crate::provider::*;
+use crate::proxy::{Cidr, ForwardedHeader};
+use crate::pumping::{PumpingDetector, Throttle};
+use crate::receipt::{InMemoryReceiptStore, Receipt, ReceiptStore};
+use crate::reload::{ConfigSource, ReloadReport};
 use crate::repo::*;
+use crate::reporting::{ErrorKind, ErrorReport, ErrorReporter};
+use crate::retry::{PendingRetry, RetryConfig, RetryQueue};
+use crate::rng::SharedRng;
+use crate::secrets::{SecretStore, SecretsConfig};
+use crate::slo::{ErrorBudgets, SloConfig, SloReport};
+use crate::templates::{Message, Templates};
+use crate::test_numbers::{parse_test_number, TestNumber, TestNumbers};
+use crate::token::{
+    parse_rotation, InMemoryRevocationStore, IntrospectResponse, RevocationStore, TokenIssuer,
+};
+use crate::totp::{
+    InMemoryTotpStore, TotpCheckRequest, TotpEnrollRequest, TotpEnrollResponse, TotpEnrollment,
+    TotpError, TotpStore,
+};
+use crate::trace::TraceContext;
+use crate::uptime::{UptimeQuery, UptimeReport, UptimeTracker};
+use crate::webhook::WebhookDispatcher;
 use anyhow::{anyhow, Error};
 use argh::FromArgs;
 use chrono::serde::ts_milliseconds;
 use chrono::{DateTime, Utc};
-use rouille::Request;
 use serde::{Deserialize, Serialize};
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::marker::Send;
+use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
 
+pub mod admin;
+pub mod alerting;
+pub mod audit;
+pub mod balancer;
+pub mod calls;
+pub mod codec;
+pub mod config;
+pub mod consent;
+pub mod country;
+pub mod debug;
+pub mod escalation;
+pub mod events;
+pub mod export;
+pub mod fraud;
+pub mod grpc;
+pub mod health;
+pub mod http;
+pub mod latency;
+pub mod loadtest;
+pub mod logging;
+pub mod lookup;
+pub mod memory;
+pub mod metrics;
+pub mod middleware;
+pub mod openapi;
+pub mod otp;
+pub mod pagination;
+pub mod policy;
 pub mod provider;
+pub mod proxy;
+pub mod pumping;
+pub mod receipt;
+pub mod reload;
+pub mod replay;
 pub mod repo;
+pub mod reporting;
+pub mod retry;
+pub mod rng;
+pub mod schema;
+pub mod secrets;
+pub mod slo;
+pub mod statsd;
+pub mod sweeper;
+pub mod systemd;
+pub mod templates;
+pub mod test_numbers;
+pub mod tls;
+pub mod token;
+pub mod totp;
+pub mod trace;
+pub mod uptime;
+pub mod webhook;
 
 /// Top-level command.
 #[derive(FromArgs, PartialEq, Debug)]
 pub struct Command {
-    /// strategy in selecting what telecom provider handles a verification attempt
+    #[argh(subcommand)]
+    pub command: SubCommand,
+}
+
+// parsed once at startup, boxing the serve flags isn't worth it
+#[allow(clippy::large_enum_variant)]
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum SubCommand {
+    Serve(ServeCommand),
+    CheckConfig(CheckConfigCommand),
+    Rank(RankCommand),
+    Replay(ReplayCommand),
+    Loadtest(LoadtestCommand),
+    Export(ExportCommand),
+    Import(ImportCommand),
+}
+
+/// Run the verification server.
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+#[argh(subcommand, name = "serve")]
+pub struct ServeCommand {
+    /// path to a TOML or YAML config file, TELECOM__ environment variables and the flags given
+    /// take precedence over its settings
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// name of a profile of the config file whose settings are merged over the others
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// strategy in selecting what telecom provider handles a verification attempt, required
+    /// unless set in the config file
+    #[argh(option)]
+    pub balancer: Option<BalancerType>,
+
+    /// the port that the telecom verification service runs on, defaults to 5000
+    #[argh(option, short = 'p')]
+    pub port: Option<String>,
+
+    /// address the HTTP and gRPC APIs listen on, defaults to localhost
+    #[argh(option)]
+    pub bind: Option<String>,
+
+    /// serve HTTP on this unix socket path instead of the TCP port
+    #[argh(option)]
+    pub unix_socket: Option<String>,
+
+    /// number of async worker threads, defaults to the number of available CPUs
+    #[argh(option)]
+    pub workers: Option<usize>,
+
+    /// maximum number of HTTP requests handled at once, further requests wait for a free slot,
+    /// defaults to 1024
+    #[argh(option)]
+    pub max_concurrency: Option<usize>,
+
+    /// maximum number of requests, retries, checks and carrier webhooks calling carriers at once,
+    /// further ones wait for their turn, defaults to 256
+    #[argh(option)]
+    pub max_provider_calls: Option<usize>,
+
+    /// maximum number of requests waiting for a carrier call, further ones are answered with 503
+    /// and Retry-After, defaults to 1024
+    #[argh(option)]
+    pub max_queued_provider_calls: Option<usize>,
+
+    /// secret used to sign callback_url notifications, callbacks are rejected when omitted
+    #[argh(option)]
+    pub webhook_secret: Option<String>,
+
+    /// delivery attempts per callback_url notification before it is dropped
+    #[argh(option, default = "5")]
+    pub webhook_max_attempts: u32,
+
+    /// host callback_urls may name, repeatable, any public host when omitted
+    #[argh(option)]
+    pub webhook_allow_host: Vec<String>,
+
+    /// notifications delivered at once, further ones wait in a queue of 1024
+    #[argh(option, default = "4")]
+    pub webhook_workers: usize,
+
+    /// number of characters in verification codes, between 4 and 8
+    #[argh(option, default = "6")]
+    pub code_length: usize,
+
+    /// characters verification codes are drawn from: numeric, alphanumeric or unambiguous
+    #[argh(option, default = "Alphabet::Numeric")]
+    pub code_alphabet: Alphabet,
+
+    /// seconds a verification code can be submitted after it was sent
+    #[argh(option, default = "300")]
+    pub code_ttl_secs: u32,
+
+    /// secret used to sign HS256 verification tokens, a random key is used when omitted
+    #[argh(option)]
+    pub token_secret: Option<String>,
+
+    /// path to a PEM encoded RSA or EC P-256 private key signing RS256 or ES256 tokens, its public
+    /// key is published at /.well-known/jwks.json
+    #[argh(option)]
+    pub token_key: Option<String>,
+
+    /// secret taking over signing HS256 tokens at a time, e.g. 2026-11-01T00:00:00Z=<secret>,
+    /// may be repeated
+    #[argh(option, from_str_fn(parse_rotation))]
+    pub rotate_token_secret: Vec<(DateTime<Utc>, String)>,
+
+    /// path to a PEM encoded private key taking over signing tokens at a time, e.g.
+    /// 2026-11-01T00:00:00Z=next.pem, it is published at /.well-known/jwks.json beforehand, may be
+    /// repeated
+    #[argh(option, from_str_fn(parse_rotation))]
+    pub rotate_token_key: Vec<(DateTime<Utc>, String)>,
+
+    /// seconds tokens signed by a rotated out key keep validating, defaults to --token-ttl-secs
+    #[argh(option)]
+    pub token_grace_secs: Option<u32>,
+
+    /// invalid codes accepted for an attempt before it is locked
+    #[argh(option, default = "5")]
+    pub max_code_attempts: u32,
+
+    /// comma separated seconds the next code submission is refused for after the first, second
+    /// and later invalid codes of an attempt, the last one repeats
+    #[argh(option, default = "CheckDelays::default()")]
+    pub check_delays: CheckDelays,
+
+    /// seconds a number can't be verified again after one of its attempts was locked
+    #[argh(option, default = "900")]
+    pub lockout_secs: u32,
+
+    /// how requests for a number with an attempt in progress are handled: allow another attempt,
+    /// reuse the one in progress or reject them
+    #[argh(option, default = "DuplicateRequests::Allow")]
+    pub duplicate_requests: DuplicateRequests,
+
+    /// seconds verified, failed and expired attempts can still be looked up for
+    #[argh(option, default = "3600")]
+    pub session_retention_secs: u32,
+
+    /// seconds after a verification in which requests with reuse_recent are issued a token
+    /// without sending another code, disabled when 0
+    #[argh(option, default = "0")]
+    pub reuse_window_secs: u32,
+
+    /// issuer shown by authenticator apps for TOTP enrollments
+    #[argh(option, default = "String::from(\"telecom\")")]
+    pub totp_issuer: String,
+
+    /// secret keying the HMAC verification codes are stored as, a random pepper is used when
+    /// omitted
+    #[argh(option)]
+    pub code_pepper: Option<String>,
+
+    /// have the configured mock carriers print the messages they deliver, codes included, never
+    /// use it in production
+    #[argh(switch)]
+    pub print_messages: bool,
+
+    /// minimum level of the events logged, e.g. debug, or per module levels such as
+    /// info,telecom::webhook=debug, defaults to info
+    #[argh(option)]
+    pub log_level: Option<String>,
+
+    /// json for one JSON object per log event or pretty for readable lines, defaults to pretty
+    #[argh(option)]
+    pub log_format: Option<LogFormat>,
+
+    /// comma separated weights of the FirstSMS, SecondSMS, FirstTextToSpeech, SecondTextToSpeech
+    /// and Unreachable steps carriers are ranked by, ascending, defaults to 1,2,3,4,5
+    #[argh(option, from_str_fn(parse_step_weights))]
+    pub step_weights: Option<[u32; 5]>,
+
+    /// route, record and escalate verifications as usual but never have carriers send messages or
+    /// webhooks be delivered, attempts are stored as simulated and left out of rankings
+    #[argh(switch)]
+    pub dry_run: bool,
+
+    /// seed the randomness of mock carriers, codes and attempt ids for reproducible runs, codes
+    /// become predictable so never use it in production
+    #[argh(option)]
+    pub seed: Option<u64>,
+
+    /// seconds verification tokens stay valid after they are issued
+    #[argh(option, default = "3600")]
+    pub token_ttl_secs: u32,
+
+    /// comma separated channels carriers try in order until the code is delivered, each
+    /// optionally followed by the seconds waited before it and the seconds the user has to submit
+    /// the code it delivered before the next one is tried anyway, e.g. sms/60,sms:30,voice:30
+    #[argh(option)]
+    pub escalation: Option<Ladder>,
+
+    /// escalation ladder for numbers of one country, e.g. DE=voice,sms:30, may be repeated
+    #[argh(option, from_str_fn(parse_country_ladder))]
+    pub country_escalation: Vec<(String, Ladder)>,
+
+    /// comma separated seconds waited before each retry of an attempt that no carrier step
+    /// delivered, e.g. 5,30,120, such attempts fail right away when omitted
+    #[argh(option)]
+    pub retry_backoff: Option<RetryConfig>,
+
+    /// only verify numbers of this country, e.g. DE, may be repeated
+    #[argh(option)]
+    pub allow_country: Vec<String>,
+
+    /// never verify numbers of this country, may be repeated
+    #[argh(option)]
+    pub deny_country: Vec<String>,
+
+    /// only verify numbers starting with this prefix, e.g. +4420, may be repeated
+    #[argh(option)]
+    pub allow_prefix: Vec<String>,
+
+    /// never verify numbers starting with this prefix, e.g. +1900, may be repeated
+    #[argh(option)]
+    pub deny_prefix: Vec<String>,
+
+    /// line type of numbers starting with a prefix, e.g. +4915678=voip, may be repeated
+    #[argh(option, from_str_fn(parse_line_type))]
+    pub line_type: Vec<(String, LineType)>,
+
+    /// mobile network of numbers starting with a prefix as mcc-mnc, e.g. +49176=262-03, carriers
+    /// directly connected to it are preferred, may be repeated
+    #[argh(option, from_str_fn(parse_network))]
+    pub network: Vec<(String, MobileNetwork)>,
+
+    /// what happens to verifications of VoIP and virtual numbers: allow, flag or reject
+    #[argh(option)]
+    pub voip_numbers: Option<FraudAction>,
+
+    /// velocity score from which allowed requests are verified differently, as
+    /// <min score>=<sms|voice|auto>[+<extra codes>], e.g. 0.5=voice to only call or 0.9=voice+1 to
+    /// also ask for a second code, may be repeated
+    #[argh(option)]
+    pub risk_tier: Vec<RiskTier>,
+
+    /// number verified without contacting a carrier, with a fixed code or always unreachable,
+    /// e.g. +15550000001=000000 or +15550000002=unreachable, may be repeated
+    #[argh(option, from_str_fn(parse_test_number))]
+    pub test_number: Vec<(String, TestNumber)>,
+
+    /// ISO 3166-1 country code numbers in national format are in, e.g. DE for 0171 2345678, only
+    /// international numbers are accepted when omitted
+    #[argh(option)]
+    pub default_region: Option<String>,
+
+    /// locale of messages to requests without a locale or in one without a template
+    #[argh(option, default = "String::from(\"en\")")]
+    pub default_locale: String,
+
+    /// path to a JSON object of locales to sms and voice message templates containing {{code}},
+    /// adding to or replacing the built-in en, de, es and fr ones
+    #[argh(option)]
+    pub templates: Option<String>,
+
+    /// maximum accepted request body size in bytes, defaults to 65536
+    #[argh(option)]
+    pub max_body_bytes: Option<usize>,
+
+    /// seconds a request body has to arrive in once its headers are read, defaults to 30
+    #[argh(option)]
+    pub read_timeout_secs: Option<u64>,
+
+    /// seconds a request has to be answered in once it is read, defaults to 30
+    #[argh(option)]
+    pub write_timeout_secs: Option<u64>,
+
+    /// seconds a connection may wait for the headers of its next request, defaults to 60
+    #[argh(option)]
+    pub idle_timeout_secs: Option<u64>,
+
+    /// milliseconds after which a request is logged with a warning saying where the time went,
+    /// never when omitted
+    #[argh(option)]
+    pub slow_request_ms: Option<u64>,
+
+    /// share of requests, between 0 and 1, whose redacted request and response bodies are logged
+    /// at debug level, defaults to 0
+    #[argh(option)]
+    pub payload_sample_rate: Option<f64>,
+
+    /// network of a proxy trusted to forward the client address, e.g. 10.0.0.0/8, may be
+    /// repeated
+    #[argh(option)]
+    pub trusted_proxy: Vec<Cidr>,
+
+    /// header trusted proxies forward the client address in: x-forwarded-for (the default),
+    /// x-real-ip or forwarded, may be repeated to honor the first one sent
+    #[argh(option)]
+    pub forwarded_header: Vec<ForwardedHeader>,
+
+    /// the port to serve the gRPC verification API on, disabled when omitted
+    #[argh(option)]
+    pub grpc_port: Option<String>,
+
+    /// serve the /admin API on this port instead of the verification API's, so it can be
+    /// firewalled apart
+    #[argh(option)]
+    pub admin_port: Option<String>,
+
+    /// address the admin API listens on with --admin-port, defaults to --bind
+    #[argh(option)]
+    pub admin_bind: Option<String>,
+
+    /// serve the /admin API on this unix socket path instead of the verification API's listener
+    #[argh(option)]
+    pub admin_unix_socket: Option<String>,
+
+    /// also serve the /admin API on the verification API's listener when it has none of its own,
+    /// it isn't served there otherwise
+    #[argh(switch)]
+    pub admin_on_public: bool,
+
+    /// bearer token admin requests authenticate with and the operator they are audited as, e.g.
+    /// alice=<token> of at least 16 characters, may be repeated
+    #[argh(option, from_str_fn(parse_admin_token))]
+    pub admin_token: Vec<(String, String)>,
+
+    /// path to a PEM encoded certificate chain, serves HTTPS when provided with --tls-key
+    #[argh(option)]
+    pub tls_cert: Option<String>,
+
+    /// path to the PEM encoded private key matching --tls-cert
+    #[argh(option)]
+    pub tls_key: Option<String>,
+
+    /// path to a PEM encoded CA bundle used to verify client certificates
+    #[argh(option)]
+    pub tls_client_ca: Option<String>,
+}
+
+/// Validate the settings serve would start with and the carrier credentials they reference.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "check-config")]
+pub struct CheckConfigCommand {
+    /// path to a TOML or YAML config file, TELECOM__ environment variables are applied on top
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// name of a profile of the config file to check, the settings outside profiles by default
+    #[argh(option)]
+    pub profile: Option<String>,
+}
+
+/// Rank carriers offline from a file of exported verification attempts.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "rank")]
+pub struct RankCommand {
+    /// JSON file of attempts, either a list or a page as returned by GET /attempts
+    #[argh(positional)]
+    pub entries: String,
+
+    /// path to a TOML or YAML config file whose ranking settings are used
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// name of a profile of the config file whose ranking settings are used
     #[argh(option)]
-    pub balancer: BalancerType,
+    pub profile: Option<String>,
 
-    /// the port that the telecom verification service runs on
-    #[argh(option, short = 'p', default = "String::from(\"5000\")")]
-    pub port: String,
+    /// only rank attempts made within the last window seconds
+    #[argh(option)]
+    pub window: Option<u64>,
+
+    /// ISO 3166-1 alpha-2 code of the destination country to rank attempts of
+    #[argh(option)]
+    pub country: Option<String>,
+
+    /// only rank attempts verified over this channel, sms or voice
+    #[argh(option)]
+    pub channel: Option<Channel>,
+
+    /// omit carriers with fewer matching attempts
+    #[argh(option)]
+    pub min_attempts: Option<usize>,
+}
+
+/// Replay recorded verification requests through the configured carriers and report the outcome.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "replay")]
+pub struct ReplayCommand {
+    /// file of one JSON verification request per line, as sent to POST /
+    #[argh(option)]
+    pub input: String,
+
+    /// path to a TOML or YAML config file whose carriers and settings handle the requests
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// name of a profile of the config file whose settings are merged over the others
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// strategy in selecting the carrier of each request, overriding the config file's
+    #[argh(option)]
+    pub balancer: Option<BalancerType>,
+
+    /// simulate deliveries rather than having the carriers send them
+    #[argh(switch)]
+    pub dry_run: bool,
+
+    /// seed the randomness of mock carriers so replays are comparable run to run
+    #[argh(option)]
+    pub seed: Option<u64>,
+}
+
+/// Write the verification attempts and fraud decisions of a repo to a JSON lines file.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "export")]
+pub struct ExportCommand {
+    /// file the records are written to, one JSON record per line
+    #[argh(option)]
+    pub out: String,
+
+    /// path to a TOML or YAML config file whose repo is exported
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// name of a profile of the config file whose settings are merged over the others
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// backend of the repo exported, overriding the config file's
+    #[argh(option)]
+    pub repo: Option<RepoBackend>,
+
+    /// file of the file repo backend, overriding the config file's
+    #[argh(option)]
+    pub repo_path: Option<String>,
 }
 
-#[derive(PartialEq, Debug)]
+/// Store the records of an export in a repo, e.g. to move history to another backend.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "import")]
+pub struct ImportCommand {
+    /// file of records written by export
+    #[argh(option)]
+    pub input: String,
+
+    /// path to a TOML or YAML config file whose repo the records are stored in
+    #[argh(option)]
+    pub config: Option<String>,
+
+    /// name of a profile of the config file whose settings are merged over the others
+    #[argh(option)]
+    pub profile: Option<String>,
+
+    /// backend of the repo imported into, overriding the config file's
+    #[argh(option)]
+    pub repo: Option<RepoBackend>,
+
+    /// file of the file repo backend, overriding the config file's
+    #[argh(option)]
+    pub repo_path: Option<String>,
+}
+
+/// Send synthetic verification requests to a server and report latencies and error rates.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "loadtest")]
+pub struct LoadtestCommand {
+    /// base URL of the server verification requests are POSTed to, defaults to
+    /// http://localhost:5000
+    #[argh(option, default = "String::from(\"http://localhost:5000\")")]
+    pub target: String,
+
+    /// requests sent per second
+    #[argh(option)]
+    pub rps: u32,
+
+    /// how long requests are sent for, e.g. 60s or 5m, defaults to 60s
+    #[argh(option, from_str_fn(parse_duration))]
+    pub duration: Option<Duration>,
+
+    /// seed the generated numbers so runs send the same traffic
+    #[argh(option)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
 pub enum BalancerType {
+    #[serde(alias = "rr")]
     RoundRobin,
+    #[serde(alias = "b")]
     Best,
 }
 
@@ -43,39 +622,221 @@ impl FromStr for BalancerType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+impl BalancerType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::RoundRobin => "round-robin",
+            Self::Best => "best",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
 pub struct VerificationRequest {
     number: String,
+    // unix timestamp in milliseconds
     #[serde(with = "ts_milliseconds")]
+    #[schema(value_type = i64)]
     time: DateTime<Utc>,
+    // receives a signed notification once the verification reaches a terminal state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    callback_url: Option<String>,
+    // override the server's code format for this attempt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code_length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code_alphabet: Option<Alphabet>,
+    // deliver only over this channel instead of walking the escalation ladder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<ChannelPreference>,
+    // BCP 47 language tag the message is written in, e.g. de or pt-BR
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+    // opaque object stored with the attempt and embedded into the issued token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    // issue a token right away when the number was verified within the server's reuse window
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    reuse_recent: bool,
+    // a token issued earlier for the number, proving the caller is the one who verified it, a
+    // reuse_recent request without one is sent a code as usual
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reuse_token: Option<String>,
+    // send the code through up to failover_depth carriers at once and keep the first to deliver
+    // it, every carrier sending it may charge for its message
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    parallel: bool,
 }
 
-#[derive(Serialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
 pub struct VerificationResponse {
+    // submitted along with the code sent to the number to obtain a token
     #[serde(skip_serializing_if = "Option::is_none")]
-    token: Option<String>,
+    attempt_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    // no carrier delivered the code yet, it is sent again in the background
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    retrying: bool,
+    // issued right away to reuse_recent requests for recently verified numbers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    // no code was sent, token reuses a recent verification
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    reused: bool,
+    // attempt_id names an attempt to the number that was already in progress, no code was sent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    in_progress: bool,
+    // the number opted out of messages, nothing is sent until it opts back in
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    opted_out: bool,
 }
 
 impl VerificationResponse {
-    pub fn to_string(&self) -> String {
+    fn error<T: ToString>(error: T) -> Self {
+        Self {
+            attempt_id: None,
+            error: Some(error.to_string()),
+            retrying: false,
+            token: None,
+            reused: false,
+            in_progress: false,
+            opted_out: false,
+        }
+    }
+
+    fn attempt(attempt_id: String, retrying: bool) -> Self {
+        Self {
+            attempt_id: Some(attempt_id),
+            error: None,
+            retrying,
+            token: None,
+            reused: false,
+            in_progress: false,
+            opted_out: false,
+        }
+    }
+}
+
+impl fmt::Display for VerificationResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match serde_json::to_string(self) {
-            Ok(s) => s,
-            Err(_) => "verification response serialization error".to_string(),
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => write!(f, "verification response serialization error"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct CheckRequest {
+    attempt_id: String,
+    code: String,
+}
+
+impl CheckRequest {
+    pub fn new<T: ToString, U: ToString>(attempt_id: T, code: U) -> Self {
+        Self {
+            attempt_id: attempt_id.to_string(),
+            code: code.to_string(),
         }
     }
 }
 
-#[derive(Serialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct CheckResponse {
+    // HS256 JWT carrying the verified number as its subject, once every code was submitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    // the code was accepted but the attempt's risk tier asks for another one, just sent over
+    // this channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_code: Option<Channel>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
 pub struct RankResponse {
-    rank: Vec<(String, f32)>,
+    // carrier name and weighted average pairs, less is better
+    #[schema(value_type = Vec<(String, f32)>)]
+    rank: Vec<(Arc<str>, f32)>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct CarrierStatus {
+    name: String,
+    draining: bool,
+    weight: u32,
 }
 
+// VerificationServer handles requests from many threads at once, the state they change is kept
+// behind locks of its own that are never held across a carrier call
 pub struct VerificationServer {
-    carriers: Vec<Box<dyn TelecomProvider>>,
+    // cloned out before carriers are called, see VerificationServer::carriers
+    carriers: RwLock<Vec<Arc<dyn TelecomProvider>>>,
+    // carriers that are excluded from routing but still registered to receive provider callbacks
+    draining: RwLock<HashSet<String>>,
+    // configs of the carriers built by apply_config, by name
+    carrier_configs: Mutex<HashMap<String, ProviderConfig>>,
+    // store the ${secret:NAME} references of carriers are resolved from, replaced by apply_config
+    secrets: RwLock<Arc<dyn SecretStore>>,
+    // settings reload_config reads again, unset when the server was built in code
+    config: Mutex<Option<ConfigSource>>,
+    // carriers built at runtime are seeded with seed too
+    seed: Option<u64>,
+    rng: SharedRng,
+    dry_run: bool,
     balancer: Box<dyn Balancer>,
+    // carriers an attempt is tried through before it is left to the background retries
+    failover_depth: usize,
+    // bounds the requests, retries and checks calling carriers at once, see calls::CarrierCalls
+    calls: CarrierCalls,
+    // carriers of parallel requests that returned still walking their ladder
+    parallel: Mutex<Vec<(mpsc::Receiver<Walked>, TraceContext)>>,
     repo: Box<dyn VerificationRepo>,
+    events: EventBus,
+    metrics: Metrics,
+    webhooks: Option<WebhookDispatcher>,
+    // where errors nobody expected are reported to besides the logs
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    otp: Mutex<Box<dyn OtpStore>>,
+    otp_config: OtpConfig,
+    // numbers a new attempt is being delivered to, duplicate requests wait for their session
+    delivering: Claims,
+    tokens: TokenIssuer,
+    revoked: Mutex<Box<dyn RevocationStore>>,
+    receipts: Mutex<Box<dyn ReceiptStore>>,
+    // admin changes and issued tokens
+    audit: Mutex<Box<dyn AuditLog>>,
+    // numbers that opted out of messages
+    consent: Mutex<Box<dyn ConsentStore>>,
+    escalation: RwLock<EscalationConfig>,
+    retry_config: RetryConfig,
+    retries: Mutex<RetryQueue>,
+    // delivered attempts waiting to be escalated to the next ladder step
+    escalations: Mutex<RetryQueue>,
+    fraud_config: RwLock<FraudConfig>,
+    alert_config: RwLock<AlertConfig>,
+    // recent verification outcomes of each carrier the alerts are raised from
+    monitor: Mutex<CarrierMonitor>,
+    // spans carriers were degraded for
+    uptime: Mutex<UptimeTracker>,
+    slo_config: RwLock<SloConfig>,
+    budgets: Mutex<ErrorBudgets>,
+    health_config: RwLock<HealthConfig>,
+    // recent request outcomes and repo writes the health score is taken from
+    health: Mutex<HealthTracker>,
+    velocity: Mutex<VelocityTracker>,
+    pumping: Mutex<PumpingDetector>,
+    policy: RwLock<NumberPolicy>,
+    lookup: Box<dyn LineTypeLookup>,
+    networks: Box<dyn NetworkLookup>,
+    templates: Templates,
+    totp: Mutex<Box<dyn TotpStore>>,
+    totp_issuer: String,
+    test_numbers: TestNumbers,
+    recent: Mutex<RecentVerifications>,
+    // calling code region numbers in national format are taken to be in
+    default_region: Option<String>,
 }
 
 impl VerificationServer {
@@ -84,99 +845,2427 @@ impl VerificationServer {
         carriers: Vec<Box<dyn TelecomProvider>>,
         repo: Box<dyn VerificationRepo>,
     ) -> VerificationServer {
-        let balancer = match client_mode {
-            BalancerType::RoundRobin => Box::new(RoundRobinBalancer::new()),
-            BalancerType::Best => unimplemented!("BestBalancer is not supported yet"),
-        };
+        let balancer = balancer::build(&BalancerConfig::new(client_mode), None);
+        let metrics = Metrics::new();
+        let memory = MemoryConfig::default();
         Self {
-            carriers,
+            carriers: RwLock::new(
+                carriers
+                    .into_iter()
+                    .map(|c| Metered::wrap(c, &metrics))
+                    .collect(),
+            ),
+            draining: RwLock::new(HashSet::new()),
+            carrier_configs: Mutex::new(HashMap::new()),
+            secrets: RwLock::new(Arc::from(
+                secrets::build_store(&SecretsConfig::default()).unwrap(),
+            )),
+            config: Mutex::new(None),
+            seed: None,
+            rng: SharedRng::default(),
+            dry_run: false,
             balancer,
+            failover_depth: 1,
+            calls: CarrierCalls::default(),
+            parallel: Mutex::new(Vec::new()),
             repo,
+            events: EventBus::new(),
+            metrics,
+            webhooks: None,
+            reporter: None,
+            otp: Mutex::new(Box::new(InMemoryOtpStore::new())),
+            otp_config: OtpConfig::default(),
+            delivering: Claims::default(),
+            tokens: TokenIssuer::ephemeral(),
+            revoked: Mutex::new(Box::new(InMemoryRevocationStore::new())),
+            receipts: Mutex::new(Box::new(
+                InMemoryReceiptStore::new().with_max_receipts(memory.max_receipts),
+            )),
+            audit: Mutex::new(Box::new(
+                InMemoryAuditLog::new().with_max_entries(memory.max_audit_entries),
+            )),
+            consent: Mutex::new(Box::new(
+                InMemoryConsentStore::new().with_max_opt_outs(memory.max_opt_outs),
+            )),
+            escalation: RwLock::new(EscalationConfig::default()),
+            retry_config: RetryConfig::default(),
+            retries: Mutex::new(RetryQueue::new()),
+            escalations: Mutex::new(RetryQueue::new()),
+            fraud_config: RwLock::new(FraudConfig::default()),
+            alert_config: RwLock::new(AlertConfig::default()),
+            monitor: Mutex::new(CarrierMonitor::new()),
+            uptime: Mutex::new(UptimeTracker::new(Utc::now())),
+            slo_config: RwLock::new(SloConfig::default()),
+            budgets: Mutex::new(ErrorBudgets::new()),
+            health_config: RwLock::new(HealthConfig::default()),
+            health: Mutex::new(HealthTracker::new()),
+            velocity: Mutex::new(VelocityTracker::new().with_max_keys(memory.max_velocity_keys)),
+            pumping: Mutex::new(PumpingDetector::new()),
+            policy: RwLock::new(NumberPolicy::default()),
+            lookup: Box::new(PrefixLineTypeLookup::default()),
+            networks: Box::new(PrefixNetworkLookup::default()),
+            templates: Templates::default(),
+            totp: Mutex::new(Box::new(InMemoryTotpStore::new())),
+            totp_issuer: "telecom".to_string(),
+            test_numbers: TestNumbers::default(),
+            recent: Mutex::new(
+                RecentVerifications::new().with_max_numbers(memory.max_recent_verifications),
+            ),
+            default_region: None,
         }
     }
 
-    pub fn handle_request(
-        &mut self,
-        request: &VerificationRequest,
-    ) -> Result<VerificationResponse, Error> {
-        let carrier = match self
-            .carriers
-            .get(self.balancer.next_idx(self.carriers.len()))
-        {
-            Some(c) => c,
-            None => {
-                return Ok(VerificationResponse {
-                    token: None,
-                    error: Some("no carriers found".to_string()),
-                })
-            }
-        };
-        println!("request handled by: {}", carrier.get_name());
-        let entry = carrier.verify(&request.number);
-        self.repo.store_attempt(entry.clone())?;
-        match entry.step {
-            VerificationStep::Unreachable => Ok(VerificationResponse {
-                token: None,
-                error: Some("verification unsuccessful".to_string()),
-            }),
-            _ => Ok(VerificationResponse {
-                token: Some(format!(
-                    "Authorization: Bearer ey{}{}",
-                    request.number,
-                    chrono::offset::Utc::now().timestamp(),
-                )),
-                error: None,
-            }),
-        }
+    // with_seed makes codes, attempt ids and carriers built from configs reproducible run to run,
+    // which also makes codes predictable
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self.rng = SharedRng::seeded(seed);
+        self
     }
 
-    // returns rankings of carrier validation rates
-    pub fn get_provider_rank(&self) -> RankResponse {
-        RankResponse {
-            rank: self.repo.get_provider_rank(),
-        }
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
     }
-}
 
-// used for BestBalancer and RoudRobinBalancer
-pub trait Balancer: Send + Sync {
-    fn next_idx(&mut self, carrier_len: usize) -> usize;
-}
+    // build_carrier builds config the way the carriers of the config file are built, with its
+    // secrets resolved from the secrets backend of the running config
+    pub fn build_carrier(
+        &self,
+        config: &ProviderConfig,
+    ) -> Result<Box<dyn TelecomProvider>, Error> {
+        let secrets = self.secrets.read().unwrap().clone();
+        build_provider(config, self.seed, secrets.as_ref())
+    }
 
-#[derive(Debug)]
-pub struct RoundRobinBalancer {
-    cur_idx: Arc<RwLock<usize>>,
-}
+    // with_dry_run stores simulated attempts in place of having carriers send messages
+    pub fn with_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
 
-impl RoundRobinBalancer {
-    pub fn new() -> RoundRobinBalancer {
-        Self {
-            cur_idx: Arc::new(RwLock::new(0)),
-        }
+    // with_metrics sets the buckets of the carrier latency histograms
+    pub fn with_metrics(self, config: &MetricsConfig) -> Result<Self, Error> {
+        config.validate()?;
+        config.apply(&self.metrics)?;
+        Ok(self)
     }
-}
 
-impl Balancer for RoundRobinBalancer {
-    fn next_idx(&mut self, carrier_len: usize) -> usize {
-        let mut ci = self.cur_idx.write().unwrap();
-        let idx = *ci;
-        // let idx = ci.into();
-        // rotate to next index
-        *ci = (*ci + 1) % carrier_len;
-        idx
-    }
-}
-
-// unwrap_request attempts
-pub fn unwrap_request(request: &Request) -> Vec<u8> {
-    let mut buffer = Vec::new();
-    let mut body = match request.data() {
-        Some(b) => b,
-        None => return buffer,
-    };
-    match body.read_to_end(&mut buffer) {
-        Err(e) => return Vec::from(e.to_string()),
-        Ok(_) => (),
-    };
-    buffer
+    // with_balancer routes attempts with the balancer config describes, seeded by with_seed when
+    // called after it
+    pub fn with_balancer(mut self, config: &BalancerConfig) -> Result<Self, Error> {
+        config.validate()?;
+        self.balancer = balancer::build(config, self.seed);
+        self.failover_depth = config.failover_depth;
+        Ok(self)
+    }
+
+    // with_max_provider_calls lets at most max of the requests, retries, checks and webhooks calling
+    // carriers run at once, further ones wait for their turn. Requests beyond max_queued waiting
+    // are turned away
+    pub fn with_max_provider_calls(mut self, max: usize, max_queued: usize) -> Result<Self, Error> {
+        self.calls = CarrierCalls::new(max, max_queued)?;
+        Ok(self)
+    }
+
+    // with_memory bounds the recent verifications, velocity counts, audit entries, receipts and
+    // opt-outs kept in memory, starting them over empty. The repo is bounded by
+    // memory::MemoryConfig when opened
+    pub fn with_memory(mut self, config: &MemoryConfig) -> Result<Self, Error> {
+        config.validate()?;
+        self.recent = Mutex::new(
+            RecentVerifications::new().with_max_numbers(config.max_recent_verifications),
+        );
+        self.velocity = Mutex::new(VelocityTracker::new().with_max_keys(config.max_velocity_keys));
+        self.audit = Mutex::new(Box::new(
+            InMemoryAuditLog::new().with_max_entries(config.max_audit_entries),
+        ));
+        self.receipts = Mutex::new(Box::new(
+            InMemoryReceiptStore::new().with_max_receipts(config.max_receipts),
+        ));
+        self.consent = Mutex::new(Box::new(
+            InMemoryConsentStore::new().with_max_opt_outs(config.max_opt_outs),
+        ));
+        Ok(self)
+    }
+
+    pub fn carrier_calls(&self) -> &CarrierCalls {
+        &self.calls
+    }
+
+    // with_tokens signs verification tokens with issuer instead of a random key
+    pub fn with_tokens(mut self, issuer: TokenIssuer) -> Self {
+        self.tokens = issuer;
+        self
+    }
+
+    pub fn tokens(&self) -> &TokenIssuer {
+        &self.tokens
+    }
+
+    // with_escalation replaces the SMS, SMS, voice, voice ladder carriers walk by default
+    pub fn with_escalation(self, config: EscalationConfig) -> Result<Self, Error> {
+        self.set_escalation_config(config)?;
+        Ok(self)
+    }
+
+    pub fn get_escalation_config(&self) -> EscalationConfig {
+        self.escalation.read().unwrap().clone()
+    }
+
+    pub fn set_escalation_config(&self, mut config: EscalationConfig) -> Result<(), Error> {
+        config.validate()?;
+        *self.escalation.write().unwrap() = config;
+        Ok(())
+    }
+
+    // with_retries schedules attempts no carrier step delivered to be sent again after each
+    // backoff in config, run by retry::spawn
+    pub fn with_retries(mut self, config: RetryConfig) -> Result<Self, Error> {
+        config.validate()?;
+        self.retry_config = config;
+        Ok(self)
+    }
+
+    // with_line_type_lookup resolves the line types fraud decisions take into account through
+    // lookup
+    pub fn with_line_type_lookup(mut self, lookup: Box<dyn LineTypeLookup>) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    // with_network_lookup resolves the mobile network attempts are routed by through lookup
+    pub fn with_network_lookup(mut self, lookup: Box<dyn NetworkLookup>) -> Self {
+        self.networks = lookup;
+        self
+    }
+
+    pub fn with_fraud_config(self, config: FraudConfig) -> Result<Self, Error> {
+        self.set_fraud_config(config)?;
+        Ok(self)
+    }
+
+    // with_settings registers the carriers of config and applies its ranking, fraud limits and
+    // number policy, without the config being read again on reload
+    pub fn with_settings(self, config: &Config) -> Result<Self, Error> {
+        self.apply_config(config)?;
+        Ok(self)
+    }
+
+    // with_config applies the reloadable settings of config, loaded from args, and keeps both so
+    // reload_config can read them again
+    pub fn with_config(self, args: ServeCommand, config: Config) -> Result<Self, Error> {
+        self.apply_config(&config)?;
+        *self.config.lock().unwrap() = Some(ConfigSource {
+            args,
+            running: config,
+        });
+        Ok(self)
+    }
+
+    // reload_config reads the settings again and applies the carriers, ranking, fraud limits and
+    // number policy that changed, nothing is applied when any of them is invalid. Requests being
+    // handled finish with the settings they started with, reloads take turns
+    pub fn reload_config(&self, actor: &str) -> Result<ReloadReport, Error> {
+        let mut config = self.config.lock().unwrap();
+        let source = config
+            .clone()
+            .ok_or_else(|| anyhow!("the server was not started from a config"))?;
+        let next = Config::load(&source.args)?;
+        let restart_required = reload::restart_required(&source.running, &next);
+        let before = self.reloadable_settings();
+        let applied = self.apply_config(&next)?;
+        *config = Some(ConfigSource {
+            args: source.args,
+            running: next,
+        });
+        drop(config);
+        let report = ReloadReport {
+            applied,
+            restart_required,
+        };
+        if !report.applied.is_empty() {
+            let after = self.reloadable_settings();
+            self.audit(
+                actor,
+                AuditAction::ConfigReloaded,
+                "config",
+                Some(before),
+                Some(after),
+            );
+        }
+        Ok(report)
+    }
+
+    // reloadable_settings are the running settings a reload can change
+    fn reloadable_settings(&self) -> serde_json::Value {
+        serde_json::json!({
+            "carriers": self.list_carriers(),
+            "ranking": self.get_ranking_config(),
+            "fraud": self.get_fraud_config(),
+            "number_policy": self.get_number_policy(),
+        })
+    }
+
+    // apply_config makes the running carriers, ranking, fraud limits and number policy match
+    // config, returning the settings that changed. Carriers and settings changed through the admin
+    // API are replaced by the ones of config
+    fn apply_config(&self, config: &Config) -> Result<Vec<String>, Error> {
+        // applies take turns, everything is validated before anything is changed
+        let mut carrier_configs = self.carrier_configs.lock().unwrap();
+        let running = self.carriers();
+        let secrets: Arc<dyn SecretStore> = secrets::build_store(&config.secrets)?.into();
+        let mut names = HashSet::new();
+        let mut built = Vec::new();
+        for carrier in &config.carriers {
+            if !names.insert(carrier.name.as_str()) {
+                return Err(anyhow!("carrier {} is listed twice", carrier.name));
+            }
+            // toggling enabled doesn't rebuild the carrier
+            let unchanged = carrier_configs.get(&carrier.name).is_some_and(|c| {
+                ProviderConfig {
+                    enabled: carrier.enabled,
+                    ..c.clone()
+                } == *carrier
+            });
+            if !unchanged || !running.iter().any(|c| *c.get_name() == *carrier.name) {
+                let provider = build_provider(carrier, self.seed, secrets.as_ref())
+                    .map_err(|e| anyhow!("carrier {}: {}", carrier.name, e))?;
+                built.push((carrier, Metered::wrap(provider, &self.metrics)));
+            }
+        }
+        let ranking = config.ranking.clone().unwrap_or_default();
+        ranking.validate()?;
+        self.validate_fraud_config(&config.fraud)?;
+        config.alerting.validate()?;
+        config.slo.validate()?;
+        config.health.validate()?;
+        let mut policy = config.number_policy.clone();
+        policy.validate()?;
+
+        *self.secrets.write().unwrap() = secrets;
+        let mut applied = Vec::new();
+        {
+            let mut carriers = self.carriers.write().unwrap();
+            let mut draining = self.draining.write().unwrap();
+            let removed = carriers
+                .iter()
+                .map(|c| c.get_name())
+                .filter(|name| !names.contains(&**name))
+                .collect::<Vec<_>>();
+            for name in removed {
+                carriers.retain(|c| c.get_name() != name);
+                draining.remove(&*name);
+                carrier_configs.remove(&*name);
+                applied.push(format!("carriers.{}: removed", name));
+            }
+            for (carrier, provider) in built {
+                // replaced carriers keep their place in the rotation
+                match carriers.iter().position(|c| *c.get_name() == *carrier.name) {
+                    Some(i) => {
+                        carriers[i] = provider;
+                        applied.push(format!("carriers.{}: updated", carrier.name));
+                    }
+                    None => {
+                        carriers.push(provider);
+                        applied.push(format!("carriers.{}: added", carrier.name));
+                    }
+                }
+            }
+            for carrier in &config.carriers {
+                match (carrier.enabled, draining.contains(&carrier.name)) {
+                    (true, true) => {
+                        draining.remove(&carrier.name);
+                        applied.push(format!("carriers.{}: enabled", carrier.name));
+                    }
+                    (false, false) => {
+                        draining.insert(carrier.name.clone());
+                        applied.push(format!("carriers.{}: disabled", carrier.name));
+                    }
+                    _ => (),
+                }
+                carrier_configs.insert(carrier.name.clone(), carrier.clone());
+            }
+        }
+        if ranking != self.get_ranking_config() {
+            self.repo.set_ranking_config(ranking)?;
+            applied.push("ranking".to_string());
+        }
+        if replace(&self.alert_config, &config.alerting) {
+            applied.push("alerting".to_string());
+        }
+        if replace(&self.slo_config, &config.slo) {
+            applied.push("slo".to_string());
+        }
+        if replace(&self.health_config, &config.health) {
+            applied.push("health".to_string());
+        }
+        if replace(&self.fraud_config, &config.fraud) {
+            applied.push("fraud".to_string());
+        }
+        if replace(&self.policy, &policy) {
+            applied.push("number_policy".to_string());
+        }
+        Ok(applied)
+    }
+
+    // with_number_policy only verifies numbers allowed by policy
+    pub fn with_number_policy(self, policy: NumberPolicy) -> Result<Self, Error> {
+        self.set_number_policy(policy)?;
+        Ok(self)
+    }
+
+    // with_templates renders messages from templates instead of the built-in ones
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    // with_default_region accepts numbers in the national format of region, an ISO 3166-1 alpha-2
+    // code, instead of only international ones
+    pub fn with_default_region(mut self, region: &str) -> Result<Self, Error> {
+        if country::region_code(region).is_none() {
+            return Err(anyhow!("unknown region: {}", region));
+        }
+        self.default_region = Some(region.to_ascii_uppercase());
+        Ok(self)
+    }
+
+    // with_test_numbers answers verifications of test numbers with their fixed outcome
+    pub fn with_test_numbers(mut self, numbers: TestNumbers) -> Self {
+        self.test_numbers = numbers;
+        self
+    }
+
+    // with_totp_issuer names the service TOTP enrollments are shown under in authenticator apps
+    pub fn with_totp_issuer<T: ToString>(mut self, issuer: T) -> Self {
+        self.totp_issuer = issuer.to_string();
+        self
+    }
+
+    pub fn with_otp_config(mut self, config: OtpConfig) -> Self {
+        self.otp_config = config;
+        self
+    }
+
+    // with_webhooks enables callback_url notifications through dispatcher
+    pub fn with_webhooks(mut self, dispatcher: WebhookDispatcher) -> Self {
+        self.webhooks = Some(dispatcher);
+        self
+    }
+
+    // with_error_reporter reports failed requests, failed retries and callbacks carriers sent
+    // that couldn't be understood to reporter
+    pub fn with_error_reporter(mut self, reporter: Arc<dyn ErrorReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    fn report(&self, report: ErrorReport) {
+        if let Some(reporter) = &self.reporter {
+            reporter.report(report);
+        }
+    }
+
+    // carriers takes the registered carriers for the caller to call without holding their lock,
+    // carriers changed meanwhile are only seen by later requests
+    fn carriers(&self) -> Vec<Arc<dyn TelecomProvider>> {
+        self.carriers.read().unwrap().clone()
+    }
+
+    // responsive takes the locks every request goes through, blocking while they are stuck and
+    // failing once a request panicked holding them
+    pub fn responsive(&self) -> bool {
+        self.carriers.read().is_ok() && self.otp.lock().is_ok()
+    }
+
+    // events returns the bus that attempt lifecycle events are published to
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn handle_request(
+        &self,
+        request: &VerificationRequest,
+    ) -> Result<VerificationResponse, Error> {
+        self.handle_traced_request(request, &TraceContext::new_root(), None)
+    }
+
+    // handle_traced_request handles a request from client as part of the caller's trace, carrier
+    // calls and webhook deliveries are reported as child spans of trace
+    pub fn handle_traced_request(
+        &self,
+        request: &VerificationRequest,
+        trace: &TraceContext,
+        client: Option<IpAddr>,
+    ) -> Result<VerificationResponse, Error> {
+        self.handle_timed_request(request, trace, client).0
+    }
+
+    // handle_timed_request handles a request like handle_traced_request, along with where the
+    // time handling it went
+    pub fn handle_timed_request(
+        &self,
+        request: &VerificationRequest,
+        trace: &TraceContext,
+        client: Option<IpAddr>,
+    ) -> (Result<VerificationResponse, Error>, RequestTimings) {
+        let _span = trace.span().entered();
+        let _scope = reporting::enter(trace.correlation_id.clone());
+        let start = Instant::now();
+        let mut timings = RequestTimings::default();
+        let response = self.verify_number(request, trace, client, &mut timings);
+        if let Err(e) = &response {
+            let mut report = ErrorReport::new(ErrorKind::Request, &format!("{:#}", e));
+            if let Some(carrier) = &timings.carrier {
+                report = report.with_context("carrier", carrier);
+            }
+            self.report(report);
+        }
+        let outcome = match &response {
+            Ok(r) if r.opted_out => "opted_out",
+            Ok(r) if r.in_progress => "in_progress",
+            Ok(r) if r.reused => "reused",
+            Ok(r) if r.error.is_some() => "rejected",
+            Ok(r) if r.retrying => "retrying",
+            Ok(_) => "sent",
+            Err(_) => "error",
+        };
+        self.metrics
+            .inc(&metrics::REQUESTS, &[("outcome", outcome)]);
+        let health_config = self.health_config.read().unwrap().clone();
+        self.health
+            .lock()
+            .unwrap()
+            .record_request(&health_config, outcome == "error", Utc::now());
+        self.metrics.observe(
+            &metrics::REQUEST_DURATION,
+            &[],
+            start.elapsed().as_secs_f64(),
+        );
+        (response, timings)
+    }
+
+    // record_repo_size updates the repo size gauges and the evictions of the in-memory stores, it
+    // takes every store's lock so it is run when metrics are scraped and by sweeper::spawn rather
+    // than by every request
+    pub fn record_repo_size(&self) {
+        let attempts = self.repo.attempt_count() as f64;
+        self.metrics.set(&metrics::REPO_ATTEMPTS, &[], attempts);
+        let decisions = self.repo.decision_count() as f64;
+        self.metrics.set(&metrics::REPO_DECISIONS, &[], decisions);
+        for (store, evicted) in &[
+            ("attempts", self.repo.evicted_attempts()),
+            ("decisions", self.repo.evicted_decisions()),
+            (
+                "recent_verifications",
+                self.recent.lock().unwrap().evicted(),
+            ),
+            ("velocity", self.velocity.lock().unwrap().evicted()),
+            ("audit", self.audit.lock().unwrap().evicted()),
+            ("receipts", self.receipts.lock().unwrap().evicted()),
+            ("opt_outs", self.consent.lock().unwrap().evicted()),
+        ] {
+            self.metrics
+                .count_to(&metrics::EVICTIONS, &[("store", store)], *evicted);
+        }
+    }
+
+    fn verify_number(
+        &self,
+        request: &VerificationRequest,
+        trace: &TraceContext,
+        client: Option<IpAddr>,
+        timings: &mut RequestTimings,
+    ) -> Result<VerificationResponse, Error> {
+        let mut phase = Instant::now();
+        // numbers are stored, routed and deduplicated in E.164
+        let request = &match country::normalize(&request.number, self.default_region.as_deref()) {
+            Ok(number) => VerificationRequest {
+                number,
+                ..request.clone()
+            },
+            Err(e) => return Ok(VerificationResponse::error(e)),
+        };
+        let callback = match (&request.callback_url, &self.webhooks) {
+            (Some(_), None) => {
+                return Ok(VerificationResponse::error(
+                    "callback_url is not enabled on this server",
+                ))
+            }
+            (Some(url), Some(webhooks)) => match webhooks.callback_url(url) {
+                Ok(u) => Some(u),
+                Err(e) => return Ok(VerificationResponse::error(e)),
+            },
+            (None, _) => None,
+        };
+
+        if let Some(Err(e)) = request.metadata.as_ref().map(token::validate_metadata) {
+            return Ok(VerificationResponse::error(e));
+        }
+
+        let test_number = self.test_numbers.contains(&request.number);
+        let allowed = self.policy.read().unwrap().check(&request.number);
+        if let (Err(e), false) = (allowed, test_number) {
+            return Ok(VerificationResponse::error(e));
+        }
+
+        // messaging regulations forbid sending anything to numbers that opted out
+        if self.is_opted_out(&request.number) {
+            return Ok(VerificationResponse {
+                opted_out: true,
+                ..VerificationResponse::error("number opted out of messages, reply START to opt in")
+            });
+        }
+
+        let locked_until = self.otp.lock().unwrap().locked_until(&request.number);
+        if let Some(until) = locked_until {
+            return Ok(VerificationResponse::error(format!(
+                "number is locked after too many invalid codes, retry after {}",
+                until.to_rfc3339()
+            )));
+        }
+
+        let reuse_window = self.otp_config.reuse_window;
+        if request.reuse_recent
+            && self.presents_token_for(request.reuse_token.as_deref(), &request.number)
+            && self.recent.lock().unwrap().verified_within(
+                &request.number,
+                reuse_window,
+                Utc::now(),
+            )
+        {
+            let attempt_id = otp::generate_attempt_id(&self.rng);
+            let token = self.issue_token(
+                &request.number,
+                &attempt_id,
+                request.metadata.clone(),
+                "reuse_recent",
+            )?;
+            return Ok(VerificationResponse {
+                attempt_id: None,
+                error: None,
+                retrying: false,
+                token: Some(token),
+                reused: true,
+                in_progress: false,
+                opted_out: false,
+            });
+        }
+
+        let duplicates = self.otp_config.duplicates;
+        // claimed before looking for a session, the request holding the claim inserts its
+        // session before letting go of it
+        let _claim = match duplicates {
+            DuplicateRequests::Allow => None,
+            _ => Some(self.delivering.take(&request.number)),
+        };
+        let in_progress = match duplicates {
+            DuplicateRequests::Allow => None,
+            _ => self
+                .otp
+                .lock()
+                .unwrap()
+                .in_progress(&request.number, Utc::now())
+                .cloned(),
+        };
+        if let Some(session) = in_progress {
+            let mut response = VerificationResponse {
+                in_progress: true,
+                ..VerificationResponse::attempt(
+                    session.attempt_id.clone(),
+                    session.state == SessionState::Retrying,
+                )
+            };
+            if duplicates == DuplicateRequests::Reject {
+                response.error = Some("number already has a verification in progress".to_string());
+            }
+            return Ok(response);
+        }
+
+        let format = CodeFormat {
+            length: request.code_length.unwrap_or(self.otp_config.format.length),
+            alphabet: request
+                .code_alphabet
+                .unwrap_or(self.otp_config.format.alphabet),
+        };
+        if let Err(e) = format.validate() {
+            return Ok(VerificationResponse::error(e));
+        }
+
+        timings.checks = latency::lap(&mut phase);
+        let mut risk = None;
+        if !test_number {
+            let fraud_config = self.get_fraud_config();
+            let throttled = self.pumping.lock().unwrap().admit(
+                &fraud_config.pumping,
+                &request.number,
+                Utc::now(),
+            );
+            let decision = self
+                .velocity
+                .lock()
+                .unwrap()
+                .score(&fraud_config, &request.number, client, Utc::now())
+                .with_line_type(self.lookup.line_type(&request.number), &fraud_config)
+                .with_throttle(throttled.as_deref());
+            let rejected = match decision.action {
+                FraudAction::Reject if throttled.is_some() => {
+                    Some("verification rejected, requests to this number range are throttled")
+                }
+                FraudAction::Reject
+                    if decision.line_type == LineType::Voip
+                        && fraud_config.voip == FraudAction::Reject =>
+                {
+                    Some("verification rejected, VoIP numbers are not supported")
+                }
+                FraudAction::Reject => Some("verification rejected, too many recent requests"),
+                _ => None,
+            };
+            risk = fraud_config
+                .risk_tier(decision.score)
+                .map(|tier| RiskDecision {
+                    score: decision.score,
+                    tier: tier.clone(),
+                });
+            self.repo.store_decision(FraudDecision {
+                correlation_id: trace.correlation_id.clone(),
+                ..decision
+            })?;
+            if let Some(e) = rejected {
+                timings.fraud = phase.elapsed();
+                return Ok(VerificationResponse::error(e));
+            }
+            let tripped = self.pumping.lock().unwrap().record(
+                &fraud_config.pumping,
+                &request.number,
+                Utc::now(),
+            );
+            for throttle in tripped {
+                self.alert(&fraud_config, &throttle, trace);
+            }
+        }
+        timings.fraud = latency::lap(&mut phase);
+
+        // risky attempts are only delivered over the channel their tier asks for
+        let channel = match risk.as_ref().and_then(|r| r.tier.channel) {
+            Some(channel) => channel.into(),
+            None => request.channel.unwrap_or_default(),
+        };
+        let locale = request.locale.as_deref();
+        let delivered = match self.test_numbers.deliver(&request.number) {
+            Some((entry, code)) => Ok((entry.into(), code, None)),
+            None if request.parallel => {
+                self.deliver_parallel(&request.number, &format, channel, locale, trace)?
+            }
+            None => self.deliver_routed(&request.number, &format, channel, locale, trace)?,
+        };
+        timings.delivery = latency::lap(&mut phase);
+        let (entry, code, escalation) = match delivered {
+            Ok(delivered) => delivered,
+            Err(e) => return Ok(VerificationResponse::error(e)),
+        };
+        timings.carrier = Some(entry.carrier.to_string());
+        let event = VerificationEvent::new(EventKind::Failed, &entry.carrier, &request.number)
+            .with_step(entry.step);
+        let attempt_id = otp::generate_attempt_id(&self.rng);
+        let mut session = OtpSession {
+            attempt_id: attempt_id.clone(),
+            number: request.number.clone(),
+            carrier: entry.carrier,
+            step: entry.step,
+            code_hash: self.otp_config.hasher.hash(&code),
+            sent_at: Utc::now(),
+            expires_at: Utc::now() + self.otp_config.ttl,
+            callback_url: callback,
+            state: SessionState::Pending,
+            failed_checks: 0,
+            next_check_at: None,
+            metadata: request.metadata.clone(),
+            extra_codes: risk.as_ref().map_or(0, |r| r.tier.extra_codes),
+            risk,
+            format,
+            locale: request.locale.clone(),
+            correlation_id: trace.correlation_id.clone(),
+        };
+        if entry.step == VerificationStep::Unreachable {
+            // a delayed step still to come is attempted like a retry resuming the ladder at it
+            let (step, delay) = match (escalation, self.retry_config.delay(0)) {
+                (Some(escalation), _) => escalation,
+                (None, Some(d)) => (0, d),
+                (None, None) => {
+                    self.events.publish(event.clone());
+                    if let (Some(url), Some(webhooks)) = (session.callback_url, &self.webhooks) {
+                        webhooks.dispatch(url, event, trace.clone());
+                    }
+                    timings.session = phase.elapsed();
+                    return Ok(VerificationResponse::error("verification unsuccessful"));
+                }
+            };
+            self.events.publish(VerificationEvent {
+                kind: EventKind::Retrying,
+                ..event
+            });
+            self.retries.lock().unwrap().push(PendingRetry {
+                attempt_id: attempt_id.clone(),
+                number: session.number.clone(),
+                format,
+                channel,
+                locale: request.locale.clone(),
+                retries: 0,
+                step,
+                due: Utc::now() + delay,
+                trace: trace.clone(),
+            });
+            session.state = SessionState::Retrying;
+            // the session has to outlive the delayed step and the retries and still leave time to
+            // submit the code
+            session.expires_at = session.expires_at + retry_span(&self.retry_config);
+            if step > 0 {
+                session.expires_at = session.expires_at + delay;
+            }
+            self.otp.lock().unwrap().insert(session);
+            timings.session = phase.elapsed();
+            return Ok(VerificationResponse::attempt(attempt_id, true));
+        }
+        self.events.publish(VerificationEvent {
+            kind: EventKind::Delivered,
+            ..event
+        });
+        if let Some((step, wait)) = escalation {
+            self.escalations.lock().unwrap().push(PendingRetry {
+                attempt_id: attempt_id.clone(),
+                number: session.number.clone(),
+                format,
+                channel,
+                locale: request.locale.clone(),
+                retries: 0,
+                step,
+                due: Utc::now() + wait,
+                trace: trace.clone(),
+            });
+        }
+        self.otp.lock().unwrap().insert(session);
+        timings.session = phase.elapsed();
+        Ok(VerificationResponse::attempt(attempt_id, false))
+    }
+
+    // route picks the carrier an attempt to number in format is sent through, or the reason no
+    // carrier can take it
+    fn route(
+        &self,
+        number: &str,
+        format: &CodeFormat,
+    ) -> Result<Arc<dyn TelecomProvider>, &'static str> {
+        self.route_excluding(number, format, &[])
+    }
+
+    // route_excluding routes like route among the carriers not named in excluded
+    fn route_excluding(
+        &self,
+        number: &str,
+        format: &CodeFormat,
+        excluded: &[Arc<str>],
+    ) -> Result<Arc<dyn TelecomProvider>, &'static str> {
+        let balancer = self.balancer.state().kind.as_str();
+        let country = country::country_of(number);
+        let now = Utc::now();
+        let carriers = self.carriers();
+        let draining = self.draining.read().unwrap().clone();
+        let slo_config = self.slo_config.read().unwrap().clone();
+        let mut capable = Vec::new();
+        let mut left_out = Vec::new();
+        let mut over_budget = Vec::new();
+        for (i, carrier) in carriers.iter().enumerate() {
+            let name = carrier.get_name();
+            let exclusion = if draining.contains(&*name) {
+                Some(Exclusion::Draining)
+            } else if excluded.contains(&name) {
+                Some(Exclusion::FailedOver)
+            } else if !carrier.supports_code_format(format) {
+                Some(Exclusion::CodeFormat)
+            } else if !carrier.serves_country(country) {
+                Some(Exclusion::Country)
+            } else if slo_config.avoid_exhausted
+                && self
+                    .budgets
+                    .lock()
+                    .unwrap()
+                    .is_exhausted(&slo_config, &name, now)
+            {
+                Some(Exclusion::ErrorBudget)
+            } else {
+                None
+            };
+            match exclusion {
+                Some(exclusion) => {
+                    if exclusion == Exclusion::ErrorBudget {
+                        over_budget.push(i);
+                    }
+                    left_out.push((name, exclusion));
+                }
+                None => capable.push(i),
+            }
+        }
+        // carriers that exhausted their budget still take attempts no other carrier can
+        if capable.is_empty() && !over_budget.is_empty() {
+            capable = over_budget;
+            left_out.retain(|(_, e)| *e != Exclusion::ErrorBudget);
+        }
+        for (name, exclusion) in &left_out {
+            self.metrics.inc(
+                &metrics::ROUTING_EXCLUSIONS,
+                &[("carrier", name), ("reason", exclusion.as_str())],
+            );
+        }
+        if capable.is_empty() {
+            let error = left_out
+                .iter()
+                .map(|(_, e)| *e)
+                .max()
+                .map_or("no carriers found", Exclusion::error);
+            debug!(
+                %balancer,
+                excluded = %balancer::describe_exclusions(&left_out),
+                error,
+                "routing decision"
+            );
+            return Err(error);
+        }
+        let network = self.networks.network(number);
+        let direct = match &network {
+            Some(n) => (0..capable.len())
+                .filter(|i| carriers[capable[*i]].connects_to(n))
+                .collect(),
+            None => Vec::new(),
+        };
+        let weights = capable.iter().map(|i| carriers[*i].weight()).collect();
+        let scores = match self.balancer.ranked() {
+            true => {
+                let rank = self.repo.get_provider_rank();
+                capable
+                    .iter()
+                    .map(|i| {
+                        let name = carriers[*i].get_name();
+                        rank.iter().find(|(c, _)| **c == *name).map(|(_, s)| *s)
+                    })
+                    .collect()
+            }
+            false => Vec::new(),
+        };
+        let context = RoutingContext {
+            network,
+            direct,
+            weights,
+            scores,
+        };
+        let picked = capable[self.balancer.next_idx(capable.len(), &context)];
+        let carrier = carriers[picked].get_name();
+        self.metrics.inc(
+            &metrics::BALANCER_PICKS,
+            &[("balancer", balancer), ("carrier", &carrier)],
+        );
+        // the fields are only rendered when debug events are logged
+        let names = |indices: &[usize]| {
+            indices
+                .iter()
+                .map(|i| carriers[*i].get_name())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        debug!(
+            %balancer,
+            candidates = %names(&capable),
+            direct = %names(&context.direct.iter().map(|i| capable[*i]).collect::<Vec<_>>()),
+            excluded = %balancer::describe_exclusions(&left_out),
+            %carrier,
+            "routing decision"
+        );
+        Ok(carriers[picked].clone())
+    }
+
+    // deliver_routed delivers a new attempt through the carrier it is routed to, failing over to
+    // the next one routed to among the others while none reached the number, up to
+    // failover_depth carriers
+    fn deliver_routed(
+        &self,
+        number: &str,
+        format: &CodeFormat,
+        channel: ChannelPreference,
+        locale: Option<&str>,
+        trace: &TraceContext,
+    ) -> Result<Result<Delivery, &'static str>, Error> {
+        let mut tried = Vec::new();
+        let mut last = None;
+        while tried.len() < self.failover_depth {
+            let carrier = match (self.route_excluding(number, format, &tried), last) {
+                (Ok(carrier), _) => carrier,
+                (Err(e), None) => return Ok(Err(e)),
+                // every carrier that can take the attempt was tried
+                (Err(_), Some(delivered)) => return Ok(Ok(delivered)),
+            };
+            let delivered = self.deliver(&*carrier, number, format, channel, locale, 0, trace)?;
+            // carriers with a delayed step left aren't failed over from until they walked it
+            if delivered.0.step != VerificationStep::Unreachable || delivered.2.is_some() {
+                return Ok(Ok(delivered));
+            }
+            tried.push(delivered.0.carrier.clone());
+            last = Some(delivered);
+        }
+        Ok(last.ok_or("no carriers found"))
+    }
+
+    // deliver_parallel sends one code through up to failover_depth carriers at once and takes
+    // the one that delivered it first, returning as soon as one did. Carriers that are still
+    // walking their ladder stop before its next stage once another delivered, but a step under
+    // way can't be called back: the number may be sent the code more than once and each carrier
+    // that sent it may charge for it. Each carrier is walked on a carrier call of its own, the
+    // ones no call is free for are left out, down to the first carrier walked on the request's
+    // own. Every carrier's attempt is stored, those finished after the request returned once
+    // record_parallel picks them up
+    fn deliver_parallel(
+        &self,
+        number: &str,
+        format: &CodeFormat,
+        channel: ChannelPreference,
+        locale: Option<&str>,
+        trace: &TraceContext,
+    ) -> Result<Result<Delivery, &'static str>, Error> {
+        self.record_parallel();
+        let mut carriers = Vec::new();
+        let mut names = Vec::new();
+        while carriers.len() < self.failover_depth {
+            match self.route_excluding(number, format, &names) {
+                Ok(carrier) => {
+                    names.push(carrier.get_name());
+                    carriers.push(carrier);
+                }
+                Err(e) if carriers.is_empty() => return Ok(Err(e)),
+                Err(_) => break,
+            }
+        }
+        let code = otp::generate_code(format, &self.rng);
+        let ladder = self.ladder_for(number, channel);
+        let message = self.templates.render(locale, &code);
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, walked) = mpsc::channel();
+        let mut walking = 0;
+        for carrier in carriers {
+            let name = carrier.get_name();
+            let walk = {
+                let (sender, stop) = (sender.clone(), stop.clone());
+                let (number, message) = (number.to_string(), message.clone());
+                let (ladder, trace, dry_run) = (ladder.clone(), trace.clone(), self.dry_run);
+                move || {
+                    let stop = Some(&*stop);
+                    let walked = walk(
+                        &*carrier, &number, &message, &ladder, 0, &trace, dry_run, stop,
+                    );
+                    // nobody waits for carriers that finished after the request returned
+                    let _ = sender.send(walked);
+                }
+            };
+            match self.calls.try_spawn(walk) {
+                Ok(()) => {}
+                Err(walk) if walking == 0 => walk(),
+                Err(_) => continue,
+            }
+            self.sent_through(&name, number);
+            walking += 1;
+        }
+        drop(sender);
+        // in the order the carriers finished in
+        let mut last = None;
+        for finished in 1..=walking {
+            // a walk that panicked sends nothing
+            let (entry, next, stopped) = match walked.recv() {
+                Ok(walked) => walked,
+                Err(_) => break,
+            };
+            let delivered = self.record_attempt(entry, trace)?;
+            let escalation = escalation(&ladder, delivered.step, next, stopped);
+            let delivery = (delivered, code.clone(), escalation);
+            if delivery.0.step != VerificationStep::Unreachable {
+                stop.store(true, Ordering::SeqCst);
+                if finished < walking {
+                    self.parallel.lock().unwrap().push((walked, trace.clone()));
+                }
+                return Ok(Ok(delivery));
+            }
+            // a carrier with a delayed step left carries on with the attempt
+            if last.as_ref().is_none_or(|l: &Delivery| l.2.is_none()) {
+                last = Some(delivery);
+            }
+        }
+        Ok(last.ok_or("no carriers found"))
+    }
+
+    // record_parallel stores the attempts of carriers that finished walking their ladder after
+    // the parallel request they were part of returned
+    fn record_parallel(&self) {
+        let pending = std::mem::take(&mut *self.parallel.lock().unwrap());
+        let mut walking = Vec::new();
+        for (walked, trace) in pending {
+            loop {
+                match walked.try_recv() {
+                    Ok((entry, _, _)) => {
+                        if let Err(e) = self.record_attempt(entry, &trace) {
+                            warn!(error = %e, "parallel attempt wasn't stored");
+                        }
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        walking.push((walked, trace));
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => break,
+                }
+            }
+        }
+        self.parallel.lock().unwrap().extend(walking);
+    }
+
+    // deliver walks the number's escalation ladder from step start with a new code through
+    // carrier, stopping after a step with a reply wait delivered it or before a delayed step, and
+    // stores the attempt, returning it along with the code and the step to escalate to once the
+    // reply wait or delay passed
+    #[allow(clippy::too_many_arguments)]
+    fn deliver(
+        &self,
+        carrier: &dyn TelecomProvider,
+        number: &str,
+        format: &CodeFormat,
+        channel: ChannelPreference,
+        locale: Option<&str>,
+        start: usize,
+        trace: &TraceContext,
+    ) -> Result<Delivery, Error> {
+        let code = otp::generate_code(format, &self.rng);
+        self.sent_through(&carrier.get_name(), number);
+        let ladder = self.ladder_for(number, channel);
+        let message = self.templates.render(locale, &code);
+        let (entry, next, stopped) = walk(
+            carrier,
+            number,
+            &message,
+            &ladder,
+            start,
+            trace,
+            self.dry_run,
+            None,
+        );
+        let delivered = self.record_attempt(entry, trace)?;
+        let escalation = escalation(&ladder, delivered.step, next, stopped);
+        Ok((delivered, code, escalation))
+    }
+
+    // ladder_for is the ladder walked for number given the channel the request prefers
+    fn ladder_for(&self, number: &str, channel: ChannelPreference) -> Ladder {
+        self.escalation
+            .read()
+            .unwrap()
+            .ladder_for(number)
+            .preferring(channel)
+    }
+
+    // sent_through announces that carrier is walking its ladder for number
+    fn sent_through(&self, carrier: &str, number: &str) {
+        info!(
+            carrier = %carrier,
+            simulated = self.dry_run,
+            "request handled by carrier"
+        );
+        self.events
+            .publish(VerificationEvent::new(EventKind::Sent, carrier, number));
+    }
+
+    // record_attempt stores an attempt a carrier made and accounts for it in the carrier's health
+    fn record_attempt(
+        &self,
+        entry: VerificationEntry,
+        trace: &TraceContext,
+    ) -> Result<Delivered, Error> {
+        // the entry is moved into the repo, only what callers and monitors read of it is kept
+        let delivered = Delivered {
+            carrier: entry.carrier.clone(),
+            step: entry.step,
+        };
+        let (time, simulated) = (entry.time, entry.simulated);
+        let stored = Instant::now();
+        self.repo.store_attempt(entry)?;
+        let health_config = self.health_config.read().unwrap().clone();
+        self.health
+            .lock()
+            .unwrap()
+            .record_repo_write(&health_config, stored.elapsed(), Utc::now());
+        if !simulated {
+            let reached = delivered.step != VerificationStep::Unreachable;
+            let alert_config = self.alert_config.read().unwrap().clone();
+            let alert = self.monitor.lock().unwrap().record(
+                &alert_config,
+                &delivered.carrier,
+                reached,
+                time,
+            );
+            if let Some(alert) = alert {
+                self.uptime.lock().unwrap().record(&alert);
+                self.alert_carrier(&alert_config, &alert, trace);
+            }
+            let slo_config = self.slo_config.read().unwrap().clone();
+            let remaining =
+                self.budgets
+                    .lock()
+                    .unwrap()
+                    .record(&slo_config, &delivered.carrier, reached, time);
+            if let Some(remaining) = remaining {
+                self.metrics.set(
+                    &metrics::ERROR_BUDGET_REMAINING,
+                    &[("carrier", &delivered.carrier)],
+                    remaining,
+                );
+            }
+        }
+        Ok(delivered)
+    }
+
+    // run_due_retries sends the attempts whose retry is due again, rescheduling the ones that
+    // still weren't delivered until the backoff schedule is exhausted
+    pub fn run_due_retries(&self) {
+        let due = self.retries.lock().unwrap().take_due(Utc::now());
+        for mut retry in due {
+            let _span = retry.trace.span().entered();
+            let _scope = reporting::enter(retry.trace.correlation_id.clone());
+            let session = self.otp.lock().unwrap().get(&retry.attempt_id).cloned();
+            let mut session = match session {
+                // numbers that opted out since are left to expire
+                Some(s) if s.state == SessionState::Retrying && !self.is_opted_out(&s.number) => s,
+                _ => continue,
+            };
+            // delayed steps don't count as retries
+            if retry.step == 0 {
+                retry.retries += 1;
+            }
+            let delivered = match self.test_numbers.deliver(&retry.number) {
+                Some((entry, code)) => Some((entry.into(), code, None)),
+                None => match self.deliver_retry(&retry, &session) {
+                    Ok(Ok(delivered)) => Some(delivered),
+                    Ok(Err(e)) => {
+                        warn!(attempt_id = %retry.attempt_id, error = %e, "retry failed");
+                        None
+                    }
+                    Err(e) => {
+                        warn!(attempt_id = %retry.attempt_id, error = %e, "retry failed");
+                        self.report(
+                            ErrorReport::new(ErrorKind::Retry, &format!("{:#}", e))
+                                .with_context("attempt_id", &retry.attempt_id),
+                        );
+                        None
+                    }
+                },
+            };
+            let kind = match delivered {
+                Some((entry, code, escalation)) if entry.step != VerificationStep::Unreachable => {
+                    session.carrier = entry.carrier;
+                    session.step = entry.step;
+                    session.code_hash = self.otp_config.hasher.hash(&code);
+                    session.sent_at = Utc::now();
+                    session.expires_at = Utc::now() + self.otp_config.ttl;
+                    session.state = SessionState::Pending;
+                    if let Some((step, wait)) = escalation {
+                        self.escalations.lock().unwrap().push(PendingRetry {
+                            step,
+                            due: Utc::now() + wait,
+                            ..retry.clone()
+                        });
+                    }
+                    EventKind::Delivered
+                }
+                Some((entry, _, Some((step, delay)))) => {
+                    session.carrier = entry.carrier;
+                    retry.step = step;
+                    retry.due = Utc::now() + delay;
+                    session.expires_at = session
+                        .expires_at
+                        .max(retry.due + self.otp_config.ttl + retry_span(&self.retry_config));
+                    self.retries.lock().unwrap().push(retry.clone());
+                    EventKind::Retrying
+                }
+                _ => match self.retry_config.delay(retry.retries) {
+                    Some(delay) => {
+                        retry.step = 0;
+                        retry.due = Utc::now() + delay;
+                        self.retries.lock().unwrap().push(retry.clone());
+                        EventKind::Retrying
+                    }
+                    None => {
+                        session.state = SessionState::Failed;
+                        EventKind::Failed
+                    }
+                },
+            };
+            // nothing but the sweeper changes a retrying session
+            let updated = self.update_session(&retry.attempt_id, SessionState::Retrying, |s| {
+                *s = session.clone()
+            });
+            if !updated {
+                continue;
+            }
+            let event = VerificationEvent::new(kind, &session.carrier, &session.number)
+                .with_step(session.step);
+            self.events.publish(event.clone());
+            if let (EventKind::Failed, Some(url), Some(webhooks)) =
+                (kind, &session.callback_url, &self.webhooks)
+            {
+                webhooks.dispatch(url.clone(), event, retry.trace.clone());
+            }
+        }
+    }
+
+    // deliver_retry sends retry's attempt again, starting the ladder over through the next
+    // carrier for retries and resuming it at a delayed step through the session's carrier
+    fn deliver_retry(
+        &self,
+        retry: &PendingRetry,
+        session: &OtpSession,
+    ) -> Result<Result<Delivery, &'static str>, Error> {
+        let locale = retry.locale.as_deref();
+        if retry.step == 0 {
+            return self.deliver_routed(
+                &retry.number,
+                &retry.format,
+                retry.channel,
+                locale,
+                &retry.trace,
+            );
+        }
+        let carrier = match self.session_carrier(session, &retry.format) {
+            Ok(carrier) => carrier,
+            Err(e) => return Ok(Err(e)),
+        };
+        self.deliver(
+            &*carrier,
+            &retry.number,
+            &retry.format,
+            retry.channel,
+            locale,
+            retry.step,
+            &retry.trace,
+        )
+        .map(Ok)
+    }
+
+    // update_session applies update to the session of attempt_id while it is still in state,
+    // sessions verified, locked or expired while their next code was being sent are left as they
+    // are. Returns whether the session was updated
+    fn update_session<F>(&self, attempt_id: &str, state: SessionState, update: F) -> bool
+    where
+        F: FnOnce(&mut OtpSession),
+    {
+        let mut otp = self.otp.lock().unwrap();
+        let mut session = match otp.get(attempt_id) {
+            Some(s) if s.state == state => s.clone(),
+            _ => return false,
+        };
+        update(&mut session);
+        otp.insert(session);
+        true
+    }
+
+    // session_carrier is the carrier further codes of session are sent through, the one that
+    // delivered the last code unless it is draining since
+    fn session_carrier(
+        &self,
+        session: &OtpSession,
+        format: &CodeFormat,
+    ) -> Result<Arc<dyn TelecomProvider>, &'static str> {
+        let draining = self.draining.read().unwrap().contains(&*session.carrier);
+        match self
+            .carriers()
+            .into_iter()
+            .find(|c| c.get_name() == session.carrier && !draining)
+        {
+            Some(carrier) => Ok(carrier),
+            None => self.route(&session.number, format),
+        }
+    }
+
+    // run_due_escalations sends a new code over the next ladder step to the delivered attempts
+    // whose reply wait passed without the code being submitted, the previous code stays valid
+    // when it can't be delivered
+    pub fn run_due_escalations(&self) {
+        let due = self.escalations.lock().unwrap().take_due(Utc::now());
+        for escalation in due {
+            let _span = escalation.trace.span().entered();
+            let _scope = reporting::enter(escalation.trace.correlation_id.clone());
+            let session = self
+                .otp
+                .lock()
+                .unwrap()
+                .get(&escalation.attempt_id)
+                .cloned();
+            let session = match session {
+                // verified, locked and expired attempts need no other code, nor do numbers that
+                // opted out since
+                Some(s)
+                    if s.state == SessionState::Pending
+                        && s.expires_at > Utc::now()
+                        && !self.is_opted_out(&s.number) =>
+                {
+                    s
+                }
+                _ => continue,
+            };
+            let carrier = match self.session_carrier(&session, &escalation.format) {
+                Ok(carrier) => carrier,
+                Err(e) => {
+                    warn!(attempt_id = %escalation.attempt_id, error = %e, "escalation failed");
+                    continue;
+                }
+            };
+            let delivered = self.deliver(
+                &*carrier,
+                &escalation.number,
+                &escalation.format,
+                escalation.channel,
+                escalation.locale.as_deref(),
+                escalation.step,
+                &escalation.trace,
+            );
+            let (entry, code, next) = match delivered {
+                Ok((entry, code, next)) if entry.step != VerificationStep::Unreachable => {
+                    (entry, code, next)
+                }
+                // the code already delivered stays valid meanwhile
+                Ok((_, _, Some((step, delay)))) => {
+                    self.escalations.lock().unwrap().push(PendingRetry {
+                        step,
+                        due: Utc::now() + delay,
+                        ..escalation.clone()
+                    });
+                    continue;
+                }
+                Ok(_) => {
+                    warn!(attempt_id = %escalation.attempt_id, "escalation wasn't delivered");
+                    continue;
+                }
+                Err(e) => {
+                    warn!(attempt_id = %escalation.attempt_id, error = %e, "escalation failed");
+                    self.report(
+                        ErrorReport::new(ErrorKind::Retry, &format!("{:#}", e))
+                            .with_context("attempt_id", &escalation.attempt_id),
+                    );
+                    continue;
+                }
+            };
+            // codes submitted meanwhile keep counting against the attempt
+            let code_hash = self.otp_config.hasher.hash(&code);
+            let updated = self.update_session(&escalation.attempt_id, SessionState::Pending, |s| {
+                s.carrier = entry.carrier.clone();
+                s.step = entry.step;
+                s.code_hash = code_hash;
+                s.sent_at = Utc::now();
+                s.expires_at = Utc::now() + self.otp_config.ttl;
+            });
+            if !updated {
+                continue;
+            }
+            if let Some((step, wait)) = next {
+                self.escalations.lock().unwrap().push(PendingRetry {
+                    step,
+                    due: Utc::now() + wait,
+                    ..escalation.clone()
+                });
+            }
+            self.events.publish(
+                VerificationEvent::new(EventKind::Delivered, &entry.carrier, &session.number)
+                    .with_step(entry.step),
+            );
+        }
+    }
+
+    // flush_repo writes the attempts and decisions the repo buffered, run by sweeper::spawn_flush
+    // and when the server stops
+    pub fn flush_repo(&self) -> Result<(), Error> {
+        self.repo.flush()
+    }
+
+    // sweep_expired moves attempts whose code wasn't submitted in time to expired, notifying
+    // their callbacks, and frees the state of long finished ones and of velocity counts that
+    // went quiet, run by sweeper::spawn
+    pub fn sweep_expired(&self) {
+        self.record_parallel();
+        let fraud = self.get_fraud_config();
+        self.velocity.lock().unwrap().sweep(&fraud, Utc::now());
+        let swept = self
+            .otp
+            .lock()
+            .unwrap()
+            .sweep(Utc::now(), self.otp_config.retention);
+        for session in swept {
+            self.retries.lock().unwrap().remove(&session.attempt_id);
+            self.escalations.lock().unwrap().remove(&session.attempt_id);
+            // locked attempts already failed
+            if session.state == SessionState::Locked {
+                continue;
+            }
+            let event =
+                VerificationEvent::new(EventKind::Expired, &session.carrier, &session.number)
+                    .with_step(session.step);
+            self.events.publish(event.clone());
+            if let (Some(url), Some(webhooks)) = (session.callback_url, &self.webhooks) {
+                webhooks.dispatch(url, event, TraceContext::new_root());
+            }
+        }
+    }
+
+    pub fn check_code(&self, request: &CheckRequest) -> Result<CheckResponse, CheckError> {
+        self.check_traced_code(request, &TraceContext::new_root())
+    }
+
+    // check_traced_code exchanges the code sent for an attempt for a token, completing the
+    // verification
+    pub fn check_traced_code(
+        &self,
+        request: &CheckRequest,
+        trace: &TraceContext,
+    ) -> Result<CheckResponse, CheckError> {
+        // submissions are part of the story of the request that started the verification
+        let stored = self.otp.lock().unwrap().get(&request.attempt_id).cloned();
+        let trace = &match stored.as_ref().and_then(|s| s.correlation_id.clone()) {
+            Some(id) => trace.clone().with_correlation_id(id),
+            None => trace.clone(),
+        };
+        let _span = trace.span().entered();
+        // checked within one lock, submissions of an attempt take turns
+        let checked = {
+            let mut otp = self.otp.lock().unwrap();
+            let was_locked = otp
+                .get(&request.attempt_id)
+                .is_some_and(|s| s.state == SessionState::Locked);
+            otp.check(&request.attempt_id, &request.code, &self.otp_config)
+                .map_err(|e| {
+                    let locking = matches!(e, CheckError::Locked { .. }) && !was_locked;
+                    let session = otp.get(&request.attempt_id).filter(|_| locking).cloned();
+                    (e, session)
+                })
+        };
+        let session = match checked {
+            Ok(s) => s,
+            Err((e, locked)) => {
+                // the submission that locks an attempt fails the verification
+                if let Some(session) = locked {
+                    {
+                        let event = VerificationEvent::new(
+                            EventKind::Failed,
+                            &session.carrier,
+                            &session.number,
+                        )
+                        .with_step(session.step);
+                        self.events.publish(event.clone());
+                        if let (Some(url), Some(webhooks)) = (&session.callback_url, &self.webhooks)
+                        {
+                            webhooks.dispatch(url.clone(), event, trace.clone());
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        };
+        if session.extra_codes > 0 {
+            return self.send_extra_code(session, trace);
+        }
+        let token = self
+            .issue_token(
+                &session.number,
+                &session.attempt_id,
+                session.metadata.clone(),
+                "code",
+            )
+            .map_err(|e| CheckError::Internal(e.to_string()))?;
+        let verified_at = Utc::now();
+        self.recent.lock().unwrap().record(
+            &session.number,
+            verified_at,
+            self.otp_config.reuse_window,
+        );
+        self.pumping
+            .lock()
+            .unwrap()
+            .verified(&session.number, verified_at);
+        self.receipts.lock().unwrap().append(&session, verified_at);
+        let event = VerificationEvent::new(EventKind::Verified, &session.carrier, &session.number)
+            .with_step(session.step);
+        self.events.publish(event.clone());
+        if let (Some(url), Some(webhooks)) = (session.callback_url, &self.webhooks) {
+            webhooks.dispatch(url, event, trace.clone());
+        }
+        Ok(CheckResponse {
+            token: Some(token),
+            next_code: None,
+        })
+    }
+
+    // send_extra_code sends the next code a risky attempt asks for after its last one was
+    // accepted, over the channel the last one wasn't delivered on
+    fn send_extra_code(
+        &self,
+        mut session: OtpSession,
+        trace: &TraceContext,
+    ) -> Result<CheckResponse, CheckError> {
+        let channel = match session.step {
+            VerificationStep::FirstSMS | VerificationStep::SecondSMS => Channel::Voice,
+            _ => Channel::Sms,
+        };
+        let delivered = match self.is_opted_out(&session.number) {
+            true => Err("number opted out of messages"),
+            false => self.session_carrier(&session, &session.format),
+        }
+        .map_err(|e| e.to_string())
+        .and_then(|carrier| {
+            self.deliver(
+                &*carrier,
+                &session.number,
+                &session.format,
+                channel.into(),
+                session.locale.as_deref(),
+                0,
+                trace,
+            )
+            .map_err(|e| e.to_string())
+        });
+        let (entry, code) = match delivered {
+            Ok((entry, code, _)) if entry.step != VerificationStep::Unreachable => (entry, code),
+            undelivered => {
+                if let Err(e) = undelivered {
+                    warn!(attempt_id = %session.attempt_id, error = %e, "extra code failed");
+                }
+                session.state = SessionState::Failed;
+                let event =
+                    VerificationEvent::new(EventKind::Failed, &session.carrier, &session.number)
+                        .with_step(session.step);
+                self.events.publish(event.clone());
+                if let (Some(url), Some(webhooks)) = (&session.callback_url, &self.webhooks) {
+                    webhooks.dispatch(url.clone(), event, trace.clone());
+                }
+                self.otp.lock().unwrap().insert(session);
+                return Err(CheckError::Undelivered);
+            }
+        };
+        session.carrier = entry.carrier;
+        session.step = entry.step;
+        session.code_hash = self.otp_config.hasher.hash(&code);
+        session.sent_at = Utc::now();
+        session.expires_at = Utc::now() + self.otp_config.ttl;
+        session.state = SessionState::Pending;
+        session.failed_checks = 0;
+        session.next_check_at = None;
+        session.extra_codes -= 1;
+        self.events.publish(
+            VerificationEvent::new(EventKind::Delivered, &session.carrier, &session.number)
+                .with_step(session.step),
+        );
+        // checked sessions are left to the request that checked them
+        self.otp.lock().unwrap().insert(session);
+        Ok(CheckResponse {
+            token: None,
+            next_code: Some(channel),
+        })
+    }
+
+    // verification_status reports the state of an attempt until its retention period ends
+    pub fn verification_status(&self, attempt_id: &str) -> Option<VerificationStatus> {
+        self.otp
+            .lock()
+            .unwrap()
+            .status(attempt_id, &self.otp_config)
+    }
+
+    fn is_opted_out(&self, number: &str) -> bool {
+        self.consent.lock().unwrap().get(number).is_some()
+    }
+
+    fn is_revoked(&self, attempt_id: &str) -> bool {
+        self.revoked.lock().unwrap().is_revoked(attempt_id)
+    }
+
+    // presents_token_for holds when token is a valid, unrevoked token issued for number
+    fn presents_token_for(&self, token: Option<&str>, number: &str) -> bool {
+        match token.map(|t| self.tokens.validate(t)) {
+            Some(Ok(claims)) => claims.sub == number && !self.is_revoked(&claims.attempt_id),
+            _ => false,
+        }
+    }
+
+    // opt_out stops any further messages to number until it opts back in
+    pub fn opt_out(&self, number: &str, source: OptOutSource) -> Result<OptOut, Error> {
+        let opt_out = OptOut {
+            number: country::normalize(number, self.default_region.as_deref())
+                .map_err(|e| anyhow!(e))?,
+            source,
+            opted_out_at: Utc::now(),
+        };
+        self.consent.lock().unwrap().opt_out(opt_out.clone());
+        Ok(opt_out)
+    }
+
+    // opt_in removes the opt-out of number, returning it if there was one
+    pub fn opt_in(&self, number: &str) -> Result<Option<OptOut>, Error> {
+        let number =
+            country::normalize(number, self.default_region.as_deref()).map_err(|e| anyhow!(e))?;
+        Ok(self.consent.lock().unwrap().opt_in(&number))
+    }
+
+    pub fn list_opt_outs(&self, page: &PageParams) -> Result<Page<OptOut>, Error> {
+        Ok(self
+            .consent
+            .lock()
+            .unwrap()
+            .list(page.position()?, page.limit()))
+    }
+
+    // receipt returns the receipt of a completed verification
+    pub fn receipt(&self, attempt_id: &str) -> Option<Receipt> {
+        self.receipts.lock().unwrap().get(attempt_id).cloned()
+    }
+
+    // list_receipts pages through the receipt chain in order, for auditors verifying it
+    pub fn list_receipts(&self, page: &PageParams) -> Result<Page<Receipt>, Error> {
+        Ok(self
+            .receipts
+            .lock()
+            .unwrap()
+            .list(page.position()?, page.limit()))
+    }
+
+    // issue_token issues the token of a verified number and records it in the audit log, method is
+    // how the number was verified
+    fn issue_token(
+        &self,
+        number: &str,
+        attempt_id: &str,
+        metadata: Option<serde_json::Map<String, serde_json::Value>>,
+        method: &str,
+    ) -> Result<String, Error> {
+        let token = self
+            .tokens
+            .issue_claims(number, attempt_id, metadata, Some(method))?;
+        // the number is the one that proved it holds the code
+        let after = serde_json::json!({ "method": method });
+        self.audit(
+            number,
+            AuditAction::TokenIssued,
+            attempt_id,
+            None,
+            Some(after),
+        );
+        Ok(token)
+    }
+
+    // audit records an action taken by actor in the audit log
+    pub fn audit<T: ToString>(
+        &self,
+        actor: &str,
+        action: AuditAction,
+        target: T,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) {
+        let entry = AuditEntry {
+            sequence: 0,
+            time: Utc::now(),
+            actor: actor.to_string(),
+            action,
+            target: target.to_string(),
+            before,
+            after,
+        };
+        self.audit.lock().unwrap().append(entry);
+    }
+
+    pub fn list_audit(
+        &self,
+        page: &PageParams,
+        query: &AuditQuery,
+    ) -> Result<Page<AuditEntry>, Error> {
+        Ok(self
+            .audit
+            .lock()
+            .unwrap()
+            .list(page.position()?, page.limit(), query))
+    }
+
+    // revoke_token invalidates a token issued by this server before it expires
+    pub fn revoke_token(&self, token: &str) -> Result<(), Error> {
+        let claims = self.tokens.validate(token)?;
+        self.revoked
+            .lock()
+            .unwrap()
+            .revoke(&claims.attempt_id, claims.exp);
+        Ok(())
+    }
+
+    // enroll_totp issues a TOTP secret to the number a code or TOTP verification token was issued
+    // for, an earlier enrollment is only replaced along with a code of its secret
+    pub fn enroll_totp(
+        &self,
+        token: &str,
+        request: &TotpEnrollRequest,
+    ) -> Result<TotpEnrollResponse, TotpError> {
+        let claims = self
+            .tokens
+            .validate(token)
+            .map_err(|e| TotpError::InvalidToken(e.to_string()))?;
+        if self.is_revoked(&claims.attempt_id) {
+            return Err(TotpError::InvalidToken("token was revoked".into()));
+        }
+        // reused tokens were issued without the number proving anything
+        if !matches!(claims.method.as_deref(), Some("code") | Some("totp")) {
+            return Err(TotpError::InvalidToken(
+                "token was not issued for a code or TOTP verification".into(),
+            ));
+        }
+        let enrolled = self.totp.lock().unwrap().get(&claims.sub).is_some();
+        if enrolled {
+            let code = request.code.as_deref().ok_or(TotpError::Enrolled)?;
+            self.prove_totp(&claims.sub, code)?;
+        }
+        let enrollment = TotpEnrollment::new(&claims.sub);
+        let response = TotpEnrollResponse {
+            secret: totp::base32(&enrollment.secret),
+            provisioning_uri: enrollment.provisioning_uri(&self.totp_issuer),
+        };
+        self.totp.lock().unwrap().insert(enrollment);
+        Ok(response)
+    }
+
+    // check_totp exchanges a TOTP code of an enrolled number for a token without sending it a
+    // code, wrong codes count towards the same lockout as sent ones
+    pub fn check_totp(&self, request: &TotpCheckRequest) -> Result<CheckResponse, TotpError> {
+        // numbers that can't be normalized can't have been enrolled either
+        let number = country::normalize(&request.number, self.default_region.as_deref())
+            .map_err(|_| TotpError::NotEnrolled)?;
+        self.prove_totp(&number, &request.code)?;
+        let attempt_id = otp::generate_attempt_id(&self.rng);
+        let token = self
+            .issue_token(&number, &attempt_id, None, "totp")
+            .map_err(|e| TotpError::Internal(e.to_string()))?;
+        self.recent
+            .lock()
+            .unwrap()
+            .record(&number, Utc::now(), self.otp_config.reuse_window);
+        Ok(CheckResponse {
+            token: Some(token),
+            next_code: None,
+        })
+    }
+
+    // prove_totp accepts a code of the number's enrollment, wrong codes count towards the same
+    // lockout as sent ones
+    fn prove_totp(&self, number: &str, code: &str) -> Result<(), TotpError> {
+        let locked_until = self.otp.lock().unwrap().locked_until(number);
+        if let Some(until) = locked_until {
+            return Err(TotpError::Locked { until });
+        }
+        // codes of a number are accepted within one lock, a code can't be used twice
+        let (accepted, remaining) = {
+            let mut totp = self.totp.lock().unwrap();
+            let mut enrollment = totp.get(number).ok_or(TotpError::NotEnrolled)?.clone();
+            let accepted = enrollment.accept(code, Utc::now());
+            let remaining = self
+                .otp_config
+                .max_failed_checks
+                .saturating_sub(enrollment.failed_checks);
+            if !accepted && remaining == 0 {
+                enrollment.failed_checks = 0;
+            }
+            totp.insert(enrollment);
+            (accepted, remaining)
+        };
+        match (accepted, remaining) {
+            (true, _) => Ok(()),
+            (false, 0) => {
+                let until = Utc::now() + self.otp_config.lockout;
+                self.otp.lock().unwrap().lock_number(number, until);
+                Err(TotpError::Locked { until })
+            }
+            (false, remaining) => Err(TotpError::Mismatch { remaining }),
+        }
+    }
+
+    pub fn introspect_token(&self, token: &str) -> IntrospectResponse {
+        match self.tokens.validate(token) {
+            Ok(claims) if self.is_revoked(&claims.attempt_id) => IntrospectResponse {
+                active: false,
+                revoked: true,
+                claims: None,
+            },
+            Ok(claims) => IntrospectResponse {
+                active: true,
+                revoked: false,
+                claims: Some(claims),
+            },
+            Err(_) => IntrospectResponse {
+                active: false,
+                revoked: false,
+                claims: None,
+            },
+        }
+    }
+
+    pub fn list_carriers(&self) -> Vec<CarrierStatus> {
+        let draining = self.draining.read().unwrap().clone();
+        self.carriers()
+            .iter()
+            .map(|c| CarrierStatus {
+                draining: draining.contains(&*c.get_name()),
+                name: c.get_name().to_string(),
+                weight: c.weight(),
+            })
+            .collect()
+    }
+
+    // add_carrier registers a carrier for routing, carrier names must be unique
+    pub fn add_carrier(&self, carrier: Box<dyn TelecomProvider>) -> Result<(), Error> {
+        let name = carrier.get_name();
+        let mut carriers = self.carriers.write().unwrap();
+        if carriers.iter().any(|c| c.get_name() == name) {
+            return Err(anyhow!("carrier already registered: {}", name));
+        }
+        carriers.push(Metered::wrap(carrier, &self.metrics));
+        Ok(())
+    }
+
+    // drain_carrier stops routing new attempts to a carrier while keeping it registered,
+    // returning false when no carrier with that name exists
+    pub fn drain_carrier(&self, name: &str) -> bool {
+        let carriers = self.carriers.read().unwrap();
+        if !carriers.iter().any(|c| &*c.get_name() == name) {
+            return false;
+        }
+        self.draining.write().unwrap().insert(name.to_string());
+        true
+    }
+
+    // remove_carrier unregisters a carrier entirely, returning false if it was not found
+    pub fn remove_carrier(&self, name: &str) -> bool {
+        let mut carriers = self.carriers.write().unwrap();
+        let len = carriers.len();
+        carriers.retain(|c| &*c.get_name() != name);
+        self.draining.write().unwrap().remove(name);
+        carriers.len() != len
+    }
+
+    // handle_provider_webhook hands an inbound callback to the named carrier, draining carriers
+    // still receive callbacks for messages they already sent, returns the number of callbacks
+    pub fn handle_provider_webhook(
+        &self,
+        provider_name: &str,
+        headers: &::http::HeaderMap,
+        body: &[u8],
+    ) -> Option<Result<usize, WebhookError>> {
+        let carrier = self
+            .carriers()
+            .into_iter()
+            .find(|c| &*c.get_name() == provider_name)?;
+        let callbacks = match carrier.handle_webhook(headers, body) {
+            Ok(c) => c,
+            Err(e) => {
+                if let WebhookError::Invalid(_) = e {
+                    self.report(
+                        ErrorReport::new(ErrorKind::Provider, &e.to_string())
+                            .with_context("carrier", provider_name),
+                    );
+                }
+                return Some(Err(e));
+            }
+        };
+        for callback in callbacks.iter() {
+            let (kind, number) = match callback {
+                ProviderCallback::DeliveryReport { number, delivered } => match delivered {
+                    true => (EventKind::Delivered, number),
+                    false => (EventKind::Failed, number),
+                },
+                ProviderCallback::VerificationResult { number, verified } => match verified {
+                    true => (EventKind::Verified, number),
+                    false => (EventKind::Failed, number),
+                },
+                ProviderCallback::InboundMessage { number, body } => {
+                    let source = OptOutSource::Carrier {
+                        carrier: provider_name.to_string(),
+                    };
+                    let changed = match consent::keyword(body) {
+                        Some(Keyword::OptOut) => self.opt_out(number, source).map(|_| ()),
+                        Some(Keyword::OptIn) => self.opt_in(number).map(|_| ()),
+                        None => Ok(()),
+                    };
+                    if let Err(e) = changed {
+                        warn!(carrier = provider_name, error = %e, "inbound message ignored");
+                    }
+                    continue;
+                }
+            };
+            self.events
+                .publish(VerificationEvent::new(kind, provider_name, number));
+        }
+        Some(Ok(callbacks.len()))
+    }
+
+    // returns a page of stored verification attempts in the order they were made
+    pub fn list_attempts(&self, page: &PageParams) -> Result<Page<VerificationEntry>, Error> {
+        Ok(self.repo.list_attempts(page.position()?, page.limit()))
+    }
+
+    pub fn get_number_policy(&self) -> NumberPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    pub fn set_number_policy(&self, mut policy: NumberPolicy) -> Result<(), Error> {
+        policy.validate()?;
+        *self.policy.write().unwrap() = policy;
+        Ok(())
+    }
+
+    pub fn get_fraud_config(&self) -> FraudConfig {
+        self.fraud_config.read().unwrap().clone()
+    }
+
+    pub fn set_fraud_config(&self, config: FraudConfig) -> Result<(), Error> {
+        self.validate_fraud_config(&config)?;
+        *self.fraud_config.write().unwrap() = config;
+        Ok(())
+    }
+
+    fn validate_fraud_config(&self, config: &FraudConfig) -> Result<(), Error> {
+        config.validate()?;
+        match (&config.pumping.alert_url, &self.webhooks) {
+            (Some(_), None) => Err(anyhow!("alert_url requires --webhook-secret")),
+            (Some(url), Some(_)) => webhook::parse_callback_url(url).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // throttles returns the prefixes currently throttled for traffic pumping
+    pub fn throttles(&self) -> Vec<Throttle> {
+        self.pumping.lock().unwrap().throttles()
+    }
+
+    pub fn lift_throttle(&self, prefix: &str) -> bool {
+        self.pumping.lock().unwrap().lift(prefix)
+    }
+
+    // alert tells operators about a new throttle, logged and sent to the alert_url of config
+    fn alert(&self, config: &FraudConfig, throttle: &Throttle, trace: &TraceContext) {
+        warn!(
+            prefix = %throttle.prefix,
+            until = %throttle.until.to_rfc3339(),
+            reason = %throttle.reason,
+            "throttling prefix"
+        );
+        let url = config.pumping.alert_url.as_deref();
+        if let (Some(url), Some(webhooks)) = (url, &self.webhooks) {
+            match webhook::parse_callback_url(url) {
+                Ok(url) => webhooks.dispatch_json(url, throttle, trace.clone()),
+                Err(e) => error!(error = %e, "alert not sent"),
+            }
+        }
+    }
+
+    // alert_carrier reports a carrier moving in or out of degradation to every alert webhook of
+    // config
+    fn alert_carrier(&self, config: &AlertConfig, alert: &CarrierAlert, trace: &TraceContext) {
+        warn!(
+            carrier = %alert.carrier,
+            kind = alert.kind.as_str(),
+            success_rate = alert.success_rate,
+            attempts = alert.attempts,
+            window_secs = alert.window_secs,
+            "carrier alert"
+        );
+        let webhooks = match &self.webhooks {
+            Some(w) => w,
+            None => return,
+        };
+        for hook in &config.webhooks {
+            match webhook::parse_callback_url(&hook.url) {
+                Ok(url) => webhooks.dispatch_json(url, &hook.payload(alert), trace.clone()),
+                Err(e) => error!(error = %e, "alert not sent"),
+            }
+        }
+    }
+
+    pub fn list_fraud_decisions(&self, page: &PageParams) -> Result<Page<FraudDecision>, Error> {
+        Ok(self.repo.list_decisions(page.position()?, page.limit()))
+    }
+
+    pub fn get_ranking_config(&self) -> RankingConfig {
+        self.repo.get_ranking_config()
+    }
+
+    pub fn set_ranking_config(&self, config: RankingConfig) -> Result<(), Error> {
+        self.repo.set_ranking_config(config)
+    }
+
+    // returns rankings of carrier validation rates
+    pub fn get_provider_rank(&self) -> RankResponse {
+        RankResponse {
+            rank: self.repo.get_provider_rank(),
+        }
+    }
+
+    // returns rankings of carrier validation rates scoped by query
+    pub fn get_provider_rank_by(&self, query: &RankQuery) -> RankResponse {
+        RankResponse {
+            rank: self.repo.get_provider_rank_by(query),
+        }
+    }
+
+    // slo_report returns the error budgets of the registered carriers with an SLO target
+    pub fn slo_report(&self) -> SloReport {
+        let now = Utc::now();
+        let slo_config = self.slo_config.read().unwrap().clone();
+        let budgets = self.budgets.lock().unwrap();
+        SloReport {
+            carriers: self
+                .carriers()
+                .iter()
+                .filter_map(|c| budgets.budget(&slo_config, &c.get_name(), now))
+                .collect(),
+        }
+    }
+
+    // uptime_report returns the share of the window each registered carrier wasn't degraded for,
+    // along with the incidents it was
+    pub fn uptime_report(&self, query: &UptimeQuery) -> Result<UptimeReport, Error> {
+        let carriers = self
+            .carriers()
+            .iter()
+            .map(|c| c.get_name().to_string())
+            .collect::<Vec<_>>();
+        let window = query.window()?;
+        Ok(self
+            .uptime
+            .lock()
+            .unwrap()
+            .report(&carriers, window, Utc::now()))
+    }
+
+    // health_score weighs the carriers that can be routed to, repo latency, server errors and
+    // pending retries and escalations into one score
+    pub fn health_score(&self) -> HealthScore {
+        let now = Utc::now();
+        let carriers = self.carriers();
+        let draining = self.draining.read().unwrap().clone();
+        let slo_config = self.slo_config.read().unwrap().clone();
+        let health_config = self.health_config.read().unwrap().clone();
+        let routable = carriers
+            .iter()
+            .map(|c| c.get_name())
+            .filter(|name| {
+                !draining.contains(&**name)
+                    && !self.monitor.lock().unwrap().is_degraded(name)
+                    && !self
+                        .budgets
+                        .lock()
+                        .unwrap()
+                        .is_exhausted(&slo_config, name, now)
+            })
+            .count();
+        let snapshot = Snapshot {
+            carriers: carriers.len(),
+            routable,
+            queue_depth: self.retries.lock().unwrap().len()
+                + self.escalations.lock().unwrap().len(),
+        };
+        self.health
+            .lock()
+            .unwrap()
+            .score(&health_config, snapshot, now)
+    }
+
+    // debug_state takes a snapshot of the routing, sessions, limits and repo for troubleshooting
+    pub fn debug_state(&self) -> DebugState {
+        let now = Utc::now();
+        let rank = self.repo.get_provider_rank();
+        let carriers = self
+            .list_carriers()
+            .into_iter()
+            .map(|c| {
+                let count = |result| {
+                    self.metrics.counter(
+                        &metrics::CARRIER_VERIFICATIONS,
+                        &[("carrier", &c.name), ("result", result)],
+                    )
+                };
+                CarrierHealth {
+                    rank: rank.iter().find(|(n, _)| **n == *c.name).map(|(_, r)| *r),
+                    delivered: count("delivered"),
+                    unreachable: count("unreachable"),
+                    degraded: self.monitor.lock().unwrap().is_degraded(&c.name),
+                    name: c.name,
+                    draining: c.draining,
+                    weight: c.weight,
+                }
+            })
+            .collect();
+        DebugState {
+            balancer: self.balancer.state(),
+            failover_depth: self.failover_depth,
+            carriers,
+            sessions: self.otp.lock().unwrap().counts(now),
+            pending_retries: self.retries.lock().unwrap().len(),
+            pending_escalations: self.escalations.lock().unwrap().len(),
+            velocity: self
+                .velocity
+                .lock()
+                .unwrap()
+                .occupancy(&self.get_fraud_config(), now),
+            throttled_prefixes: self.throttles().len(),
+            repo: RepoStats {
+                attempts: self.repo.attempt_count(),
+                decisions: self.repo.decision_count(),
+            },
+        }
+    }
+}
+
+// Claims are the numbers a new attempt is being delivered to, requests for the same number take
+// turns while requests for other numbers go ahead
+#[derive(Default)]
+struct Claims {
+    numbers: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+impl Claims {
+    // take claims number, waiting for the request holding it to let go
+    fn take(&self, number: &str) -> Claim<'_> {
+        let mut numbers = self.numbers.lock().unwrap();
+        while numbers.contains(number) {
+            numbers = self.released.wait(numbers).unwrap();
+        }
+        numbers.insert(number.to_string());
+        Claim {
+            claims: self,
+            number: number.to_string(),
+        }
+    }
+}
+
+// Claim holds a number until it is dropped
+struct Claim<'a> {
+    claims: &'a Claims,
+    number: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        // dropped while unwinding too, a poisoned lock is taken as is
+        let mut numbers = self
+            .claims
+            .numbers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        numbers.remove(&self.number);
+        self.claims.released.notify_all();
+    }
+}
+
+// replace sets setting to value, telling whether it changed
+fn replace<T: PartialEq + Clone>(setting: &RwLock<T>, value: &T) -> bool {
+    let mut setting = setting.write().unwrap();
+    if *setting == *value {
+        return false;
+    }
+    *setting = value.clone();
+    true
+}
+
+// Escalation is the ladder step an attempt moves on to and the wait before it, the reply wait
+// of a delivered attempt or the step's delay for one the earlier steps didn't deliver
+type Escalation = (usize, chrono::Duration);
+
+// Delivered is what is read of an attempt once it was moved into the repo
+#[derive(Debug)]
+struct Delivered {
+    carrier: Arc<str>,
+    step: VerificationStep,
+}
+
+impl From<VerificationEntry> for Delivered {
+    fn from(entry: VerificationEntry) -> Self {
+        Self {
+            carrier: entry.carrier,
+            step: entry.step,
+        }
+    }
+}
+
+// Walked is the attempt a carrier made walking a ladder, the index of the step after it and
+// whether it was stopped
+type Walked = (VerificationEntry, usize, bool);
+
+// Delivery is a stored attempt along with the code it sent and the escalation it is due for
+type Delivery = (Delivered, String, Option<Escalation>);
+
+// walk walks ladder from step start through carrier for number, stage by stage until one
+// delivered message, the ladder ran out, a delayed step is next or stop was set, returning the
+// attempt along with the index of the step after it and whether it was stopped
+#[allow(clippy::too_many_arguments)]
+fn walk(
+    carrier: &dyn TelecomProvider,
+    number: &str,
+    message: &Message,
+    ladder: &Ladder,
+    start: usize,
+    trace: &TraceContext,
+    dry_run: bool,
+    stop: Option<&AtomicBool>,
+) -> (VerificationEntry, usize, bool) {
+    let mut start = start;
+    loop {
+        let (stage, next) = ladder.stage(start);
+        // in a dry run the first step of the stage stands in for the carrier's delivery
+        let mut entry = match dry_run {
+            true => VerificationEntry {
+                carrier: carrier.get_name(),
+                number: number.to_string(),
+                time: chrono::offset::Utc::now(),
+                step: stage.verification_step(0),
+                simulated: true,
+                correlation_id: None,
+            },
+            false => carrier.verify_traced(number, message, &stage, &trace.child()),
+        };
+        entry.step = ladder.staged_step(start, entry.step);
+        entry.correlation_id = trace.correlation_id.clone();
+        // stages no step delivered move on to the next one right away, unless it has a delay
+        let stopped = stop.is_some_and(|s| s.load(Ordering::SeqCst));
+        if entry.step != VerificationStep::Unreachable
+            || next >= ladder.steps.len()
+            || ladder.steps[next].delay_secs > 0
+            || stopped
+        {
+            return (entry, next, stopped);
+        }
+        start = next;
+    }
+}
+
+// escalation is the step the scheduler attempts after an attempt that ended before step next,
+// once the step's delay or the reply wait before it has passed
+fn escalation(
+    ladder: &Ladder,
+    step: VerificationStep,
+    next: usize,
+    stopped: bool,
+) -> Option<Escalation> {
+    let delay = |next: usize| chrono::Duration::seconds(ladder.steps[next].delay_secs as i64);
+    match step {
+        VerificationStep::Unreachable if next < ladder.steps.len() && !stopped => {
+            Some((next, delay(next)))
+        }
+        VerificationStep::Unreachable => None,
+        _ if ladder.escalates(next) => Some((
+            next,
+            chrono::Duration::seconds(ladder.steps[next - 1].reply_wait_secs as i64) + delay(next),
+        )),
+        _ => None,
+    }
+}
+
+// retry_span is the longest a session can wait for its retries
+fn retry_span(config: &RetryConfig) -> chrono::Duration {
+    config
+        .backoff
+        .iter()
+        .fold(chrono::Duration::zero(), |span, d| span + *d)
+}
+
+// RoutingContext is what is known about an attempt's destination before a carrier is picked
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RoutingContext {
+    // mobile network the number is served by, when the lookup knows it
+    pub network: Option<MobileNetwork>,
+    // indices of the candidate carriers with a direct connection to network
+    pub direct: Vec<usize>,
+    // weight of each candidate carrier, every carrier is weighted 1 when empty
+    pub weights: Vec<u32>,
+    // rank score of each candidate carrier, less is better and None for carriers without
+    // attempts, only filled in for ranked balancers
+    pub scores: Vec<Option<f32>>,
+}
+
+impl RoutingContext {
+    pub fn weight(&self, idx: usize) -> u32 {
+        self.weights.get(idx).copied().unwrap_or(1)
+    }
+
+    pub fn score(&self, idx: usize) -> Option<f32> {
+        self.scores.get(idx).copied().flatten()
+    }
+}
+
+// used for BestBalancer and RoudRobinBalancer
+pub trait Balancer: Send + Sync {
+    fn next_idx(&self, carrier_len: usize, context: &RoutingContext) -> usize;
+    // ranked balancers are given the rank scores of the candidate carriers
+    fn ranked(&self) -> bool {
+        false
+    }
+    fn state(&self) -> BalancerState;
+}
+
+#[derive(Debug)]
+pub struct RoundRobinBalancer {
+    // turns taken so far, concurrent requests each take their own without a lock
+    cur_idx: AtomicUsize,
+}
+
+impl RoundRobinBalancer {
+    pub fn new() -> RoundRobinBalancer {
+        Self {
+            cur_idx: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Default for RoundRobinBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Balancer for RoundRobinBalancer {
+    fn next_idx(&self, carrier_len: usize, context: &RoutingContext) -> usize {
+        // directly connected carriers take turns among themselves
+        let candidates: Vec<usize> = match context.direct.is_empty() {
+            true => (0..carrier_len).collect(),
+            false => context.direct.clone(),
+        };
+        // each carrier takes as many consecutive turns as its weight
+        let total = candidates
+            .iter()
+            .map(|i| context.weight(*i) as usize)
+            .sum::<usize>();
+        // the carrier list can shrink at runtime, keep the index within bounds. Wrapping around
+        // after usize::MAX turns skips at most one carrier's turns once
+        let turn = self.cur_idx.fetch_add(1, Ordering::Relaxed) % total;
+        let mut passed = 0;
+        for idx in candidates {
+            passed += context.weight(idx) as usize;
+            if turn < passed {
+                return idx;
+            }
+        }
+        unreachable!("turn is less than the total weight")
+    }
+
+    fn state(&self) -> BalancerState {
+        BalancerState {
+            next_turn: Some(self.cur_idx.load(Ordering::Relaxed)),
+            ..BalancerState::new(BalancerType::RoundRobin)
+        }
+    }
 }
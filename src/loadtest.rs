@@ -0,0 +1,239 @@
+use crate::rng::SharedRng;
+use anyhow::{anyhow, Error};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// share of the generated requests and the number pattern of each, # is a random digit. Mobile
+// ranges of the countries verification traffic usually comes from, and a few numbers the server
+// rejects as clients send them too
+const NUMBER_MIX: &[(u32, &str)] = &[
+    (30, "+1##########"),
+    (18, "+91#########"),
+    (12, "+5511#########"),
+    (10, "+447#########"),
+    (8, "+4917########"),
+    (6, "+336########"),
+    (6, "+628#########"),
+    (5, "+5215########"),
+    (4, "+8190########"),
+    (1, "+12345"),
+];
+
+// share of requests, in percent, sent again for a number already requested like users asking
+// for another code
+const REPEAT_PERCENT: u32 = 5;
+
+// repeated numbers are drawn from the most recent ones
+const RECENT_NUMBERS: usize = 1000;
+
+// LoadTest is the traffic a load test sends
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    // base URL of the server, requests are POSTed to it
+    pub target: String,
+    pub rps: u32,
+    pub duration: Duration,
+    pub rng: SharedRng,
+}
+
+// LoadTestReport is how the target answered the generated requests
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct LoadTestReport {
+    pub requests: usize,
+    pub achieved_rps: f64,
+    // answered with an attempt id
+    pub sent: usize,
+    // answered with an error body, e.g. for invalid numbers or fraud limits
+    pub rejected: usize,
+    // responses other than 200, by status code
+    pub statuses: BTreeMap<u16, usize>,
+    // requests that got no response, e.g. timeouts or refused connections
+    pub transport_errors: usize,
+    // share of requests with a status other than 200 or no response at all
+    pub error_rate: f64,
+    pub latency_ms: Latency,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Latency {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+enum Outcome {
+    Sent,
+    Rejected,
+    Status(u16),
+    Failed,
+}
+
+// parse_duration reads durations such as 90, 90s, 5m or 1h, plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration {}, e.g. 60s or 5m", s))?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 3600,
+        _ => return Err(format!("invalid duration unit in {}, use s, m or h", s)),
+    };
+    match secs {
+        0 => Err("duration must be positive".to_string()),
+        _ => Ok(Duration::from_secs(secs)),
+    }
+}
+
+// synthetic_number draws a number from NUMBER_MIX
+fn synthetic_number(rng: &SharedRng) -> String {
+    let total = NUMBER_MIX.iter().map(|(share, _)| share).sum::<u32>();
+    rng.with(|rng| {
+        let mut pick = rng.gen_range(0, total);
+        let pattern = NUMBER_MIX
+            .iter()
+            .find(|(share, _)| match pick < *share {
+                true => true,
+                false => {
+                    pick -= share;
+                    false
+                }
+            })
+            .map_or(NUMBER_MIX[0].1, |(_, pattern)| pattern);
+        pattern
+            .chars()
+            .map(|c| match c {
+                '#' => std::char::from_digit(rng.gen_range(0, 10), 10).unwrap(),
+                c => c,
+            })
+            .collect()
+    })
+}
+
+// percentile returns the value below which q of sorted falls
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        n => sorted[((n as f64 * q).ceil() as usize).clamp(1, n) - 1],
+    }
+}
+
+async fn send(client: reqwest::Client, url: String, number: String) -> Outcome {
+    let body = serde_json::json!({
+        "number": number,
+        "time": chrono::offset::Utc::now().timestamp_millis(),
+    });
+    let response = match client.post(&url).json(&body).send().await {
+        Ok(r) => r,
+        Err(_) => return Outcome::Failed,
+    };
+    match response.status().as_u16() {
+        200 => match response.json::<serde_json::Value>().await {
+            Ok(body) if body.get("error").is_some() => Outcome::Rejected,
+            Ok(_) => Outcome::Sent,
+            Err(_) => Outcome::Failed,
+        },
+        status => Outcome::Status(status),
+    }
+}
+
+// run sends rps requests a second to the target for the duration of test, open loop, so a slow
+// target sees requests pile up rather than the rate drop
+pub async fn run(test: LoadTest) -> Result<LoadTestReport, Error> {
+    if test.rps == 0 {
+        return Err(anyhow!("rps must be positive"));
+    }
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let (sender, mut receiver) = mpsc::unbounded_channel::<(Outcome, Duration)>();
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / test.rps);
+    let mut recent: VecDeque<String> = VecDeque::with_capacity(RECENT_NUMBERS);
+    let started = Instant::now();
+    let mut requests = 0;
+    while started.elapsed() < test.duration {
+        ticks.tick().await;
+        let repeat = test.rng.with(|r| r.gen_range(0, 100)) < REPEAT_PERCENT;
+        let number = match recent.is_empty() || !repeat {
+            true => synthetic_number(&test.rng),
+            false => recent[test.rng.with(|r| r.gen_range(0, recent.len()))].clone(),
+        };
+        if recent.len() == RECENT_NUMBERS {
+            recent.pop_front();
+        }
+        recent.push_back(number.clone());
+        let (client, url, sender) = (client.clone(), test.target.clone(), sender.clone());
+        tokio::spawn(async move {
+            let sent_at = Instant::now();
+            let outcome = send(client, url, number).await;
+            let _ = sender.send((outcome, sent_at.elapsed()));
+        });
+        requests += 1;
+    }
+    let elapsed = started.elapsed();
+    // requests still in flight are waited for
+    drop(sender);
+
+    let mut report = LoadTestReport {
+        requests,
+        achieved_rps: requests as f64 / elapsed.as_secs_f64(),
+        ..LoadTestReport::default()
+    };
+    let mut latencies = Vec::with_capacity(requests);
+    while let Some((outcome, latency)) = receiver.recv().await {
+        match outcome {
+            Outcome::Sent => report.sent += 1,
+            Outcome::Rejected => report.rejected += 1,
+            Outcome::Status(status) => *report.statuses.entry(status).or_default() += 1,
+            Outcome::Failed => report.transport_errors += 1,
+        }
+        latencies.push(latency.as_secs_f64() * 1000.0);
+    }
+    latencies.sort_by(|a, b| a.total_cmp(b));
+    let errors = report.statuses.values().sum::<usize>() + report.transport_errors;
+    report.error_rate = errors as f64 / requests.max(1) as f64;
+    report.latency_ms = Latency {
+        p50: percentile(&latencies, 0.5),
+        p90: percentile(&latencies, 0.9),
+        p99: percentile(&latencies, 0.99),
+        max: latencies.last().copied().unwrap_or_default(),
+    };
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::country;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("60s"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn test_synthetic_number() {
+        let rng = SharedRng::seeded(1);
+        let numbers = (0..500).map(|_| synthetic_number(&rng)).collect::<Vec<_>>();
+        let valid = numbers
+            .iter()
+            .filter(|n| country::normalize(n, None).is_ok())
+            .count();
+        // all but the share of too short numbers are valid
+        assert!(valid < numbers.len() && valid > numbers.len() * 95 / 100);
+        assert!(numbers.iter().any(|n| country::country_of(n) == Some("IN")));
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::info;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+use utoipa::ToSchema;
+
+// the level control of the subscriber installed by init
+static CONTROL: OnceLock<LevelControl> = OnceLock::new();
+
+// LogFormat is how log events are written to stdout
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // one readable line per event, for local development
+    #[default]
+    Pretty,
+    // one JSON object per event with its fields at the top level, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!(
+                "invalid log format: {}, expected json or pretty",
+                s
+            )),
+        }
+    }
+}
+
+// LogFileConfig is a file log events are written to instead of stdout, for hosts without a log
+// shipper. The file is rotated by renaming it after the time it was rotated at, e.g.
+// telecom.log.20261014T085136.123, and a new one is started
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: String,
+    // the file is rotated before it grows past this many bytes, never by size when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    pub rotation: Rotation,
+    // rotated files kept, the oldest ones beyond it are deleted
+    pub max_files: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            max_bytes: None,
+            rotation: Rotation::default(),
+            max_files: 7,
+        }
+    }
+}
+
+impl LogFileConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.path.is_empty() || Path::new(&self.path).file_name().is_none() {
+            return Err(anyhow!("log_file requires the path of a file"));
+        }
+        if self.max_bytes == Some(0) {
+            return Err(anyhow!("log_file max_bytes must be greater than 0"));
+        }
+        if self.max_files == 0 {
+            return Err(anyhow!("log_file max_files must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+// Rotation is how often the log file is rotated regardless of its size, by UTC hours and days
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+impl Rotation {
+    // period names the hour or day time falls in, files are rotated once it changes
+    fn period(self, time: DateTime<Utc>) -> String {
+        match self {
+            Rotation::Never => String::new(),
+            Rotation::Hourly => time.format("%Y-%m-%dT%H").to_string(),
+            Rotation::Daily => time.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+// RotatingFile writes log events to the file of a LogFileConfig, rotating it as it goes
+#[derive(Debug)]
+pub struct RotatingFile {
+    config: LogFileConfig,
+    path: PathBuf,
+    current: Mutex<Current>,
+}
+
+#[derive(Debug)]
+struct Current {
+    file: File,
+    size: u64,
+    // the rotation period the file was started or last written in
+    period: String,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> Result<Self, Error> {
+        config.validate()?;
+        let path = PathBuf::from(&config.path);
+        let current = Self::open_current(config, &path)
+            .map_err(|e| anyhow!("failed to open log file {}: {}", config.path, e))?;
+        Ok(Self {
+            config: config.clone(),
+            path,
+            current: Mutex::new(current),
+        })
+    }
+
+    fn open_current(config: &LogFileConfig, path: &Path) -> io::Result<Current> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // a file left by an earlier run is rotated when it was last written in an earlier period
+        let modified = metadata
+            .modified()
+            .map_or_else(|_| Utc::now(), DateTime::from);
+        Ok(Current {
+            file,
+            size: metadata.len(),
+            period: config.rotation.period(modified),
+        })
+    }
+
+    fn write_event(&self, buf: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let now = Utc::now();
+        let period = self.config.rotation.period(now);
+        let full = self
+            .config
+            .max_bytes
+            .is_some_and(|max| current.size + buf.len() as u64 > max);
+        if current.size > 0 && (full || period != current.period) {
+            *current = self.rotate(now)?;
+        }
+        current.period = period;
+        current.file.write_all(buf)?;
+        current.size += buf.len() as u64;
+        Ok(())
+    }
+
+    // rotate renames the current file after now, starts a new one and deletes the rotated files
+    // beyond max_files
+    fn rotate(&self, now: DateTime<Utc>) -> io::Result<Current> {
+        let stamp = now.format("%Y%m%dT%H%M%S%.3f");
+        let mut rotated = PathBuf::from(format!("{}.{}", self.config.path, stamp));
+        // rotated within the same millisecond
+        for n in 1.. {
+            if !rotated.exists() {
+                break;
+            }
+            rotated = PathBuf::from(format!("{}.{}.{}", self.config.path, stamp, n));
+        }
+        fs::rename(&self.path, &rotated)?;
+        let current = Self::open_current(&self.config, &self.path)?;
+        for old in self.rotated()?.iter().rev().skip(self.config.max_files) {
+            fs::remove_file(old)?;
+        }
+        Ok(current)
+    }
+
+    // rotated lists the rotated files oldest first
+    fn rotated(&self) -> io::Result<Vec<PathBuf>> {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let prefix = format!("{}.", name);
+        let dir = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let mut rotated = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        rotated.sort();
+        Ok(rotated)
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_event(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = &'a RotatingFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+// parse_filter reads a level such as debug, or per module levels such as
+// info,telecom::webhook=debug
+pub fn parse_filter(level: &str) -> Result<EnvFilter, Error> {
+    // EnvFilter reads a bare word as a module name, which would silently ignore a misspelt level
+    for directive in level.split(',') {
+        let directive_level = directive.rsplit('=').next().unwrap_or_default().trim();
+        if directive_level.parse::<LevelFilter>().is_err() {
+            return Err(anyhow!("invalid log level: {}", directive));
+        }
+    }
+    EnvFilter::try_new(level).map_err(|e| anyhow!("invalid log level {}: {}", level, e))
+}
+
+// init installs the subscriber every log event of the process goes through, writing them to
+// file when one is given and to stdout otherwise. Its level can be changed later on through
+// set_log_level
+pub fn init(level: &str, format: LogFormat, file: Option<&LogFileConfig>) -> Result<(), Error> {
+    let (writer, ansi) = match file {
+        Some(config) => (BoxMakeWriter::new(RotatingFile::open(config)?), false),
+        // colors only where someone is reading them
+        None => (BoxMakeWriter::new(io::stdout), io::stdout().is_terminal()),
+    };
+    let (filter, handle) = reload::Layer::new(parse_filter(level)?);
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    let layer = match format {
+        LogFormat::Pretty => layer.with_target(false).boxed(),
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()
+        .map_err(|e| anyhow!("failed to set up logging: {}", e))?;
+    let _ = CONTROL.set(LevelControl::new(handle, level));
+    Ok(())
+}
+
+// LogLevelRequest changes the level of PUT /admin/log-level, e.g. info,telecom::provider=trace
+#[derive(Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogLevelRequest {
+    pub level: String,
+    // the configured level is restored after this many seconds, the level is kept until the next
+    // restart when omitted
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct LogLevelStatus {
+    pub level: String,
+    // the level of the config file or --log-level, restored at until
+    pub configured: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+}
+
+// Override is the level in place of the configured one
+#[derive(Debug)]
+struct Override {
+    level: String,
+    until: Option<DateTime<Utc>>,
+    // bumped on every change so that the restore of a replaced override does nothing
+    generation: u64,
+}
+
+// LevelControl swaps the filter of a subscriber for another one
+struct LevelControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    configured: String,
+    current: Mutex<Override>,
+}
+
+impl LevelControl {
+    fn new(handle: reload::Handle<EnvFilter, Registry>, configured: &str) -> Self {
+        Self {
+            handle,
+            configured: configured.to_string(),
+            current: Mutex::new(Override {
+                level: configured.to_string(),
+                until: None,
+                generation: 0,
+            }),
+        }
+    }
+
+    fn status(&self) -> LogLevelStatus {
+        let current = self.current.lock().unwrap();
+        LogLevelStatus {
+            level: current.level.clone(),
+            configured: self.configured.clone(),
+            until: current.until,
+        }
+    }
+
+    // set puts level in place until the duration passed, returning the generation restore
+    // takes it back with
+    fn set(&self, level: &str, duration: Option<Duration>) -> Result<u64, Error> {
+        if duration.is_some_and(|d| d.is_zero()) {
+            return Err(anyhow!("duration_secs must be greater than 0"));
+        }
+        let filter = parse_filter(level)?;
+        let mut current = self.current.lock().unwrap();
+        self.handle
+            .reload(filter)
+            .map_err(|e| anyhow!("failed to change the log level: {}", e))?;
+        current.level = level.to_string();
+        current.until = duration
+            .and_then(|d| chrono::Duration::from_std(d).ok())
+            .map(|d| Utc::now() + d);
+        current.generation += 1;
+        Ok(current.generation)
+    }
+
+    // restore puts the configured level back unless the level was changed again since
+    // generation, returning whether it did
+    fn restore(&self, generation: u64) -> bool {
+        let mut current = self.current.lock().unwrap();
+        if current.generation != generation {
+            return false;
+        }
+        // the configured level was parsed at startup
+        if let Ok(filter) = parse_filter(&self.configured) {
+            let _ = self.handle.reload(filter);
+        }
+        current.level = self.configured.clone();
+        current.until = None;
+        true
+    }
+}
+
+// log_level returns the level of the installed subscriber, None before init
+pub fn log_level() -> Option<LogLevelStatus> {
+    CONTROL.get().map(LevelControl::status)
+}
+
+// set_log_level changes the level of the installed subscriber, restoring the configured one on
+// the tokio runtime once the duration of request passed. None before init
+pub fn set_log_level(request: &LogLevelRequest) -> Option<Result<LogLevelStatus, Error>> {
+    let control = CONTROL.get()?;
+    let duration = request.duration_secs.map(Duration::from_secs);
+    let generation = match control.set(&request.level, duration) {
+        Ok(g) => g,
+        Err(e) => return Some(Err(e)),
+    };
+    info!(level = %request.level, duration_secs = request.duration_secs, "log level changed");
+    if let Some(duration) = duration {
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            if control.restore(generation) {
+                info!(level = %control.configured, "log level restored");
+            }
+        });
+    }
+    Some(Ok(control.status()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert!(parse_filter("debug").is_ok());
+        assert!(parse_filter("info,telecom::webhook=trace").is_ok());
+        assert!(parse_filter("loud").is_err());
+    }
+
+    #[test]
+    fn test_level_control() {
+        let (_filter, handle) = reload::Layer::<_, Registry>::new(parse_filter("info").unwrap());
+        let control = LevelControl::new(handle, "info");
+        assert!(control.set("loud", None).is_err());
+        assert!(control.set("debug", Some(Duration::ZERO)).is_err());
+
+        let first = control
+            .set("debug", Some(Duration::from_secs(600)))
+            .unwrap();
+        let status = control.status();
+        assert_eq!(status.level, "debug");
+        assert!(status.until.is_some());
+
+        // the restore of a replaced level leaves the new one alone
+        let second = control.set("info,telecom::provider=trace", None).unwrap();
+        assert!(!control.restore(first));
+        assert_eq!(control.status().level, "info,telecom::provider=trace");
+        assert!(control.restore(second));
+        assert_eq!(
+            control.status(),
+            LogLevelStatus {
+                level: "info".to_string(),
+                configured: "info".to_string(),
+                until: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("telecom-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config = LogFileConfig {
+            path: dir.join("telecom.log").to_string_lossy().to_string(),
+            max_bytes: Some(16),
+            rotation: Rotation::Never,
+            max_files: 2,
+        };
+        let file = RotatingFile::open(&config).unwrap();
+        for line in [
+            "event one\n",
+            "event two\n",
+            "event three\n",
+            "event four\n",
+        ] {
+            file.make_writer().write_all(line.as_bytes()).unwrap();
+        }
+        // every event but the first rotated the file, the oldest rotated one was deleted
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "event four\n");
+        let rotated = file.rotated().unwrap();
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(fs::read_to_string(&rotated[0]).unwrap(), "event two\n");
+        drop(file);
+
+        // a file last written yesterday is rotated on the first event of the day
+        let daily = RotatingFile::open(&LogFileConfig {
+            max_bytes: None,
+            rotation: Rotation::Daily,
+            ..config.clone()
+        })
+        .unwrap();
+        daily.current.lock().unwrap().period = "2000-01-01".to_string();
+        daily.make_writer().write_all(b"event five\n").unwrap();
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "event five\n");
+
+        assert!(LogFileConfig::default().validate().is_err());
+        assert!(LogFileConfig {
+            max_files: 0,
+            ..config
+        }
+        .validate()
+        .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
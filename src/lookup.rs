@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+// LineType is the kind of line a number is assigned to
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LineType {
+    Mobile,
+    Landline,
+    // VoIP, virtual and disposable numbers not tied to a subscriber line
+    Voip,
+    Unknown,
+}
+
+impl FromStr for LineType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mobile" => Ok(Self::Mobile),
+            "landline" => Ok(Self::Landline),
+            "voip" => Ok(Self::Voip),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err(anyhow!("invalid line type: {}", s)),
+        }
+    }
+}
+
+// LineTypeLookup resolves the line type of a number, e.g. through a carrier's number intelligence
+// API
+pub trait LineTypeLookup: Send + Sync {
+    fn line_type(&self, number: &str) -> LineType;
+}
+
+// MobileNetwork identifies the network a number is served by through its mobile country and
+// network codes
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct MobileNetwork {
+    pub mcc: String,
+    pub mnc: String,
+}
+
+impl fmt::Display for MobileNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.mcc, self.mnc)
+    }
+}
+
+// parses networks written as "<mcc>-<mnc>", e.g. "262-01"
+impl FromStr for MobileNetwork {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = |d: &str, lengths: &[usize]| {
+            lengths.contains(&d.len()) && d.chars().all(|c| c.is_ascii_digit())
+        };
+        match s.trim().split_once('-') {
+            Some((mcc, mnc)) if digits(mcc, &[3]) && digits(mnc, &[2, 3]) => Ok(Self {
+                mcc: mcc.to_string(),
+                mnc: mnc.to_string(),
+            }),
+            _ => Err(anyhow!(
+                "invalid mobile network, expected <mcc>-<mnc>: {}",
+                s
+            )),
+        }
+    }
+}
+
+// NetworkLookup resolves the mobile network a number is currently served by, e.g. through an
+// HLR or number portability lookup
+pub trait NetworkLookup: Send + Sync {
+    fn network(&self, number: &str) -> Option<MobileNetwork>;
+}
+
+// PrefixTable maps number prefixes to values, the longest matching prefix wins
+#[derive(Debug, Clone)]
+struct PrefixTable<T> {
+    prefixes: Vec<(String, T)>,
+}
+
+impl<T> Default for PrefixTable<T> {
+    fn default() -> Self {
+        Self {
+            prefixes: Vec::new(),
+        }
+    }
+}
+
+impl<T> PrefixTable<T> {
+    fn new(prefixes: Vec<(String, T)>) -> Result<Self, Error> {
+        let mut normalized = Vec::with_capacity(prefixes.len());
+        for (prefix, value) in prefixes {
+            let digits = prefix.strip_prefix('+').unwrap_or(&prefix);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return Err(anyhow!("invalid number prefix: {}", prefix));
+            }
+            normalized.push((digits.to_string(), value));
+        }
+        normalized.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+        Ok(Self {
+            prefixes: normalized,
+        })
+    }
+
+    fn get(&self, number: &str) -> Option<&T> {
+        let digits = number
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect::<String>();
+        self.prefixes
+            .iter()
+            .find(|(p, _)| digits.starts_with(p.as_str()))
+            .map(|(_, value)| value)
+    }
+}
+
+// PrefixLineTypeLookup answers lookups from a table of number prefixes, the longest matching
+// prefix wins
+#[derive(Debug, Default, Clone)]
+pub struct PrefixLineTypeLookup {
+    table: PrefixTable<LineType>,
+}
+
+impl PrefixLineTypeLookup {
+    pub fn new(prefixes: Vec<(String, LineType)>) -> Result<Self, Error> {
+        Ok(Self {
+            table: PrefixTable::new(prefixes)?,
+        })
+    }
+}
+
+impl LineTypeLookup for PrefixLineTypeLookup {
+    fn line_type(&self, number: &str) -> LineType {
+        self.table.get(number).copied().unwrap_or(LineType::Unknown)
+    }
+}
+
+// PrefixNetworkLookup answers lookups from a table of number prefixes, numbers ported to another
+// network need a more specific prefix
+#[derive(Debug, Default, Clone)]
+pub struct PrefixNetworkLookup {
+    table: PrefixTable<MobileNetwork>,
+}
+
+impl PrefixNetworkLookup {
+    pub fn new(prefixes: Vec<(String, MobileNetwork)>) -> Result<Self, Error> {
+        Ok(Self {
+            table: PrefixTable::new(prefixes)?,
+        })
+    }
+}
+
+impl NetworkLookup for PrefixNetworkLookup {
+    fn network(&self, number: &str) -> Option<MobileNetwork> {
+        self.table.get(number).cloned()
+    }
+}
+
+// parses a lookup table entry passed as "+4915678=voip"
+pub fn parse_line_type(s: &str) -> Result<(String, LineType), String> {
+    parse_entry(s, "line type")
+}
+
+// parses a lookup table entry passed as "+4917=262-03"
+pub fn parse_network(s: &str) -> Result<(String, MobileNetwork), String> {
+    parse_entry(s, "mcc-mnc")
+}
+
+fn parse_entry<T: FromStr<Err = Error>>(s: &str, value: &str) -> Result<(String, T), String> {
+    let (prefix, entry) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <prefix>=<{}>, got {}", value, s))?;
+    let entry = entry.parse().map_err(|e: Error| e.to_string())?;
+    Ok((prefix.trim().to_string(), entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_lookup() {
+        let lookup = PrefixLineTypeLookup::new(vec![
+            parse_line_type("+49=landline").unwrap(),
+            parse_line_type("+4915=mobile").unwrap(),
+            parse_line_type("+4915678=voip").unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(lookup.line_type("+49 30 1234567"), LineType::Landline);
+        assert_eq!(lookup.line_type("+4915112345678"), LineType::Mobile);
+        assert_eq!(lookup.line_type("+4915678123456"), LineType::Voip);
+        assert_eq!(lookup.line_type("+14155550100"), LineType::Unknown);
+
+        assert!(parse_line_type("+49=fax").is_err());
+        assert!(PrefixLineTypeLookup::new(vec![("+49-".to_string(), LineType::Voip)]).is_err());
+    }
+
+    #[test]
+    fn test_network_lookup() {
+        let lookup = PrefixNetworkLookup::new(vec![
+            parse_network("+49170=262-01").unwrap(),
+            parse_network("+49176=262-03").unwrap(),
+            parse_network("+491701=262-02").unwrap(),
+        ])
+        .unwrap();
+        let network = |number| lookup.network(number).map(|n| n.to_string());
+        assert_eq!(network("+491709876543").as_deref(), Some("262-01"));
+        assert_eq!(network("+491701234567").as_deref(), Some("262-02"));
+        assert_eq!(network("+4917612345678").as_deref(), Some("262-03"));
+        assert_eq!(network("+14155550100"), None);
+
+        assert!(parse_network("+49170=26201").is_err());
+        assert!(parse_network("+49170=262-1").is_err());
+        assert!("310-260".parse::<MobileNetwork>().is_ok());
+    }
+}
@@ -1,60 +1,417 @@
-use crate::provider::{MockTelecomProvider, TelecomProvider};
-use crate::repo::VerificationKeeper;
+use crate::repo::{RankQuery, VerificationEntry, VerificationKeeper, VerificationRepo};
 use crate::VerificationServer;
 use anyhow::{anyhow, Error};
-use rouille::{router, Response};
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use telecom::admin::AdminTokens;
+use telecom::config::{Config, RepoBackend};
+use telecom::escalation::EscalationConfig;
+use telecom::loadtest::LoadTest;
+use telecom::lookup::{PrefixLineTypeLookup, PrefixNetworkLookup};
+use telecom::memory::MemoryConfig;
+use telecom::otp::{CodeFormat, CodeHasher, OtpConfig};
+use telecom::reporting::{self, ErrorReporter, SentryReporter};
+use telecom::rng::SharedRng;
+use telecom::templates::Templates;
+use telecom::test_numbers::TestNumbers;
+use telecom::tls::TlsConfig;
+use telecom::token::{SigningKey, TokenConfig, TokenIssuer};
+use telecom::webhook::{WebhookConfig, WebhookDispatcher};
 use telecom::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
 
 fn main() -> Result<(), Error> {
     let args: Command = argh::from_env();
-    let address = format!("localhost:{}", args.port);
-    let mut carriers: Vec<Box<dyn TelecomProvider>> = Vec::new();
-
-    carriers.push(Box::new(MockTelecomProvider::new("carrier_1", 60, 50)?));
-    carriers.push(Box::new(MockTelecomProvider::new("carrier_2", 50, 60)?));
-    carriers.push(Box::new(MockTelecomProvider::new("carrier_3", 10, 100)?));
-
-    let keeper =
-        Box::new(VerificationKeeper::new([1, 2, 3, 4, 5]).expect("failed to create new keeper"));
-
-    let server = Mutex::new(VerificationServer::new(args.balancer, carriers, keeper));
-    println!("Now listening on {}", address);
-    rouille::start_server(address, move |request| {
-        router!(request,
-            // -------------------------
-            // POST VERIFICATION ATTEMPT
-            // -------------------------
-            (POST) (/) => {
-                println!("POST /");
-                let body = telecom::unwrap_request(request);
-                let request = match serde_json::from_slice::<VerificationRequest>(&body) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        return Response::text(format!(
-                            "from_slice error - {}:\n\t{}",
-                            e.to_string(),
-                            String::from_utf8(body).expect("from_utf8")
-                        ))
-                    }
-                };
-
-                match server.lock().unwrap().handle_request(&request) {
-                    Ok(r) => return Response::text(r.to_string()),
-                    Err(e) => return Response::text(format!("{}", anyhow!(e))),
-                }
-            },
-            // -------------------------
-            // GET CARRIER RANKINGS
-            // -------------------------
-            (GET) (/rank) => {
-                println!("GET /rank");
-                Response::json(&server.lock().unwrap().get_provider_rank())
-            },
-            _ => {
-                println!("invalid endpoint: {}", request.raw_url());
-                Response::text("404")
+    match args.command {
+        SubCommand::Serve(args) => serve(args),
+        SubCommand::CheckConfig(args) => check_config(args),
+        SubCommand::Rank(args) => rank(args),
+        SubCommand::Replay(args) => replay(args),
+        SubCommand::Loadtest(args) => loadtest(args),
+        SubCommand::Export(args) => export(args),
+        SubCommand::Import(args) => import(args),
+    }
+}
+
+fn serve(args: ServeCommand) -> Result<(), Error> {
+    let config = Config::load(&args)?;
+    logging::init(
+        &config.log_level,
+        config.log_format,
+        config.log_file.as_ref(),
+    )?;
+    let workers = http::worker_threads(config.workers)?;
+    // taken while the process is still single threaded
+    let sockets = systemd::listen_fds()?;
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .enable_all()
+        .build()?
+        .block_on(run(args, config, sockets))
+}
+
+fn check_config(args: CheckConfigCommand) -> Result<(), Error> {
+    let problems = schema::validate(args.config.as_deref(), args.profile.as_deref())?;
+    for problem in &problems {
+        match (&args.config, problem.line) {
+            (Some(path), Some(line)) => println!("{}:{}: {}", path, line, problem),
+            _ => println!("{}", problem),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!("{} problems found", problems.len()));
+    }
+    let config = Config::read(args.config.as_deref(), args.profile.as_deref())?;
+    if config.balancer.is_none() {
+        println!("no balancer set, serve must be passed --balancer");
+    }
+    println!("config ok, {} carriers", config.carriers.len());
+    Ok(())
+}
+
+fn rank(args: RankCommand) -> Result<(), Error> {
+    let config = Config::read(args.config.as_deref(), args.profile.as_deref())?;
+    let contents = std::fs::read_to_string(&args.entries)
+        .map_err(|e| anyhow!("failed to read {}: {}", args.entries, e))?;
+    // pages exported from GET /attempts keep the attempts under items
+    let entries = match serde_json::from_str::<serde_json::Value>(&contents)? {
+        serde_json::Value::Object(mut page) => page.remove("items").unwrap_or_default(),
+        entries => entries,
+    };
+    let entries: Vec<VerificationEntry> = serde_json::from_value(entries)
+        .map_err(|e| anyhow!("invalid attempts in {}: {}", args.entries, e))?;
+    let ranking = config.ranking.unwrap_or_default();
+    let keeper = VerificationKeeper::new(ranking.step_weights)?;
+    keeper.set_ranking_config(ranking)?;
+    for entry in entries {
+        keeper.store_attempt(entry)?;
+    }
+    let query = RankQuery {
+        window: args.window,
+        country: args.country,
+        channel: args.channel,
+        min_attempts: args.min_attempts,
+    };
+    let rank = keeper.get_provider_rank_by(&query);
+    println!("{}", serde_json::json!({ "rank": rank }));
+    Ok(())
+}
+
+fn replay(args: ReplayCommand) -> Result<(), Error> {
+    let mut config = Config::read(args.config.as_deref(), args.profile.as_deref())?;
+    if let Some(kind) = args.balancer {
+        config.set_balancer(kind);
+    }
+    if args.seed.is_some() {
+        config.seed = args.seed;
+    }
+    config.dry_run |= args.dry_run;
+    let requests = replay::read_requests(&args.input)?;
+    let server = replay::build_server(&config)?;
+    let report = replay::replay(&server, &requests)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+fn loadtest(args: LoadtestCommand) -> Result<(), Error> {
+    let test = LoadTest {
+        target: args.target,
+        rps: args.rps,
+        duration: args.duration.unwrap_or(Duration::from_secs(60)),
+        rng: args.seed.map(SharedRng::seeded).unwrap_or_default(),
+    };
+    let report = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(loadtest::run(test))?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+// progress is reported every this many records moved
+const PROGRESS_EVERY: u64 = 10000;
+
+// open_history opens the repo an export or import moves records from or to, the in-memory
+// backend of another process can't be reached and one of its own would be lost on exit
+fn open_history(
+    config: Option<&str>,
+    profile: Option<&str>,
+    backend: Option<RepoBackend>,
+    path: Option<String>,
+) -> Result<Box<dyn VerificationRepo>, Error> {
+    let mut config = Config::read(config, profile)?;
+    if let Some(backend) = backend {
+        config.repo.backend = backend;
+    }
+    if path.is_some() {
+        config.repo.path = path;
+    }
+    if config.repo.backend == RepoBackend::Memory {
+        return Err(anyhow!(
+            "the memory repo backend only lives in the serving process, list its attempts \
+             through GET /attempts or use --repo file"
+        ));
+    }
+    let step_weights = config.ranking.unwrap_or_default().step_weights;
+    // every attempt is exported, however many the server keeps in memory
+    repo::open_repo(&config.repo, &MemoryConfig::unbounded(), step_weights)
+}
+
+fn progress(verb: &'static str) -> impl FnMut(&export::Report) {
+    let mut reported = 0;
+    move |report| {
+        if report.total() >= reported + PROGRESS_EVERY {
+            reported = report.total();
+            eprintln!(
+                "{} {} attempts and {} decisions",
+                verb, report.attempts, report.decisions
+            );
+        }
+    }
+}
+
+fn export(args: ExportCommand) -> Result<(), Error> {
+    let repo = open_history(
+        args.config.as_deref(),
+        args.profile.as_deref(),
+        args.repo,
+        args.repo_path.clone(),
+    )?;
+    let file = std::fs::File::create(&args.out)
+        .map_err(|e| anyhow!("failed to create {}: {}", args.out, e))?;
+    let mut out = std::io::BufWriter::new(file);
+    let report = export::export(repo.as_ref(), &mut out, &mut progress("exported"))?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+fn import(args: ImportCommand) -> Result<(), Error> {
+    let repo = open_history(
+        args.config.as_deref(),
+        args.profile.as_deref(),
+        args.repo,
+        args.repo_path.clone(),
+    )?;
+    let file = std::fs::File::open(&args.input)
+        .map_err(|e| anyhow!("failed to open {}: {}", args.input, e))?;
+    let mut input = std::io::BufReader::new(file);
+    let report = export::import(repo.as_ref(), &mut input, &mut progress("imported"))
+        .map_err(|e| anyhow!("{}: {}", args.input, e))?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn run(args: ServeCommand, config: Config, sockets: systemd::Sockets) -> Result<(), Error> {
+    let balancer = config.balancer()?;
+    // kept so the config can be loaded again on reload
+    let source = args.clone();
+    let step_weights = config.ranking.clone().unwrap_or_default().step_weights;
+    let keeper = repo::open_repo(&config.repo, &config.memory, step_weights)?;
+
+    let tls = TlsConfig::from_paths(
+        args.tls_cert.as_deref(),
+        args.tls_key.as_deref(),
+        args.tls_client_ca.as_deref(),
+    )?;
+
+    let otp_config = OtpConfig {
+        format: CodeFormat {
+            length: args.code_length,
+            alphabet: args.code_alphabet,
+        },
+        ttl: chrono::Duration::seconds(args.code_ttl_secs.max(1).into()),
+        max_failed_checks: args.max_code_attempts.max(1),
+        check_delays: args.check_delays,
+        lockout: chrono::Duration::seconds(args.lockout_secs.into()),
+        retention: chrono::Duration::seconds(args.session_retention_secs.into()),
+        reuse_window: chrono::Duration::seconds(args.reuse_window_secs.into()),
+        duplicates: args.duplicate_requests,
+        hasher: args
+            .code_pepper
+            .as_ref()
+            .map_or_else(CodeHasher::ephemeral, CodeHasher::new),
+    };
+    otp_config.format.validate()?;
+    let mut token_config = match (&args.token_secret, &args.token_key) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!(
+                "--token-secret and --token-key are mutually exclusive"
+            ))
+        }
+        (Some(secret), None) => TokenConfig::new(secret),
+        (None, Some(path)) => TokenConfig::with_key(SigningKey::from_pem_file(path)?),
+        (None, None) => {
+            warn!("no --token-secret or --token-key given, tokens are signed with a random key");
+            TokenConfig::ephemeral()
+        }
+    };
+    token_config.ttl = chrono::Duration::seconds(args.token_ttl_secs.max(1).into());
+    token_config.grace = args
+        .token_grace_secs
+        .map(|secs| chrono::Duration::seconds(secs.into()));
+    for (at, secret) in &args.rotate_token_secret {
+        token_config = token_config.rotate_at(*at, SigningKey::Hs256(secret.as_bytes().to_vec()));
+    }
+    for (at, path) in &args.rotate_token_key {
+        token_config = token_config.rotate_at(*at, SigningKey::from_pem_file(path)?);
+    }
+    let templates = match &args.templates {
+        Some(path) => Templates::from_file(&args.default_locale, path)?,
+        None => Templates::default().with_overrides(&args.default_locale, HashMap::new())?,
+    };
+    // test numbers are matched against normalized request numbers
+    let region = args.default_region.as_deref();
+    let test_numbers = args
+        .test_number
+        .into_iter()
+        .map(|(number, test)| {
+            Ok((
+                country::normalize(&number, region).map_err(Error::msg)?,
+                test,
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    // carriers are registered from the config by with_config
+    let mut server = VerificationServer::new(balancer.kind, Vec::new(), keeper)
+        .with_templates(templates)
+        .with_otp_config(otp_config)
+        .with_totp_issuer(&args.totp_issuer)
+        .with_test_numbers(TestNumbers::new(test_numbers))
+        .with_tokens(TokenIssuer::new(&token_config)?)
+        .with_escalation(EscalationConfig {
+            default: args.escalation.unwrap_or_default(),
+            countries: args.country_escalation.into_iter().collect(),
+        })?
+        .with_retries(args.retry_backoff.unwrap_or_default())?
+        .with_line_type_lookup(Box::new(PrefixLineTypeLookup::new(args.line_type)?))
+        .with_network_lookup(Box::new(PrefixNetworkLookup::new(args.network)?));
+    if let Some(region) = region {
+        server = server.with_default_region(region)?;
+    }
+    if let Some(secret) = &args.webhook_secret {
+        let mut webhooks = WebhookConfig::new(secret);
+        webhooks.max_attempts = args.webhook_max_attempts.max(1);
+        webhooks.allowed_hosts = args.webhook_allow_host.clone();
+        webhooks.workers = args.webhook_workers.max(1);
+        webhooks.dry_run = config.dry_run;
+        server = server.with_webhooks(WebhookDispatcher::spawn(webhooks)?);
+    }
+    if let Some(reporter) = SentryReporter::spawn(&config.reporting, server.metrics())? {
+        let reporter: Arc<dyn ErrorReporter> = Arc::new(reporter);
+        reporting::install_panic_hook(reporter.clone());
+        server = server.with_error_reporter(reporter);
+    }
+    if let Some(seed) = config.seed {
+        warn!(seed, "randomness is seeded, codes are predictable");
+        server = server.with_seed(seed);
+    }
+    if config.dry_run {
+        warn!("dry run, carriers send no messages and webhooks are not delivered");
+        server = server.with_dry_run();
+    }
+    // seeded by with_seed
+    server = server.with_balancer(&balancer)?;
+    server = server.with_metrics(&config.metrics)?;
+    server = server.with_memory(&config.memory)?;
+    server = server
+        .with_max_provider_calls(config.max_provider_calls, config.max_queued_provider_calls)?;
+    // fraud alerts need the webhooks set up first
+    let server = server.with_config(source, config.clone())?;
+    let server = Arc::new(server);
+    retry::spawn(server.clone());
+    sweeper::spawn(server.clone());
+    sweeper::spawn_flush(server.clone(), config.repo.flush_interval());
+    reload::spawn(server.clone())?;
+    let http_config = config.http();
+    http_config.validate()?;
+    if let Some(problem) = config.check_admin_listener().first() {
+        return Err(anyhow!("{}", problem));
+    }
+    // sockets passed by systemd take the place of the configured ones
+    let listener = match (sockets.http, &config.unix_socket) {
+        (Some(fd), _) => http::Listener::Inherited(fd),
+        (None, Some(path)) => http::Listener::Unix(path.into()),
+        (None, None) => http::Listener::Tcp(config.address()),
+    };
+    let admin_listener = match (sockets.admin, &config.admin_unix_socket) {
+        (Some(fd), _) => Some(http::Listener::Inherited(fd)),
+        (None, Some(path)) => Some(http::Listener::Unix(path.into())),
+        (None, None) => config.admin_address().map(http::Listener::Tcp),
+    };
+    let grpc_listener = match (sockets.grpc, &config.grpc_port) {
+        (Some(fd), _) => match http::Listener::Inherited(fd).bind()? {
+            http::Bound::Tcp(listener) => Some(listener),
+            http::Bound::Unix(_) => return Err(anyhow!("the grpc socket must be a TCP socket")),
+        },
+        (None, Some(port)) => Some(std::net::TcpListener::bind(format!(
+            "{}:{}",
+            config.bind, port
+        ))?),
+        (None, None) => None,
+    };
+    let bound = listener.bind()?;
+    let admin_bound = admin_listener.map(|l| l.bind()).transpose()?;
+    // a socket passed by systemd isn't seen by check_admin_listener
+    if admin_bound.is_some() && config.admin_tokens.is_empty() {
+        return Err(anyhow!(
+            "admin_tokens: the admin API needs at least one token to authenticate operators with"
+        ));
+    }
+    // every listener accepts connections from here on
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog(server.clone());
+
+    let state = http::AppState::new(server.clone())
+        .with_admin_tokens(AdminTokens::new(&config.admin_tokens));
+    let routes = match (&admin_bound, config.admin_on_public) {
+        (Some(_), _) => http::Routes::Public,
+        (None, true) => http::Routes::All,
+        (None, false) => {
+            info!("admin API not served, set admin_port, admin_unix_socket or admin_on_public");
+            http::Routes::Public
+        }
+    };
+    let app = http::router(state.clone(), &http_config, routes);
+    let public = http::serve(bound, app, tls.clone(), &http_config);
+    // the admin API is served with the same TLS settings as the verification API
+    let admin = async {
+        match admin_bound {
+            Some(admin_bound) => {
+                let app = http::router(state, &http_config, http::Routes::Admin);
+                http::serve(admin_bound, app, tls, &http_config).await
             }
-        )
-    });
+            None => Ok(()),
+        }
+    };
+    let grpc = async {
+        match grpc_listener {
+            Some(grpc_listener) => {
+                grpc::serve(grpc_listener, server.clone(), config.proxies.clone()).await
+            }
+            None => Ok(()),
+        }
+    };
+    tokio::select! {
+        served = async { tokio::try_join!(public, admin, grpc) } => {
+            served?;
+        }
+        stopped = terminated() => {
+            let signal = stopped?;
+            info!(signal, "stopping server");
+        }
+    }
+    // records the repo buffered would be lost with the process
+    server.flush_repo()
+}
+
+// terminated resolves with the name of the signal once the process is asked to stop
+async fn terminated() -> Result<&'static str, Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
 }
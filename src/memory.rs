@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// MemoryConfig bounds the state kept in memory under sustained traffic, stores beyond their
+// bound evict what they were least recently told about first
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    // attempts the in-memory and file repos keep for listing and ranking, the oldest are evicted
+    // first. The file keeps every attempt regardless
+    pub max_attempts: usize,
+    // fraud decisions the repos keep for listing, the oldest are evicted first
+    pub max_decisions: usize,
+    // numbers remembered as verified for reuse_recent, the least recently verified are evicted
+    // first
+    pub max_recent_verifications: usize,
+    // numbers, prefixes and addresses velocity limits count requests of, the least recently
+    // requested are evicted first
+    pub max_velocity_keys: usize,
+    // audit entries and receipts kept for listing, the oldest are evicted first
+    pub max_audit_entries: usize,
+    pub max_receipts: usize,
+    // opted out numbers remembered, the ones that opted out longest ago are evicted first and
+    // can be sent messages again
+    pub max_opt_outs: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1_000_000,
+            max_decisions: 100_000,
+            max_recent_verifications: 100_000,
+            max_velocity_keys: 100_000,
+            max_audit_entries: 100_000,
+            max_receipts: 100_000,
+            max_opt_outs: 1_000_000,
+        }
+    }
+}
+
+impl MemoryConfig {
+    // unbounded keeps everything, for exports that move every record of a repo
+    pub fn unbounded() -> Self {
+        Self {
+            max_attempts: usize::MAX,
+            max_decisions: usize::MAX,
+            max_recent_verifications: usize::MAX,
+            max_velocity_keys: usize::MAX,
+            max_audit_entries: usize::MAX,
+            max_receipts: usize::MAX,
+            max_opt_outs: usize::MAX,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let bounds = [
+            self.max_attempts,
+            self.max_decisions,
+            self.max_recent_verifications,
+            self.max_velocity_keys,
+            self.max_audit_entries,
+            self.max_receipts,
+            self.max_opt_outs,
+        ];
+        if bounds.contains(&0) {
+            return Err(anyhow!("memory bounds must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+// LruMap is a map of at most max keys, evicting the least recently touched first without
+// scanning the others
+#[derive(Debug, Clone)]
+pub struct LruMap<V> {
+    entries: HashMap<String, (u64, V)>,
+    // keys by the tick they were last touched at, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    max: usize,
+    evicted: u64,
+}
+
+impl<V> Default for LruMap<V> {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl<V> LruMap<V> {
+    pub fn new(max: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            max,
+            evicted: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries.get(key).map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(_, v)| v)
+    }
+
+    // touch makes key the most recently touched, inserting value() when it is missing. Keys
+    // beyond max are only evicted by evict, so the value can still be used
+    pub fn touch<F: FnOnce() -> V>(&mut self, key: &str, value: F) -> &mut V {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.order.remove(&entry.0);
+                entry.0 = tick;
+            }
+            None => {
+                self.entries.insert(key.to_string(), (tick, value()));
+            }
+        }
+        self.order.insert(tick, key.to_string());
+        &mut self.entries.get_mut(key).expect("touched key").1
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let (tick, value) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+
+    // oldest is the least recently touched key along with its value
+    pub fn oldest(&self) -> Option<(&str, &V)> {
+        let key = self.order.values().next()?;
+        self.entries.get(key).map(|(_, v)| (key.as_str(), v))
+    }
+
+    // evict removes the least recently touched keys beyond max, counting them as evicted
+    pub fn evict(&mut self) {
+        while self.entries.len() > self.max {
+            match self.order.pop_first() {
+                Some((_, key)) => {
+                    self.entries.remove(&key);
+                    self.evicted += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    // evicted tells the keys evicted so far to stay within max
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.entries.iter().map(|(k, (_, v))| (k.as_str(), v))
+    }
+
+    // retain keeps the keys f holds for, the others are removed without counting as evicted
+    pub fn retain<F: FnMut(&str, &mut V) -> bool>(&mut self, mut f: F) {
+        let order = &mut self.order;
+        self.entries.retain(|k, (tick, v)| {
+            let keep = f(k, v);
+            if !keep {
+                order.remove(tick);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_map() {
+        let mut map = LruMap::new(2);
+        *map.touch("a", || 0) += 1;
+        map.touch("b", || 0);
+        // touching a again makes b the least recently touched
+        *map.touch("a", || 0) += 1;
+        map.touch("c", || 0);
+        assert_eq!(map.len(), 3);
+        map.evict();
+        assert_eq!(map.get("a"), Some(&2));
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.oldest(), Some(("a", &2)));
+        assert_eq!(map.evicted(), 1);
+
+        map.retain(|k, _| k != "a");
+        assert_eq!(map.oldest(), Some(("c", &0)));
+        assert_eq!(map.remove("c"), Some(0));
+        assert!(map.is_empty());
+        assert_eq!(map.evicted(), 1);
+    }
+}
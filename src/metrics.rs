@@ -0,0 +1,597 @@
+use crate::escalation::Ladder;
+use crate::lookup::MobileNetwork;
+use crate::otp::CodeFormat;
+use crate::provider::{ProviderCallback, TelecomProvider, WebhookError};
+use crate::repo::{VerificationEntry, VerificationStep};
+use crate::statsd::{Sample, Statsd, StatsdConfig};
+use crate::templates::Message;
+use crate::trace::TraceContext;
+use anyhow::{anyhow, Error};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// content type of the Prometheus text exposition format rendered by Metrics::render
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// bucket upper bounds of duration histograms, in seconds
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// MetricsConfig tunes the histograms of the registry and where else metrics are pushed to
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    // bucket bounds in seconds of the carrier verify and send duration histograms, ascending
+    pub carrier_latency_buckets: Vec<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statsd: Option<StatsdConfig>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            carrier_latency_buckets: DEFAULT_BUCKETS.to_vec(),
+            statsd: None,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        let buckets = &self.carrier_latency_buckets;
+        if buckets.is_empty() {
+            return Err(anyhow!("carrier_latency_buckets must not be empty"));
+        }
+        if buckets.iter().any(|b| !b.is_finite() || *b <= 0.0) {
+            return Err(anyhow!("carrier_latency_buckets must be positive seconds"));
+        }
+        if let Some(w) = buckets.windows(2).find(|w| w[0] >= w[1]) {
+            return Err(anyhow!(
+                "carrier_latency_buckets must be ascending, {} is followed by {}",
+                w[0],
+                w[1]
+            ));
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.validate()?;
+        }
+        Ok(())
+    }
+
+    // apply sets the buckets of metrics and starts pushing them to the StatsD agent, validate
+    // first
+    pub fn apply(&self, metrics: &Metrics) -> Result<(), Error> {
+        for metric in &[&CARRIER_VERIFY_DURATION, &CARRIER_SEND_DURATION] {
+            metrics.set_buckets(metric, &self.carrier_latency_buckets);
+        }
+        let statsd = match &self.statsd {
+            Some(config) => Some(Arc::new(Statsd::connect(config)?)),
+            None => None,
+        };
+        metrics.registry.lock().unwrap().statsd = statsd;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+// Metric names and explains a series of values, labels tell the series of one metric apart
+#[derive(Debug, PartialEq)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+pub const REQUESTS: Metric = Metric {
+    name: "telecom_requests_total",
+    help: "Verification requests handled, by outcome.",
+    kind: Kind::Counter,
+};
+
+pub const REQUEST_DURATION: Metric = Metric {
+    name: "telecom_request_duration_seconds",
+    help: "Time taken to handle a verification request, carrier deliveries included.",
+    kind: Kind::Histogram,
+};
+
+pub const CARRIER_VERIFICATIONS: Metric = Metric {
+    name: "telecom_carrier_verifications_total",
+    help: "Verifications carriers were asked for, by whether they reached the number.",
+    kind: Kind::Counter,
+};
+
+pub const CARRIER_VERIFY_DURATION: Metric = Metric {
+    name: "telecom_carrier_verify_duration_seconds",
+    help: "Time carriers took to walk a stage of the ladder for a number.",
+    kind: Kind::Histogram,
+};
+
+pub const CARRIER_SEND_DURATION: Metric = Metric {
+    name: "telecom_carrier_send_duration_seconds",
+    help: "Time carriers took to send a single SMS or voice message.",
+    kind: Kind::Histogram,
+};
+
+pub const BALANCER_PICKS: Metric = Metric {
+    name: "telecom_balancer_picks_total",
+    help: "Attempts the balancer routed to each carrier.",
+    kind: Kind::Counter,
+};
+
+pub const ROUTING_EXCLUSIONS: Metric = Metric {
+    name: "telecom_routing_exclusions_total",
+    help: "Carriers left out of routing decisions, by reason.",
+    kind: Kind::Counter,
+};
+
+pub const ERROR_BUDGET_REMAINING: Metric = Metric {
+    name: "telecom_carrier_error_budget_remaining",
+    help: "Share of each carrier's error budget left in the longest SLO window.",
+    kind: Kind::Gauge,
+};
+
+pub const REPO_ATTEMPTS: Metric = Metric {
+    name: "telecom_repo_attempts",
+    help: "Verification attempts stored in the repo.",
+    kind: Kind::Gauge,
+};
+
+pub const REPO_DECISIONS: Metric = Metric {
+    name: "telecom_repo_decisions",
+    help: "Fraud decisions stored in the repo.",
+    kind: Kind::Gauge,
+};
+
+pub const EVICTIONS: Metric = Metric {
+    name: "telecom_evictions_total",
+    help: "Records evicted from in-memory stores to stay within their bounds, by store.",
+    kind: Kind::Counter,
+};
+
+pub const DROPPED_REPORTS: Metric = Metric {
+    name: "telecom_error_reports_dropped_total",
+    help: "Error reports that were not sent, by reason.",
+    kind: Kind::Counter,
+};
+
+// every metric in the order they are rendered
+const METRICS: &[&Metric] = &[
+    &REQUESTS,
+    &REQUEST_DURATION,
+    &CARRIER_VERIFICATIONS,
+    &CARRIER_VERIFY_DURATION,
+    &CARRIER_SEND_DURATION,
+    &BALANCER_PICKS,
+    &ROUTING_EXCLUSIONS,
+    &ERROR_BUDGET_REMAINING,
+    &REPO_ATTEMPTS,
+    &REPO_DECISIONS,
+    &EVICTIONS,
+    &DROPPED_REPORTS,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Clone)]
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        bounds: Arc<[f64]>,
+        // observations at or below each bound and above the one before, not cumulative
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    series: BTreeMap<&'static str, BTreeMap<Labels, Value>>,
+    // bucket bounds of the histograms that don't use DEFAULT_BUCKETS
+    buckets: HashMap<&'static str, Arc<[f64]>>,
+    statsd: Option<Arc<Statsd>>,
+}
+
+// Metrics is the registry of the server's counters, gauges and histograms, clones share it
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // set_buckets has the histogram metric count observations into buckets from now on, the
+    // observations counted into its previous buckets are dropped
+    pub fn set_buckets(&self, metric: &Metric, buckets: &[f64]) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.buckets.insert(metric.name, buckets.into());
+        registry.series.remove(metric.name);
+    }
+
+    // update changes a value of the registry and pushes sample to the StatsD agent if there is one
+    fn update(
+        &self,
+        metric: &Metric,
+        labels: &[(&'static str, &str)],
+        sample: Sample,
+        f: impl FnOnce(&mut Value),
+    ) {
+        let statsd = self.apply_update(metric, labels, f);
+        if let Some(statsd) = statsd {
+            statsd.send(metric, labels, sample);
+        }
+    }
+
+    fn apply_update(
+        &self,
+        metric: &Metric,
+        labels: &[(&'static str, &str)],
+        f: impl FnOnce(&mut Value),
+    ) -> Option<Arc<Statsd>> {
+        let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let registry = &mut *self.registry.lock().unwrap();
+        let buckets = &registry.buckets;
+        let value = registry
+            .series
+            .entry(metric.name)
+            .or_default()
+            .entry(labels)
+            .or_insert_with(|| match metric.kind {
+                Kind::Counter => Value::Counter(0),
+                Kind::Gauge => Value::Gauge(0.0),
+                Kind::Histogram => {
+                    let bounds = buckets
+                        .get(metric.name)
+                        .cloned()
+                        .unwrap_or_else(|| DEFAULT_BUCKETS.into());
+                    Value::Histogram {
+                        counts: vec![0; bounds.len()],
+                        bounds,
+                        sum: 0.0,
+                        count: 0,
+                    }
+                }
+            });
+        f(value);
+        registry.statsd.clone()
+    }
+
+    // counter returns the count of a counter, 0 when it wasn't incremented yet
+    pub fn counter(&self, metric: &Metric, labels: &[(&'static str, &str)]) -> u64 {
+        let labels: Labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let registry = self.registry.lock().unwrap();
+        match registry
+            .series
+            .get(metric.name)
+            .and_then(|s| s.get(&labels))
+        {
+            Some(Value::Counter(n)) => *n,
+            _ => 0,
+        }
+    }
+
+    // inc adds one to a counter
+    pub fn inc(&self, metric: &Metric, labels: &[(&'static str, &str)]) {
+        self.update(metric, labels, Sample::Count(1), |value| {
+            if let Value::Counter(n) = value {
+                *n += 1;
+            }
+        })
+    }
+
+    // count_to raises a counter to total, for counts kept by what is counted, e.g. evictions
+    pub fn count_to(&self, metric: &Metric, labels: &[(&'static str, &str)], total: u64) {
+        let added = total.saturating_sub(self.counter(metric, labels));
+        if added == 0 {
+            return;
+        }
+        self.update(metric, labels, Sample::Count(added), |value| {
+            if let Value::Counter(n) = value {
+                *n = (*n).max(total);
+            }
+        })
+    }
+
+    pub fn set(&self, metric: &Metric, labels: &[(&'static str, &str)], to: f64) {
+        self.update(metric, labels, Sample::Gauge(to), |value| {
+            if let Value::Gauge(v) = value {
+                *v = to;
+            }
+        })
+    }
+
+    // observe records a value of a histogram, e.g. a duration in seconds
+    pub fn observe(&self, metric: &Metric, labels: &[(&'static str, &str)], observed: f64) {
+        self.update(metric, labels, Sample::Timing(observed), |value| {
+            if let Value::Histogram {
+                bounds,
+                counts,
+                sum,
+                count,
+            } = value
+            {
+                if let Some(i) = bounds.iter().position(|b| observed <= *b) {
+                    counts[i] += 1;
+                }
+                *sum += observed;
+                *count += 1;
+            }
+        })
+    }
+
+    // render writes every series in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+        for metric in METRICS {
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+            for (labels, value) in registry.series.get(metric.name).into_iter().flatten() {
+                match value {
+                    Value::Counter(n) => {
+                        let _ = writeln!(out, "{}{} {}", metric.name, format_labels(labels), n);
+                    }
+                    Value::Gauge(v) => {
+                        let _ = writeln!(out, "{}{} {}", metric.name, format_labels(labels), v);
+                    }
+                    Value::Histogram {
+                        bounds,
+                        counts,
+                        sum,
+                        count,
+                    } => {
+                        let mut cumulative = 0;
+                        for (bound, n) in bounds.iter().zip(counts) {
+                            cumulative += n;
+                            write_bucket(&mut out, metric, labels, bound.to_string(), cumulative);
+                        }
+                        write_bucket(&mut out, metric, labels, "+Inf".to_string(), *count);
+                        let labels = format_labels(labels);
+                        let _ = writeln!(out, "{}_sum{} {}", metric.name, labels, sum);
+                        let _ = writeln!(out, "{}_count{} {}", metric.name, labels, count);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+// write_bucket writes the count of observations at or below bound, buckets are cumulative
+fn write_bucket(out: &mut String, metric: &Metric, labels: &Labels, bound: String, count: u64) {
+    let mut labels = labels.clone();
+    labels.push(("le", bound));
+    let _ = writeln!(
+        out,
+        "{}_bucket{} {}",
+        metric.name,
+        format_labels(&labels),
+        count
+    );
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", pairs.join(","))
+}
+
+// Metered counts the verifications of the carrier it wraps by whether they reached the number,
+// and times them along with the sends made through it
+pub struct Metered {
+    inner: Box<dyn TelecomProvider>,
+    metrics: Metrics,
+}
+
+impl Metered {
+    // wrap shares the carrier, requests handled at once call it concurrently
+    pub fn wrap(inner: Box<dyn TelecomProvider>, metrics: &Metrics) -> Arc<dyn TelecomProvider> {
+        Arc::new(Self {
+            inner,
+            metrics: metrics.clone(),
+        })
+    }
+
+    fn record(&self, start: Instant, entry: VerificationEntry) -> VerificationEntry {
+        self.metrics.observe(
+            &CARRIER_VERIFY_DURATION,
+            &[("carrier", &entry.carrier)],
+            start.elapsed().as_secs_f64(),
+        );
+        let result = match entry.step {
+            VerificationStep::Unreachable => "unreachable",
+            _ => "delivered",
+        };
+        self.metrics.inc(
+            &CARRIER_VERIFICATIONS,
+            &[("carrier", &entry.carrier), ("result", result)],
+        );
+        entry
+    }
+
+    fn timed_send(&self, channel: &'static str, send: impl FnOnce() -> bool) -> bool {
+        let start = Instant::now();
+        let sent = send();
+        self.metrics.observe(
+            &CARRIER_SEND_DURATION,
+            &[("carrier", &self.inner.get_name()), ("channel", channel)],
+            start.elapsed().as_secs_f64(),
+        );
+        sent
+    }
+}
+
+impl TelecomProvider for Metered {
+    fn send_sms(&self, number: &str, message: &str) -> bool {
+        self.timed_send("sms", || self.inner.send_sms(number, message))
+    }
+
+    fn send_voice(&self, number: &str, message: &str) -> bool {
+        self.timed_send("voice", || self.inner.send_voice(number, message))
+    }
+
+    fn get_name(&self) -> Arc<str> {
+        self.inner.get_name()
+    }
+
+    fn verify(&self, number: &str, message: &Message, ladder: &Ladder) -> VerificationEntry {
+        let start = Instant::now();
+        self.record(start, self.inner.verify(number, message, ladder))
+    }
+
+    fn supports_code_format(&self, format: &CodeFormat) -> bool {
+        self.inner.supports_code_format(format)
+    }
+
+    fn connects_to(&self, network: &MobileNetwork) -> bool {
+        self.inner.connects_to(network)
+    }
+
+    fn serves_country(&self, country: Option<&str>) -> bool {
+        self.inner.serves_country(country)
+    }
+
+    fn weight(&self) -> u32 {
+        self.inner.weight()
+    }
+
+    fn verify_traced(
+        &self,
+        number: &str,
+        message: &Message,
+        ladder: &Ladder,
+        trace: &TraceContext,
+    ) -> VerificationEntry {
+        let start = Instant::now();
+        self.record(
+            start,
+            self.inner.verify_traced(number, message, ladder, trace),
+        )
+    }
+
+    fn handle_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Vec<ProviderCallback>, WebhookError> {
+        self.inner.handle_webhook(headers, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.inc(&REQUESTS, &[("outcome", "sent")]);
+        metrics.inc(&REQUESTS, &[("outcome", "sent")]);
+        metrics.inc(&REQUESTS, &[("outcome", "rejected")]);
+        metrics.set(&REPO_ATTEMPTS, &[], 3.0);
+        metrics.observe(&REQUEST_DURATION, &[], 0.02);
+        metrics.observe(&REQUEST_DURATION, &[], 0.3);
+        metrics.observe(&REQUEST_DURATION, &[], 60.0);
+        metrics.inc(&BALANCER_PICKS, &[("carrier", "a \"quoted\" name")]);
+        assert_eq!(metrics.counter(&REQUESTS, &[("outcome", "sent")]), 2);
+        assert_eq!(metrics.counter(&REQUESTS, &[("outcome", "error")]), 0);
+        // counts kept elsewhere only ever raise the counter
+        for &evicted in &[3, 5, 5, 4] {
+            metrics.count_to(&EVICTIONS, &[("store", "velocity")], evicted);
+        }
+        assert_eq!(metrics.counter(&EVICTIONS, &[("store", "velocity")]), 5);
+        let text = metrics.render();
+        for line in &[
+            "# TYPE telecom_requests_total counter",
+            "telecom_requests_total{outcome=\"rejected\"} 1",
+            "telecom_requests_total{outcome=\"sent\"} 2",
+            "telecom_repo_attempts 3",
+            "telecom_evictions_total{store=\"velocity\"} 5",
+            "telecom_request_duration_seconds_bucket{le=\"0.01\"} 0",
+            "telecom_request_duration_seconds_bucket{le=\"0.025\"} 1",
+            "telecom_request_duration_seconds_bucket{le=\"0.5\"} 2",
+            "telecom_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "telecom_request_duration_seconds_count 3",
+            "telecom_balancer_picks_total{carrier=\"a \\\"quoted\\\" name\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "{} missing:\n{}",
+                line,
+                text
+            );
+        }
+        // clones share the registry
+        metrics.clone().inc(&REQUESTS, &[("outcome", "sent")]);
+        assert!(metrics
+            .render()
+            .contains("telecom_requests_total{outcome=\"sent\"} 3"));
+    }
+
+    #[test]
+    fn test_buckets() {
+        let metrics = Metrics::new();
+        let config = MetricsConfig {
+            carrier_latency_buckets: vec![0.5, 2.0],
+            ..MetricsConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        config.apply(&metrics).unwrap();
+        let labels = [("carrier", "carrier_1")];
+        metrics.observe(&CARRIER_VERIFY_DURATION, &labels, 1.5);
+        metrics.observe(&CARRIER_VERIFY_DURATION, &labels, 3.0);
+        let text = metrics.render();
+        for line in &[
+            "telecom_carrier_verify_duration_seconds_bucket{carrier=\"carrier_1\",le=\"0.5\"} 0",
+            "telecom_carrier_verify_duration_seconds_bucket{carrier=\"carrier_1\",le=\"2\"} 1",
+            "telecom_carrier_verify_duration_seconds_bucket{carrier=\"carrier_1\",le=\"+Inf\"} 2",
+            "telecom_carrier_verify_duration_seconds_sum{carrier=\"carrier_1\"} 4.5",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "{} missing:\n{}",
+                line,
+                text
+            );
+        }
+
+        for buckets in &[vec![], vec![1.0, 1.0], vec![2.0, 1.0], vec![0.0, 1.0]] {
+            let config = MetricsConfig {
+                carrier_latency_buckets: buckets.clone(),
+                ..MetricsConfig::default()
+            };
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+}
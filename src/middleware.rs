@@ -0,0 +1,465 @@
+use crate::codec::Format;
+use crate::http::error_response;
+use crate::latency::RequestTimings;
+use crate::proxy::ProxyConfig;
+use crate::trace::TraceContext;
+use axum::body::{Body, BodyDataStream, Bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::BoxError;
+use rand::Rng;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+use tokio_stream::Stream;
+use tracing::{debug, field, info, warn, Level};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// request ids supplied by callers longer than this are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// RequestId identifies a request in logs, handlers read it from the request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// ClientIp is the address of the client a request was made by, as forwarded by a trusted proxy or
+// else the peer's, None for unix socket clients not behind a trusted proxy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+// resolve_client works out the client address of each request for the handlers and the logs
+pub async fn resolve_client(
+    State(proxies): State<Arc<ProxyConfig>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client = proxies.client_ip(peer, request.headers());
+    request.extensions_mut().insert(ClientIp(client));
+    next.run(request).await
+}
+
+// enforce_body rejects request bodies that aren't JSON or msgpack with 415 and bodies whose declared length
+// exceeds max_body_bytes with 413, bodies without a length are capped while being read by
+// enforce_timeouts
+pub async fn enforce_body(
+    State(max_body_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    let has_body =
+        content_length.unwrap_or(0) > 0 || headers.contains_key(header::TRANSFER_ENCODING);
+
+    if content_length.is_some_and(|l| l > max_body_bytes) {
+        return error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("request body exceeds {} bytes", max_body_bytes),
+        );
+    }
+
+    // carriers choose their own callback encodings, the provider handlers parse those
+    let accepts_body = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH
+    ) && !request.uri().path().starts_with("/webhooks/");
+    if accepts_body && has_body && Format::from_content_type(headers).is_none() {
+        return error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/json or application/msgpack",
+        );
+    }
+
+    next.run(request).await
+}
+
+// enforce_timeouts answers requests whose body takes longer than read_timeout to arrive with 408,
+// so clients trickling a body in can't hold a request open, bodies growing past max_body_bytes
+// with 413, and requests that take longer than write_timeout to answer with 503. Bodies are handed
+// to the handlers as they arrive, which fail reading them past either limit
+pub async fn enforce_timeouts(
+    State((read_timeout, write_timeout, max_body_bytes)): State<(Duration, Duration, usize)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // HTTP/2 bodies needn't declare a length, so every body is limited
+    let exceeded = Arc::new(Mutex::new(None));
+    let (parts, body) = request.into_parts();
+    let body = LimitedBody {
+        chunks: body.into_data_stream(),
+        deadline: Box::pin(tokio::time::sleep(read_timeout)),
+        remaining: max_body_bytes,
+        exceeded: exceeded.clone(),
+    };
+    let request = Request::from_parts(parts, Body::from_stream(body));
+    let response = match tokio::time::timeout(write_timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("request not answered within {:?}", write_timeout),
+        ),
+    };
+    // whatever the handler made of a body it couldn't read, the client is told which limit it hit
+    let exceeded = *exceeded.lock().unwrap();
+    match exceeded {
+        Some(Exceeded::Size) => error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("request body exceeds {} bytes", max_body_bytes),
+        ),
+        Some(Exceeded::Time) => error_response(
+            StatusCode::REQUEST_TIMEOUT,
+            format!("request body not received within {:?}", read_timeout),
+        ),
+        None => response,
+    }
+}
+
+// Exceeded is the limit a request body was cut off at
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exceeded {
+    Size,
+    Time,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exceeded::Size => write!(f, "request body too large"),
+            Exceeded::Time => write!(f, "request body not received in time"),
+        }
+    }
+}
+
+impl std::error::Error for Exceeded {}
+
+// LimitedBody passes the chunks of a request body on until it grows past remaining bytes or
+// deadline passes, it then fails and records the limit in exceeded
+struct LimitedBody {
+    chunks: BodyDataStream,
+    deadline: Pin<Box<Sleep>>,
+    remaining: usize,
+    exceeded: Arc<Mutex<Option<Exceeded>>>,
+}
+
+impl LimitedBody {
+    fn exceed(&self, limit: Exceeded) -> Poll<Option<Result<Bytes, BoxError>>> {
+        *self.exceeded.lock().unwrap() = Some(limit);
+        Poll::Ready(Some(Err(Box::new(limit))))
+    }
+}
+
+impl Stream for LimitedBody {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.exceeded.lock().unwrap().is_some() {
+            return Poll::Ready(None);
+        }
+        if self.deadline.as_mut().poll(cx).is_ready() {
+            return self.exceed(Exceeded::Time);
+        }
+        match Pin::new(&mut self.chunks).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) if chunk.len() > self.remaining => {
+                self.exceed(Exceeded::Size)
+            }
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.remaining -= chunk.len();
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            // clients that hang up mid body never see the answer
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// log_requests prints one line per call with its status and latency, the request id is taken from
+// X-Request-Id when the caller sends one and echoed back in the response. Calls slower than
+// slow_request are warned about too, with where the time went when the handler timed it
+pub async fn log_requests(
+    State(slow_request): State<Option<Duration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let request_id = request_id(request.headers());
+    let method = request.method().clone();
+    let target = request
+        .uri()
+        .path_and_query()
+        .map_or_else(|| request.uri().path().to_string(), |p| p.to_string());
+    let trace_id = request
+        .extensions()
+        .get::<TraceContext>()
+        .map(|t| t.trace_id.clone());
+    let client_ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .and_then(|ClientIp(ip)| *ip);
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+    // the work a request starts is logged and stored under its id
+    if let Some(trace) = request.extensions_mut().get_mut::<TraceContext>() {
+        trace.correlation_id = Some(request_id.clone());
+    }
+
+    let mut response = next.run(request).await;
+    let elapsed = start.elapsed();
+    info!(
+        %method,
+        path = %redact(&target),
+        status = response.status().as_u16(),
+        elapsed_ms = elapsed.as_millis() as u64,
+        %request_id,
+        trace_id = trace_id.as_deref().map(field::display),
+        client_ip = client_ip.map(field::display),
+        "request handled"
+    );
+    if slow_request.is_some_and(|t| elapsed > t) {
+        let timings = response.extensions().get::<RequestTimings>();
+        let ms =
+            |phase: fn(&RequestTimings) -> Duration| timings.map(|t| phase(t).as_millis() as u64);
+        warn!(
+            %method,
+            path = %redact(&target),
+            status = response.status().as_u16(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            %request_id,
+            trace_id = trace_id.as_deref().map(field::display),
+            carrier = timings.and_then(|t| t.carrier.as_deref()).map(field::display),
+            checks_ms = ms(|t| t.checks),
+            fraud_ms = ms(|t| t.fraud),
+            delivery_ms = ms(|t| t.delivery),
+            session_ms = ms(|t| t.session),
+            "slow request"
+        );
+    }
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, v);
+    }
+    response
+}
+
+// sample_payloads logs the full request and response bodies of a share of the requests at debug
+// level, redacted and cut at MAX_SAMPLED_BYTES. Event streams are never sampled
+pub async fn sample_payloads(
+    State((rate, max_body_bytes)): State<(f64, usize)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if rate <= 0.0 || !tracing::enabled!(Level::DEBUG) || rand::thread_rng().gen::<f64>() >= rate {
+        return next.run(request).await;
+    }
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone());
+    let method = request.method().clone();
+    let path = redact(request.uri().path());
+    let (parts, body) = request.into_parts();
+    let request_body = match axum::body::to_bytes(body, max_body_bytes).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("request body exceeds {} bytes", max_body_bytes),
+            )
+        }
+    };
+    let request_payload = redact_payload(&parts.headers, &request_body);
+    let response = next
+        .run(Request::from_parts(parts, Body::from(request_body)))
+        .await;
+    let streamed = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    if streamed {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let response_body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    debug!(
+        %method,
+        %path,
+        status = parts.status.as_u16(),
+        request_id = request_id.as_deref().map(field::display),
+        request = %request_payload,
+        response = %redact_payload(&parts.headers, &response_body),
+        "payload sample"
+    );
+    Response::from_parts(parts, Body::from(response_body))
+}
+
+// fields of sampled payloads whose values are never logged
+const SECRET_FIELDS: &[&str] = &["code", "token", "secret", "otpauth_uri", "metadata"];
+
+// sampled payloads longer than this are cut
+const MAX_SAMPLED_BYTES: usize = 4096;
+
+// redact_payload renders a JSON or msgpack body as JSON with secret fields masked and digit runs
+// redacted, anything else, such as a body that fails to parse, as redacted text
+fn redact_payload(headers: &HeaderMap, body: &[u8]) -> String {
+    let value =
+        Format::from_content_type(headers).and_then(|f| f.decode::<serde_json::Value>(body).ok());
+    let mut text = match value {
+        Some(mut value) => {
+            mask_secrets(&mut value);
+            redact(&value.to_string())
+        }
+        None => redact(&String::from_utf8_lossy(body)),
+    };
+    if text.len() > MAX_SAMPLED_BYTES {
+        let mut end = MAX_SAMPLED_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str("...");
+    }
+    text
+}
+
+fn mask_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match SECRET_FIELDS.contains(&key.as_str()) {
+                    true => *value = serde_json::Value::from("***"),
+                    false => mask_secrets(value),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(mask_secrets),
+        _ => (),
+    }
+}
+
+// propagate_trace makes every request a span in the caller's trace, or the root of a new one,
+// handlers pass the TraceContext extension on to outbound calls
+pub async fn propagate_trace(mut request: Request, next: Next) -> Response {
+    let trace = TraceContext::from_headers(request.headers());
+    request.extensions_mut().insert(trace);
+    next.run(request).await
+}
+
+// request_id is the X-Request-Id a caller sent, or a new one when it sent none or one too long
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id)
+}
+
+fn new_request_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+// mask_number hides the middle of a phone number, keeping its calling code and last two digits so
+// its country can still be told and numbers told apart, e.g. +14155550123 as +141******23. Numbers
+// too short to keep anything of are masked whole
+pub fn mask_number(number: &str) -> String {
+    let chars = number.chars().collect::<Vec<_>>();
+    let kept = match number.starts_with('+') {
+        true => 4,
+        false => 3,
+    };
+    chars
+        .iter()
+        .enumerate()
+        .map(
+            |(i, c)| match chars.len() >= kept + 4 && (i < kept || i >= chars.len() - 2) {
+                true => *c,
+                false if c.is_ascii_digit() => '*',
+                false => *c,
+            },
+        )
+        .collect()
+}
+
+// redact masks every run of four or more digits, which covers phone numbers and verification
+// codes wherever they appear in a path or query string
+pub fn redact(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut digits = String::new();
+    for c in text.chars().chain(std::iter::once('\0')) {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        if digits.len() >= 4 {
+            redacted.push_str("***");
+        } else {
+            redacted.push_str(&digits);
+        }
+        digits.clear();
+        if c != '\0' {
+            redacted.push(c);
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("/rank?window=60"), "/rank?window=60");
+        assert_eq!(redact("/status/+4917112345678"), "/status/+***");
+        assert_eq!(redact("/check?code=123456&x=1"), "/check?code=***&x=1");
+        assert_eq!(redact("%2B14155550100"), "%2B***");
+        assert_eq!(redact(""), "");
+
+        assert_eq!(mask_number("+14155550123"), "+141******23");
+        assert_eq!(mask_number("4917112345678"), "491********78");
+        assert_eq!(mask_number("+150012"), "+******");
+    }
+
+    #[test]
+    fn test_redact_payload() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(crate::codec::JSON),
+        );
+        let body = br#"{"attempt_id":"a1","code":"12345678","nested":[{"token":"eyJ0"}],"number":"+4917112345678"}"#;
+        assert_eq!(
+            redact_payload(&headers, body),
+            r#"{"attempt_id":"a1","code":"***","nested":[{"token":"***"}],"number":"+***"}"#
+        );
+        // bodies that don't parse are logged as they arrived
+        assert_eq!(
+            redact_payload(&headers, br#"{"number": 4917112345678"#),
+            r#"{"number": ***"#
+        );
+        let long = vec![b'x'; MAX_SAMPLED_BYTES + 10];
+        assert_eq!(
+            redact_payload(&HeaderMap::new(), &long).len(),
+            MAX_SAMPLED_BYTES + 3
+        );
+    }
+}
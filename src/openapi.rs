@@ -0,0 +1,177 @@
+use crate::admin;
+use crate::audit::{AuditAction, AuditEntry};
+use crate::balancer::BalancerState;
+use crate::consent::{OptOut, OptOutSource};
+use crate::debug::{CarrierHealth, DebugState, RepoStats};
+use crate::escalation::{ChannelPreference, EscalationConfig, EscalationStep, Ladder};
+use crate::events::{EventKind, VerificationEvent};
+use crate::fraud::{FraudAction, FraudConfig, FraudDecision, Occupancy, RiskDecision, RiskTier};
+use crate::health::{HealthComponent, HealthScore};
+use crate::http::{self, ErrorResponse, RevokeRequest, RevokeResponse, WebhookResponse};
+use crate::logging::{LogLevelRequest, LogLevelStatus};
+use crate::lookup::{LineType, MobileNetwork};
+use crate::otp::{Alphabet, SessionCounts, SessionState, VerificationStatus};
+use crate::policy::NumberPolicy;
+use crate::provider::{ProviderConfig, ProviderKind};
+use crate::pumping::{PumpingConfig, Throttle};
+use crate::receipt::Receipt;
+use crate::reload::ReloadReport;
+use crate::repo::{Channel, RankingConfig, VerificationEntry, VerificationStep};
+use crate::slo::{BudgetStatus, BudgetWindow, CarrierBudget, SloReport};
+use crate::token::{Claims, IntrospectResponse, Jwk, JwkSet};
+use crate::totp::{TotpCheckRequest, TotpEnrollResponse};
+use crate::uptime::{CarrierUptime, Incident, UptimeReport};
+use crate::{
+    BalancerType, CarrierStatus, CheckRequest, CheckResponse, RankResponse, VerificationRequest,
+    VerificationResponse,
+};
+use utoipa::OpenApi;
+
+// ApiDoc is the OpenAPI 3 document served at GET /openapi.json, built from the handler
+// annotations in the http module and the request/response types they reference
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "telecom",
+        description = "SMS/text-to-speech verification server"
+    ),
+    paths(
+        http::post_verification,
+        http::post_check,
+        http::get_verification_status,
+        http::get_receipt,
+        http::post_revoke_token,
+        http::get_introspect_token,
+        http::post_totp_enroll,
+        http::post_totp_check,
+        http::get_jwks,
+        http::get_rank,
+        http::get_rank_uptime,
+        http::get_events,
+        http::get_attempts,
+        http::get_metrics,
+        http::get_slo,
+        http::get_health_score,
+        http::post_provider_webhook,
+        admin::list_carriers,
+        admin::add_carrier,
+        admin::remove_carrier,
+        admin::get_ranking,
+        admin::put_ranking,
+        admin::get_escalation,
+        admin::put_escalation,
+        admin::get_fraud,
+        admin::put_fraud,
+        admin::list_fraud_decisions,
+        admin::list_throttles,
+        admin::lift_throttle,
+        admin::list_receipts,
+        admin::list_opt_outs,
+        admin::put_opt_out,
+        admin::delete_opt_out,
+        admin::get_number_policy,
+        admin::put_number_policy,
+        admin::post_reload,
+        admin::get_log_level,
+        admin::put_log_level,
+        admin::list_audit,
+        admin::get_debug_state
+    ),
+    components(schemas(
+        VerificationRequest,
+        VerificationResponse,
+        CheckRequest,
+        Alphabet,
+        ChannelPreference,
+        VerificationStatus,
+        SessionState,
+        Receipt,
+        OptOut,
+        OptOutSource,
+        CheckResponse,
+        TotpEnrollResponse,
+        TotpCheckRequest,
+        RevokeRequest,
+        RevokeResponse,
+        IntrospectResponse,
+        Claims,
+        JwkSet,
+        Jwk,
+        RankResponse,
+        VerificationEvent,
+        EventKind,
+        VerificationStep,
+        Channel,
+        VerificationEntry,
+        CarrierStatus,
+        ProviderConfig,
+        ProviderKind,
+        RankingConfig,
+        EscalationConfig,
+        Ladder,
+        EscalationStep,
+        FraudConfig,
+        FraudDecision,
+        PumpingConfig,
+        Throttle,
+        FraudAction,
+        RiskTier,
+        RiskDecision,
+        LineType,
+        MobileNetwork,
+        NumberPolicy,
+        ReloadReport,
+        LogLevelRequest,
+        LogLevelStatus,
+        AuditEntry,
+        AuditAction,
+        DebugState,
+        BalancerState,
+        BalancerType,
+        CarrierHealth,
+        SessionCounts,
+        Occupancy,
+        RepoStats,
+        SloReport,
+        CarrierBudget,
+        BudgetWindow,
+        BudgetStatus,
+        HealthScore,
+        HealthComponent,
+        UptimeReport,
+        CarrierUptime,
+        Incident,
+        ErrorResponse,
+        WebhookResponse
+    ))
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_documents_routes() {
+        let doc = ApiDoc::openapi();
+        for path in &[
+            "/",
+            "/check",
+            "/rank",
+            "/rank/uptime",
+            "/events",
+            "/attempts",
+            "/metrics",
+            "/slo",
+            "/health/score",
+            "/admin/carriers",
+            "/admin/audit",
+            "/admin/log-level",
+            "/debug/state",
+        ] {
+            assert!(doc.paths.paths.contains_key(*path), "{} missing", path);
+        }
+        let schemas = doc.components.expect("components").schemas;
+        assert!(schemas.contains_key("VerificationRequest"));
+    }
+}
@@ -0,0 +1,871 @@
+use crate::fraud::RiskDecision;
+use crate::memory::LruMap;
+use crate::repo::VerificationStep;
+use crate::rng::SharedRng;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+// shorter codes are too easy to guess, longer ones too hard to type
+pub const CODE_LENGTHS: RangeInclusive<usize> = 4..=8;
+
+// Alphabet is the set of characters codes are drawn from
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Alphabet {
+    Numeric,
+    Alphanumeric,
+    // alphanumeric without 0/O and 1/I/L, which are easily misread
+    Unambiguous,
+}
+
+impl Alphabet {
+    pub fn chars(self) -> &'static [u8] {
+        match self {
+            Alphabet::Numeric => b"0123456789",
+            Alphabet::Alphanumeric => b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+            Alphabet::Unambiguous => b"23456789ABCDEFGHJKMNPQRSTUVWXYZ",
+        }
+    }
+}
+
+impl FromStr for Alphabet {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numeric" => Ok(Self::Numeric),
+            "alphanumeric" => Ok(Self::Alphanumeric),
+            "unambiguous" => Ok(Self::Unambiguous),
+            _ => Err(anyhow!("Invalid code alphabet: {}", s)),
+        }
+    }
+}
+
+// CodeFormat is the shape of the codes sent for an attempt
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CodeFormat {
+    pub length: usize,
+    pub alphabet: Alphabet,
+}
+
+impl Default for CodeFormat {
+    fn default() -> Self {
+        Self {
+            length: 6,
+            alphabet: Alphabet::Numeric,
+        }
+    }
+}
+
+impl CodeFormat {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !CODE_LENGTHS.contains(&self.length) {
+            return Err(anyhow!(
+                "code length must be between {} and {}",
+                CODE_LENGTHS.start(),
+                CODE_LENGTHS.end()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// OtpConfig controls the codes sent to numbers being verified
+#[derive(Debug, Clone)]
+pub struct OtpConfig {
+    // used for requests that don't ask for a format of their own
+    pub format: CodeFormat,
+    // how long a code can be submitted after it was sent
+    pub ttl: Duration,
+    // wrong codes accepted for an attempt before it is locked
+    pub max_failed_checks: u32,
+    // how long the next submission is refused after each wrong code
+    pub check_delays: CheckDelays,
+    // how long a number stays locked out of new verifications once an attempt is locked
+    pub lockout: Duration,
+    // how long finished and expired attempts are kept around for status lookups
+    pub retention: Duration,
+    // numbers verified this recently can be issued a token without a new code, zero disables it
+    pub reuse_window: Duration,
+    // keys the hashes codes are stored as
+    pub hasher: CodeHasher,
+    // what happens to requests for numbers with an attempt in progress
+    pub duplicates: DuplicateRequests,
+}
+
+impl Default for OtpConfig {
+    fn default() -> Self {
+        Self {
+            format: CodeFormat::default(),
+            ttl: Duration::minutes(5),
+            max_failed_checks: 5,
+            check_delays: CheckDelays::default(),
+            lockout: Duration::minutes(15),
+            retention: Duration::hours(1),
+            reuse_window: Duration::zero(),
+            hasher: CodeHasher::ephemeral(),
+            duplicates: DuplicateRequests::default(),
+        }
+    }
+}
+
+// DuplicateRequests is how a request for a number that already has an attempt in progress is
+// answered
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DuplicateRequests {
+    // start another attempt in parallel
+    #[default]
+    Allow,
+    // return the attempt in progress without sending another code
+    Reuse,
+    // refuse the request, naming the attempt in progress
+    Reject,
+}
+
+impl FromStr for DuplicateRequests {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "reuse" => Ok(Self::Reuse),
+            "reject" => Ok(Self::Reject),
+            _ => Err(anyhow!("invalid duplicate request handling: {}", s)),
+        }
+    }
+}
+
+// CheckDelays are the waits enforced after the first, second and later wrong submissions of an
+// attempt's code, the last one repeats
+#[derive(Debug, PartialEq, Clone)]
+pub struct CheckDelays(pub Vec<Duration>);
+
+impl Default for CheckDelays {
+    fn default() -> Self {
+        Self(vec![
+            Duration::zero(),
+            Duration::seconds(5),
+            Duration::seconds(30),
+        ])
+    }
+}
+
+impl CheckDelays {
+    // after is the wait following failed_checks wrong submissions
+    pub fn after(&self, failed_checks: u32) -> Duration {
+        match failed_checks {
+            0 => Duration::zero(),
+            n => self
+                .0
+                .get(n as usize - 1)
+                .or(self.0.last())
+                .copied()
+                .unwrap_or_else(Duration::zero),
+        }
+    }
+}
+
+// parses comma separated seconds, e.g. "0,5,30"
+impl FromStr for CheckDelays {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|secs| {
+                secs.trim()
+                    .parse::<u32>()
+                    .map(|secs| Duration::seconds(secs.into()))
+                    .map_err(|_| anyhow!("invalid check delay: {}", secs))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+// generate_code returns a random code in format, leading zeros included
+pub fn generate_code(format: &CodeFormat, rng: &SharedRng) -> String {
+    let chars = format.alphabet.chars();
+    rng.with(|rng| {
+        (0..format.length)
+            .map(|_| char::from(chars[rng.gen_range(0, chars.len())]))
+            .collect()
+    })
+}
+
+// generate_attempt_id returns an opaque identifier clients submit codes against
+pub fn generate_attempt_id(rng: &SharedRng) -> String {
+    rng.with(|rng| {
+        (0..16)
+            .map(|_| format!("{:02x}", rng.gen::<u8>()))
+            .collect()
+    })
+}
+
+// CodeHasher turns codes into the HMAC-SHA256 stored in their place, keyed with a server-side
+// pepper so a leaked store can't be brute forced through the small code space
+#[derive(Clone)]
+pub struct CodeHasher {
+    pepper: Vec<u8>,
+}
+
+impl CodeHasher {
+    pub fn new<T: AsRef<[u8]>>(pepper: T) -> Self {
+        Self {
+            pepper: pepper.as_ref().to_vec(),
+        }
+    }
+
+    // ephemeral peppers only last until the server restarts, which outlives in-memory sessions
+    pub fn ephemeral() -> Self {
+        Self::new(rand::thread_rng().gen::<[u8; 32]>())
+    }
+
+    fn mac(&self, code: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.pepper).expect("HMAC accepts keys of any size");
+        // letters are generated upper case so submissions are matched case insensitively
+        mac.update(code.trim().to_ascii_uppercase().as_bytes());
+        mac
+    }
+
+    // hash is what gets stored in place of a code, plain codes are never kept
+    pub fn hash(&self, code: &str) -> String {
+        hex::encode(self.mac(code).finalize().into_bytes())
+    }
+
+    // matches compares code against a stored hash in constant time
+    pub fn matches(&self, code: &str, hash: &str) -> bool {
+        hex::decode(hash).is_ok_and(|hash| self.mac(code).verify_slice(&hash).is_ok())
+    }
+}
+
+// the pepper is a secret, keep it out of logs
+impl fmt::Debug for CodeHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodeHasher").finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    // waiting for the code to be submitted
+    Pending,
+    Verified,
+    // too many wrong codes were submitted, a new verification has to be started
+    Locked,
+    // no carrier delivered the code yet, it is sent again once the next retry is due
+    Retrying,
+    // every scheduled retry failed to deliver the code
+    Failed,
+    // the code wasn't submitted within the validity window, its hash is discarded
+    Expired,
+}
+
+// OtpSession is a code sent to a number, kept until it expires
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtpSession {
+    pub attempt_id: String,
+    pub number: String,
+    pub carrier: Arc<str>,
+    pub step: VerificationStep,
+    pub code_hash: String,
+    // when the current code was sent
+    pub sent_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    // notified once the code is submitted
+    pub callback_url: Option<Url>,
+    pub state: SessionState,
+    pub failed_checks: u32,
+    // wrong submissions hold off the next one until then
+    pub next_check_at: Option<DateTime<Utc>>,
+    // embedded into the token issued once the code is submitted
+    pub metadata: Option<Map<String, Value>>,
+    // the risk tier a risky attempt is verified under
+    pub risk: Option<RiskDecision>,
+    // codes still to be submitted after the current one, sent in format and locale
+    pub extra_codes: u32,
+    pub format: CodeFormat,
+    pub locale: Option<String>,
+    // request id of the request that started the verification, code submissions for the attempt
+    // are logged under it too
+    pub correlation_id: Option<String>,
+}
+
+impl OtpSession {
+    // expire moves the session to Expired, dropping everything but what status lookups report
+    pub fn expire(mut self) -> Self {
+        self.state = SessionState::Expired;
+        self.code_hash.clear();
+        self.callback_url = None;
+        self.metadata = None;
+        self
+    }
+
+    // finished sessions no longer change and can be freed after the retention period
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.state,
+            SessionState::Verified | SessionState::Failed | SessionState::Expired
+        )
+    }
+}
+
+// VerificationStatus is the state of an attempt as reported by GET /verifications/{attempt_id}
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct VerificationStatus {
+    pub attempt_id: String,
+    pub carrier: String,
+    pub state: SessionState,
+    pub expires_at: DateTime<Utc>,
+    pub failed_checks: u32,
+    pub remaining_checks: u32,
+    // when the number can be verified again after the attempt was locked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<DateTime<Utc>>,
+    // when the next code is accepted after a wrong one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_check_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk: Option<RiskDecision>,
+    // codes still to be submitted after the current one
+    pub extra_codes: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CheckError {
+    // no code was sent for the attempt or it was already used
+    NotFound,
+    Expired,
+    // the submitted code does not match the one sent
+    Mismatch { remaining: u32 },
+    // too many wrong codes, the number can be verified again after until
+    Locked { until: DateTime<Utc> },
+    // the code matched but no token could be issued
+    Internal(String),
+    // the attempt has no delivered code to check against
+    Undelivered,
+    // a wrong code was submitted too recently, the next one is accepted at retry_at
+    TooEarly { retry_at: DateTime<Utc> },
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckError::NotFound => write!(f, "verification attempt not found"),
+            CheckError::Expired => write!(f, "verification code expired"),
+            CheckError::Mismatch { remaining } => write!(
+                f,
+                "invalid verification code, {} attempt(s) left",
+                remaining
+            ),
+            CheckError::Locked { until } => write!(
+                f,
+                "too many invalid codes, start a new verification after {}",
+                until.to_rfc3339()
+            ),
+            CheckError::Internal(e) => write!(f, "token could not be issued: {}", e),
+            CheckError::Undelivered => write!(f, "verification code was not delivered"),
+            CheckError::TooEarly { retry_at } => write!(
+                f,
+                "too many invalid codes, submit the next one after {}",
+                retry_at.to_rfc3339()
+            ),
+        }
+    }
+}
+
+// OtpStore keeps the sessions of codes sent and the numbers locked out after too many wrong codes
+// SessionCounts is how many sessions a store holds, for troubleshooting
+#[derive(Serialize, ToSchema, Debug, Default, PartialEq, Clone)]
+pub struct SessionCounts {
+    #[schema(value_type = Object)]
+    pub by_state: BTreeMap<SessionState, usize>,
+    // numbers locked out after too many invalid codes
+    pub locked_numbers: usize,
+}
+
+pub trait OtpStore: Send + Sync {
+    fn insert(&mut self, session: OtpSession);
+    fn get(&self, attempt_id: &str) -> Option<&OtpSession>;
+    fn remove(&mut self, attempt_id: &str) -> Option<OtpSession>;
+    fn lock_number(&mut self, number: &str, until: DateTime<Utc>);
+    // locked_until returns when a number can be verified again, None when it isn't locked
+    fn locked_until(&self, number: &str) -> Option<DateTime<Utc>>;
+    // in_progress returns the latest attempt to number that is still being delivered or can
+    // still be submitted a code
+    fn in_progress(&self, number: &str, now: DateTime<Utc>) -> Option<&OtpSession>;
+    // sweep expires the sessions whose code wasn't submitted in time, returning them as they were
+    // before, and frees finished sessions and lockouts older than retention
+    fn sweep(&mut self, now: DateTime<Utc>, retention: Duration) -> Vec<OtpSession>;
+    // counts returns how many sessions are in each state and how many numbers are locked at now
+    fn counts(&self, now: DateTime<Utc>) -> SessionCounts;
+
+    // check marks the session verified when code matches, counting wrong codes and locking the
+    // session and its number once config.max_failed_checks is reached
+    fn check(
+        &mut self,
+        attempt_id: &str,
+        code: &str,
+        config: &OtpConfig,
+    ) -> Result<OtpSession, CheckError> {
+        let mut session = self
+            .get(attempt_id)
+            .filter(|s| s.state != SessionState::Verified)
+            .ok_or(CheckError::NotFound)?
+            .clone();
+        if session.state == SessionState::Expired || session.expires_at <= Utc::now() {
+            self.insert(session.expire());
+            return Err(CheckError::Expired);
+        }
+        if session.state == SessionState::Locked {
+            let until = self.locked_until(&session.number).unwrap_or_else(Utc::now);
+            return Err(CheckError::Locked { until });
+        }
+        if let SessionState::Retrying | SessionState::Failed = session.state {
+            return Err(CheckError::Undelivered);
+        }
+        // submissions arriving before the delay is over are refused without counting against
+        // the attempt
+        if let Some(retry_at) = session.next_check_at.filter(|at| *at > Utc::now()) {
+            return Err(CheckError::TooEarly { retry_at });
+        }
+        if !config.hasher.matches(code, &session.code_hash) {
+            session.failed_checks += 1;
+            let remaining = config
+                .max_failed_checks
+                .saturating_sub(session.failed_checks);
+            if remaining == 0 {
+                session.state = SessionState::Locked;
+                let until = Utc::now() + config.lockout;
+                self.lock_number(&session.number, until);
+                self.insert(session);
+                return Err(CheckError::Locked { until });
+            }
+            let delay = config.check_delays.after(session.failed_checks);
+            session.next_check_at = Some(Utc::now() + delay).filter(|_| delay > Duration::zero());
+            self.insert(session);
+            return Err(CheckError::Mismatch { remaining });
+        }
+        session.state = SessionState::Verified;
+        self.insert(session.clone());
+        Ok(session)
+    }
+
+    fn status(&self, attempt_id: &str, config: &OtpConfig) -> Option<VerificationStatus> {
+        let session = self.get(attempt_id)?;
+        Some(VerificationStatus {
+            attempt_id: session.attempt_id.clone(),
+            carrier: session.carrier.to_string(),
+            state: session.state,
+            expires_at: session.expires_at,
+            failed_checks: session.failed_checks,
+            remaining_checks: match session.state {
+                SessionState::Pending | SessionState::Retrying => config
+                    .max_failed_checks
+                    .saturating_sub(session.failed_checks),
+                _ => 0,
+            },
+            locked_until: match session.state {
+                SessionState::Locked => self.locked_until(&session.number),
+                _ => None,
+            },
+            next_check_at: session.next_check_at.filter(|at| *at > Utc::now()),
+            risk: session.risk.clone(),
+            extra_codes: session.extra_codes,
+        })
+    }
+}
+
+// RecentVerifications remembers when numbers were last verified
+#[derive(Debug, Default)]
+pub struct RecentVerifications {
+    // numbers by when they were last verified, unbounded unless with_max_numbers bounds them
+    verified: LruMap<DateTime<Utc>>,
+}
+
+impl RecentVerifications {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_max_numbers forgets the least recently verified numbers beyond max, they are sent a
+    // code again
+    pub fn with_max_numbers(mut self, max: usize) -> Self {
+        self.verified = LruMap::new(max);
+        self
+    }
+
+    // record notes a verification of number, forgetting the ones older than window and the
+    // least recently verified beyond max_numbers. Numbers are kept in the order they were
+    // verified, so only the expired ones are looked at
+    pub fn record(&mut self, number: &str, at: DateTime<Utc>, window: Duration) {
+        while let Some((oldest, verified)) = self.verified.oldest() {
+            if *verified + window > at {
+                break;
+            }
+            let oldest = oldest.to_string();
+            self.verified.remove(&oldest);
+        }
+        *self.verified.touch(number, || at) = at;
+        self.verified.evict();
+    }
+
+    // evicted tells the numbers forgotten so far to stay within max_numbers
+    pub fn evicted(&self) -> u64 {
+        self.verified.evicted()
+    }
+
+    pub fn verified_within(&self, number: &str, window: Duration, now: DateTime<Utc>) -> bool {
+        self.verified
+            .get(number)
+            .is_some_and(|verified| *verified + window > now)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryOtpStore {
+    sessions: HashMap<String, OtpSession>,
+    locked_numbers: HashMap<String, DateTime<Utc>>,
+}
+
+impl InMemoryOtpStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl OtpStore for InMemoryOtpStore {
+    fn insert(&mut self, session: OtpSession) {
+        self.sessions.insert(session.attempt_id.clone(), session);
+    }
+
+    fn get(&self, attempt_id: &str) -> Option<&OtpSession> {
+        self.sessions.get(attempt_id)
+    }
+
+    fn remove(&mut self, attempt_id: &str) -> Option<OtpSession> {
+        self.sessions.remove(attempt_id)
+    }
+
+    fn lock_number(&mut self, number: &str, until: DateTime<Utc>) {
+        let now = Utc::now();
+        self.locked_numbers.retain(|_, until| *until > now);
+        self.locked_numbers.insert(number.to_string(), until);
+    }
+
+    fn locked_until(&self, number: &str) -> Option<DateTime<Utc>> {
+        self.locked_numbers
+            .get(number)
+            .copied()
+            .filter(|until| *until > Utc::now())
+    }
+
+    fn in_progress(&self, number: &str, now: DateTime<Utc>) -> Option<&OtpSession> {
+        self.sessions
+            .values()
+            .filter(|s| s.number == number && s.expires_at > now)
+            .filter(|s| matches!(s.state, SessionState::Pending | SessionState::Retrying))
+            .max_by_key(|s| s.expires_at)
+    }
+
+    fn sweep(&mut self, now: DateTime<Utc>, retention: Duration) -> Vec<OtpSession> {
+        self.sessions
+            .retain(|_, s| !(s.is_finished() && s.expires_at + retention <= now));
+        self.locked_numbers.retain(|_, until| *until > now);
+        let mut expired = Vec::new();
+        for session in self.sessions.values_mut() {
+            if session.is_finished() || session.expires_at > now {
+                continue;
+            }
+            expired.push(session.clone());
+            *session = session.clone().expire();
+        }
+        expired
+    }
+
+    fn counts(&self, now: DateTime<Utc>) -> SessionCounts {
+        let mut counts = SessionCounts::default();
+        for session in self.sessions.values() {
+            *counts.by_state.entry(session.state).or_default() += 1;
+        }
+        counts.locked_numbers = self.locked_numbers.values().filter(|u| **u > now).count();
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(config: &OtpConfig, code: &str, expires_at: DateTime<Utc>) -> OtpSession {
+        OtpSession {
+            attempt_id: "attempt".to_string(),
+            number: "555".to_string(),
+            carrier: "carrier_1".into(),
+            step: VerificationStep::FirstSMS,
+            code_hash: config.hasher.hash(code),
+            sent_at: Utc::now(),
+            expires_at,
+            callback_url: None,
+            state: SessionState::Pending,
+            failed_checks: 0,
+            next_check_at: None,
+            metadata: None,
+            risk: None,
+            extra_codes: 0,
+            format: CodeFormat::default(),
+            locale: None,
+            correlation_id: None,
+        }
+    }
+
+    #[test]
+    fn test_generate_code() {
+        let rng = SharedRng::default();
+        let code = generate_code(&CodeFormat::default(), &rng);
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(generate_attempt_id(&rng).len(), 32);
+        // seeded codes repeat run to run
+        assert_eq!(
+            generate_code(&CodeFormat::default(), &SharedRng::seeded(42)),
+            generate_code(&CodeFormat::default(), &SharedRng::seeded(42))
+        );
+
+        let format = CodeFormat {
+            length: 8,
+            alphabet: Alphabet::Unambiguous,
+        };
+        let code = generate_code(&format, &rng);
+        assert_eq!(code.len(), 8);
+        assert!(code
+            .bytes()
+            .all(|c| Alphabet::Unambiguous.chars().contains(&c)));
+        let hasher = CodeHasher::new("pepper");
+        assert!(hasher.matches(&code.to_lowercase(), &hasher.hash(&code)));
+        // the same code hashes differently under another pepper
+        assert_ne!(hasher.hash(&code), CodeHasher::new("other").hash(&code));
+        assert!(!CodeHasher::new("other").matches(&code, &hasher.hash(&code)));
+        assert!(!hasher.matches(&code, "not hex"));
+
+        assert!(CodeFormat {
+            length: 3,
+            ..format
+        }
+        .validate()
+        .is_err());
+        assert!(CodeFormat {
+            length: 9,
+            ..format
+        }
+        .validate()
+        .is_err());
+        assert!(CodeFormat {
+            length: 4,
+            ..format
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        let config = OtpConfig::default();
+        let mut store = InMemoryOtpStore::new();
+        store.insert(session(
+            &config,
+            "123456",
+            Utc::now() + Duration::minutes(5),
+        ));
+        assert_eq!(
+            store.check("other", "123456", &config),
+            Err(CheckError::NotFound)
+        );
+        assert_eq!(
+            store.check("attempt", "654321", &config),
+            Err(CheckError::Mismatch { remaining: 4 })
+        );
+        assert_eq!(
+            store.check("attempt", "123456", &config).unwrap().number,
+            "555"
+        );
+        // codes are single use
+        assert_eq!(
+            store.check("attempt", "123456", &config),
+            Err(CheckError::NotFound)
+        );
+        assert_eq!(
+            store.status("attempt", &config).unwrap().state,
+            SessionState::Verified
+        );
+
+        store.insert(session(
+            &config,
+            "123456",
+            Utc::now() - Duration::seconds(1),
+        ));
+        assert_eq!(
+            store.check("attempt", "123456", &config),
+            Err(CheckError::Expired)
+        );
+        assert_eq!(store.get("attempt").unwrap().state, SessionState::Expired);
+        assert_eq!(store.get("attempt").unwrap().code_hash, "");
+    }
+
+    #[test]
+    fn test_sweep() {
+        let config = OtpConfig::default();
+        let now = Utc::now();
+        let mut store = InMemoryOtpStore::new();
+        store.insert(session(&config, "123456", now - Duration::seconds(1)));
+        let mut active = session(&config, "123456", now + Duration::minutes(5));
+        active.attempt_id = "active".to_string();
+        store.insert(active);
+
+        let expired = store.sweep(now, config.retention);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].state, SessionState::Pending);
+        assert_eq!(store.get("attempt").unwrap().state, SessionState::Expired);
+        assert_eq!(store.get("active").unwrap().state, SessionState::Pending);
+        assert_eq!(
+            store.check("attempt", "123456", &config),
+            Err(CheckError::Expired)
+        );
+        // expired sessions are only swept once, then freed after the retention period
+        assert!(store.sweep(now, config.retention).is_empty());
+        store.sweep(now + config.retention, config.retention);
+        assert_eq!(store.get("attempt"), None);
+        assert!(store.get("active").is_some());
+    }
+
+    #[test]
+    fn test_in_progress() {
+        let config = OtpConfig::default();
+        let now = Utc::now();
+        let mut store = InMemoryOtpStore::new();
+        store.insert(session(&config, "123456", now - Duration::seconds(1)));
+        assert_eq!(store.in_progress("555", now), None);
+
+        let mut latest = session(&config, "123456", now + Duration::minutes(5));
+        latest.attempt_id = "latest".to_string();
+        latest.state = SessionState::Retrying;
+        store.insert(latest);
+        let mut earlier = session(&config, "123456", now + Duration::minutes(1));
+        earlier.attempt_id = "earlier".to_string();
+        store.insert(earlier);
+        assert_eq!(store.in_progress("555", now).unwrap().attempt_id, "latest");
+        assert_eq!(store.in_progress("556", now), None);
+
+        store.check("earlier", "123456", &config).unwrap();
+        store.remove("latest");
+        assert_eq!(store.in_progress("555", now), None);
+    }
+
+    #[test]
+    fn test_check_delays() {
+        let config = OtpConfig {
+            check_delays: "0,30".parse().unwrap(),
+            ..OtpConfig::default()
+        };
+        assert_eq!(config.check_delays.after(0), Duration::zero());
+        assert_eq!(config.check_delays.after(3), Duration::seconds(30));
+        assert!("0,soon".parse::<CheckDelays>().is_err());
+
+        let mut store = InMemoryOtpStore::new();
+        store.insert(session(
+            &config,
+            "123456",
+            Utc::now() + Duration::minutes(5),
+        ));
+        assert_eq!(
+            store.check("attempt", "000000", &config),
+            Err(CheckError::Mismatch { remaining: 4 })
+        );
+        assert_eq!(
+            store.check("attempt", "000000", &config),
+            Err(CheckError::Mismatch { remaining: 3 })
+        );
+        // even the right code waits out the delay, which doesn't count as a wrong submission
+        assert!(matches!(
+            store.check("attempt", "123456", &config),
+            Err(CheckError::TooEarly { .. })
+        ));
+        let status = store.status("attempt", &config).unwrap();
+        assert_eq!(status.failed_checks, 2);
+        assert!(status.next_check_at.is_some());
+    }
+
+    #[test]
+    fn test_recent_verifications() {
+        let now = Utc::now();
+        let window = Duration::minutes(10);
+        let mut recent = RecentVerifications::new();
+        recent.record("555", now, window);
+        assert!(recent.verified_within("555", window, now + Duration::minutes(9)));
+        assert!(!recent.verified_within("555", window, now + window));
+        assert!(!recent.verified_within("556", window, now));
+        assert!(!recent.verified_within("555", Duration::zero(), now));
+
+        // the least recently verified are forgotten first
+        let mut bounded = RecentVerifications::new().with_max_numbers(2);
+        for (i, number) in ["555", "556", "557"].iter().enumerate() {
+            bounded.record(number, now + Duration::seconds(i as i64), window);
+        }
+        assert!(!bounded.verified_within("555", window, now));
+        assert!(bounded.verified_within("557", window, now));
+        assert_eq!(bounded.evicted(), 1);
+        // verifying a number again makes it the most recently verified
+        bounded.record("556", now + Duration::seconds(3), window);
+        bounded.record("558", now + Duration::seconds(4), window);
+        assert!(bounded.verified_within("556", window, now));
+        assert!(!bounded.verified_within("557", window, now));
+        assert_eq!(bounded.evicted(), 2);
+        // expired numbers are forgotten without counting as evicted
+        bounded.record("559", now + window + Duration::seconds(4), window);
+        assert!(!bounded.verified_within("556", window, now));
+        assert_eq!(bounded.evicted(), 2);
+    }
+
+    #[test]
+    fn test_lockout() {
+        let config = OtpConfig {
+            max_failed_checks: 2,
+            ..OtpConfig::default()
+        };
+        let mut store = InMemoryOtpStore::new();
+        store.insert(session(
+            &config,
+            "123456",
+            Utc::now() + Duration::minutes(5),
+        ));
+        assert_eq!(
+            store.check("attempt", "000000", &config),
+            Err(CheckError::Mismatch { remaining: 1 })
+        );
+        assert!(matches!(
+            store.check("attempt", "000000", &config),
+            Err(CheckError::Locked { .. })
+        ));
+        // the right code no longer helps
+        assert!(matches!(
+            store.check("attempt", "123456", &config),
+            Err(CheckError::Locked { .. })
+        ));
+        let status = store.status("attempt", &config).unwrap();
+        assert_eq!(status.state, SessionState::Locked);
+        assert_eq!(status.remaining_checks, 0);
+        assert!(status.locked_until.is_some());
+        assert!(store.locked_until("555").is_some());
+        assert_eq!(store.locked_until("556"), None);
+    }
+}
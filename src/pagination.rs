@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Error};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+// cursors are versioned so their encoding can change without breaking ones already handed out
+const CURSOR_PREFIX: &str = "v1:";
+
+// PageParams are the query parameters accepted by every paginated list endpoint
+#[derive(Deserialize, IntoParams, Debug, Default, Clone)]
+pub struct PageParams {
+    // opaque next_cursor value from the previous page, the first page is returned when omitted
+    pub cursor: Option<String>,
+    // maximum number of items to return, capped at MAX_LIMIT
+    pub limit: Option<usize>,
+}
+
+impl PageParams {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    // position returns the sequence position the requested page starts at
+    pub fn position(&self) -> Result<u64, Error> {
+        match &self.cursor {
+            Some(c) => decode_cursor(c),
+            None => Ok(0),
+        }
+    }
+}
+
+// Page is one slice of a list in stable sequence order, next_cursor is omitted on the last page
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    // new builds a page from items fetched at position, handing out a cursor when the page is full
+    // and more items may follow
+    pub fn new(items: Vec<T>, position: u64, limit: usize, total: u64) -> Self {
+        let next = position + items.len() as u64;
+        let next_cursor = match items.len() == limit && next < total {
+            true => Some(encode_cursor(next)),
+            false => None,
+        };
+        Self { items, next_cursor }
+    }
+}
+
+pub fn encode_cursor(position: u64) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}{}", CURSOR_PREFIX, position))
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<u64, Error> {
+    let invalid = || anyhow!("invalid cursor: {}", cursor);
+    let decoded = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    decoded
+        .strip_prefix(CURSOR_PREFIX)
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        assert_eq!(decode_cursor(&encode_cursor(42)).unwrap(), 42);
+        assert!(decode_cursor("bm9wZQ").is_err());
+        assert!(decode_cursor("!!").is_err());
+    }
+
+    #[test]
+    fn test_page_cursor() {
+        let page = Page::new(vec![1, 2], 0, 2, 3);
+        assert_eq!(page.next_cursor, Some(encode_cursor(2)));
+        let page = Page::new(vec![3], 2, 2, 3);
+        assert_eq!(page.next_cursor, None);
+        let page = Page::new(vec![1, 2], 0, 2, 2);
+        assert_eq!(page.next_cursor, None);
+    }
+}
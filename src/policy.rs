@@ -0,0 +1,131 @@
+use crate::country;
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use utoipa::ToSchema;
+
+// NumberPolicy restricts which numbers can be verified, deny entries win over allow entries and
+// numbers must match an allow entry when any are configured
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct NumberPolicy {
+    // ISO 3166-1 alpha-2 codes of the only countries numbers are verified in
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub allow_countries: BTreeSet<String>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub deny_countries: BTreeSet<String>,
+    // leading digits of international numbers, e.g. "+1900" for US premium-rate numbers
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub allow_prefixes: BTreeSet<String>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub deny_prefixes: BTreeSet<String>,
+}
+
+impl NumberPolicy {
+    // validate checks every entry, normalizing country codes to upper case and prefixes to start
+    // with a plus
+    pub fn validate(&mut self) -> Result<(), Error> {
+        for countries in [&mut self.allow_countries, &mut self.deny_countries] {
+            *countries = std::mem::take(countries)
+                .into_iter()
+                .map(
+                    |c| match c.len() == 2 && c.chars().all(|c| c.is_ascii_alphabetic()) {
+                        true => Ok(c.to_ascii_uppercase()),
+                        false => Err(anyhow!("invalid country code: {}", c)),
+                    },
+                )
+                .collect::<Result<_, _>>()?;
+        }
+        for prefixes in [&mut self.allow_prefixes, &mut self.deny_prefixes] {
+            *prefixes = std::mem::take(prefixes)
+                .into_iter()
+                .map(|p| {
+                    let d = p.strip_prefix('+').unwrap_or(&p);
+                    match !d.is_empty() && d.chars().all(|c| c.is_ascii_digit()) {
+                        true => Ok(format!("+{}", d)),
+                        false => Err(anyhow!("invalid number prefix: {}", p)),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    // check returns why number can't be verified, if anything
+    pub fn check(&self, number: &str) -> Result<(), String> {
+        let number_digits = digits(number);
+        let country = country::country_of(number);
+        let matches = |p: &&String| number_digits.starts_with(p.trim_start_matches('+'));
+        if let Some(prefix) = self.deny_prefixes.iter().find(matches) {
+            return Err(format!("numbers starting with {} are blocked", prefix));
+        }
+        if let Some(c) = country.filter(|c| self.deny_countries.contains(*c)) {
+            return Err(format!("numbers in {} are blocked", c));
+        }
+        if self.allow_countries.is_empty() && self.allow_prefixes.is_empty() {
+            return Ok(());
+        }
+        let allowed = country.is_some_and(|c| self.allow_countries.contains(c))
+            || self.allow_prefixes.iter().any(|p| matches(&p));
+        match allowed {
+            true => Ok(()),
+            false => Err("numbers in this region are not supported".to_string()),
+        }
+    }
+}
+
+fn digits(number: &str) -> String {
+    number.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> NumberPolicy {
+        let mut policy = NumberPolicy::default();
+        for entry in allow {
+            match entry.starts_with('+') {
+                true => policy.allow_prefixes.insert(entry.to_string()),
+                false => policy.allow_countries.insert(entry.to_string()),
+            };
+        }
+        for entry in deny {
+            match entry.starts_with('+') {
+                true => policy.deny_prefixes.insert(entry.to_string()),
+                false => policy.deny_countries.insert(entry.to_string()),
+            };
+        }
+        policy.validate().unwrap();
+        policy
+    }
+
+    #[test]
+    fn test_check() {
+        let open = policy(&[], &[]);
+        assert!(open.check("+491711234567").is_ok());
+
+        let premium = policy(&[], &["+1900", "ru"]);
+        assert!(premium.check("+14155550100").is_ok());
+        assert!(premium.check("+1 900 555 0100").is_err());
+        assert!(premium.check("+79001234567").is_err());
+
+        let launch = policy(&["DE", "+4420"], &["+49900"]);
+        assert!(launch.check("+491711234567").is_ok());
+        assert!(launch.check("+442071234567").is_ok());
+        assert!(launch.check("+441611234567").is_err());
+        assert!(launch.check("+14155550100").is_err());
+        assert!(launch.check("+499001234567").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut invalid = NumberPolicy::default();
+        invalid.deny_countries.insert("DEU".to_string());
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = NumberPolicy::default();
+        invalid.deny_prefixes.insert("+1-900".to_string());
+        assert!(invalid.validate().is_err());
+    }
+}
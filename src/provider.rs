@@ -1,24 +1,153 @@
-use crate::repo::{VerificationEntry, VerificationStep};
+use crate::escalation::Ladder;
+use crate::lookup::MobileNetwork;
+use crate::middleware::redact;
+use crate::otp::{Alphabet, CodeFormat};
+use crate::repo::{Channel, VerificationEntry};
+use crate::rng::SharedRng;
+use crate::secrets::SecretStore;
+use crate::templates::Message;
+use crate::trace::TraceContext;
 use anyhow::{anyhow, Error};
+use hmac::{Hmac, Mac};
+use http::HeaderMap;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+
+// header carrying the hex encoded HMAC-SHA256 of a mock provider webhook body
+pub const MOCK_SIGNATURE_HEADER: &str = "X-Mock-Signature";
 
 // TelecomProvider encapsulates the verification flow between a telecom provider
 //
-// For this scenario there is an assumption that a TelecomProvider handles not only the SMS/Voice
-// request to the provide but also the webhook that listens to a user's valid submission of the 6
-// digit string and verification token
+// A TelecomProvider delivers the message carrying a verification code over SMS or voice, the
+// server generates the code and validates the user's submission of it
+//
+// Providers are called synchronously and block the thread calling them until the carrier
+// answers, the server only calls them, handle_webhook included, through calls::CarrierCalls so
+// they never run on its runtime's worker threads
 pub trait TelecomProvider: Send + Sync {
-    fn send_sms(&self, number: &String) -> bool;
-    fn send_voice(&self, number: &String) -> bool;
-    fn verify(&self, number: &String) -> VerificationEntry;
-    fn get_name(&self) -> String;
+    fn send_sms(&self, number: &str, message: &str) -> bool;
+    fn send_voice(&self, number: &str, message: &str) -> bool;
+    // shared by every attempt the carrier makes, names are compared and hashed far more often
+    // than they are built
+    fn get_name(&self) -> Arc<str>;
+
+    // verify walks ladder until a step delivers the message and returns the step the number was
+    // reached on, the server hands carriers one stage at a time so step delays are already
+    // waited out by its scheduler
+    fn verify(&self, number: &str, message: &Message, ladder: &Ladder) -> VerificationEntry {
+        let delivered = ladder.steps.iter().position(|step| match step.channel {
+            Channel::Sms => self.send_sms(number, &message.sms),
+            Channel::Voice => self.send_voice(number, &message.voice),
+        });
+        VerificationEntry {
+            carrier: self.get_name(),
+            number: number.to_string(),
+            time: chrono::offset::Utc::now(),
+            step: ladder.verification_step(delivered.unwrap_or(ladder.steps.len())),
+            simulated: false,
+            correlation_id: None,
+        }
+    }
+
+    // supports_code_format reports whether the carrier can deliver codes in format, attempts
+    // are only routed to carriers that can
+    fn supports_code_format(&self, _format: &CodeFormat) -> bool {
+        true
+    }
+
+    // connects_to reports whether the carrier has a direct connection to network, balancers
+    // prefer those carriers for numbers served by it
+    fn connects_to(&self, _network: &MobileNetwork) -> bool {
+        false
+    }
+
+    // serves_country reports whether the carrier covers numbers of country, attempts are only
+    // routed to carriers that do
+    fn serves_country(&self, _country: Option<&str>) -> bool {
+        true
+    }
+
+    // weight is the carrier's share of the attempts balancers route among candidates, relative to
+    // the weights of the others
+    fn weight(&self) -> u32 {
+        1
+    }
+
+    // verify_traced runs verify as a span of an existing trace, providers calling out over HTTP
+    // override it to send trace.inject headers with their requests
+    fn verify_traced(
+        &self,
+        number: &str,
+        message: &Message,
+        ladder: &Ladder,
+        _trace: &TraceContext,
+    ) -> VerificationEntry {
+        self.verify(number, message, ladder)
+    }
+
+    // handle_webhook authenticates and parses a callback sent by the carrier to
+    // POST /webhooks/{provider_name}
+    fn handle_webhook(
+        &self,
+        _headers: &HeaderMap,
+        _body: &[u8],
+    ) -> Result<Vec<ProviderCallback>, WebhookError> {
+        Err(WebhookError::Unsupported)
+    }
+}
+
+// ProviderCallback is a normalized notification sent by a carrier about a number it contacted
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderCallback {
+    DeliveryReport { number: String, delivered: bool },
+    VerificationResult { number: String, verified: bool },
+    // a message the number sent, opt-out and opt-in keywords change its consent
+    InboundMessage { number: String, body: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WebhookError {
+    // the provider does not accept webhooks
+    Unsupported,
+    // the signature is missing or does not match the body
+    Unauthorized,
+    // the body could not be parsed by the provider
+    Invalid(String),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookError::Unsupported => write!(f, "provider does not accept webhooks"),
+            WebhookError::Unauthorized => write!(f, "invalid webhook signature"),
+            WebhookError::Invalid(e) => write!(f, "invalid webhook payload: {}", e),
+        }
+    }
 }
 
 pub struct MockTelecomProvider {
-    name: String,
+    name: Arc<str>,
     // percentage based likelyhood of success
     chance_sms: u8,
     chance_voice: u8,
+    // webhooks must be signed with this secret when set
+    webhook_secret: Option<String>,
+    // API token the carrier was built with, webhooks are signed with it without a webhook_secret
+    credentials: Option<String>,
+    // carriers whose voice calls only read out digits can't deliver letters
+    numeric_codes_only: bool,
+    direct_networks: Vec<MobileNetwork>,
+    // messages carry the code, they are only logged when explicitly asked for
+    print_messages: bool,
+    // decides which sends fail
+    rng: SharedRng,
 }
 
 impl MockTelecomProvider {
@@ -28,47 +157,466 @@ impl MockTelecomProvider {
         }
 
         Ok(Self {
-            name: name.to_string(),
+            name: name.to_string().into(),
             chance_sms,
             chance_voice,
+            webhook_secret: None,
+            credentials: None,
+            numeric_codes_only: false,
+            direct_networks: Vec::new(),
+            print_messages: false,
+            rng: SharedRng::default(),
         })
     }
+
+    // with_seed makes which sends fail the same every run
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = SharedRng::seeded_for(seed, &self.name);
+        self
+    }
+
+    pub fn with_webhook_secret<T: ToString>(mut self, secret: T) -> Self {
+        self.webhook_secret = Some(secret.to_string());
+        self
+    }
+
+    pub fn with_credentials<T: ToString>(mut self, token: T) -> Self {
+        self.credentials = Some(token.to_string());
+        self
+    }
+
+    pub fn with_numeric_codes_only(mut self) -> Self {
+        self.numeric_codes_only = true;
+        self
+    }
+
+    pub fn with_direct_networks(mut self, networks: Vec<MobileNetwork>) -> Self {
+        self.direct_networks = networks;
+        self
+    }
+
+    // with_printed_messages logs every delivered message code included, for local testing only
+    pub fn with_printed_messages(mut self) -> Self {
+        self.print_messages = true;
+        self
+    }
+
+    // nothing is actually sent, printing the message stands in for the phone receiving it
+    fn delivered(&self, delivered: bool, number: &str, message: &str) -> bool {
+        match (delivered, self.print_messages) {
+            (true, true) => {
+                info!(carrier = %self.name, number = %redact(number), message, "message delivered")
+            }
+            (true, false) => {
+                info!(carrier = %self.name, number = %redact(number), "message delivered")
+            }
+            _ => (),
+        }
+        delivered
+    }
 }
 
 impl TelecomProvider for MockTelecomProvider {
     // return a probability likelyhood of verification success,
-    fn send_sms(&self, _number: &String) -> bool {
-        let num = rand::thread_rng().gen_range(0, 100);
-        num <= self.chance_sms
-    }
-    fn send_voice(&self, _number: &String) -> bool {
-        let num = rand::thread_rng().gen_range(0, 100);
-        num <= self.chance_voice
-    }
-
-    // step through the steps outlined in VerificationStep with each having an independent chance
-    // of success, returning the first verification attempt that returns true
-    fn verify(&self, number: &String) -> VerificationEntry
-    where
-        Self: Send + Sync,
-    {
-        let rng_verification_step: VerificationStep = match () {
-            _ if self.send_sms(number) => VerificationStep::FirstSMS,
-            _ if self.send_sms(number) => VerificationStep::SecondSMS,
-            _ if self.send_voice(number) => VerificationStep::FirstTextToSpeech,
-            _ if self.send_voice(number) => VerificationStep::SecondTextToSpeech,
-            _ => VerificationStep::Unreachable,
+    fn send_sms(&self, number: &str, message: &str) -> bool {
+        let num = self.rng.with(|rng| rng.gen_range(0, 100));
+        self.delivered(num <= self.chance_sms, number, message)
+    }
+    fn send_voice(&self, number: &str, message: &str) -> bool {
+        let num = self.rng.with(|rng| rng.gen_range(0, 100));
+        self.delivered(num <= self.chance_voice, number, message)
+    }
+
+    fn get_name(&self) -> Arc<str> {
+        self.name.clone()
+    }
+
+    fn supports_code_format(&self, format: &CodeFormat) -> bool {
+        !self.numeric_codes_only || format.alphabet == Alphabet::Numeric
+    }
+
+    fn connects_to(&self, network: &MobileNetwork) -> bool {
+        self.direct_networks.contains(network)
+    }
+
+    // mock callbacks are JSON encoded ProviderCallback values, optionally signed with
+    // MOCK_SIGNATURE_HEADER
+    fn handle_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Vec<ProviderCallback>, WebhookError> {
+        if let Some(secret) = self.webhook_secret.as_ref().or(self.credentials.as_ref()) {
+            let signature = headers
+                .get(MOCK_SIGNATURE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| hex::decode(v).ok())
+                .ok_or(WebhookError::Unauthorized)?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any size");
+            mac.update(body);
+            mac.verify_slice(&signature)
+                .map_err(|_| WebhookError::Unauthorized)?;
+        }
+        let callback = serde_json::from_slice::<ProviderCallback>(body)
+            .map_err(|e| WebhookError::Invalid(e.to_string()))?;
+        Ok(vec![callback])
+    }
+}
+
+// ProviderConfig describes a carrier that can be constructed at runtime by build_provider
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct ProviderConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ProviderKind,
+    // reference to where the provider credentials are resolved from, never the secret itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
+    // e.g. 2 to be routed twice the attempts of a carrier weighted 1
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    // ISO 3166-1 alpha-2 codes of the only countries the carrier is routed numbers of
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub countries: BTreeSet<String>,
+    // disabled carriers are registered but, like draining ones, not routed attempts
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ProviderConfig {
+    // mock builds the config of a mock carrier with the given chances of delivering messages
+    pub fn mock<T: ToString>(name: T, chance_sms: u8, chance_voice: u8) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: ProviderKind::Mock {
+                chance_sms,
+                chance_voice,
+                webhook_secret: None,
+                numeric_codes_only: false,
+                direct_networks: Vec::new(),
+                print_messages: false,
+            },
+            credentials: None,
+            weight: default_weight(),
+            countries: BTreeSet::new(),
+            enabled: default_enabled(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderKind {
+    Mock {
+        chance_sms: u8,
+        chance_voice: u8,
+        // reference to the secret webhooks are signed with, resolved like credentials
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        webhook_secret: Option<String>,
+        #[serde(default)]
+        numeric_codes_only: bool,
+        // mobile networks the carrier delivers to directly rather than through an aggregator
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        direct_networks: Vec<MobileNetwork>,
+        #[serde(default)]
+        print_messages: bool,
+    },
+}
+
+// resolve_credentials reads the secret a credentials reference points to, ${secret:NAME} for a
+// secret of secrets, env:NAME for the value of an environment variable or file:PATH for the
+// trimmed contents of a file
+pub fn resolve_credentials(reference: &str, secrets: &dyn SecretStore) -> Result<String, Error> {
+    let named = reference
+        .strip_prefix("${secret:")
+        .and_then(|r| r.strip_suffix('}'));
+    let secret = match (named, reference.split_once(':')) {
+        (Some(name), _) => secrets.get(name)?,
+        (None, Some(("env", name))) => std::env::var(name)
+            .map_err(|_| anyhow!("credentials environment variable {} is not set", name))?,
+        (None, Some(("file", path))) => std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read credentials file {}: {}", path, e))?
+            .trim()
+            .to_string(),
+        _ => {
+            return Err(anyhow!(
+                "credentials must reference ${{secret:NAME}}, env:NAME or file:PATH, got {}",
+                reference
+            ))
+        }
+    };
+    match secret.is_empty() {
+        true => Err(anyhow!("credentials {} are empty", reference)),
+        false => Ok(secret),
+    }
+}
+
+// Credentials are the secrets the references of a ProviderConfig resolved to
+#[derive(Default)]
+pub struct Credentials {
+    token: Option<String>,
+    webhook_secret: Option<String>,
+}
+
+impl Credentials {
+    // resolve reads the secrets the references of config point to, failing with the field whose
+    // reference didn't resolve
+    pub fn resolve(
+        config: &ProviderConfig,
+        secrets: &dyn SecretStore,
+    ) -> Result<Self, (&'static str, Error)> {
+        let resolve = |field, reference: Option<&String>| {
+            reference
+                .map(|r| resolve_credentials(r, secrets))
+                .transpose()
+                .map_err(|e| (field, e))
         };
+        let webhook_secret = match &config.kind {
+            ProviderKind::Mock { webhook_secret, .. } => webhook_secret.as_ref(),
+        };
+        Ok(Self {
+            token: resolve("credentials", config.credentials.as_ref())?,
+            webhook_secret: resolve("webhook_secret", webhook_secret)?,
+        })
+    }
+}
 
-        VerificationEntry {
-            carrier: self.name.clone(),
-            number: number.clone(),
-            time: chrono::offset::Utc::now(),
-            step: rng_verification_step,
+// build_provider is the factory turning a ProviderConfig into a TelecomProvider, with the secrets
+// its references point to resolved from secrets. The randomness of mock carriers is seeded by seed
+// when given
+pub fn build_provider(
+    config: &ProviderConfig,
+    seed: Option<u64>,
+    secrets: &dyn SecretStore,
+) -> Result<Box<dyn TelecomProvider>, Error> {
+    let credentials =
+        Credentials::resolve(config, secrets).map_err(|(field, e)| anyhow!("{}: {}", field, e))?;
+    build_with_credentials(config, seed, credentials)
+}
+
+// build_with_credentials builds config with credentials already resolved
+pub fn build_with_credentials(
+    config: &ProviderConfig,
+    seed: Option<u64>,
+    credentials: Credentials,
+) -> Result<Box<dyn TelecomProvider>, Error> {
+    if config.weight == 0 {
+        return Err(anyhow!("carrier weight must be greater than 0"));
+    }
+    let countries = config
+        .countries
+        .iter()
+        .map(
+            |c| match c.len() == 2 && c.chars().all(|c| c.is_ascii_alphabetic()) {
+                true => Ok(c.to_ascii_uppercase()),
+                false => Err(anyhow!("invalid country code: {}", c)),
+            },
+        )
+        .collect::<Result<_, _>>()?;
+    Ok(Box::new(ConfiguredProvider {
+        inner: build_kind(config, seed, credentials)?,
+        weight: config.weight,
+        countries,
+    }))
+}
+
+fn build_kind(
+    config: &ProviderConfig,
+    seed: Option<u64>,
+    credentials: Credentials,
+) -> Result<Box<dyn TelecomProvider>, Error> {
+    match &config.kind {
+        ProviderKind::Mock {
+            chance_sms,
+            chance_voice,
+            webhook_secret: _,
+            numeric_codes_only,
+            direct_networks,
+            print_messages,
+        } => {
+            let mut provider = MockTelecomProvider::new(&config.name, *chance_sms, *chance_voice)?;
+            if let Some(secret) = credentials.webhook_secret {
+                provider = provider.with_webhook_secret(secret);
+            }
+            if let Some(token) = credentials.token {
+                provider = provider.with_credentials(token);
+            }
+            if *numeric_codes_only {
+                provider = provider.with_numeric_codes_only();
+            }
+            if *print_messages {
+                provider = provider.with_printed_messages();
+            }
+            if let Some(seed) = seed {
+                provider = provider.with_seed(seed);
+            }
+            Ok(Box::new(
+                provider.with_direct_networks(direct_networks.clone()),
+            ))
         }
     }
+}
 
-    fn get_name(&self) -> String {
-        self.name.clone()
+// ConfiguredProvider applies the routing settings of a ProviderConfig to the provider it built
+struct ConfiguredProvider {
+    inner: Box<dyn TelecomProvider>,
+    weight: u32,
+    // every country is served when empty
+    countries: BTreeSet<String>,
+}
+
+impl TelecomProvider for ConfiguredProvider {
+    fn send_sms(&self, number: &str, message: &str) -> bool {
+        self.inner.send_sms(number, message)
+    }
+
+    fn send_voice(&self, number: &str, message: &str) -> bool {
+        self.inner.send_voice(number, message)
+    }
+
+    fn get_name(&self) -> Arc<str> {
+        self.inner.get_name()
+    }
+
+    fn verify(&self, number: &str, message: &Message, ladder: &Ladder) -> VerificationEntry {
+        self.inner.verify(number, message, ladder)
+    }
+
+    fn supports_code_format(&self, format: &CodeFormat) -> bool {
+        self.inner.supports_code_format(format)
+    }
+
+    fn connects_to(&self, network: &MobileNetwork) -> bool {
+        self.inner.connects_to(network)
+    }
+
+    fn serves_country(&self, country: Option<&str>) -> bool {
+        self.countries.is_empty() || country.is_some_and(|c| self.countries.contains(c))
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    fn verify_traced(
+        &self,
+        number: &str,
+        message: &Message,
+        ladder: &Ladder,
+        trace: &TraceContext,
+    ) -> VerificationEntry {
+        self.inner.verify_traced(number, message, ladder, trace)
+    }
+
+    fn handle_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Vec<ProviderCallback>, WebhookError> {
+        self.inner.handle_webhook(headers, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Secrets holds the secret named webhook
+    struct Secrets;
+
+    impl SecretStore for Secrets {
+        fn get(&self, name: &str) -> Result<String, Error> {
+            match name {
+                "webhook" => Ok("secret".to_string()),
+                _ => Err(anyhow!("secret {} is not set", name)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mock_webhook_signature() {
+        let mut config = ProviderConfig::mock("carrier_1", 50, 50);
+        let ProviderKind::Mock { webhook_secret, .. } = &mut config.kind;
+        *webhook_secret = Some("${secret:webhook}".to_string());
+        let provider = build_provider(&config, None, &Secrets).unwrap();
+        let body = br#"{"type": "delivery_report", "number": "0177", "delivered": true}"#;
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            provider.handle_webhook(&headers, body),
+            Err(WebhookError::Unauthorized)
+        );
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        headers.insert(MOCK_SIGNATURE_HEADER, signature.parse().unwrap());
+        assert_eq!(
+            provider.handle_webhook(&headers, body),
+            Ok(vec![ProviderCallback::DeliveryReport {
+                number: "0177".to_owned(),
+                delivered: true
+            }])
+        );
+        assert!(matches!(
+            provider.handle_webhook(&headers, b"{}"),
+            Err(WebhookError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_build_provider() {
+        let mut config: ProviderConfig = serde_json::from_str(
+            r#"{"name": "carrier_1", "type": "mock", "chance_sms": 60, "chance_voice": 50,
+                "weight": 3, "countries": ["de", "AT"]}"#,
+        )
+        .unwrap();
+        let provider = build_provider(&config, None, &Secrets).unwrap();
+        assert_eq!(
+            (provider.get_name(), provider.weight()),
+            ("carrier_1".into(), 3)
+        );
+        assert!(provider.serves_country(Some("DE")));
+        assert!(!provider.serves_country(Some("FR")));
+        assert!(!provider.serves_country(None));
+        assert!(
+            build_provider(&ProviderConfig::mock("carrier_2", 60, 50), None, &Secrets)
+                .unwrap()
+                .serves_country(None)
+        );
+
+        // seeded carriers fail the same sends every run
+        let sends = |seed| {
+            let provider =
+                build_provider(&ProviderConfig::mock("carrier_2", 50, 50), seed, &Secrets).unwrap();
+            (0..32)
+                .map(|_| provider.send_sms("+15555550100", "code"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sends(Some(7)), sends(Some(7)));
+
+        config.weight = 0;
+        assert!(build_provider(&config, None, &Secrets).is_err());
+        config.weight = 1;
+        config.countries.insert("Germany".to_string());
+        assert!(build_provider(&config, None, &Secrets).is_err());
+        config.countries.clear();
+        config.credentials = Some("${secret:missing}".to_string());
+        assert_eq!(
+            build_provider(&config, None, &Secrets)
+                .err()
+                .unwrap()
+                .to_string(),
+            "credentials: secret missing is not set"
+        );
     }
 }
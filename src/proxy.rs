@@ -0,0 +1,307 @@
+use anyhow::{anyhow, Error};
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Cidr is a network of addresses such as 10.0.0.0/8, a bare address is a network of its own
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of dual stack listeners show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow!("invalid network {}, e.g. 10.0.0.0/8 or fd00::/8", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(anyhow!("invalid prefix length in {}, at most {}", s, max)),
+            },
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+// ForwardedHeader is a header proxies pass the address of the client they forward for in
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    // comma separated addresses, each proxy appending the one it received the request from
+    XForwardedFor,
+    // the client's address alone
+    XRealIp,
+    // RFC 7239, the for= parameters of each element
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::XForwardedFor => "x-forwarded-for",
+            Self::XRealIp => "x-real-ip",
+            Self::Forwarded => "forwarded",
+        }
+    }
+
+    // hops returns the addresses of the header in headers, the client first and the proxy
+    // nearest this server last. Entries that aren't addresses, like unknown or obfuscated
+    // identifiers, are None
+    fn hops(&self, headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+        let values = headers
+            .get_all(self.name())
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        let mut hops = Vec::new();
+        for value in values {
+            for element in value.split(',') {
+                let node = match self {
+                    Self::Forwarded => element
+                        .split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                        .map(|(_, node)| node.trim().trim_matches('"')),
+                    _ => Some(element.trim()),
+                };
+                match node {
+                    Some(node) => hops.push(parse_node(node)),
+                    // an element without for= names no hop of its own
+                    None if *self == Self::Forwarded => continue,
+                    None => hops.push(None),
+                }
+            }
+        }
+        hops
+    }
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "x-real-ip" => Ok(Self::XRealIp),
+            "forwarded" => Ok(Self::Forwarded),
+            _ => Err(anyhow!(
+                "invalid forwarded header {}, use x-forwarded-for, x-real-ip or forwarded",
+                s
+            )),
+        }
+    }
+}
+
+// parse_node reads an address that may carry a port, e.g. 192.0.2.1:4711 or [2001:db8::1]:4711
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    let host = match node.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => node.rsplit_once(':')?.0,
+    };
+    host.parse().ok()
+}
+
+// ProxyConfig is which peers are trusted to report the address of the client they forward for,
+// and the headers they report it in. Headers sent by any other peer are ignored, so clients can't
+// claim an address of their choosing
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub trusted: Vec<Cidr>,
+    // the first of these headers a trusted proxy sent is honored
+    pub headers: Vec<ForwardedHeader>,
+    // clients of the unix socket are a local reverse proxy
+    pub trust_unix_socket: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            trusted: Vec::new(),
+            headers: vec![ForwardedHeader::XForwardedFor],
+            trust_unix_socket: false,
+        }
+    }
+}
+
+impl ProxyConfig {
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // client_ip returns the address of the client a request from peer was made by, peer is None
+    // for unix socket connections. Forwarded addresses are walked from the nearest proxy back,
+    // the first one that isn't a trusted proxy being the client
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let trusted = match peer {
+            Some(ip) => self.trusts(ip),
+            None => self.trust_unix_socket,
+        };
+        if !trusted {
+            return peer;
+        }
+        let hops = match self
+            .headers
+            .iter()
+            .map(|h| h.hops(headers))
+            .find(|hops| !hops.is_empty())
+        {
+            Some(hops) => hops,
+            None => return peer,
+        };
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.trusts(ip) {
+                        break;
+                    }
+                }
+                // what comes before an entry that isn't an address can't be told apart from
+                // what the client sent, the proxy that added it is taken as the client
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.200.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.0.9".parse().unwrap()));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.0.2.1".parse().unwrap()));
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains("fd12::1".parse().unwrap()));
+        assert_eq!(
+            "127.0.0.1".parse::<Cidr>().unwrap().to_string(),
+            "127.0.0.1/32"
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let config = ProxyConfig {
+            trusted: vec!["10.0.0.0/8".parse().unwrap()],
+            ..ProxyConfig::default()
+        };
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.7, 10.0.0.3")]);
+        // untrusted peers can't claim an address
+        assert_eq!(
+            config.client_ip(ip("203.0.113.9"), &forwarded),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            config.client_ip(ip("10.0.0.2"), &forwarded),
+            ip("198.51.100.7")
+        );
+        // an address the client prepended itself is left alone
+        let spoofed = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ]);
+        assert_eq!(
+            config.client_ip(ip("10.0.0.2"), &spoofed),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            config.client_ip(ip("10.0.0.2"), &HeaderMap::new()),
+            ip("10.0.0.2")
+        );
+        assert_eq!(config.client_ip(None, &forwarded), None);
+
+        let config = ProxyConfig {
+            headers: vec![ForwardedHeader::Forwarded, ForwardedHeader::XRealIp],
+            trust_unix_socket: true,
+            ..config
+        };
+        let forwarded = headers(&[(
+            "forwarded",
+            "for=192.0.2.60;proto=http, for=\"[2001:db8::1]:4711\"",
+        )]);
+        assert_eq!(config.client_ip(None, &forwarded), ip("2001:db8::1"));
+        let real_ip = headers(&[("x-real-ip", "192.0.2.61")]);
+        assert_eq!(config.client_ip(None, &real_ip), ip("192.0.2.61"));
+        let obfuscated = headers(&[("forwarded", "for=_hidden, for=10.0.0.4")]);
+        assert_eq!(
+            config.client_ip(ip("10.0.0.2"), &obfuscated),
+            ip("10.0.0.4")
+        );
+    }
+}
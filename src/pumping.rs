@@ -0,0 +1,367 @@
+use crate::country;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use utoipa::ToSchema;
+
+// PumpingConfig detects artificially inflated traffic, where fraudsters have codes sent to
+// ranges of numbers they collect termination fees on. Each signal is off until its threshold is
+// set, and tripping one throttles the affected prefix
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct PumpingConfig {
+    pub window_secs: u64,
+    // leading digits of a number, country code included, that make up its range
+    pub range_digits: usize,
+    // fewest requests to a range or country within the window before its conversion or share is
+    // judged
+    pub min_requests: u32,
+    // distinct numbers of one range requested within the window
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_range_numbers: Option<u32>,
+    // consecutive numbers of one range requested within the window, e.g. +15555550100 to 0104
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sequential: Option<u32>,
+    // fraction of the requests to a range that were verified, pumped codes are never submitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_conversion: Option<f32>,
+    // fraction of all requests a country outside expected_countries may account for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_country_share: Option<f32>,
+    // ISO 3166-1 alpha-2 codes of the countries most traffic is expected from
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub expected_countries: BTreeSet<String>,
+    pub throttle_secs: u64,
+    // requests to a throttled prefix still sent per window, 0 blocks the prefix
+    pub throttled_limit: u32,
+    // receives every new throttle as a signed JSON notification, as completion webhooks do
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert_url: Option<String>,
+}
+
+impl Default for PumpingConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 3600,
+            range_digits: 9,
+            min_requests: 20,
+            max_range_numbers: None,
+            max_sequential: None,
+            min_conversion: None,
+            max_country_share: None,
+            expected_countries: BTreeSet::new(),
+            throttle_secs: 3600,
+            throttled_limit: 1,
+            alert_url: None,
+        }
+    }
+}
+
+impl PumpingConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.window_secs == 0 || self.throttle_secs == 0 {
+            return Err(anyhow!(
+                "pumping window_secs and throttle_secs must be greater than 0"
+            ));
+        }
+        if self.range_digits == 0 {
+            return Err(anyhow!("pumping range_digits must be greater than 0"));
+        }
+        if [self.max_range_numbers, self.max_sequential].contains(&Some(0)) {
+            return Err(anyhow!("pumping limits must be greater than 0"));
+        }
+        let fractions = [self.min_conversion, self.max_country_share];
+        if fractions.iter().flatten().any(|f| !(0.0..=1.0).contains(f)) {
+            return Err(anyhow!(
+                "min_conversion and max_country_share must be between 0 and 1"
+            ));
+        }
+        if self.max_country_share.is_some() && self.expected_countries.is_empty() {
+            return Err(anyhow!("max_country_share requires expected_countries"));
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.window_secs as i64)
+    }
+}
+
+// Throttle limits requests to numbers starting with prefix after it looked pumped
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct Throttle {
+    pub prefix: String,
+    // the signal that tripped, e.g. "8 sequential numbers requested"
+    pub reason: String,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    // requests sent to the prefix while throttled
+    #[serde(skip)]
+    sent: VecDeque<DateTime<Utc>>,
+}
+
+// PumpingDetector keeps the requests of the last window and the prefixes throttled because of
+// them
+#[derive(Debug, Default)]
+pub struct PumpingDetector {
+    // digits of every number sent a code
+    requests: VecDeque<(DateTime<Utc>, String)>,
+    verified: VecDeque<(DateTime<Utc>, String)>,
+    throttles: BTreeMap<String, Throttle>,
+}
+
+impl PumpingDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // admit returns the throttled prefix of number when it has used up its requests, otherwise
+    // the request counts against the throttle
+    pub fn admit(
+        &mut self,
+        config: &PumpingConfig,
+        number: &str,
+        now: DateTime<Utc>,
+    ) -> Option<String> {
+        self.prune(config, now);
+        let number = digits(number);
+        let throttle = self
+            .throttles
+            .values_mut()
+            .find(|t| number.starts_with(t.prefix.trim_start_matches('+')))?;
+        if throttle.sent.len() >= config.throttled_limit as usize {
+            return Some(throttle.prefix.clone());
+        }
+        throttle.sent.push_back(now);
+        None
+    }
+
+    // record counts a code sent to number, returning the throttles its request tripped
+    pub fn record(
+        &mut self,
+        config: &PumpingConfig,
+        number: &str,
+        now: DateTime<Utc>,
+    ) -> Vec<Throttle> {
+        let number = digits(number);
+        self.requests.push_back((now, number.clone()));
+
+        let mut tripped = Vec::new();
+        let range = number.chars().take(config.range_digits).collect::<String>();
+        if let Some(reason) = self.range_signal(config, &range) {
+            tripped.push((format!("+{}", range), reason));
+        }
+        if let Some(reason) = self.country_signal(config, &number) {
+            let code = country::calling_code(&number).unwrap_or_default();
+            tripped.push((format!("+{}", code), reason));
+        }
+        let mut throttles = Vec::new();
+        for (prefix, reason) in tripped {
+            if self.throttles.contains_key(&prefix) {
+                continue;
+            }
+            let throttle = Throttle {
+                prefix: prefix.clone(),
+                reason,
+                since: now,
+                until: now + Duration::seconds(config.throttle_secs as i64),
+                sent: VecDeque::new(),
+            };
+            self.throttles.insert(prefix, throttle.clone());
+            throttles.push(throttle);
+        }
+        throttles
+    }
+
+    // verified counts a submitted code towards the conversion of its number's range
+    pub fn verified(&mut self, number: &str, now: DateTime<Utc>) {
+        let number = digits(number);
+        if self.requests.iter().any(|(_, n)| *n == number) {
+            self.verified.push_back((now, number));
+        }
+    }
+
+    pub fn throttles(&self) -> Vec<Throttle> {
+        self.throttles.values().cloned().collect()
+    }
+
+    // lift ends the throttle of prefix before it runs out
+    pub fn lift(&mut self, prefix: &str) -> bool {
+        let prefix = format!("+{}", prefix.trim_start_matches('+'));
+        self.throttles.remove(&prefix).is_some()
+    }
+
+    fn range_signal(&self, config: &PumpingConfig, range: &str) -> Option<String> {
+        let requests = self
+            .requests
+            .iter()
+            .filter(|(_, n)| n.starts_with(range))
+            .count();
+        let numbers = self
+            .requests
+            .iter()
+            .filter(|(_, n)| n.starts_with(range))
+            .filter_map(|(_, n)| n.parse::<u64>().ok())
+            .collect::<BTreeSet<_>>();
+        if let Some(max) = config.max_range_numbers {
+            if numbers.len() >= max as usize {
+                return Some(format!("{} numbers of the range requested", numbers.len()));
+            }
+        }
+        if let Some(max) = config.max_sequential {
+            let sequential = longest_run(&numbers);
+            if sequential >= max as usize {
+                return Some(format!("{} sequential numbers requested", sequential));
+            }
+        }
+        match config.min_conversion {
+            Some(min) if requests >= config.min_requests as usize => {
+                let verified = self
+                    .verified
+                    .iter()
+                    .filter(|(_, n)| n.starts_with(range))
+                    .count();
+                let conversion = verified as f32 / requests as f32;
+                (conversion < min).then(|| {
+                    format!(
+                        "{:.0}% of {} requests verified",
+                        conversion * 100.0,
+                        requests
+                    )
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn country_signal(&self, config: &PumpingConfig, number: &str) -> Option<String> {
+        let max = config.max_country_share?;
+        let country = country::country_of(number)?;
+        if config
+            .expected_countries
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(country))
+        {
+            return None;
+        }
+        let mut counts = HashMap::new();
+        for (_, n) in &self.requests {
+            *counts.entry(country::country_of(n)).or_insert(0_usize) += 1;
+        }
+        let requests = counts[&Some(country)];
+        let share = requests as f32 / self.requests.len() as f32;
+        match requests >= config.min_requests as usize && share > max {
+            true => Some(format!("{} is {:.0}% of requests", country, share * 100.0)),
+            false => None,
+        }
+    }
+
+    // prune drops requests older than the window and throttles that ran out
+    fn prune(&mut self, config: &PumpingConfig, now: DateTime<Utc>) {
+        let since = now - config.window();
+        for seen in [&mut self.requests, &mut self.verified] {
+            while seen.front().is_some_and(|(t, _)| *t <= since) {
+                seen.pop_front();
+            }
+        }
+        self.throttles.retain(|_, t| t.until > now);
+        for throttle in self.throttles.values_mut() {
+            while throttle.sent.front().is_some_and(|t| *t <= since) {
+                throttle.sent.pop_front();
+            }
+        }
+    }
+}
+
+// longest_run is the length of the longest streak of consecutive values in numbers
+fn longest_run(numbers: &BTreeSet<u64>) -> usize {
+    let (mut longest, mut run, mut previous) = (0, 0, None);
+    for n in numbers {
+        run = match previous {
+            Some(p) if p + 1 == *n => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(*n);
+    }
+    longest
+}
+
+fn digits(number: &str) -> String {
+    number.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential() {
+        let config = PumpingConfig {
+            max_sequential: Some(3),
+            throttled_limit: 1,
+            ..PumpingConfig::default()
+        };
+        let now = Utc::now();
+        let mut detector = PumpingDetector::new();
+        for number in ["+15555550100", "+15555550101", "+15555550107"].iter() {
+            assert_eq!(detector.admit(&config, number, now), None);
+            assert!(detector.record(&config, number, now).is_empty());
+        }
+        let tripped = detector.record(&config, "+15555550102", now);
+        assert_eq!(tripped[0].prefix, "+155555501");
+        assert_eq!(tripped[0].reason, "3 sequential numbers requested");
+
+        // one more request to the range is let through, then it is blocked until lifted
+        assert_eq!(detector.admit(&config, "+15555550103", now), None);
+        assert_eq!(
+            detector.admit(&config, "+15555550104", now),
+            Some("+155555501".to_string())
+        );
+        assert_eq!(detector.admit(&config, "+15555550200", now), None);
+        assert!(detector.lift("155555501"));
+        assert_eq!(detector.admit(&config, "+15555550104", now), None);
+
+        // throttles run out
+        detector.record(&config, "+15555550103", now);
+        let later = now + Duration::seconds(config.throttle_secs as i64);
+        assert_eq!(detector.admit(&config, "+15555550105", later), None);
+        assert!(detector.throttles().is_empty());
+    }
+
+    #[test]
+    fn test_conversion_and_country() {
+        let mut config = PumpingConfig {
+            min_requests: 2,
+            min_conversion: Some(0.5),
+            max_country_share: Some(0.3),
+            expected_countries: vec!["gb".to_string()].into_iter().collect(),
+            ..PumpingConfig::default()
+        };
+        config.validate().unwrap();
+        let now = Utc::now();
+        let mut detector = PumpingDetector::new();
+        detector.record(&config, "+447700900100", now);
+        detector.verified("+447700900100", now);
+        detector.record(&config, "+447700900200", now);
+        detector.verified("+447700900200", now);
+        assert!(detector.record(&config, "+447700900300", now).is_empty());
+
+        // the first request of an unexpected country is no spike yet, the second one is
+        assert!(detector.record(&config, "+37120000001", now).is_empty());
+        let tripped = detector.record(&config, "+37120000002", now);
+        assert_eq!(
+            tripped
+                .iter()
+                .map(|t| t.prefix.as_str())
+                .collect::<Vec<_>>(),
+            vec!["+371200000", "+371"]
+        );
+        assert_eq!(tripped[0].reason, "0% of 2 requests verified");
+        assert_eq!(tripped[1].reason, "LV is 40% of requests");
+
+        config.expected_countries.clear();
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::otp::OtpSession;
+use crate::pagination::Page;
+use crate::repo::VerificationStep;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use utoipa::ToSchema;
+
+// previous_hash of the first receipt in a chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Receipt is the tamper-evident record of a completed verification, every receipt is chained to
+// the one before it so changing or dropping one breaks the hashes of all later ones
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct Receipt {
+    // position in the chain, starting at 0
+    pub sequence: u64,
+    pub attempt_id: String,
+    // hex SHA-256 of the attempt_id followed by the verified number, recomputable by auditors who
+    // know the number without receipts revealing it
+    pub number_hash: String,
+    pub carrier: String,
+    pub step: VerificationStep,
+    // when the submitted code was sent and when it was submitted
+    pub sent_at: DateTime<Utc>,
+    pub verified_at: DateTime<Utc>,
+    pub previous_hash: String,
+    // hex SHA-256 over the fields above in order, each followed by a newline, with timestamps as
+    // unix milliseconds
+    pub hash: String,
+}
+
+impl Receipt {
+    // new builds the receipt of a verified session chained onto previous
+    pub fn new(
+        session: &OtpSession,
+        verified_at: DateTime<Utc>,
+        previous: Option<&Receipt>,
+    ) -> Self {
+        let mut receipt = Self {
+            sequence: previous.map_or(0, |p| p.sequence + 1),
+            attempt_id: session.attempt_id.clone(),
+            number_hash: hex::encode(Sha256::digest(
+                format!("{}{}", session.attempt_id, session.number).as_bytes(),
+            )),
+            carrier: session.carrier.to_string(),
+            step: session.step,
+            sent_at: session.sent_at,
+            verified_at,
+            previous_hash: previous.map_or(GENESIS_HASH.to_string(), |p| p.hash.clone()),
+            hash: String::new(),
+        };
+        receipt.hash = receipt.digest();
+        receipt
+    }
+
+    pub fn digest(&self) -> String {
+        let step = serde_json::to_value(self.step)
+            .ok()
+            .and_then(|s| s.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let fields = [
+            self.sequence.to_string(),
+            self.attempt_id.clone(),
+            self.number_hash.clone(),
+            self.carrier.clone(),
+            step,
+            self.sent_at.timestamp_millis().to_string(),
+            self.verified_at.timestamp_millis().to_string(),
+            self.previous_hash.clone(),
+        ];
+        let mut hasher = Sha256::new();
+        for field in &fields {
+            hasher.update(field.as_bytes());
+            hasher.update(b"\n");
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+// verify_chain checks that receipts, a consecutive run of a chain, link up and weren't changed
+pub fn verify_chain(receipts: &[Receipt]) -> Result<(), Error> {
+    for (i, receipt) in receipts.iter().enumerate() {
+        if receipt.digest() != receipt.hash {
+            return Err(anyhow!("receipt {} was altered", receipt.sequence));
+        }
+        let linked = match i {
+            0 => receipt.sequence > 0 || receipt.previous_hash == GENESIS_HASH,
+            _ => {
+                let previous = &receipts[i - 1];
+                receipt.sequence == previous.sequence + 1 && receipt.previous_hash == previous.hash
+            }
+        };
+        if !linked {
+            return Err(anyhow!(
+                "receipt {} does not follow the one before it",
+                receipt.sequence
+            ));
+        }
+    }
+    Ok(())
+}
+
+// ReceiptStore keeps the chain of receipts, stores with a bound evict the oldest ones and keep
+// chaining onto the last
+pub trait ReceiptStore: Send + Sync {
+    fn push(&mut self, receipt: Receipt);
+    fn last(&self) -> Option<&Receipt>;
+    fn get(&self, attempt_id: &str) -> Option<&Receipt>;
+    // list returns receipts in chain order starting at sequence position
+    fn list(&self, position: u64, limit: usize) -> Page<Receipt>;
+    // receipts evicted so far to stay within the store's bound
+    fn evicted(&self) -> u64 {
+        0
+    }
+
+    // append chains the receipt of a verified session onto the last one
+    fn append(&mut self, session: &OtpSession, verified_at: DateTime<Utc>) -> Receipt {
+        let receipt = Receipt::new(session, verified_at, self.last());
+        self.push(receipt.clone());
+        receipt
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemoryReceiptStore {
+    receipts: VecDeque<Receipt>,
+    // attempt_id to the receipt's sequence
+    by_attempt: HashMap<String, u64>,
+    // receipts evicted so far, the sequence of the oldest one kept
+    evicted: u64,
+    max_receipts: usize,
+}
+
+impl Default for InMemoryReceiptStore {
+    fn default() -> Self {
+        Self {
+            receipts: VecDeque::new(),
+            by_attempt: HashMap::new(),
+            evicted: 0,
+            max_receipts: usize::MAX,
+        }
+    }
+}
+
+impl InMemoryReceiptStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_max_receipts keeps at most max receipts, evicting the oldest ones beyond it
+    pub fn with_max_receipts(mut self, max: usize) -> Self {
+        self.max_receipts = max;
+        self
+    }
+}
+
+impl ReceiptStore for InMemoryReceiptStore {
+    fn push(&mut self, receipt: Receipt) {
+        let sequence = self.evicted + self.receipts.len() as u64;
+        self.by_attempt.insert(receipt.attempt_id.clone(), sequence);
+        self.receipts.push_back(receipt);
+        while self.receipts.len() > self.max_receipts {
+            if let Some(evicted) = self.receipts.pop_front() {
+                self.by_attempt.remove(&evicted.attempt_id);
+            }
+            self.evicted += 1;
+        }
+    }
+
+    fn last(&self) -> Option<&Receipt> {
+        self.receipts.back()
+    }
+
+    fn get(&self, attempt_id: &str) -> Option<&Receipt> {
+        let sequence = self.by_attempt.get(attempt_id)?;
+        self.receipts.get((sequence - self.evicted) as usize)
+    }
+
+    // pages start at the oldest receipt kept once the one at position was evicted
+    fn list(&self, position: u64, limit: usize) -> Page<Receipt> {
+        let start = position.max(self.evicted);
+        let items = self
+            .receipts
+            .iter()
+            .skip((start - self.evicted) as usize)
+            .take(limit)
+            .cloned()
+            .collect();
+        let stored = self.evicted + self.receipts.len() as u64;
+        Page::new(items, start, limit, stored)
+    }
+
+    fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::otp::{CodeFormat, SessionState};
+
+    fn session(attempt_id: &str) -> OtpSession {
+        OtpSession {
+            attempt_id: attempt_id.to_string(),
+            number: "+15555550100".to_string(),
+            carrier: "carrier_1".into(),
+            step: VerificationStep::FirstSMS,
+            code_hash: String::new(),
+            sent_at: Utc::now(),
+            expires_at: Utc::now(),
+            callback_url: None,
+            state: SessionState::Verified,
+            failed_checks: 0,
+            next_check_at: None,
+            metadata: None,
+            risk: None,
+            extra_codes: 0,
+            format: CodeFormat::default(),
+            locale: None,
+            correlation_id: None,
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut store = InMemoryReceiptStore::new();
+        for attempt_id in ["first", "second", "third"].iter() {
+            store.append(&session(attempt_id), Utc::now());
+        }
+        let second = store.get("second").unwrap();
+        assert_eq!(second.sequence, 1);
+        assert_eq!(second.previous_hash, store.get("first").unwrap().hash);
+        assert_eq!(
+            second.number_hash,
+            hex::encode(Sha256::digest(b"second+15555550100"))
+        );
+
+        let mut receipts = store.list(0, 10).items;
+        verify_chain(&receipts).unwrap();
+        verify_chain(&receipts[1..]).unwrap();
+
+        receipts[1].carrier = "carrier_2".to_string();
+        assert!(verify_chain(&receipts).is_err());
+        receipts[1].hash = receipts[1].digest();
+        // rehashing an altered receipt breaks the link to it instead
+        assert!(verify_chain(&receipts).is_err());
+        receipts.remove(1);
+        assert!(verify_chain(&store.list(0, 10).items).is_ok());
+        assert!(verify_chain(&receipts).is_err());
+
+        // the oldest receipts are evicted beyond max_receipts, the rest still verify
+        let mut bounded = InMemoryReceiptStore::new().with_max_receipts(2);
+        for attempt_id in ["first", "second", "third"].iter() {
+            bounded.append(&session(attempt_id), Utc::now());
+        }
+        assert_eq!(bounded.evicted(), 1);
+        assert_eq!(bounded.get("first"), None);
+        assert_eq!(bounded.get("third").unwrap().sequence, 2);
+        let kept = bounded.list(0, 10).items;
+        assert_eq!(kept[0].sequence, 1);
+        verify_chain(&kept).unwrap();
+    }
+}
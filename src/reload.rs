@@ -0,0 +1,159 @@
+use crate::config::Config;
+use crate::http::SharedServer;
+use crate::ServeCommand;
+use anyhow::Error;
+use serde::Serialize;
+use std::fmt;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+// ConfigSource is how the running settings were loaded, so reloading can read them again
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub args: ServeCommand,
+    pub running: Config,
+}
+
+// ReloadReport is the difference between the reloaded settings and the running ones
+#[derive(Serialize, ToSchema, Debug, Default, PartialEq, Clone)]
+pub struct ReloadReport {
+    // settings now in effect, e.g. "fraud" or "carriers.carrier_2: disabled"
+    pub applied: Vec<String>,
+    // settings that changed but are only read at startup
+    pub restart_required: Vec<String>,
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.applied.is_empty() {
+            true => write!(f, "no changes applied")?,
+            false => write!(f, "applied {}", self.applied.join(", "))?,
+        }
+        if !self.restart_required.is_empty() {
+            write!(
+                f,
+                ", restart required for {}",
+                self.restart_required.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// restart_required lists the startup only settings that differ between running and next
+pub fn restart_required(running: &Config, next: &Config) -> Vec<String> {
+    [
+        ("port", running.port != next.port),
+        ("bind", running.bind != next.bind),
+        ("unix_socket", running.unix_socket != next.unix_socket),
+        ("grpc_port", running.grpc_port != next.grpc_port),
+        ("admin_port", running.admin_port != next.admin_port),
+        ("admin_bind", running.admin_bind != next.admin_bind),
+        (
+            "admin_unix_socket",
+            running.admin_unix_socket != next.admin_unix_socket,
+        ),
+        (
+            "admin_on_public",
+            running.admin_on_public != next.admin_on_public,
+        ),
+        ("admin_tokens", running.admin_tokens != next.admin_tokens),
+        ("workers", running.workers != next.workers),
+        (
+            "max_concurrency",
+            running.max_concurrency != next.max_concurrency,
+        ),
+        (
+            "max_provider_calls",
+            running.max_provider_calls != next.max_provider_calls,
+        ),
+        (
+            "max_queued_provider_calls",
+            running.max_queued_provider_calls != next.max_queued_provider_calls,
+        ),
+        (
+            "max_body_bytes",
+            running.max_body_bytes != next.max_body_bytes,
+        ),
+        (
+            "read_timeout_secs",
+            running.read_timeout_secs != next.read_timeout_secs,
+        ),
+        (
+            "write_timeout_secs",
+            running.write_timeout_secs != next.write_timeout_secs,
+        ),
+        (
+            "idle_timeout_secs",
+            running.idle_timeout_secs != next.idle_timeout_secs,
+        ),
+        (
+            "slow_request_ms",
+            running.slow_request_ms != next.slow_request_ms,
+        ),
+        (
+            "payload_sample_rate",
+            running.payload_sample_rate != next.payload_sample_rate,
+        ),
+        ("balancer", running.balancer != next.balancer),
+        ("proxies", running.proxies != next.proxies),
+        ("metrics", running.metrics != next.metrics),
+        ("seed", running.seed != next.seed),
+        ("dry_run", running.dry_run != next.dry_run),
+        ("log_level", running.log_level != next.log_level),
+        ("log_format", running.log_format != next.log_format),
+        ("log_file", running.log_file != next.log_file),
+        ("repo", running.repo != next.repo),
+        ("memory", running.memory != next.memory),
+        ("reporting", running.reporting != next.reporting),
+    ]
+    .iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(name, _)| name.to_string())
+    .collect()
+}
+
+// spawn reloads the settings whenever the process receives SIGHUP
+pub fn spawn(server: SharedServer) -> Result<tokio::task::JoinHandle<()>, Error> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let server = server.clone();
+            // the config file is read synchronously, keep it off the async workers
+            let reloaded =
+                tokio::task::spawn_blocking(move || server.reload_config("SIGHUP")).await;
+            match reloaded {
+                Ok(Ok(report)) => info!(%report, "config reloaded"),
+                Ok(Err(e)) => error!(error = %e, "config reload failed"),
+                Err(e) => error!(error = %e, "config reload failed"),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_required() {
+        let running = Config::default();
+        let mut next = running.clone();
+        next.fraud.max_per_number = Some(5);
+        next.carriers.pop();
+        assert!(restart_required(&running, &next).is_empty());
+
+        next.port = "6000".to_string();
+        next.grpc_port = Some("50051".to_string());
+        assert_eq!(restart_required(&running, &next), vec!["port", "grpc_port"]);
+        let report = ReloadReport {
+            applied: vec!["fraud".to_string()],
+            restart_required: restart_required(&running, &next),
+        };
+        assert_eq!(
+            report.to_string(),
+            "applied fraud, restart required for port, grpc_port"
+        );
+    }
+}
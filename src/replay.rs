@@ -0,0 +1,151 @@
+use crate::config::Config;
+use crate::pagination::PageParams;
+use crate::repo::{VerificationKeeper, VerificationStep};
+use crate::{VerificationRequest, VerificationServer};
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// ReplayReport is how the server handled a replayed set of verification requests
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub requests: usize,
+    // requests a code was sent to, or simulated to be in a dry run
+    pub sent: usize,
+    // requests no carrier delivered to that would have been retried in the background
+    pub retrying: usize,
+    // requests answered with the attempt already in progress for the number
+    pub in_progress: usize,
+    // requests turned away, by error
+    pub rejected: BTreeMap<String, usize>,
+    // attempts stored per carrier, by the step the number was reached on
+    pub carriers: BTreeMap<String, BTreeMap<VerificationStep, usize>>,
+    pub rank: Vec<(Arc<str>, f32)>,
+}
+
+// read_requests parses a file of one JSON verification request per line, blank lines are skipped
+pub fn read_requests(path: &str) -> Result<Vec<VerificationRequest>, Error> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("failed to read {}: {}", path, e))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| anyhow!("{} line {}: {}", path, i + 1, e))
+        })
+        .collect()
+}
+
+// build_server builds the server config describes with attempts kept in memory, the way serve
+// would route and rank them
+pub fn build_server(config: &Config) -> Result<VerificationServer, Error> {
+    let step_weights = config.ranking.clone().unwrap_or_default().step_weights;
+    let keeper = VerificationKeeper::new(step_weights).map_err(|e| anyhow!("ranking: {}", e))?;
+    let balancer = config.balancer()?;
+    let mut server = VerificationServer::new(balancer.kind, Vec::new(), Box::new(keeper));
+    // carriers and the balancer are built with the seed
+    if let Some(seed) = config.seed {
+        server = server.with_seed(seed);
+    }
+    server = server.with_balancer(&balancer)?;
+    if config.dry_run {
+        server = server.with_dry_run();
+    }
+    server.with_settings(config)
+}
+
+// replay handles requests in order and reports the outcome. Escalations and retries are left
+// scheduled, only the first delivery of each request is replayed
+pub fn replay(
+    server: &VerificationServer,
+    requests: &[VerificationRequest],
+) -> Result<ReplayReport, Error> {
+    let mut report = ReplayReport {
+        requests: requests.len(),
+        ..ReplayReport::default()
+    };
+    for request in requests {
+        let rejected = match server.handle_request(request) {
+            Ok(response) => match response.error {
+                Some(error) => Some(error),
+                None if response.in_progress => {
+                    report.in_progress += 1;
+                    None
+                }
+                None if response.retrying => {
+                    report.retrying += 1;
+                    None
+                }
+                None => {
+                    report.sent += 1;
+                    None
+                }
+            },
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(error) = rejected {
+            *report.rejected.entry(error).or_default() += 1;
+        }
+    }
+
+    let mut page = PageParams::default();
+    loop {
+        let attempts = server.list_attempts(&page)?;
+        for entry in attempts.items {
+            *report
+                .carriers
+                .entry(entry.carrier.to_string())
+                .or_default()
+                .entry(entry.step)
+                .or_default() += 1;
+        }
+        match attempts.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    report.rank = server.get_provider_rank().rank;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::BalancerConfig;
+    use crate::provider::ProviderConfig;
+    use crate::BalancerType;
+
+    #[test]
+    fn test_replay() {
+        let config = Config {
+            balancer: Some(BalancerConfig::new(BalancerType::RoundRobin)),
+            carriers: vec![
+                ProviderConfig::mock("carrier_1", 100, 100),
+                ProviderConfig::mock("carrier_2", 100, 100),
+            ],
+            ..Config::default()
+        };
+        let requests = ["+15555550100", "+15555550101", "+15555550100", "12"]
+            .iter()
+            .map(|number| {
+                serde_json::from_value(serde_json::json!({ "number": number, "time": 0 })).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let report = replay(&build_server(&config).unwrap(), &requests).unwrap();
+        assert_eq!(report.requests, 4);
+        assert_eq!(report.sent, 3);
+        assert_eq!(report.rejected.values().sum::<usize>(), 1);
+        // the round robin alternates between the carriers
+        for (carrier, attempts) in [("carrier_1", 2), ("carrier_2", 1)] {
+            assert_eq!(
+                report.carriers[carrier],
+                vec![(VerificationStep::FirstSMS, attempts)]
+                    .into_iter()
+                    .collect()
+            );
+        }
+        assert_eq!(report.rank.len(), 2);
+    }
+}
@@ -1,18 +1,169 @@
+use crate::config::{RepoBackend, RepoConfig};
+use crate::country;
+use crate::fraud::FraudDecision;
+use crate::memory::MemoryConfig;
+use crate::pagination::Page;
 use anyhow::{anyhow, Error};
-use chrono::{DateTime, Utc};
-use std::collections::HashMap;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{error, warn};
+use utoipa::ToSchema;
 
+// VerificationRepo is shared by every request the server handles at once, implementations
+// synchronize themselves. Calls are synchronous and expected to return quickly, stores that
+// block on IO such as FileKeeper buffer their writes and are called with the attempts carriers
+// made, from the carrier pool
 pub trait VerificationRepo: Send + Sync {
-    fn store_attempt(&mut self, entry: VerificationEntry) -> Result<(), Error>;
-    fn get_provider_rank(&self) -> Vec<(String, f32)>;
+    fn store_attempt(&self, entry: VerificationEntry) -> Result<(), Error>;
+    // return the ranking of every carrier across all stored attempts
+    fn get_provider_rank(&self) -> Vec<(Arc<str>, f32)> {
+        self.get_provider_rank_by(&RankQuery::default())
+    }
+    fn get_provider_rank_by(&self, query: &RankQuery) -> Vec<(Arc<str>, f32)>;
+    // return stored attempts in the order they were stored, starting at position
+    fn list_attempts(&self, position: u64, limit: usize) -> Page<VerificationEntry>;
+    fn get_ranking_config(&self) -> RankingConfig;
+    fn set_ranking_config(&self, config: RankingConfig) -> Result<(), Error>;
+    // record the fraud decision taken on a verification request
+    fn store_decision(&self, decision: FraudDecision) -> Result<(), Error>;
+    // return stored fraud decisions in the order they were taken, starting at position
+    fn list_decisions(&self, position: u64, limit: usize) -> Page<FraudDecision>;
+    // number of stored attempts and decisions
+    fn attempt_count(&self) -> usize;
+    fn decision_count(&self) -> usize;
+    // attempts and decisions evicted so far to stay within max_attempts and max_decisions, see
+    // memory::MemoryConfig
+    fn evicted_attempts(&self) -> u64 {
+        0
+    }
+    fn evicted_decisions(&self) -> u64 {
+        0
+    }
+    // write the records buffered so far, repos that don't buffer have nothing to do
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// RankingConfig controls how get_provider_rank weighs stored verification attempts
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RankingConfig {
+    // weighted values of each VerificationStep in declaration order, must be ascending
+    pub step_weights: [u32; 5],
+    // only rank attempts made within the last window_secs, all attempts are ranked when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_secs: Option<u64>,
+    // an attempt's influence on the average halves every decay_half_life_secs, disabled when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decay_half_life_secs: Option<u64>,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            step_weights: [1, 2, 3, 4, 5],
+            window_secs: None,
+            decay_half_life_secs: None,
+        }
+    }
+}
+
+// steps in the order their weights are given
+const STEPS: [VerificationStep; 5] = [
+    VerificationStep::FirstSMS,
+    VerificationStep::SecondSMS,
+    VerificationStep::FirstTextToSpeech,
+    VerificationStep::SecondTextToSpeech,
+    VerificationStep::Unreachable,
+];
+
+// parse_step_weights reads the comma separated weights of the five steps, e.g. 1,2,3,4,5
+pub fn parse_step_weights(s: &str) -> Result<[u32; 5], String> {
+    let weights = s
+        .split(',')
+        .map(|w| {
+            w.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid step weight: {}", w))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights: [u32; 5] = weights.try_into().map_err(|w: Vec<u32>| {
+        format!(
+            "expected 5 step weights, FirstSMS through Unreachable, got {}",
+            w.len()
+        )
+    })?;
+    validate_step_weights(&weights).map_err(|e| e.to_string())?;
+    Ok(weights)
+}
+
+// validate_step_weights checks that no step outweighs a later one, a worse outcome must never
+// rank a carrier better
+fn validate_step_weights(weights: &[u32; 5]) -> Result<(), Error> {
+    for (i, pair) in weights.windows(2).enumerate() {
+        if pair[1] < pair[0] {
+            return Err(anyhow!(
+                "step weights must be ascending, {:?} is weighted {} but the step before it, {:?}, {}",
+                STEPS[i + 1],
+                pair[1],
+                STEPS[i],
+                pair[0]
+            ));
+        }
+    }
+    Ok(())
 }
 
-#[derive(Clone)]
+impl RankingConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_step_weights(&self.step_weights)?;
+        if self.window_secs == Some(0) {
+            return Err(anyhow!("window_secs must be greater than 0"));
+        }
+        if self.decay_half_life_secs == Some(0) {
+            return Err(anyhow!("decay_half_life_secs must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+// RankQuery scopes which stored attempts get_provider_rank_by considers
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct RankQuery {
+    // only rank attempts made within the last window seconds, overriding the configured window
+    pub window: Option<u64>,
+    // ISO 3166-1 alpha-2 code of the destination country
+    pub country: Option<String>,
+    // only rank attempts verified over this channel, unreachable attempts have no channel
+    pub channel: Option<Channel>,
+    // omit carriers with fewer matching attempts than min_attempts
+    pub min_attempts: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
 pub struct VerificationEntry {
-    pub carrier: String,
+    // shared with the carrier that made the attempt, see TelecomProvider::get_name
+    #[schema(value_type = String)]
+    pub carrier: Arc<str>,
     pub number: String,
     pub time: DateTime<Utc>,
     pub step: VerificationStep,
+    // simulated attempts were recorded by a --dry-run server without a message being sent, they
+    // are listed but never ranked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub simulated: bool,
+    // request id of the API call the attempt was made for, see trace::TraceContext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
 }
 
 /// represents outcome of last verification attempt for a given phone number, values 1-5 represent:
@@ -21,7 +172,9 @@ pub struct VerificationEntry {
 /// 3. verified on first text to speech call from telecom provider
 /// 4. verified on second text to speech call from telecom provider
 /// 5.  phone number was unreachable from telecom provider
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(
+    Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone,
+)]
 pub enum VerificationStep {
     FirstSMS,
     SecondSMS,
@@ -30,21 +183,171 @@ pub enum VerificationStep {
     Unreachable,
 }
 
-// in-memory implementation of VerificationEntry trait
+impl VerificationStep {
+    // channel returns the delivery channel the number was verified over
+    pub fn channel(&self) -> Option<Channel> {
+        match self {
+            VerificationStep::FirstSMS | VerificationStep::SecondSMS => Some(Channel::Sms),
+            VerificationStep::FirstTextToSpeech | VerificationStep::SecondTextToSpeech => {
+                Some(Channel::Voice)
+            }
+            VerificationStep::Unreachable => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Sms,
+    Voice,
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sms" => Ok(Self::Sms),
+            "voice" => Ok(Self::Voice),
+            _ => Err(anyhow!("invalid channel: {}", s)),
+        }
+    }
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Sms => "sms",
+            Channel::Voice => "voice",
+        }
+    }
+}
+
+// shards attempts are spread over in turn
+const SHARDS: usize = 16;
+
+// in-memory implementation of VerificationEntry trait. Attempts are spread over shards in turn so
+// concurrent stores, even for the same carrier, rarely wait for each other. Stores wait for ranks
+// being taken of their shard but ranks are taken concurrently, one shard at a time
 pub struct VerificationKeeper {
-    entries: Vec<VerificationEntry>,
+    shards: Vec<RwLock<Shard>>,
+    // shard the next attempt is stored in
+    next_shard: AtomicUsize,
+    // attempts stored across every shard, numbering them in the order they were stored
+    stored: AtomicU64,
+    // attempts kept across every shard, the oldest are evicted beyond max_attempts
+    kept: AtomicUsize,
+    max_attempts: usize,
+    evicted: AtomicU64,
+    decisions: RwLock<Decisions>,
+    max_decisions: usize,
+    ranking: RwLock<Ranking>,
+}
+
+// Decisions are the fraud decisions kept, in the order they were taken
+#[derive(Default)]
+struct Decisions {
+    entries: VecDeque<FraudDecision>,
+    // decisions evicted so far, the position of the oldest one kept
+    evicted: u64,
+}
+
+// Shard keeps the attempts stored in it along with the number each was stored as
+#[derive(Default)]
+struct Shard {
+    entries: VecDeque<(u64, VerificationEntry)>,
+    // attempts each carrier made in STEPS order, ranks that aren't scoped by time or country are
+    // taken from them without walking the entries, summed across shards
+    totals: HashMap<Arc<str>, [u64; 5]>,
+}
+
+struct Ranking {
+    config: RankingConfig,
     step_weights: HashMap<VerificationStep, u32>,
 }
 
+// a panic while holding a lock leaves what it guards as it was, pushes can't be torn
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
 impl VerificationKeeper {
     pub fn new(step_values: [u32; 5]) -> Result<Self, Error> {
-        let mut sorted_steps = step_values.clone();
-        sorted_steps.sort();
-        if step_values != sorted_steps {
-            return Err(anyhow!(
-                "step_values must be provided in ascending sequence"
-            ));
+        let config = RankingConfig {
+            step_weights: step_values,
+            window_secs: None,
+            decay_half_life_secs: None,
+        };
+        config.validate()?;
+
+        Ok(Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            next_shard: AtomicUsize::new(0),
+            stored: AtomicU64::new(0),
+            kept: AtomicUsize::new(0),
+            max_attempts: usize::MAX,
+            evicted: AtomicU64::new(0),
+            decisions: RwLock::default(),
+            max_decisions: usize::MAX,
+            ranking: RwLock::new(Ranking::new(config)),
+        })
+    }
+
+    // with_max_attempts keeps at most max attempts, evicting the oldest ones beyond it
+    pub fn with_max_attempts(mut self, max: usize) -> Self {
+        self.max_attempts = max;
+        self
+    }
+
+    // with_max_decisions keeps at most max fraud decisions, evicting the oldest ones beyond it
+    pub fn with_max_decisions(mut self, max: usize) -> Self {
+        self.max_decisions = max;
+        self
+    }
+
+    // evict_oldest evicts the oldest attempt kept across every shard, ranks stop counting it
+    fn evict_oldest(&self) -> bool {
+        loop {
+            let oldest = self
+                .shards
+                .iter()
+                .enumerate()
+                .filter_map(|(i, s)| read(s).entries.front().map(|(n, _)| (i, *n)))
+                .min_by_key(|(_, n)| *n);
+            let (i, number) = match oldest {
+                Some(oldest) => oldest,
+                None => return false,
+            };
+            let mut shard = write(&self.shards[i]);
+            // another store may have evicted it meanwhile
+            if shard.entries.front().map(|(n, _)| *n) != Some(number) {
+                continue;
+            }
+            if let Some((_, entry)) = shard.entries.pop_front() {
+                if !entry.simulated {
+                    if let Some(counts) = shard.totals.get_mut(&entry.carrier) {
+                        counts[entry.step as usize] -= 1;
+                    }
+                }
+            }
+            self.evicted.fetch_add(1, Ordering::SeqCst);
+            return true;
         }
+    }
+
+    // shard is where the next attempt is kept, a carrier's attempts are spread over every shard
+    fn shard(&self) -> &RwLock<Shard> {
+        &self.shards[self.next_shard.fetch_add(1, Ordering::Relaxed) % SHARDS]
+    }
+}
+
+impl Ranking {
+    fn new(config: RankingConfig) -> Self {
+        let step_values = config.step_weights;
         let mut step_weights = HashMap::new();
 
         // assign weighted value to the corresponding VerificationStep
@@ -53,104 +356,900 @@ impl VerificationKeeper {
         step_weights.insert(VerificationStep::FirstTextToSpeech, step_values[2]);
         step_weights.insert(VerificationStep::SecondTextToSpeech, step_values[3]);
         step_weights.insert(VerificationStep::Unreachable, step_values[4]);
+        Self {
+            config,
+            step_weights,
+        }
+    }
 
-        Ok(Self {
-            entries: Vec::new(),
-            step_weights: step_weights,
-        })
+    // scoped tells whether query depends on each attempt's time and number, windows, decay and
+    // countries do
+    fn scoped(&self, query: &RankQuery) -> bool {
+        query.window.or(self.config.window_secs).is_some()
+            || self.config.decay_half_life_secs.is_some()
+            || query.country.is_some()
+    }
+
+    // get_weighted_avg returns the weighted value of a particular carrier's verification attempts,
+    // older attempts count for less when a decay half life is configured. Attempts so old their
+    // influence underflows to nothing are averaged undecayed
+    fn get_weighted_avg(
+        &self,
+        attempts: &[(DateTime<Utc>, VerificationStep)],
+        now: DateTime<Utc>,
+    ) -> f32 {
+        let mut weighted_sum = 0.0;
+        let mut total_influence = 0.0;
+        for (time, step) in attempts {
+            let influence = match self.config.decay_half_life_secs {
+                Some(half_life) => {
+                    let age = (now - *time).num_milliseconds().max(0) as f64 / 1000.0;
+                    0.5_f64.powf(age / half_life as f64)
+                }
+                None => 1.0,
+            };
+            weighted_sum += influence * self.step_weights[step] as f64;
+            total_influence += influence;
+        }
+        if total_influence == 0.0 {
+            let sum = attempts
+                .iter()
+                .map(|(_, step)| self.step_weights[step] as f64)
+                .sum::<f64>();
+            return (sum / attempts.len() as f64) as f32;
+        }
+        (weighted_sum / total_influence) as f32
+    }
+
+    // scan_rank ranks the carriers of shards by walking every matching entry, holding one shard
+    // at a time
+    fn scan_rank<'a>(
+        &self,
+        shards: impl Iterator<Item = RwLockReadGuard<'a, Shard>>,
+        query: &RankQuery,
+        now: DateTime<Utc>,
+    ) -> Vec<(Arc<str>, f32)> {
+        let since = query
+            .window
+            .or(self.config.window_secs)
+            .map(|w| now - Duration::seconds(w as i64));
+
+        let mut by_carrier: HashMap<Arc<str>, Vec<(DateTime<Utc>, VerificationStep)>> =
+            HashMap::new();
+        for shard in shards {
+            for (_, entry) in shard.entries.iter() {
+                if entry.simulated || since.is_some_and(|s| entry.time < s) {
+                    continue;
+                }
+                if query.channel.is_some() && entry.step.channel() != query.channel {
+                    continue;
+                }
+                if let Some(c) = &query.country {
+                    if !country::country_of(&entry.number)
+                        .is_some_and(|e| e.eq_ignore_ascii_case(c))
+                    {
+                        continue;
+                    }
+                }
+                by_carrier
+                    .entry(entry.carrier.clone())
+                    .or_default()
+                    .push((entry.time, entry.step));
+            }
+        }
+
+        let min_attempts = query.min_attempts.unwrap_or(0);
+        by_carrier
+            .iter()
+            .filter(|(_, v)| v.len() >= min_attempts)
+            .map(|(k, v)| (k.clone(), self.get_weighted_avg(v, now)))
+            .collect()
     }
 
-    // get_weighted_avg returns the weighted value of a particular carrier's verification attempts
-    fn get_weighted_avg(&self, attempts: &Vec<VerificationStep>) -> f32 {
-        let total_attempts = &attempts.len();
-        let weighted_sum: u32 = attempts.into_iter().map(|s| self.step_weights[&s]).sum();
-        weighted_sum as f32 / *total_attempts as f32
+    // total_rank ranks the carriers of shards from their totals summed across them, only when
+    // every attempt counts the same
+    fn total_rank<'a>(
+        &self,
+        shards: impl Iterator<Item = RwLockReadGuard<'a, Shard>>,
+        query: &RankQuery,
+    ) -> Vec<(Arc<str>, f32)> {
+        let mut totals: HashMap<Arc<str>, [u64; 5]> = HashMap::new();
+        for shard in shards {
+            for (carrier, counts) in &shard.totals {
+                let summed = totals.entry(carrier.clone()).or_default();
+                summed.iter_mut().zip(counts).for_each(|(s, c)| *s += c);
+            }
+        }
+        let min_attempts = query.min_attempts.unwrap_or(0) as u64;
+        totals
+            .iter()
+            .filter_map(|(carrier, counts)| {
+                let (sum, attempts) = STEPS
+                    .iter()
+                    .zip(counts)
+                    .filter(|(step, _)| query.channel.is_none() || step.channel() == query.channel)
+                    .fold((0, 0), |(sum, n), (step, count)| {
+                        (sum + count * self.step_weights[step] as u64, n + count)
+                    });
+                match attempts {
+                    0 => None,
+                    n if n < min_attempts => None,
+                    n => Some((carrier.clone(), (sum as f64 / n as f64) as f32)),
+                }
+            })
+            .collect()
     }
 }
 
 impl VerificationRepo for VerificationKeeper {
     // store_attempt attempts to store a VerificationEntry in the keeper struct
     // Error would be returned in the a failed transaction for a production DB
-    fn store_attempt(&mut self, entry: VerificationEntry) -> Result<(), Error> {
-        self.entries.push(entry);
+    fn store_attempt(&self, entry: VerificationEntry) -> Result<(), Error> {
+        let mut shard = write(self.shard());
+        if !entry.simulated {
+            // steps are declared in STEPS order
+            shard.totals.entry(entry.carrier.clone()).or_default()[entry.step as usize] += 1;
+        }
+        // numbered while the shard is held, attempts listed across shards leave none out
+        let number = self.stored.fetch_add(1, Ordering::SeqCst);
+        shard.entries.push_back((number, entry));
+        drop(shard);
+        self.kept.fetch_add(1, Ordering::SeqCst);
+        // every attempt beyond max_attempts is claimed by one store, so concurrent ones don't
+        // evict it twice
+        let max = self.max_attempts;
+        let claim = |k: usize| (k > max).then(|| k - 1);
+        while self
+            .kept
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, claim)
+            .is_ok()
+        {
+            if !self.evict_oldest() {
+                self.kept.fetch_add(1, Ordering::SeqCst);
+                break;
+            }
+        }
         Ok(())
     }
 
     // return the telecom providers and their corresponding weighted average
-    fn get_provider_rank(&self) -> Vec<(String, f32)> {
-        let mut by_carrier: HashMap<String, Vec<VerificationStep>> = HashMap::new();
-        for entry in self.entries.iter() {
-            match by_carrier.get_mut(&entry.carrier) {
-                Some(v) => v.push(entry.step),
-                None => {
-                    by_carrier.insert(entry.carrier.clone(), vec![entry.step]);
+    fn get_provider_rank_by(&self, query: &RankQuery) -> Vec<(Arc<str>, f32)> {
+        let ranking = read(&self.ranking);
+        let scoped = ranking.scoped(query);
+        let now = Utc::now();
+        let shards = self.shards.iter().map(read);
+        let mut rank = match scoped {
+            true => ranking.scan_rank(shards, query, now),
+            false => ranking.total_rank(shards, query),
+        };
+
+        rank.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        // sort by weighted value
+        rank
+    }
+
+    fn list_attempts(&self, position: u64, limit: usize) -> Page<VerificationEntry> {
+        let shards = self.shards.iter().map(read).collect::<Vec<_>>();
+        let stored = self.stored.load(Ordering::SeqCst);
+        // every shard keeps its attempts in the order they were stored, they are merged from
+        // the one stored at position on, or the oldest one kept once that one was evicted
+        let mut next = shards
+            .iter()
+            .map(|s| s.entries.partition_point(|(n, _)| *n < position))
+            .collect::<Vec<_>>();
+        let (mut items, mut start) = (Vec::new(), position);
+        while items.len() < limit {
+            let oldest = (0..SHARDS)
+                .filter_map(|i| shards[i].entries.get(next[i]).map(|(n, _)| (*n, i)))
+                .min();
+            match oldest {
+                Some((n, i)) => {
+                    if items.is_empty() {
+                        start = n;
+                    }
+                    items.push(shards[i].entries[next[i]].1.clone());
+                    next[i] += 1;
                 }
+                None => break,
             }
         }
+        Page::new(items, start, limit, stored)
+    }
+
+    fn get_ranking_config(&self) -> RankingConfig {
+        read(&self.ranking).config.clone()
+    }
 
-        let mut rank = by_carrier
+    fn set_ranking_config(&self, config: RankingConfig) -> Result<(), Error> {
+        config.validate()?;
+        *write(&self.ranking) = Ranking::new(config);
+        Ok(())
+    }
+
+    fn store_decision(&self, decision: FraudDecision) -> Result<(), Error> {
+        let mut decisions = write(&self.decisions);
+        decisions.entries.push_back(decision);
+        while decisions.entries.len() > self.max_decisions {
+            decisions.entries.pop_front();
+            decisions.evicted += 1;
+        }
+        Ok(())
+    }
+
+    // pages start at the oldest decision kept once the one at position was evicted
+    fn list_decisions(&self, position: u64, limit: usize) -> Page<FraudDecision> {
+        let decisions = read(&self.decisions);
+        let start = position.max(decisions.evicted);
+        let items = decisions
+            .entries
             .iter()
-            .map(|(k, v)| (k.clone(), self.get_weighted_avg(v)))
-            .collect::<Vec<(String, f32)>>();
+            .skip((start - decisions.evicted) as usize)
+            .take(limit)
+            .cloned()
+            .collect();
+        let stored = decisions.evicted + decisions.entries.len() as u64;
+        Page::new(items, start, limit, stored)
+    }
 
-        rank.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    fn attempt_count(&self) -> usize {
+        self.shards.iter().map(|s| read(s).entries.len()).sum()
+    }
 
-        // sort by weighted value
-        rank
+    fn decision_count(&self) -> usize {
+        read(&self.decisions).entries.len()
+    }
+
+    fn evicted_attempts(&self) -> u64 {
+        self.evicted.load(Ordering::SeqCst)
+    }
+
+    fn evicted_decisions(&self) -> u64 {
+        read(&self.decisions).evicted
+    }
+}
+
+// Record is a line of a file repo or an export, an attempt or a decision tagged as such
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    Attempt(VerificationEntry),
+    Decision(FraudDecision),
+}
+
+// FileKeeper keeps attempts and decisions in memory like VerificationKeeper, appending each one
+// to a JSON lines file it is loaded from again on start
+pub struct FileKeeper {
+    keeper: VerificationKeeper,
+    // held until the record is kept in memory too, so the file lists records in the order
+    // they are listed
+    file: Mutex<Buffered>,
+    // records buffered before they are written together
+    flush_records: usize,
+}
+
+// Buffered is the repo file along with the bytes not written to it yet
+struct Buffered {
+    file: File,
+    lines: Vec<u8>,
+    records: usize,
+}
+
+impl Buffered {
+    // flush writes the buffer, dropping what was written from it as it goes so a line a failed
+    // write tore is completed by the next one
+    fn flush(&mut self) -> Result<(), Error> {
+        while !self.lines.is_empty() {
+            match self.file.write(&self.lines) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero).into()),
+                Ok(written) => {
+                    self.lines.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.records = 0;
+        Ok(())
+    }
+}
+
+impl FileKeeper {
+    // open loads the records of path into keeper, creating it when missing. A last line the
+    // process died while writing is cut off the file, one only missing its newline gets it
+    pub fn open(path: &str, keeper: VerificationKeeper) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| anyhow!("failed to open repo file {}: {}", path, e))?;
+        let mut reader = BufReader::new(&file);
+        let (mut line, mut read) = (Vec::new(), 0);
+        for i in 1.. {
+            line.clear();
+            match reader.read_until(b'\n', &mut line)? {
+                0 => break,
+                n if line.last() != Some(&b'\n') => {
+                    if let Ok(record) = serde_json::from_slice(&line) {
+                        keep(&keeper, record)?;
+                        (&file).write_all(b"\n")?;
+                        break;
+                    }
+                    warn!(path, line = i, bytes = n, "cutting off torn repo file line");
+                    file.set_len(read)?;
+                    break;
+                }
+                n => read += n as u64,
+            }
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let record =
+                serde_json::from_slice(&line).map_err(|e| anyhow!("{} line {}: {}", path, i, e))?;
+            keep(&keeper, record)?;
+        }
+        Ok(Self {
+            keeper,
+            file: Mutex::new(Buffered {
+                file,
+                lines: Vec::new(),
+                records: 0,
+            }),
+            flush_records: 1,
+        })
+    }
+
+    // with_flush_records buffers records until there are flush_records of them, records
+    // buffered are lost if the process dies before they are flushed
+    pub fn with_flush_records(mut self, flush_records: usize) -> Self {
+        self.flush_records = flush_records.max(1);
+        self
+    }
+
+    fn buffered(&self) -> std::sync::MutexGuard<'_, Buffered> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // append buffers record, writing the buffer once it is full, and keeps it in memory. A
+    // record none of which was written isn't kept, what is left of the ones buffered before it
+    // is written with the next write. A record the failed write tore stays buffered to be
+    // completed, and is kept
+    fn append(&self, record: Record) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let mut buffered = self.buffered();
+        buffered.lines.extend_from_slice(&line);
+        buffered.records += 1;
+        if buffered.records >= self.flush_records {
+            if let Err(e) = buffered.flush() {
+                let unwritten = buffered.lines.len();
+                if unwritten < line.len() {
+                    error!(error = %e, "failed to write repo file, retrying with the next record");
+                    return keep(&self.keeper, record);
+                }
+                buffered.lines.truncate(unwritten - line.len());
+                buffered.records -= 1;
+                return Err(e);
+            }
+        }
+        keep(&self.keeper, record)
+    }
+}
+
+// keep stores record in keeper, moving it there
+fn keep(keeper: &VerificationKeeper, record: Record) -> Result<(), Error> {
+    match record {
+        Record::Attempt(entry) => keeper.store_attempt(entry),
+        Record::Decision(decision) => keeper.store_decision(decision),
+    }
+}
+
+impl Drop for FileKeeper {
+    fn drop(&mut self) {
+        if let Err(e) = self.buffered().flush() {
+            error!(error = %e, "failed to flush repo file");
+        }
+    }
+}
+
+impl VerificationRepo for FileKeeper {
+    // attempts are only kept once they are written
+    fn store_attempt(&self, entry: VerificationEntry) -> Result<(), Error> {
+        self.append(Record::Attempt(entry))
+    }
+
+    fn get_provider_rank_by(&self, query: &RankQuery) -> Vec<(Arc<str>, f32)> {
+        self.keeper.get_provider_rank_by(query)
+    }
+
+    fn list_attempts(&self, position: u64, limit: usize) -> Page<VerificationEntry> {
+        self.keeper.list_attempts(position, limit)
+    }
+
+    fn get_ranking_config(&self) -> RankingConfig {
+        self.keeper.get_ranking_config()
+    }
+
+    fn set_ranking_config(&self, config: RankingConfig) -> Result<(), Error> {
+        self.keeper.set_ranking_config(config)
+    }
+
+    fn store_decision(&self, decision: FraudDecision) -> Result<(), Error> {
+        self.append(Record::Decision(decision))
+    }
+
+    fn list_decisions(&self, position: u64, limit: usize) -> Page<FraudDecision> {
+        self.keeper.list_decisions(position, limit)
+    }
+
+    fn attempt_count(&self) -> usize {
+        self.keeper.attempt_count()
+    }
+
+    fn decision_count(&self) -> usize {
+        self.keeper.decision_count()
+    }
+
+    fn evicted_attempts(&self) -> u64 {
+        self.keeper.evicted_attempts()
+    }
+
+    fn evicted_decisions(&self) -> u64 {
+        self.keeper.evicted_decisions()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.buffered().flush()
     }
 }
+
+// open_repo opens the repo config describes, ranked with step_values and keeping as many
+// attempts in memory as memory allows
+pub fn open_repo(
+    config: &RepoConfig,
+    memory: &MemoryConfig,
+    step_values: [u32; 5],
+) -> Result<Box<dyn VerificationRepo>, Error> {
+    validate_step_weights(&step_values).map_err(|e| anyhow!("ranking: {}", e))?;
+    config.validate()?;
+    memory.validate()?;
+    let keeper = VerificationKeeper::new(step_values)?
+        .with_max_attempts(memory.max_attempts)
+        .with_max_decisions(memory.max_decisions);
+    Ok(match (config.backend, &config.path) {
+        (RepoBackend::Memory, _) => Box::new(keeper),
+        (RepoBackend::File, Some(path)) => {
+            Box::new(FileKeeper::open(path, keeper)?.with_flush_records(config.flush_records))
+        }
+        (RepoBackend::File, None) => return Err(anyhow!("the file repo backend requires a path")),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_new_keeper() {
-        let mut keeper =
-            VerificationKeeper::new([1, 2, 3, 4, 5]).expect("failed to create new keeper");
+        let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).expect("failed to create new keeper");
         keeper
             .store_attempt(VerificationEntry {
-                carrier: "carrier_1".to_owned(),
+                carrier: "carrier_1".into(),
                 number: "0177".to_owned(),
                 time: chrono::offset::Utc::now(),
                 step: VerificationStep::FirstSMS,
+                simulated: false,
+                correlation_id: None,
             })
             .unwrap();
 
         assert_eq!(
             keeper.get_provider_rank(),
-            vec![("carrier_1".to_owned(), 1.0)]
+            vec![(Arc::from("carrier_1"), 1.0)]
         );
 
         keeper
             .store_attempt(VerificationEntry {
-                carrier: "carrier_1".to_owned(),
+                carrier: "carrier_1".into(),
                 number: "0178".to_owned(),
                 time: chrono::offset::Utc::now(),
                 step: VerificationStep::Unreachable,
+                simulated: false,
+                correlation_id: None,
             })
             .unwrap();
 
         keeper
             .store_attempt(VerificationEntry {
-                carrier: "carrier_2".to_owned(),
+                carrier: "carrier_2".into(),
                 number: "0179".to_owned(),
                 time: chrono::offset::Utc::now(),
                 step: VerificationStep::FirstSMS,
+                simulated: false,
+                correlation_id: None,
             })
             .unwrap();
 
         keeper
             .store_attempt(VerificationEntry {
-                carrier: "carrier_2".to_owned(),
+                carrier: "carrier_2".into(),
                 number: "0180".to_owned(),
                 time: chrono::offset::Utc::now(),
                 step: VerificationStep::SecondSMS,
+                simulated: false,
+                correlation_id: None,
             })
             .unwrap();
 
         assert_eq!(
             keeper.get_provider_rank(),
-            vec![("carrier_2".to_owned(), 1.5), ("carrier_1".to_owned(), 3.0)]
+            vec![(Arc::from("carrier_2"), 1.5), (Arc::from("carrier_1"), 3.0)]
+        );
+
+        // simulated attempts are listed but leave the rank unchanged
+        keeper
+            .store_attempt(VerificationEntry {
+                carrier: "carrier_3".into(),
+                number: "0181".to_owned(),
+                time: chrono::offset::Utc::now(),
+                step: VerificationStep::FirstSMS,
+                simulated: true,
+                correlation_id: None,
+            })
+            .unwrap();
+        assert_eq!(keeper.list_attempts(0, 10).items.len(), 5);
+        assert_eq!(
+            keeper.get_provider_rank(),
+            vec![(Arc::from("carrier_2"), 1.5), (Arc::from("carrier_1"), 3.0)]
         );
     }
+
+    #[test]
+    fn test_sharded_keeper() {
+        let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+        let stored = |carrier: &str, i: usize| VerificationEntry {
+            carrier: carrier.into(),
+            number: format!("+1415555{:04}", i),
+            time: Utc::now(),
+            step: VerificationStep::FirstSMS,
+            simulated: false,
+            correlation_id: None,
+        };
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let (keeper, stored) = (&keeper, &stored);
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let carrier = format!("carrier_{}", t);
+                        keeper.store_attempt(stored(&carrier, t * 50 + i)).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(keeper.attempt_count(), 400);
+        assert_eq!(keeper.get_provider_rank().len(), 8);
+
+        // attempts spread over shards are listed once each, in the order they were stored
+        let (mut listed, mut position) = (Vec::new(), 0);
+        loop {
+            let page = keeper.list_attempts(position, 64);
+            listed.extend(page.items.into_iter().map(|e| e.number));
+            match page.next_cursor {
+                Some(_) => position += 64,
+                None => break,
+            }
+        }
+        assert_eq!(listed.len(), 400);
+        for t in 0..8 {
+            let numbers = listed
+                .iter()
+                .filter(|n| (n[8..].parse::<usize>().unwrap() / 50) == t)
+                .collect::<Vec<_>>();
+            assert!(numbers.windows(2).all(|w| w[0] < w[1]), "{:?}", numbers);
+        }
+        keeper.store_attempt(stored("carrier_9", 400)).unwrap();
+        assert_eq!(keeper.list_attempts(0, 1).items[0].number, listed[0]);
+        assert_eq!(keeper.list_attempts(400, 3).items[0].number, "+14155550400");
+
+        // one carrier's concurrent stores spread over every shard and rank as a whole
+        let busy = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let busy = &busy;
+                s.spawn(move || {
+                    for i in 0..20 {
+                        busy.store_attempt(stored("carrier_1", t * 20 + i)).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(busy.shards.iter().all(|s| read(s).entries.len() == 10));
+        for window in &[None, Some(3600)] {
+            let query = RankQuery {
+                window: *window,
+                min_attempts: Some(160),
+                ..RankQuery::default()
+            };
+            assert_eq!(
+                busy.get_provider_rank_by(&query),
+                vec![(Arc::from("carrier_1"), 1.0)],
+                "{:?}",
+                window
+            );
+        }
+
+        // the oldest attempts are evicted beyond max_attempts, pages start at the oldest kept
+        let bounded = VerificationKeeper::new([1, 2, 3, 4, 5])
+            .unwrap()
+            .with_max_attempts(10);
+        for i in 0..30 {
+            let carrier = format!("carrier_{}", i % 3);
+            bounded.store_attempt(stored(&carrier, i)).unwrap();
+        }
+        assert_eq!(bounded.attempt_count(), 10);
+        assert_eq!(bounded.evicted_attempts(), 20);
+        let page = bounded.list_attempts(0, 5);
+        assert_eq!(page.items[0].number, "+14155550020");
+        let position = crate::pagination::decode_cursor(&page.next_cursor.unwrap()).unwrap();
+        let rest = bounded.list_attempts(position, 5);
+        assert_eq!(rest.items[0].number, "+14155550025");
+        assert_eq!(rest.next_cursor, None);
+        let counted = bounded
+            .shards
+            .iter()
+            .map(|s| read(s).totals.values().flatten().sum::<u64>())
+            .sum::<u64>();
+        assert_eq!(counted, 10);
+
+        // so are the oldest decisions beyond max_decisions
+        let bounded = bounded.with_max_decisions(3);
+        let mut tracker = crate::fraud::VelocityTracker::new();
+        let config = crate::fraud::FraudConfig::default();
+        for i in 0..5 {
+            let number = format!("+1415555010{}", i);
+            let decision = tracker.score(&config, &number, None, Utc::now());
+            bounded.store_decision(decision).unwrap();
+        }
+        assert_eq!(bounded.decision_count(), 3);
+        assert_eq!(bounded.evicted_decisions(), 2);
+        let page = bounded.list_decisions(0, 2);
+        assert_eq!(page.items[0].number, "+14155550102");
+        let position = crate::pagination::decode_cursor(&page.next_cursor.unwrap()).unwrap();
+        let rest = bounded.list_decisions(position, 2);
+        assert_eq!(rest.items[0].number, "+14155550104");
+        assert_eq!(rest.next_cursor, None);
+    }
+
+    #[test]
+    fn test_parse_step_weights() {
+        assert_eq!(parse_step_weights("1, 2,3,4,10"), Ok([1, 2, 3, 4, 10]));
+        assert_eq!(parse_step_weights("1,1,2,2,3"), Ok([1, 1, 2, 2, 3]));
+        assert!(parse_step_weights("1,2,3,4").unwrap_err().contains("got 4"));
+        assert!(parse_step_weights("1,2,x,4,5")
+            .unwrap_err()
+            .contains("invalid step weight: x"));
+        assert!(parse_step_weights("1,3,2,4,5")
+            .unwrap_err()
+            .contains("FirstTextToSpeech is weighted 2"));
+    }
+
+    #[test]
+    fn test_ranking_config() {
+        let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).expect("failed to create new keeper");
+        let now = chrono::offset::Utc::now();
+        for (number, age, step) in &[
+            ("0177", 7200, VerificationStep::Unreachable),
+            ("0178", 0, VerificationStep::FirstSMS),
+        ] {
+            keeper
+                .store_attempt(VerificationEntry {
+                    carrier: "carrier_1".into(),
+                    number: number.to_string(),
+                    time: now - Duration::seconds(*age),
+                    step: *step,
+                    simulated: false,
+                    correlation_id: None,
+                })
+                .unwrap();
+        }
+
+        assert!(keeper
+            .set_ranking_config(RankingConfig {
+                step_weights: [5, 4, 3, 2, 1],
+                window_secs: None,
+                decay_half_life_secs: None,
+            })
+            .is_err());
+
+        keeper
+            .set_ranking_config(RankingConfig {
+                step_weights: [1, 2, 3, 4, 10],
+                window_secs: Some(3600),
+                decay_half_life_secs: None,
+            })
+            .unwrap();
+        assert_eq!(
+            keeper.get_provider_rank(),
+            vec![(Arc::from("carrier_1"), 1.0)]
+        );
+
+        // the two hour old attempt carries a quarter of the influence of the fresh one
+        keeper
+            .set_ranking_config(RankingConfig {
+                step_weights: [1, 2, 3, 4, 11],
+                window_secs: None,
+                decay_half_life_secs: Some(3600),
+            })
+            .unwrap();
+        let rank = keeper.get_provider_rank();
+        assert!((rank[0].1 - 3.0).abs() < 0.01, "{:?}", rank);
+
+        // a carrier idle for thousands of half lives still ranks
+        for step in &[VerificationStep::FirstSMS, VerificationStep::SecondSMS] {
+            keeper
+                .store_attempt(VerificationEntry {
+                    carrier: "carrier_2".into(),
+                    number: "0179".to_string(),
+                    time: now - Duration::hours(18),
+                    step: *step,
+                    simulated: false,
+                    correlation_id: None,
+                })
+                .unwrap();
+        }
+        keeper
+            .set_ranking_config(RankingConfig {
+                step_weights: [1, 2, 3, 4, 11],
+                window_secs: None,
+                decay_half_life_secs: Some(1),
+            })
+            .unwrap();
+        let rank = keeper.get_provider_rank();
+        assert_eq!(rank.len(), 2, "{:?}", rank);
+        assert!(rank.iter().all(|(_, r)| r.is_finite()), "{:?}", rank);
+        assert_eq!(rank[1], (Arc::from("carrier_2"), 1.5));
+    }
+
+    #[test]
+    fn test_rank_query() {
+        let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).expect("failed to create new keeper");
+        for (carrier, number, step) in &[
+            ("carrier_1", "+491711234567", VerificationStep::FirstSMS),
+            (
+                "carrier_1",
+                "+491711234568",
+                VerificationStep::FirstTextToSpeech,
+            ),
+            ("carrier_1", "+14155550100", VerificationStep::Unreachable),
+            (
+                "carrier_2",
+                "+491711234569",
+                VerificationStep::SecondTextToSpeech,
+            ),
+        ] {
+            keeper
+                .store_attempt(VerificationEntry {
+                    carrier: (*carrier).into(),
+                    number: number.to_string(),
+                    time: chrono::offset::Utc::now(),
+                    step: *step,
+                    simulated: false,
+                    correlation_id: None,
+                })
+                .unwrap();
+        }
+
+        let query = RankQuery {
+            country: Some("de".to_owned()),
+            ..RankQuery::default()
+        };
+        assert_eq!(
+            keeper.get_provider_rank_by(&query),
+            vec![(Arc::from("carrier_1"), 2.0), (Arc::from("carrier_2"), 4.0)]
+        );
+
+        let query = RankQuery {
+            channel: Some(Channel::Voice),
+            ..RankQuery::default()
+        };
+        assert_eq!(
+            keeper.get_provider_rank_by(&query),
+            vec![(Arc::from("carrier_1"), 3.0), (Arc::from("carrier_2"), 4.0)]
+        );
+
+        let query = RankQuery {
+            min_attempts: Some(2),
+            ..RankQuery::default()
+        };
+        assert_eq!(
+            keeper.get_provider_rank_by(&query),
+            vec![(Arc::from("carrier_1"), 3.0)]
+        );
+
+        // the totals rank like walking the entries does
+        let ranking = read(&keeper.ranking);
+        for channel in &[None, Some(Channel::Sms), Some(Channel::Voice)] {
+            let query = RankQuery {
+                channel: *channel,
+                min_attempts: Some(1),
+                ..RankQuery::default()
+            };
+            let shards = || keeper.shards.iter().map(read);
+            let mut totals = ranking.total_rank(shards(), &query);
+            let mut scanned = ranking.scan_rank(shards(), &query, Utc::now());
+            totals.sort_by(|a, b| a.0.cmp(&b.0));
+            scanned.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(totals, scanned, "{:?}", channel);
+        }
+    }
+
+    #[test]
+    fn test_buffered_file() {
+        let path = std::env::temp_dir().join(format!("telecom-repo-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_string_lossy().to_string();
+        let lines = || std::fs::read_to_string(&path).unwrap().lines().count();
+        let keeper = FileKeeper::open(&path, VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap())
+            .unwrap()
+            .with_flush_records(2);
+        let store = |i| {
+            keeper.store_attempt(VerificationEntry {
+                carrier: "carrier_1".into(),
+                number: format!("+1415555010{}", i),
+                time: Utc::now(),
+                step: VerificationStep::FirstSMS,
+                simulated: false,
+                correlation_id: None,
+            })
+        };
+        for i in 0..3 {
+            store(i).unwrap();
+        }
+        // kept at once, written two at a time
+        assert_eq!(keeper.attempt_count(), 3);
+        assert_eq!(lines(), 2);
+        keeper.flush().unwrap();
+        assert_eq!(lines(), 3);
+
+        // the rest is written once the repo is dropped
+        store(3).unwrap();
+        assert_eq!(lines(), 3);
+        drop(keeper);
+        let reopened =
+            FileKeeper::open(&path, VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap()).unwrap();
+        assert_eq!(reopened.attempt_count(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_file() {
+        let path = std::env::temp_dir().join(format!("telecom-torn-{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let open = || FileKeeper::open(&path, VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap());
+        let entry = |i| VerificationEntry {
+            carrier: "carrier_1".into(),
+            number: format!("+1415555010{}", i),
+            time: Utc::now(),
+            step: VerificationStep::FirstSMS,
+            simulated: false,
+            correlation_id: None,
+        };
+        let line = serde_json::to_string(&Record::Attempt(entry(0))).unwrap();
+        // the process died while writing the second line
+        std::fs::write(&path, format!("{}\n{}", line, &line[..line.len() / 2])).unwrap();
+        let keeper = open().unwrap();
+        assert_eq!(keeper.attempt_count(), 1);
+        keeper.store_attempt(entry(1)).unwrap();
+        drop(keeper);
+        assert_eq!(open().unwrap().attempt_count(), 2);
+
+        // or right before the newline
+        std::fs::write(&path, &line).unwrap();
+        let keeper = open().unwrap();
+        keeper.store_attempt(entry(1)).unwrap();
+        drop(keeper);
+        assert_eq!(open().unwrap().attempt_count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,389 @@
+use crate::metrics::{Metrics, DROPPED_REPORTS};
+use crate::middleware;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+// ReportingConfig is where errors nobody expected and panics are reported to, on top of being
+// logged
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportingConfig {
+    // Sentry DSN, e.g. https://<key>@o0.ingest.sentry.io/<project>, nothing is reported when
+    // omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dsn: Option<String>,
+    // e.g. production, reports are grouped by it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    // context keys whose values are never reported, on top of numbers and codes being redacted
+    // wherever they appear
+    pub scrub_fields: Vec<String>,
+    pub timeout_secs: u64,
+    // reports waiting to be sent, further ones are dropped
+    pub max_queued: usize,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        Self {
+            dsn: None,
+            environment: None,
+            scrub_fields: vec![
+                "number".to_string(),
+                "code".to_string(),
+                "token".to_string(),
+                "callback_url".to_string(),
+            ],
+            timeout_secs: 5,
+            max_queued: 1024,
+        }
+    }
+}
+
+impl ReportingConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(dsn) = &self.dsn {
+            Dsn::parse(dsn)?;
+        }
+        if self.timeout_secs == 0 {
+            return Err(anyhow!("reporting timeout_secs must be greater than 0"));
+        }
+        if self.max_queued == 0 {
+            return Err(anyhow!("reporting max_queued must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+// Dsn is where a Sentry project takes events and the key it takes them with
+#[derive(Debug, PartialEq, Clone)]
+struct Dsn {
+    key: String,
+    store_url: Url,
+}
+
+impl Dsn {
+    fn parse(dsn: &str) -> Result<Self, Error> {
+        let invalid = || anyhow!("invalid reporting dsn, e.g. https://key@sentry.example.com/42");
+        let url = Url::parse(dsn).map_err(|_| invalid())?;
+        if !matches!(url.scheme(), "http" | "https") || url.username().is_empty() {
+            return Err(invalid());
+        }
+        let path = url.path().trim_end_matches('/');
+        let (prefix, project) = path.rsplit_once('/').ok_or_else(invalid)?;
+        if project.is_empty() || !project.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let mut store_url = url.clone();
+        store_url.set_username("").map_err(|_| invalid())?;
+        store_url.set_password(None).map_err(|_| invalid())?;
+        store_url.set_path(&format!("{}/api/{}/store/", prefix, project));
+        Ok(Self {
+            key: url.username().to_string(),
+            store_url,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Panic,
+    // a request failed with a server error, e.g. the repo failing to store its attempt
+    Request,
+    // a background retry or escalation failed other than by the number being unreachable
+    Retry,
+    // a carrier sent a callback that couldn't be understood
+    Provider,
+}
+
+// ErrorReport is an error along with the context of the request it happened in, reports taken
+// within a scope carry the correlation id it was entered with
+#[derive(Debug, PartialEq, Clone)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub correlation_id: Option<String>,
+    // e.g. the carrier or attempt_id
+    pub context: BTreeMap<String, String>,
+    pub time: DateTime<Utc>,
+}
+
+impl ErrorReport {
+    pub fn new(kind: ErrorKind, message: &str) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            // panics may be raised while the thread is being torn down
+            correlation_id: CORRELATION_ID
+                .try_with(|c| c.borrow().clone())
+                .ok()
+                .flatten(),
+            context: BTreeMap::new(),
+            time: Utc::now(),
+        }
+    }
+
+    pub fn with_correlation_id(mut self, id: Option<String>) -> Self {
+        self.correlation_id = id;
+        self
+    }
+
+    pub fn with_context(mut self, key: &str, value: &str) -> Self {
+        self.context.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    // scrub redacts numbers and codes from the message and context and drops the values of
+    // fields, reports leave the process
+    pub fn scrub(mut self, fields: &[String]) -> Self {
+        self.message = middleware::redact(&self.message);
+        for (key, value) in self.context.iter_mut() {
+            *value = match fields.contains(key) {
+                true => "[scrubbed]".to_string(),
+                false => middleware::redact(value),
+            };
+        }
+        self
+    }
+}
+
+// ErrorReporter is told about errors nobody expected, reporting must not block the caller
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, report: ErrorReport);
+}
+
+// SentryReporter sends reports to a Sentry project in the background, reports it can't send
+// or that find max_queued reports waiting are dropped and counted in metrics
+pub struct SentryReporter {
+    sender: mpsc::Sender<Value>,
+    config: ReportingConfig,
+    metrics: Metrics,
+}
+
+impl SentryReporter {
+    // spawn starts the sending worker on the current tokio runtime, None without a DSN
+    pub fn spawn(config: &ReportingConfig, metrics: &Metrics) -> Result<Option<Self>, Error> {
+        let dsn = match &config.dsn {
+            Some(dsn) => Dsn::parse(dsn)?,
+            None => return Ok(None),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()?;
+        let (sender, mut receiver) = mpsc::channel::<Value>(config.max_queued);
+        let dropped = metrics.clone();
+        tokio::spawn(async move {
+            let auth = format!(
+                "Sentry sentry_version=7, sentry_key={}, sentry_client=telecom/{}",
+                dsn.key,
+                env!("CARGO_PKG_VERSION")
+            );
+            while let Some(event) = receiver.recv().await {
+                let sent = client
+                    .post(dsn.store_url.clone())
+                    .header("X-Sentry-Auth", &auth)
+                    .json(&event)
+                    .send()
+                    .await;
+                match sent {
+                    Ok(r) if r.status().is_success() => continue,
+                    Ok(r) => warn!(status = r.status().as_u16(), "error report rejected"),
+                    Err(e) => warn!(error = %e, "error report not sent"),
+                }
+                dropped.inc(&DROPPED_REPORTS, &[("reason", "not_sent")]);
+            }
+        });
+        Ok(Some(Self {
+            sender,
+            config: config.clone(),
+            metrics: metrics.clone(),
+        }))
+    }
+}
+
+// event renders a scrubbed report as a Sentry event
+fn event(config: &ReportingConfig, report: &ErrorReport) -> Value {
+    let kind = json!(report.kind);
+    let mut tags = BTreeMap::new();
+    tags.insert("kind", kind.clone());
+    if let Some(id) = &report.correlation_id {
+        tags.insert("correlation_id", json!(id));
+    }
+    json!({
+        "event_id": format!("{:032x}", rand::thread_rng().gen::<u128>()),
+        "timestamp": report.time.to_rfc3339(),
+        "platform": "other",
+        "logger": "telecom",
+        "level": match report.kind {
+            ErrorKind::Panic => "fatal",
+            _ => "error",
+        },
+        "release": format!("telecom@{}", env!("CARGO_PKG_VERSION")),
+        "environment": config.environment,
+        "message": { "formatted": report.message },
+        "exception": { "values": [{ "type": kind, "value": report.message }] },
+        "tags": tags,
+        "extra": report.context,
+    })
+}
+
+impl ErrorReporter for SentryReporter {
+    fn report(&self, report: ErrorReport) {
+        let report = report.scrub(&self.config.scrub_fields);
+        let reason = match self.sender.try_send(event(&self.config, &report)) {
+            Ok(()) => return,
+            // reporting a burst of errors must not hold up the requests failing
+            Err(mpsc::error::TrySendError::Full(_)) => "queue_full",
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                error!("error reporter stopped, dropping report");
+                "stopped"
+            }
+        };
+        self.metrics.inc(&DROPPED_REPORTS, &[("reason", reason)]);
+    }
+}
+
+thread_local! {
+    // correlation id of the request handled on this thread, panics are reported with it
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Scope is the request a thread is handling until it is dropped, errors reported and panics
+// raised meanwhile are reported with its correlation id
+pub struct Scope {
+    previous: Option<String>,
+}
+
+// enter marks the current thread as handling the request of correlation_id, requests are
+// handled synchronously on their own thread so the thread doesn't change underneath
+pub fn enter(correlation_id: Option<String>) -> Scope {
+    Scope {
+        previous: CORRELATION_ID.with(|c| c.replace(correlation_id)),
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CORRELATION_ID.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+// install_panic_hook reports every panic to reporter, along with the request the thread was
+// handling, before the previous hook prints it
+pub fn install_panic_hook(reporter: Arc<dyn ErrorReporter>) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("panicked");
+        let mut report = ErrorReport::new(ErrorKind::Panic, message);
+        if let Some(location) = info.location() {
+            report = report.with_context("location", &location.to_string());
+        }
+        if let Some(thread) = std::thread::current().name() {
+            report = report.with_context("thread", thread);
+        }
+        reporter.report(report);
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dsn() {
+        let dsn = Dsn::parse("https://abc123@o1.ingest.sentry.io/42").unwrap();
+        assert_eq!(dsn.key, "abc123");
+        assert_eq!(
+            dsn.store_url.as_str(),
+            "https://o1.ingest.sentry.io/api/42/store/"
+        );
+        let prefixed = Dsn::parse("http://key@localhost:9000/sentry/7/").unwrap();
+        assert_eq!(
+            prefixed.store_url.as_str(),
+            "http://localhost:9000/sentry/api/7/store/"
+        );
+        for invalid in &[
+            "https://sentry.example.com/42",
+            "https://key@sentry.example.com/",
+            "https://key@sentry.example.com/project",
+            "ftp://key@sentry.example.com/42",
+        ] {
+            assert!(Dsn::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_reports() {
+        let config = ReportingConfig {
+            dsn: Some("https://key@sentry.example.com/42".to_string()),
+            max_queued: 1,
+            ..ReportingConfig::default()
+        };
+        let metrics = Metrics::new();
+        let reporter = SentryReporter::spawn(&config, &metrics).unwrap().unwrap();
+        // the worker doesn't run before the test yields
+        for _ in 0..3 {
+            reporter.report(ErrorReport::new(ErrorKind::Request, "failed"));
+        }
+        assert_eq!(
+            metrics.counter(&DROPPED_REPORTS, &[("reason", "queue_full")]),
+            2
+        );
+    }
+
+    #[test]
+    fn test_event() {
+        let config = ReportingConfig {
+            dsn: Some("https://key@sentry.example.com/42".to_string()),
+            environment: Some("test".to_string()),
+            ..ReportingConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        let report = ErrorReport::new(ErrorKind::Request, "could not store +14155550100")
+            .with_correlation_id(Some("3f0c9a1e".to_string()))
+            .with_context("carrier", "carrier_1")
+            .with_context("number", "+14155550100")
+            .scrub(&config.scrub_fields);
+        assert_eq!(report.message, "could not store +***");
+        assert_eq!(report.context["number"], "[scrubbed]");
+
+        let event = event(&config, &report);
+        assert_eq!(event["level"], "error");
+        assert_eq!(event["environment"], "test");
+        assert_eq!(event["exception"]["values"][0]["type"], "request");
+        assert_eq!(event["tags"]["correlation_id"], "3f0c9a1e");
+        assert_eq!(event["extra"]["carrier"], "carrier_1");
+        assert_eq!(event["event_id"].as_str().unwrap().len(), 32);
+
+        // reports taken while handling a request carry its correlation id
+        {
+            let _scope = enter(Some("request".to_string()));
+            let report = ErrorReport::new(ErrorKind::Panic, "panicked");
+            assert_eq!(report.correlation_id.as_deref(), Some("request"));
+        }
+        assert_eq!(ErrorReport::new(ErrorKind::Panic, "").correlation_id, None);
+        assert!(
+            SentryReporter::spawn(&ReportingConfig::default(), &Metrics::new())
+                .unwrap()
+                .is_none()
+        );
+    }
+}
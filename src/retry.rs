@@ -0,0 +1,192 @@
+use crate::escalation::ChannelPreference;
+use crate::http::SharedServer;
+use crate::otp::CodeFormat;
+use crate::trace::TraceContext;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+use tracing::error;
+
+// bounds on a backoff schedule, a session is kept for the whole schedule
+pub const MAX_RETRIES: usize = 10;
+pub const MAX_BACKOFF_SECS: i64 = 3600;
+
+// how often the scheduler looks for retries that are due
+const TICK: std::time::Duration = std::time::Duration::from_secs(1);
+
+// RetryConfig is the backoff waited before each retry of an attempt no carrier step delivered,
+// attempts fail right away when it is empty
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RetryConfig {
+    pub backoff: Vec<Duration>,
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.backoff.len() > MAX_RETRIES {
+            return Err(anyhow!("at most {} retries can be scheduled", MAX_RETRIES));
+        }
+        if self
+            .backoff
+            .iter()
+            .any(|d| *d <= Duration::zero() || *d > Duration::seconds(MAX_BACKOFF_SECS))
+        {
+            return Err(anyhow!(
+                "retry backoff must be between 1 and {} seconds",
+                MAX_BACKOFF_SECS
+            ));
+        }
+        Ok(())
+    }
+
+    // delay is the backoff before retry number retries, None once the schedule is exhausted
+    pub fn delay(&self, retries: usize) -> Option<Duration> {
+        self.backoff.get(retries).copied()
+    }
+}
+
+// parses comma separated seconds, e.g. "5,30,120"
+impl FromStr for RetryConfig {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let backoff = s
+            .split(',')
+            .map(|secs| {
+                secs.trim()
+                    .parse::<i64>()
+                    .map(Duration::seconds)
+                    .map_err(|_| anyhow!("invalid retry backoff: {}", secs))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let config = Self { backoff };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// PendingRetry is everything needed to send an attempt again once it is due
+#[derive(Debug, Clone)]
+pub struct PendingRetry {
+    pub attempt_id: String,
+    pub number: String,
+    pub format: CodeFormat,
+    pub channel: ChannelPreference,
+    pub locale: Option<String>,
+    // retries already made
+    pub retries: usize,
+    // escalation ladder step the send resumes at, 0 for retries
+    pub step: usize,
+    pub due: DateTime<Utc>,
+    pub trace: TraceContext,
+}
+
+// RetryQueue holds the attempts waiting for their next retry
+#[derive(Debug, Default)]
+pub struct RetryQueue {
+    pending: Vec<PendingRetry>,
+}
+
+impl RetryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, retry: PendingRetry) {
+        self.pending.push(retry);
+    }
+
+    // take_due removes and returns the retries due at now, earliest first
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<PendingRetry> {
+        let (mut due, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|r| r.due <= now);
+        self.pending = pending;
+        due.sort_by_key(|r| r.due);
+        due
+    }
+
+    // remove drops the retries of an attempt that no longer needs them
+    pub fn remove(&mut self, attempt_id: &str) {
+        self.pending.retain(|r| r.attempt_id != attempt_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// spawn runs the retry scheduler on the tokio runtime until the process exits
+pub fn spawn(server: SharedServer) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            let due = server.clone();
+            // carriers are called synchronously, keep them off the async workers
+            let retried = server
+                .carrier_calls()
+                .run(move || {
+                    due.run_due_retries();
+                    due.run_due_escalations();
+                })
+                .await;
+            if let Err(e) = retried {
+                error!(error = %e, "retry scheduler failed");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry(attempt_id: &str, due: DateTime<Utc>) -> PendingRetry {
+        PendingRetry {
+            attempt_id: attempt_id.to_string(),
+            number: "+15555550100".to_string(),
+            format: CodeFormat::default(),
+            channel: ChannelPreference::Auto,
+            locale: None,
+            retries: 0,
+            step: 0,
+            due,
+            trace: TraceContext::new_root(),
+        }
+    }
+
+    #[test]
+    fn test_parse_backoff() {
+        let config: RetryConfig = "5, 30,120".parse().unwrap();
+        assert_eq!(config.delay(0), Some(Duration::seconds(5)));
+        assert_eq!(config.delay(2), Some(Duration::seconds(120)));
+        assert_eq!(config.delay(3), None);
+
+        assert!("".parse::<RetryConfig>().is_err());
+        assert!("0".parse::<RetryConfig>().is_err());
+        assert!("5,soon".parse::<RetryConfig>().is_err());
+        assert!("3601".parse::<RetryConfig>().is_err());
+    }
+
+    #[test]
+    fn test_take_due() {
+        let now = Utc::now();
+        let mut queue = RetryQueue::new();
+        queue.push(retry("later", now + Duration::seconds(30)));
+        queue.push(retry("second", now - Duration::seconds(1)));
+        queue.push(retry("first", now - Duration::seconds(5)));
+
+        let due = queue
+            .take_due(now)
+            .into_iter()
+            .map(|r| r.attempt_id)
+            .collect::<Vec<_>>();
+        assert_eq!(due, vec!["first", "second"]);
+        assert_eq!(queue.len(), 1);
+        assert!(queue.take_due(now).is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+// SharedRng is the randomness of mock carriers, codes and attempt ids. It is the thread RNG unless
+// seeded by --seed, which makes runs reproducible and codes predictable, so it's for demos and
+// tests only. Keys, peppers and TOTP secrets never come from it
+#[derive(Debug, Clone, Default)]
+pub struct SharedRng {
+    seeded: Option<Arc<Mutex<StdRng>>>,
+}
+
+impl SharedRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seeded: Some(Arc::new(Mutex::new(StdRng::seed_from_u64(seed)))),
+        }
+    }
+
+    // seeded_for gives each label, e.g. a carrier name, its own sequence so adding a carrier leaves
+    // the draws of the others unchanged
+    pub fn seeded_for(seed: u64, label: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        (seed, label).hash(&mut hasher);
+        Self::seeded(hasher.finish())
+    }
+
+    // with runs f with the RNG, seeded draws are serialized so their order is that of the calls
+    pub fn with<T, F: FnOnce(&mut dyn RngCore) -> T>(&self, f: F) -> T {
+        match &self.seeded {
+            Some(rng) => f(&mut *rng.lock().unwrap()),
+            None => f(&mut rand::thread_rng()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_seeded() {
+        let draw =
+            |rng: &SharedRng| rng.with(|r| (0..8).map(|_| r.gen::<u32>()).collect::<Vec<_>>());
+        assert_eq!(draw(&SharedRng::seeded(7)), draw(&SharedRng::seeded(7)));
+        assert_ne!(draw(&SharedRng::seeded(7)), draw(&SharedRng::seeded(8)));
+        assert_eq!(
+            draw(&SharedRng::seeded_for(7, "carrier_1")),
+            draw(&SharedRng::seeded_for(7, "carrier_1"))
+        );
+        assert_ne!(
+            draw(&SharedRng::seeded_for(7, "carrier_1")),
+            draw(&SharedRng::seeded_for(7, "carrier_2"))
+        );
+    }
+}
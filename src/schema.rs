@@ -0,0 +1,256 @@
+use crate::config::{Config, Problem};
+use crate::provider::ProviderConfig;
+use anyhow::{anyhow, Error};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::path::Path;
+
+// validate checks the config file at path, if any, reporting every problem found rather than
+// just the first. Each section, carrier and profile of the file is checked against the schema on
+// its own, and once they all match it the settings serve would load, including the environment
+// variables and the named profile, are checked like check_config does. Problems are given the
+// line of the file they were found on where it can be told
+pub fn validate(path: Option<&str>, profile: Option<&str>) -> Result<Vec<Problem>, Error> {
+    let contents = match path {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("failed to read config file {}: {}", path, e))?,
+        ),
+        None => None,
+    };
+    let mut problems = match (path, &contents) {
+        (Some(path), Some(contents)) => match parse(path, contents) {
+            Ok(tree) => schema_problems("", &tree),
+            Err(problem) => return Ok(vec![problem]),
+        },
+        _ => Vec::new(),
+    };
+    if problems.is_empty() {
+        problems = match Config::read(path, profile) {
+            Ok(config) => config.check(),
+            Err(e) => vec![Problem::new("", e)],
+        };
+    }
+    if let Some(contents) = &contents {
+        for problem in problems.iter_mut() {
+            problem.line = locate(contents, &problem.path);
+        }
+    }
+    Ok(problems)
+}
+
+// parse reads the TOML or YAML contents of path into a tree, syntax errors are returned with
+// their line
+fn parse(path: &str, contents: &str) -> Result<Value, Problem> {
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    match extension {
+        Some("toml") => toml::from_str(contents).map_err(|e| Problem {
+            line: e
+                .span()
+                .map(|span| contents[..span.start].matches('\n').count() + 1),
+            ..Problem::new("", e.message())
+        }),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(contents).map_err(|e| Problem {
+            line: e.location().map(|l| l.line()),
+            ..Problem::new("", e)
+        }),
+        _ => Err(Problem::new(
+            "",
+            format!("config file {} must end in .toml, .yaml or .yml", path),
+        )),
+    }
+}
+
+// schema_problems checks every setting of tree, a config or the overrides of a profile found at
+// prefix, on its own so one invalid setting doesn't hide the others
+fn schema_problems(prefix: &str, tree: &Value) -> Vec<Problem> {
+    let settings = match tree {
+        Value::Object(settings) => settings,
+        _ => return vec![Problem::new(prefix, "must be a table of settings")],
+    };
+    let mut problems = Vec::new();
+    for (key, value) in settings {
+        let path = format!("{}{}", prefix, key);
+        match (key.as_str(), value) {
+            ("carriers", Value::Array(carriers)) => {
+                for (i, carrier) in carriers.iter().enumerate() {
+                    let path = format!("{}[{}]", path, i);
+                    problems.extend(deserialize::<ProviderConfig>(&path, carrier.clone()));
+                }
+            }
+            ("profiles", Value::Object(profiles)) if prefix.is_empty() => {
+                for (name, profile) in profiles {
+                    let mut profile = profile.clone();
+                    if let Value::Object(overrides) = &mut profile {
+                        overrides.remove("inherits");
+                    }
+                    let prefix = format!("{}.{}.", path, name);
+                    problems.extend(schema_problems(&prefix, &profile));
+                }
+            }
+            (key, value) => {
+                let mut single = serde_json::Map::new();
+                single.insert(key.to_string(), value.clone());
+                problems.extend(deserialize::<Config>(prefix, Value::Object(single)));
+            }
+        }
+    }
+    problems
+}
+
+// deserialize returns the problem deserializing value as T, if any, with the path to the setting
+// at fault below prefix
+fn deserialize<T: DeserializeOwned>(prefix: &str, value: Value) -> Option<Problem> {
+    let e = serde_path_to_error::deserialize::<_, T>(value).err()?;
+    let path = e.path().to_string();
+    let path = match (prefix.trim_end_matches('.'), path.as_str()) {
+        (prefix, ".") => prefix.to_string(),
+        ("", path) => path.to_string(),
+        (prefix, path) if path.starts_with('[') => format!("{}{}", prefix, path),
+        (prefix, path) => format!("{}.{}", prefix, path),
+    };
+    Some(Problem::new(path, e.into_inner()))
+}
+
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+// segments splits a path such as carriers[1].weight into its keys and list indices
+fn segments(path: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        let mut pieces = part.split('[');
+        if let Some(key) = pieces.next().filter(|k| !k.is_empty()) {
+            segments.push(Segment::Key(key));
+        }
+        for index in pieces {
+            if let Ok(i) = index.trim_end_matches(']').parse() {
+                segments.push(Segment::Index(i));
+            }
+        }
+    }
+    segments
+}
+
+// locate returns the line of contents path is set on, following its keys line by line. This
+// covers tables, arrays of tables and YAML lists, settings of inline tables resolve to the line
+// the table starts on
+fn locate(contents: &str, path: &str) -> Option<usize> {
+    let lines = contents.lines().collect::<Vec<_>>();
+    let mut at: Option<usize> = None;
+    let mut key = None;
+    for segment in segments(path) {
+        let from = at.unwrap_or(0);
+        let found = match segment {
+            Segment::Key(k) => (from..lines.len())
+                .find(|&i| defines(lines[i], k) || (Some(i) == at && mentions(lines[i], k))),
+            Segment::Index(n) => {
+                // TOML repeats the header of every table in a list, YAML starts items with a dash
+                let header = key.map(|k| format!("[[{}]]", k));
+                let indent = (from + 1..lines.len())
+                    .find(|&i| lines[i].trim_start().starts_with("- "))
+                    .map(|i| indentation(lines[i]));
+                (from..lines.len())
+                    .filter(|&i| {
+                        Some(lines[i].trim()) == header.as_deref()
+                            || (i > from
+                                && lines[i].trim_start().starts_with("- ")
+                                && Some(indentation(lines[i])) == indent)
+                    })
+                    .nth(n)
+            }
+        };
+        match found {
+            Some(i) => at = Some(i),
+            None => break,
+        }
+        if let Segment::Key(k) = segment {
+            key = Some(k);
+        }
+    }
+    at.map(|i| i + 1)
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+// defines reports whether line sets key, as key = .., key: .., - key: .. or a [key] header
+fn defines(line: &str, key: &str) -> bool {
+    let line = line.trim_start();
+    let line = line
+        .strip_prefix("- ")
+        .unwrap_or(line)
+        .trim_start_matches('[');
+    line.strip_prefix(key)
+        .is_some_and(|rest| rest.trim_start().starts_with(['=', ':', ']', '.']))
+}
+
+// mentions reports whether key is set somewhere within line, e.g. in an inline table or a dotted
+// table header
+fn mentions(line: &str, key: &str) -> bool {
+    line.match_indices(key).any(|(i, _)| {
+        let before = line[..i].chars().next_back();
+        let after = line[i + key.len()..].trim_start().chars().next();
+        !before.is_some_and(|c| c.is_alphanumeric() || c == '_')
+            && after.is_some_and(|c| "=:].".contains(c))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"prot = "6000"
+balancer = "round-robin"
+
+[[carriers]]
+name = "carrier_1"
+type = "mock"
+chance_sms = 60
+chance_voice = 50
+
+[[carriers]]
+name = "carrier_2"
+type = "mock"
+chance_sms = "often"
+chance_voice = 50
+
+[fraud]
+max_per_ip = -1
+
+[profiles.dev]
+number_policy = { allow_country = ["DE"] }
+"#;
+
+    #[test]
+    fn test_schema_problems() {
+        let tree = parse("telecom.toml", TOML).unwrap();
+        let mut problems = schema_problems("", &tree);
+        for problem in problems.iter_mut() {
+            problem.line = locate(TOML, &problem.path);
+        }
+        let found = problems
+            .iter()
+            .map(|p| (p.path.as_str(), p.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                // carriers are told by their type, their settings aren't named
+                ("carriers[1]", Some(10)),
+                ("fraud.max_per_ip", Some(17)),
+                ("profiles.dev.number_policy.allow_country", Some(20)),
+                ("prot", Some(1)),
+            ]
+        );
+
+        let yaml = "port: \"6000\"\ncarriers:\n  - name: carrier_1\n    type: mock\n  - name: c\n    type: mock\n    chance_sms: 200\n    chance_voice: 1\n";
+        let problems = schema_problems("", &parse("telecom.yaml", yaml).unwrap());
+        assert_eq!(problems[0].path, "carriers[0]");
+        assert_eq!(locate(yaml, "carriers[1].chance_sms"), Some(7));
+        assert_eq!(parse("telecom.toml", "port = ").unwrap_err().line, Some(1));
+    }
+}
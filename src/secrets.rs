@@ -0,0 +1,370 @@
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// SecretStore resolves the secrets ${secret:NAME} credentials references name
+pub trait SecretStore: Send + Sync {
+    fn get(&self, name: &str) -> Result<String, Error>;
+}
+
+// SecretsConfig is the backend ${secret:NAME} references are resolved from, tokens and keys of
+// the backends themselves are read from their usual environment variables
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "backend", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SecretsConfig {
+    // environment variable named prefix followed by the upper cased name
+    Env {
+        #[serde(default = "default_env_prefix")]
+        prefix: String,
+    },
+    // file named after the secret in dir, e.g. a mounted Kubernetes or Docker secret
+    File {
+        dir: String,
+    },
+    // keys of the KV version 2 secret at path of the mount, authenticated with VAULT_TOKEN and
+    // VAULT_NAMESPACE when set
+    Vault {
+        address: String,
+        #[serde(default = "default_vault_mount")]
+        mount: String,
+        path: String,
+    },
+    // AWS Secrets Manager secret named prefix followed by the name, authenticated with
+    // AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN when set
+    Aws {
+        region: String,
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self::Env {
+            prefix: default_env_prefix(),
+        }
+    }
+}
+
+fn default_env_prefix() -> String {
+    "TELECOM_SECRET_".to_string()
+}
+
+fn default_vault_mount() -> String {
+    "secret".to_string()
+}
+
+// secret requests never hold up startup or a reload for long
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// build_store returns the store config describes
+pub fn build_store(config: &SecretsConfig) -> Result<Box<dyn SecretStore>, Error> {
+    Ok(match config {
+        SecretsConfig::Env { prefix } => Box::new(EnvSecrets {
+            prefix: prefix.clone(),
+            vars: None,
+        }),
+        SecretsConfig::File { dir } => Box::new(FileSecrets {
+            dir: PathBuf::from(dir),
+        }),
+        SecretsConfig::Vault {
+            address,
+            mount,
+            path,
+        } => Box::new(VaultSecrets {
+            url: format!(
+                "{}/v1/{}/data/{}",
+                address.trim_end_matches('/'),
+                mount.trim_matches('/'),
+                path.trim_matches('/')
+            ),
+            token: std::env::var("VAULT_TOKEN")
+                .map_err(|_| anyhow!("the vault secrets backend requires VAULT_TOKEN"))?,
+            namespace: std::env::var("VAULT_NAMESPACE").ok(),
+        }),
+        SecretsConfig::Aws { region, prefix } => {
+            let var = |name: &str| {
+                std::env::var(name)
+                    .map_err(|_| anyhow!("the aws secrets backend requires {}", name))
+            };
+            Box::new(AwsSecrets {
+                region: region.clone(),
+                prefix: prefix.clone(),
+                access_key_id: var("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            })
+        }
+    })
+}
+
+// secret names are also environment variable and file names, so they're kept to those characters
+fn validate_name(name: &str) -> Result<(), Error> {
+    match !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "invalid secret name {}, use letters, digits, _ and -",
+            name
+        )),
+    }
+}
+
+pub struct EnvSecrets {
+    prefix: String,
+    // variables looked up in place of the process environment when set, e.g. by tests
+    vars: Option<HashMap<String, String>>,
+}
+
+impl SecretStore for EnvSecrets {
+    fn get(&self, name: &str) -> Result<String, Error> {
+        validate_name(name)?;
+        let var = format!(
+            "{}{}",
+            self.prefix,
+            name.to_ascii_uppercase().replace('-', "_")
+        );
+        let value = match &self.vars {
+            Some(vars) => vars.get(&var).cloned(),
+            None => std::env::var(&var).ok(),
+        };
+        value.ok_or_else(|| anyhow!("secret {}: {} is not set", name, var))
+    }
+}
+
+pub struct FileSecrets {
+    dir: PathBuf,
+}
+
+impl SecretStore for FileSecrets {
+    fn get(&self, name: &str) -> Result<String, Error> {
+        validate_name(name)?;
+        let path = self.dir.join(name);
+        std::fs::read_to_string(&path)
+            .map(|s| s.trim().to_string())
+            .map_err(|e| anyhow!("secret {}: failed to read {}: {}", name, path.display(), e))
+    }
+}
+
+pub struct VaultSecrets {
+    url: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl SecretStore for VaultSecrets {
+    fn get(&self, name: &str) -> Result<String, Error> {
+        validate_name(name)?;
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        let mut request = client.get(&self.url).header("X-Vault-Token", &self.token);
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let url = self.url.clone();
+        let body: serde_json::Value = block_on(async move {
+            let response = request.send().await?;
+            if !response.status().is_success() {
+                return Err(anyhow!("vault returned {} for {}", response.status(), url));
+            }
+            Ok(response.json().await?)
+        })
+        .map_err(|e| anyhow!("secret {}: {}", name, e))?;
+        // KV version 2 nests the secret's keys under data.data
+        body.pointer(&format!("/data/data/{}", name))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("secret {}: no such key in {}", name, self.url))
+    }
+}
+
+pub struct AwsSecrets {
+    region: String,
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+const AWS_SERVICE: &str = "secretsmanager";
+const AWS_TARGET: &str = "secretsmanager.GetSecretValue";
+const AWS_CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+impl SecretStore for AwsSecrets {
+    fn get(&self, name: &str) -> Result<String, Error> {
+        validate_name(name)?;
+        let host = format!("{}.{}.amazonaws.com", AWS_SERVICE, self.region);
+        let body = serde_json::to_vec(
+            &serde_json::json!({ "SecretId": format!("{}{}", self.prefix, name) }),
+        )?;
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut headers = vec![
+            ("content-type", AWS_CONTENT_TYPE.to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", AWS_TARGET.to_string()));
+        let authorization = self.authorization(&amz_date, &headers, &body);
+
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        let mut request = client
+            .post(format!("https://{}/", host))
+            .header("Authorization", authorization)
+            .body(body);
+        for (header, value) in headers.into_iter().filter(|(h, _)| *h != "host") {
+            request = request.header(header, value);
+        }
+        let body: serde_json::Value = block_on(async move {
+            let response = request.send().await?;
+            if !response.status().is_success() {
+                let status = response.status();
+                let error = response.text().await.unwrap_or_default();
+                return Err(anyhow!("secrets manager returned {}: {}", status, error));
+            }
+            Ok(response.json().await?)
+        })
+        .map_err(|e| anyhow!("secret {}: {}", name, e))?;
+        body.get("SecretString")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("secret {}: no SecretString", name))
+    }
+}
+
+impl AwsSecrets {
+    // authorization signs a request with AWS Signature Version 4, headers are lower case and
+    // sorted by name
+    fn authorization(&self, amz_date: &str, headers: &[(&str, String)], body: &[u8]) -> String {
+        let date = &amz_date[..8];
+        let canonical_headers = headers
+            .iter()
+            .map(|(h, v)| format!("{}:{}\n", h, v.trim()))
+            .collect::<String>();
+        let signed_headers = headers
+            .iter()
+            .map(|(h, _)| *h)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed_headers,
+            hex::encode(Sha256::digest(body))
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, AWS_SERVICE);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&self.secret_access_key, date, &self.region, AWS_SERVICE);
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id,
+            scope,
+            signed_headers,
+            hex::encode(hmac(&key, string_to_sign.as_bytes()))
+        )
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// signing_key derives the Signature Version 4 key of a day, region and service
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+// block_on runs a request to a backend on a runtime of its own thread, secrets are resolved from
+// sync code that may itself be running on a tokio worker
+fn block_on<T, F>(request: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T, Error>> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(request)
+    })
+    .join()
+    .map_err(|_| anyhow!("secret request panicked"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stores() {
+        // the process environment is shared with the tests running alongside
+        let env = EnvSecrets {
+            prefix: default_env_prefix(),
+            vars: Some(HashMap::from([(
+                "TELECOM_SECRET_TEST_AUTH_TOKEN".to_string(),
+                "t0ken".to_string(),
+            )])),
+        };
+        assert_eq!(env.get("test-auth_token").unwrap(), "t0ken");
+        assert!(env.get("unset_token").is_err());
+        assert!(env.get("../etc/passwd").is_err());
+
+        let dir = std::env::temp_dir().join("telecom_test_secrets");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("carrier_key"), "k3y\n").unwrap();
+        let files = build_store(&SecretsConfig::File {
+            dir: dir.display().to_string(),
+        })
+        .unwrap();
+        assert_eq!(files.get("carrier_key").unwrap(), "k3y");
+        assert!(files.get("missing").is_err());
+
+        let config: SecretsConfig = toml::from_str(
+            "backend = \"vault\"\naddress = \"http://vault:8200\"\npath = \"telecom\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            SecretsConfig::Vault {
+                address: "http://vault:8200".to_string(),
+                mount: "secret".to_string(),
+                path: "telecom".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // the derivation example of the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use utoipa::ToSchema;
+
+// longest window budgets can be tracked over, outcomes are kept per minute for as long
+const MAX_WINDOW_SECS: u64 = 7 * 24 * 3600;
+
+// SloConfig is the share of verifications each carrier is expected to get to the number, the
+// rest is its error budget. Budgets are tracked over rolling windows, the longest one is the
+// window the budget is spent in and the shortest one catches fast burns
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SloConfig {
+    // target of the carriers not listed in carriers, they aren't tracked when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<f64>,
+    // targets by carrier name, e.g. carrier_1 = 0.95
+    pub carriers: BTreeMap<String, f64>,
+    pub windows_secs: Vec<u64>,
+    // verifications a window needs before it is judged
+    pub min_attempts: u64,
+    // carriers that exhausted their budget are left out of routing while other carriers can take
+    // the attempt
+    pub avoid_exhausted: bool,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            target: None,
+            carriers: BTreeMap::new(),
+            windows_secs: vec![3600, 24 * 3600],
+            min_attempts: 20,
+            avoid_exhausted: false,
+        }
+    }
+}
+
+impl SloConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        for target in self.target.iter().chain(self.carriers.values()) {
+            if *target <= 0.0 || *target >= 1.0 {
+                return Err(anyhow!("slo targets must be between 0 and 1, exclusive"));
+            }
+        }
+        if self.windows_secs.is_empty() {
+            return Err(anyhow!("slo windows_secs must list at least one window"));
+        }
+        if self
+            .windows_secs
+            .iter()
+            .any(|w| *w < 60 || *w > MAX_WINDOW_SECS)
+        {
+            return Err(anyhow!(
+                "slo windows_secs must be between 60 and {}",
+                MAX_WINDOW_SECS
+            ));
+        }
+        if self.min_attempts == 0 {
+            return Err(anyhow!("slo min_attempts must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    pub fn target(&self, carrier: &str) -> Option<f64> {
+        self.carriers.get(carrier).copied().or(self.target)
+    }
+
+    fn windows(&self) -> Vec<u64> {
+        let mut windows = self.windows_secs.clone();
+        windows.sort_unstable();
+        windows.dedup();
+        windows
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetStatus {
+    Ok,
+    // the shortest window spends budget faster than the longest one can afford
+    Burning,
+    // the longest window spent the whole budget
+    Exhausted,
+}
+
+// SloReport is served at GET /slo
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct SloReport {
+    pub carriers: Vec<CarrierBudget>,
+}
+
+// CarrierBudget is how much of its error budget a carrier has spent
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct CarrierBudget {
+    pub carrier: String,
+    pub target: f64,
+    pub status: BudgetStatus,
+    // shortest first
+    pub windows: Vec<BudgetWindow>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct BudgetWindow {
+    pub window_secs: u64,
+    pub attempts: u64,
+    pub unreachable: u64,
+    // share of the budget the window spent relative to its attempts, more than 1 spends it faster
+    // than the target allows
+    pub burn_rate: f64,
+    // share of the budget left, negative once overspent
+    pub budget_remaining: f64,
+    // the window had min_attempts verifications, the status ignores the ones that didn't
+    pub judged: bool,
+}
+
+// Bucket counts the verifications of a carrier within one minute
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    minute: i64,
+    attempts: u64,
+    unreachable: u64,
+}
+
+// ErrorBudgets keeps the verification outcomes of the carriers with an SLO target by minute
+#[derive(Debug, Default)]
+pub struct ErrorBudgets {
+    buckets: HashMap<String, VecDeque<Bucket>>,
+}
+
+impl ErrorBudgets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // record adds a verification of carrier at now when it has a target, returning the share of
+    // its budget left
+    pub fn record(
+        &mut self,
+        config: &SloConfig,
+        carrier: &str,
+        delivered: bool,
+        now: DateTime<Utc>,
+    ) -> Option<f64> {
+        config.target(carrier)?;
+        let minute = now.timestamp().div_euclid(60);
+        let buckets = self.buckets.entry(carrier.to_string()).or_default();
+        match buckets.back_mut() {
+            Some(b) if b.minute == minute => {
+                b.attempts += 1;
+                b.unreachable += u64::from(!delivered);
+            }
+            _ => buckets.push_back(Bucket {
+                minute,
+                attempts: 1,
+                unreachable: u64::from(!delivered),
+            }),
+        }
+        let oldest = minute - (MAX_WINDOW_SECS / 60) as i64;
+        while buckets.front().is_some_and(|b| b.minute <= oldest) {
+            buckets.pop_front();
+        }
+        self.budget(config, carrier, now)
+            .and_then(|b| b.windows.last().map(|w| w.budget_remaining))
+    }
+
+    // budget reports the budget of carrier at now, None for carriers without a target
+    pub fn budget(
+        &self,
+        config: &SloConfig,
+        carrier: &str,
+        now: DateTime<Utc>,
+    ) -> Option<CarrierBudget> {
+        let target = config.target(carrier)?;
+        let allowed = 1.0 - target;
+        let buckets = self.buckets.get(carrier);
+        let windows = config
+            .windows()
+            .into_iter()
+            .map(|window_secs| {
+                // minutes that started within the window
+                let since = (now.timestamp() - window_secs as i64).div_euclid(60);
+                let (attempts, unreachable) = buckets
+                    .into_iter()
+                    .flatten()
+                    .filter(|b| b.minute > since)
+                    .fold((0, 0), |(a, u), b| (a + b.attempts, u + b.unreachable));
+                let burn_rate = match attempts {
+                    0 => 0.0,
+                    _ => unreachable as f64 / attempts as f64 / allowed,
+                };
+                BudgetWindow {
+                    window_secs,
+                    attempts,
+                    unreachable,
+                    burn_rate,
+                    budget_remaining: 1.0 - burn_rate,
+                    judged: attempts >= config.min_attempts,
+                }
+            })
+            .collect::<Vec<_>>();
+        let status = match (windows.first(), windows.last()) {
+            (_, Some(w)) if w.judged && w.burn_rate >= 1.0 => BudgetStatus::Exhausted,
+            (Some(w), _) if w.judged && w.burn_rate > 1.0 => BudgetStatus::Burning,
+            _ => BudgetStatus::Ok,
+        };
+        Some(CarrierBudget {
+            carrier: carrier.to_string(),
+            target,
+            status,
+            windows,
+        })
+    }
+
+    pub fn is_exhausted(&self, config: &SloConfig, carrier: &str, now: DateTime<Utc>) -> bool {
+        self.budget(config, carrier, now)
+            .is_some_and(|b| b.status == BudgetStatus::Exhausted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_error_budget() {
+        let config = SloConfig {
+            target: Some(0.9),
+            carriers: vec![("carrier_2".to_string(), 0.5)].into_iter().collect(),
+            windows_secs: vec![24 * 3600, 3600],
+            min_attempts: 10,
+            avoid_exhausted: true,
+        };
+        assert!(config.validate().is_ok());
+        let now = Utc::now();
+        let mut budgets = ErrorBudgets::new();
+        // 1 in 10 unreachable spends the whole budget of a 90% target...
+        let earlier = now - Duration::hours(2);
+        for i in 0..20 {
+            budgets.record(&config, "carrier_1", i % 10 != 0, earlier);
+        }
+        let budget = budgets.budget(&config, "carrier_1", now).unwrap();
+        assert_eq!(budget.windows[0].window_secs, 3600);
+        assert_eq!(budget.windows[0].attempts, 0);
+        assert_eq!(budget.windows[1].unreachable, 2);
+        assert_eq!(budget.status, BudgetStatus::Exhausted);
+        assert!(budgets.is_exhausted(&config, "carrier_1", now));
+
+        // ...and a tenth of the budget of carrier_2's 50% one
+        let mut remaining = None;
+        for i in 0..20 {
+            remaining = budgets.record(&config, "carrier_2", i % 20 != 0, now);
+        }
+        assert!((remaining.unwrap() - 0.9).abs() < 1e-9);
+        assert_eq!(
+            budgets.budget(&config, "carrier_2", now).unwrap().status,
+            BudgetStatus::Ok
+        );
+
+        // a burst of failures burns the short window first
+        for _ in 0..10 {
+            budgets.record(&config, "carrier_3", false, now);
+        }
+        for _ in 0..100 {
+            budgets.record(&config, "carrier_3", true, now - Duration::hours(3));
+        }
+        let budget = budgets.budget(&config, "carrier_3", now).unwrap();
+        assert_eq!(budget.status, BudgetStatus::Burning);
+        assert!(budget.windows[1].budget_remaining > 0.0);
+
+        let untracked = SloConfig {
+            target: None,
+            ..config.clone()
+        };
+        assert_eq!(budgets.record(&untracked, "carrier_4", false, now), None);
+        assert_eq!(budgets.budget(&untracked, "carrier_1", now), None);
+
+        for invalid in &[
+            SloConfig {
+                target: Some(1.0),
+                ..config.clone()
+            },
+            SloConfig {
+                windows_secs: Vec::new(),
+                ..config.clone()
+            },
+            SloConfig {
+                windows_secs: vec![30 * 24 * 3600],
+                ..config
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
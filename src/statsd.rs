@@ -0,0 +1,200 @@
+use crate::metrics::Metric;
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+// StatsdConfig is the StatsD or DogStatsD agent every counter increment, gauge update and
+// histogram observation is pushed to, alongside the registry served at /metrics
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    // host:port of the agent, e.g. 127.0.0.1:8125
+    pub address: String,
+    // prepended to every metric name along with a dot
+    pub prefix: String,
+    pub flavor: StatsdFlavor,
+    // dogstatsd only, sent with every metric, e.g. "env:production"
+    pub tags: Vec<String>,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:8125".to_string(),
+            prefix: "telecom".to_string(),
+            flavor: StatsdFlavor::default(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl StatsdConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        self.resolve()?;
+        if self.flavor == StatsdFlavor::Statsd && !self.tags.is_empty() {
+            return Err(anyhow!("statsd tags require the dogstatsd flavor"));
+        }
+        Ok(())
+    }
+
+    fn resolve(&self) -> Result<SocketAddr, Error> {
+        self.address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut a| a.next())
+            .ok_or_else(|| {
+                anyhow!(
+                    "invalid statsd address {}, e.g. 127.0.0.1:8125",
+                    self.address
+                )
+            })
+    }
+}
+
+// StatsdFlavor is how labels are sent, plain StatsD has no tags and gets them as name segments
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsdFlavor {
+    Statsd,
+    #[default]
+    Dogstatsd,
+}
+
+// Sample is one update of a metric
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Sample {
+    Count(u64),
+    Gauge(f64),
+    // histograms are observed in seconds and sent as timings in milliseconds
+    Timing(f64),
+}
+
+// Statsd sends samples to the agent over UDP, samples that can't be sent right away are dropped
+// rather than holding up the request they were taken in
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    config: StatsdConfig,
+}
+
+impl Statsd {
+    pub fn connect(config: &StatsdConfig) -> Result<Self, Error> {
+        let address = config.resolve()?;
+        let local = match address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            config: config.clone(),
+        })
+    }
+
+    pub fn send(&self, metric: &Metric, labels: &[(&'static str, &str)], sample: Sample) {
+        let _ = self
+            .socket
+            .send(self.line(metric, labels, sample).as_bytes());
+    }
+
+    // line renders a sample, e.g. telecom.requests:1|c|#outcome:sent
+    fn line(&self, metric: &Metric, labels: &[(&'static str, &str)], sample: Sample) -> String {
+        let base = metric.name.strip_prefix("telecom_").unwrap_or(metric.name);
+        let base = base
+            .strip_suffix("_total")
+            .or_else(|| base.strip_suffix("_seconds"))
+            .unwrap_or(base);
+        let mut name = match self.config.prefix.is_empty() {
+            true => base.to_string(),
+            false => format!("{}.{}", self.config.prefix, base),
+        };
+        let mut tags = self.config.tags.clone();
+        for (key, value) in labels {
+            match self.config.flavor {
+                StatsdFlavor::Statsd => name = format!("{}.{}", name, sanitize(value, ".:|@#")),
+                StatsdFlavor::Dogstatsd => tags.push(format!("{}:{}", key, sanitize(value, ",|#"))),
+            }
+        }
+        let value = match sample {
+            Sample::Count(n) => format!("{}|c", n),
+            Sample::Gauge(v) => format!("{}|g", v),
+            // to the microsecond
+            Sample::Timing(seconds) => format!("{}|ms", (seconds * 1e6).round() / 1e3),
+        };
+        match tags.is_empty() {
+            true => format!("{}:{}", name, value),
+            false => format!("{}:{}|#{}", name, value, tags.join(",")),
+        }
+    }
+}
+
+// sanitize replaces the characters the line format reserves, and whitespace, with underscores
+fn sanitize(value: &str, reserved: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.is_whitespace() || reserved.contains(c) {
+            true => '_',
+            false => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics;
+
+    #[test]
+    fn test_lines() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = StatsdConfig {
+            address: receiver.local_addr().unwrap().to_string(),
+            tags: vec!["env:test".to_string()],
+            ..StatsdConfig::default()
+        };
+        let statsd = Statsd::connect(&config).unwrap();
+        assert_eq!(
+            statsd.line(&metrics::REQUESTS, &[("outcome", "sent")], Sample::Count(1)),
+            "telecom.requests:1|c|#env:test,outcome:sent"
+        );
+        assert_eq!(
+            statsd.line(&metrics::REQUEST_DURATION, &[], Sample::Timing(0.0258009)),
+            "telecom.request_duration:25.801|ms|#env:test"
+        );
+
+        statsd.send(&metrics::REPO_ATTEMPTS, &[], Sample::Gauge(3.0));
+        let mut buf = [0; 512];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"telecom.repo_attempts:3|g|#env:test");
+
+        let plain = Statsd::connect(&StatsdConfig {
+            flavor: StatsdFlavor::Statsd,
+            prefix: String::new(),
+            tags: Vec::new(),
+            ..config.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            plain.line(
+                &metrics::CARRIER_VERIFICATIONS,
+                &[("carrier", "carrier 1.eu"), ("result", "delivered")],
+                Sample::Count(1)
+            ),
+            "carrier_verifications.carrier_1_eu.delivered:1|c"
+        );
+        assert!(StatsdConfig {
+            flavor: StatsdFlavor::Statsd,
+            ..config
+        }
+        .validate()
+        .is_err());
+        assert!(StatsdConfig {
+            address: "localhost".to_string(),
+            ..StatsdConfig::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use crate::http::SharedServer;
+use tracing::error;
+
+// how often sessions are checked for expiry and the repo size gauges are updated
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// spawn runs the expiry sweeper on the tokio runtime until the process exits
+pub fn spawn(server: SharedServer) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            server.sweep_expired();
+            // pushed to the StatsD agent too, which isn't scraped
+            server.record_repo_size();
+        }
+    })
+}
+
+// spawn_flush writes the records the repo buffered every interval until the process exits
+pub fn spawn_flush(
+    server: SharedServer,
+    every: std::time::Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let flushed = server.clone();
+            // the repo file is written synchronously, keep it off the async workers
+            match tokio::task::spawn_blocking(move || flushed.flush_repo()).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => error!(error = %e, "failed to flush repo"),
+                Err(e) => error!(error = %e, "repo flush failed"),
+            }
+        }
+    })
+}
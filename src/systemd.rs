@@ -0,0 +1,176 @@
+use crate::http::SharedServer;
+use anyhow::{anyhow, Error};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// first file descriptor systemd passes sockets from
+const LISTEN_FDS_START: RawFd = 3;
+
+// Sockets are the listening sockets systemd passed the process, matched to the APIs by their
+// FileDescriptorName=, admin and grpc for those APIs and any other name for the HTTP API
+#[derive(Debug, Default, PartialEq)]
+pub struct Sockets {
+    pub http: Option<RawFd>,
+    pub admin: Option<RawFd>,
+    pub grpc: Option<RawFd>,
+}
+
+// listen_fds takes the sockets of a socket activated start, the variables passing them are
+// removed so they aren't handed down any further. Must run before any threads are started
+pub fn listen_fds() -> Result<Sockets, Error> {
+    let var = |name| std::env::var(name).ok();
+    let sockets = parse_listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    )?;
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    Ok(sockets)
+}
+
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<Sockets, Error> {
+    let mut sockets = Sockets::default();
+    // the sockets were meant for another process when the pid doesn't match
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid.parse() == Ok(own_pid) => (pid, fds),
+        _ => return Ok(sockets),
+    };
+    let count = fds
+        .parse::<RawFd>()
+        .map_err(|_| anyhow!("invalid LISTEN_FDS {} passed to pid {}", fds, pid))?;
+    let names = names.unwrap_or_default().split(':').collect::<Vec<_>>();
+    for (i, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+        let name = names.get(i).copied().unwrap_or("unknown");
+        let slot = match name {
+            "admin" => &mut sockets.admin,
+            "grpc" => &mut sockets.grpc,
+            _ => &mut sockets.http,
+        };
+        if slot.replace(fd).is_some() {
+            return Err(anyhow!(
+                "systemd passed more than one socket named {}, name them admin, grpc or http",
+                name
+            ));
+        }
+    }
+    Ok(sockets)
+}
+
+// notify sends state, e.g. READY=1, to the service manager when started by systemd with
+// NotifyAccess=, doing nothing otherwise
+pub fn notify(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if let Err(e) = send(&path, state) {
+        warn!(error = %e, socket = %path, "failed to notify systemd");
+    }
+}
+
+fn send(path: &str, state: &str) -> Result<(), Error> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        // an abstract socket, addressed without a file
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+// watchdog_interval is how often systemd expects to hear from the process, half its WatchdogSec=
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    match usec?.parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec) / 2),
+    }
+}
+
+// spawn_watchdog pings the systemd watchdog while the server is responsive, a stuck or poisoned
+// server stops the pings and gets the service restarted
+pub fn spawn_watchdog(server: SharedServer) -> Option<tokio::task::JoinHandle<()>> {
+    let var = |name| std::env::var(name).ok();
+    let interval = watchdog_interval(
+        var("WATCHDOG_USEC").as_deref(),
+        var("WATCHDOG_PID").as_deref(),
+        std::process::id(),
+    )?;
+    info!(
+        interval_ms = interval.as_millis() as u64,
+        "systemd watchdog enabled"
+    );
+    Some(tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if !server.responsive() {
+                warn!("server state poisoned, stopping systemd watchdog");
+                return;
+            }
+            notify("WATCHDOG=1");
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(
+            parse_listen_fds(None, None, None, 7).unwrap(),
+            Sockets::default()
+        );
+        // meant for the parent
+        assert_eq!(
+            parse_listen_fds(Some("6"), Some("1"), None, 7).unwrap(),
+            Sockets::default()
+        );
+        assert_eq!(
+            parse_listen_fds(Some("7"), Some("1"), None, 7).unwrap(),
+            Sockets {
+                http: Some(3),
+                ..Sockets::default()
+            }
+        );
+        assert_eq!(
+            parse_listen_fds(Some("7"), Some("3"), Some("grpc:http:admin"), 7).unwrap(),
+            Sockets {
+                http: Some(4),
+                admin: Some(5),
+                grpc: Some(3),
+            }
+        );
+        assert!(parse_listen_fds(Some("7"), Some("2"), None, 7).is_err());
+        assert!(parse_listen_fds(Some("7"), Some("x"), None, 7).is_err());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let interval = watchdog_interval(Some("30000000"), None, 7);
+        assert_eq!(interval, Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(watchdog_interval(Some("0"), Some("7"), 7), None);
+        assert_eq!(watchdog_interval(None, None, 7), None);
+    }
+}
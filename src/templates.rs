@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Context, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+// placeholder replaced with the code in every template
+pub const CODE_PLACEHOLDER: &str = "{code}";
+
+// Template is the text of a verification message in one locale
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Template {
+    pub sms: String,
+    // read out by text-to-speech, the code is spelled out one character at a time
+    pub voice: String,
+}
+
+impl Template {
+    fn new(sms: &str, voice: &str) -> Self {
+        Self {
+            sms: sms.to_string(),
+            voice: voice.to_string(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if !self.sms.contains(CODE_PLACEHOLDER) || !self.voice.contains(CODE_PLACEHOLDER) {
+            return Err(anyhow!(
+                "templates must contain {} in both sms and voice",
+                CODE_PLACEHOLDER
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, code: &str) -> Message {
+        let spoken = code
+            .chars()
+            .map(String::from)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Message {
+            sms: self.sms.replace(CODE_PLACEHOLDER, code),
+            voice: self.voice.replace(CODE_PLACEHOLDER, &spoken),
+        }
+    }
+}
+
+// Message is what carriers deliver to the number, per channel
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub sms: String,
+    pub voice: String,
+}
+
+// Templates holds the message templates of every supported locale
+#[derive(Debug, PartialEq, Clone)]
+pub struct Templates {
+    default_locale: String,
+    locales: HashMap<String, Template>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        let locales = [
+            (
+                "en",
+                Template::new(
+                    "Your verification code is {code}",
+                    "Your verification code is {code}. Again, your code is {code}.",
+                ),
+            ),
+            (
+                "de",
+                Template::new(
+                    "Ihr Bestätigungscode lautet {code}",
+                    "Ihr Bestätigungscode lautet {code}. Noch einmal, Ihr Code lautet {code}.",
+                ),
+            ),
+            (
+                "es",
+                Template::new(
+                    "Tu código de verificación es {code}",
+                    "Tu código de verificación es {code}. Repetimos, tu código es {code}.",
+                ),
+            ),
+            (
+                "fr",
+                Template::new(
+                    "Votre code de vérification est {code}",
+                    "Votre code de vérification est {code}. Je répète, votre code est {code}.",
+                ),
+            ),
+        ];
+        Self {
+            default_locale: "en".to_string(),
+            locales: locales
+                .iter()
+                .map(|(l, t)| (l.to_string(), t.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Templates {
+    // with_overrides adds or replaces locales, falling back to default_locale for requests in
+    // locales that have no template
+    pub fn with_overrides(
+        mut self,
+        default_locale: &str,
+        overrides: HashMap<String, Template>,
+    ) -> Result<Self, Error> {
+        for (locale, template) in overrides {
+            template
+                .validate()
+                .map_err(|e| anyhow!("{}: {}", locale, e))?;
+            self.locales.insert(locale.to_ascii_lowercase(), template);
+        }
+        let default_locale = default_locale.to_ascii_lowercase();
+        if !self.locales.contains_key(&default_locale) {
+            return Err(anyhow!("no template for default locale {}", default_locale));
+        }
+        self.default_locale = default_locale;
+        Ok(self)
+    }
+
+    // from_file loads overrides from a JSON object of locales to templates
+    pub fn from_file(default_locale: &str, path: &str) -> Result<Self, Error> {
+        let file = fs::read(path).with_context(|| format!("reading {}", path))?;
+        let overrides = serde_json::from_slice::<HashMap<String, Template>>(&file)
+            .with_context(|| format!("parsing {}", path))?;
+        Self::default().with_overrides(default_locale, overrides)
+    }
+
+    // template resolves a BCP 47 locale such as pt-BR, falling back from the full tag to its
+    // language and then to the default locale
+    pub fn template(&self, locale: Option<&str>) -> &Template {
+        locale
+            .map(|l| l.trim().to_ascii_lowercase().replace('_', "-"))
+            .and_then(|l| {
+                self.locales.get(&l).or_else(|| {
+                    l.split('-')
+                        .next()
+                        .and_then(|language| self.locales.get(language))
+                })
+            })
+            .unwrap_or(&self.locales[&self.default_locale])
+    }
+
+    pub fn render(&self, locale: Option<&str>, code: &str) -> Message {
+        self.template(locale).render(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locale_fallback() {
+        let templates = Templates::default();
+        let sms = |locale| templates.render(locale, "1234").sms;
+        assert_eq!(sms(None), "Your verification code is 1234");
+        assert_eq!(sms(Some("de")), "Ihr Bestätigungscode lautet 1234");
+        assert_eq!(sms(Some("de-AT")), "Ihr Bestätigungscode lautet 1234");
+        assert_eq!(sms(Some("FR_ca")), "Votre code de vérification est 1234");
+        assert_eq!(sms(Some("xx")), "Your verification code is 1234");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            "pt-BR".to_string(),
+            Template::new("Seu código é {code}", "Seu código é {code}"),
+        );
+        let templates = Templates::default()
+            .with_overrides("pt-br", overrides)
+            .unwrap();
+        let message = templates.render(Some("xx"), "12");
+        assert_eq!(message.sms, "Seu código é 12");
+        assert_eq!(message.voice, "Seu código é 1, 2");
+
+        let mut invalid = HashMap::new();
+        invalid.insert("it".to_string(), Template::new("Il tuo codice", "{code}"));
+        assert!(Templates::default().with_overrides("en", invalid).is_err());
+        assert!(Templates::default()
+            .with_overrides("it", HashMap::new())
+            .is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::repo::{VerificationEntry, VerificationStep};
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// carrier name attempts to test numbers are recorded under
+pub const TEST_CARRIER: &str = "test";
+
+// TestNumber is the fixed outcome of verifying a test number, no carrier is ever contacted
+#[derive(Debug, PartialEq, Clone)]
+pub enum TestNumber {
+    // always delivered, with this code
+    Code(String),
+    // never delivered, as if every step of the ladder failed
+    Unreachable,
+}
+
+// parses "unreachable" or the fixed code, e.g. "000000"
+impl FromStr for TestNumber {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "unreachable" => Ok(Self::Unreachable),
+            code if !code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                Ok(Self::Code(code.to_ascii_uppercase()))
+            }
+            _ => Err(anyhow!("invalid test number code: {}", s)),
+        }
+    }
+}
+
+// TestNumbers lets app store reviewers and end-to-end tests verify numbers in production without
+// sending anything, test numbers also skip number policies and velocity limits
+#[derive(Debug, Default, Clone)]
+pub struct TestNumbers {
+    numbers: HashMap<String, TestNumber>,
+}
+
+impl TestNumbers {
+    pub fn new(numbers: Vec<(String, TestNumber)>) -> Self {
+        Self {
+            numbers: numbers.into_iter().collect(),
+        }
+    }
+
+    pub fn contains(&self, number: &str) -> bool {
+        self.numbers.contains_key(number)
+    }
+
+    // deliver stands in for a carrier, returning the attempt and the code of a test number, which
+    // is empty for unreachable ones
+    pub fn deliver(&self, number: &str) -> Option<(VerificationEntry, String)> {
+        let (step, code) = match self.numbers.get(number)? {
+            TestNumber::Code(code) => (VerificationStep::FirstSMS, code.clone()),
+            TestNumber::Unreachable => (VerificationStep::Unreachable, String::new()),
+        };
+        let entry = VerificationEntry {
+            carrier: TEST_CARRIER.into(),
+            number: number.to_string(),
+            time: chrono::offset::Utc::now(),
+            step,
+            simulated: false,
+            correlation_id: None,
+        };
+        Some((entry, code))
+    }
+}
+
+// parses a test number passed as "+15550000001=000000" or "+15550000002=unreachable"
+pub fn parse_test_number(s: &str) -> Result<(String, TestNumber), String> {
+    let (number, outcome) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <number>=<code|unreachable>, got {}", s))?;
+    let outcome = outcome.parse().map_err(|e: Error| e.to_string())?;
+    Ok((number.trim().to_string(), outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliver() {
+        let numbers = TestNumbers::new(vec![
+            parse_test_number("+15550000001=000000").unwrap(),
+            parse_test_number("+15550000002=unreachable").unwrap(),
+        ]);
+        let (entry, code) = numbers.deliver("+15550000001").unwrap();
+        assert_eq!(
+            (entry.step, code.as_str()),
+            (VerificationStep::FirstSMS, "000000")
+        );
+        let (entry, _) = numbers.deliver("+15550000002").unwrap();
+        assert_eq!(entry.step, VerificationStep::Unreachable);
+        assert!(numbers.deliver("+15550000003").is_none());
+
+        assert!(parse_test_number("+15550000001").is_err());
+        assert!(parse_test_number("+15550000001=00 00").is_err());
+    }
+}
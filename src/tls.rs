@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Context, Error};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fs;
+use std::sync::Arc;
+
+// TlsConfig holds the PEM encoded material needed to terminate HTTPS in-process
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub certificate: Vec<u8>,
+    pub private_key: Vec<u8>,
+    pub client_ca: Option<Vec<u8>>,
+}
+
+impl TlsConfig {
+    // from_paths reads the certificate chain, private key and optional client CA bundle from disk,
+    // returning None when neither cert nor key were configured so the server falls back to HTTP
+    pub fn from_paths(
+        cert_path: Option<&str>,
+        key_path: Option<&str>,
+        client_ca_path: Option<&str>,
+    ) -> Result<Option<Self>, Error> {
+        let (cert_path, key_path) = match (cert_path, key_path) {
+            (Some(c), Some(k)) => (c, k),
+            (None, None) => {
+                if client_ca_path.is_some() {
+                    return Err(anyhow!("--tls-client-ca requires --tls-cert and --tls-key"));
+                }
+                return Ok(None);
+            }
+            _ => {
+                return Err(anyhow!(
+                    "--tls-cert and --tls-key must be provided together"
+                ))
+            }
+        };
+
+        let client_ca = match client_ca_path {
+            Some(p) => Some(read_pem(p)?),
+            None => None,
+        };
+
+        Ok(Some(Self {
+            certificate: read_pem(cert_path)?,
+            private_key: read_pem(key_path)?,
+            client_ca,
+        }))
+    }
+
+    // server_config builds the rustls configuration, requiring clients to present a certificate
+    // signed by client_ca when one is configured
+    pub fn server_config(&self) -> Result<ServerConfig, Error> {
+        let certs = CertificateDer::pem_slice_iter(&self.certificate)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("invalid TLS certificate: {}", e))?;
+        let key = PrivateKeyDer::from_pem_slice(&self.private_key)
+            .map_err(|e| anyhow!("invalid TLS private key: {}", e))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_slice_iter(ca) {
+                    let cert = cert.map_err(|e| anyhow!("invalid client CA certificate: {}", e))?;
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, Error> {
+    fs::read(path).with_context(|| format!("failed to read PEM file: {}", path))
+}
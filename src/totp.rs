@@ -0,0 +1,245 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::Url;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use utoipa::ToSchema;
+
+// the parameters every authenticator app supports, other digit counts and periods are often
+// silently ignored
+pub const DIGITS: usize = 6;
+pub const PERIOD_SECS: i64 = 30;
+// codes from one period before or after are accepted to allow for clock drift
+const SKEW_PERIODS: i64 = 1;
+// 160 bits as recommended by RFC 4226
+const SECRET_BYTES: usize = 20;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// TotpEnrollment is the TOTP secret a verified number enrolled
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotpEnrollment {
+    pub number: String,
+    pub secret: Vec<u8>,
+    // period of the last accepted code, codes can't be used twice
+    pub last_period: Option<i64>,
+    pub failed_checks: u32,
+    pub enrolled_at: DateTime<Utc>,
+}
+
+impl TotpEnrollment {
+    pub fn new(number: &str) -> Self {
+        Self {
+            number: number.to_string(),
+            secret: rand::thread_rng().gen::<[u8; SECRET_BYTES]>().to_vec(),
+            last_period: None,
+            failed_checks: 0,
+            enrolled_at: Utc::now(),
+        }
+    }
+
+    // provisioning_uri is the otpauth:// URI authenticator apps enroll from, usually shown as a QR
+    // code
+    pub fn provisioning_uri(&self, issuer: &str) -> String {
+        let mut uri = Url::parse("otpauth://totp/").expect("valid base URI");
+        // some apps decode a plus in the label as a space
+        uri.set_path(&format!("{}:{}", issuer, self.number.replace('+', "%2B")));
+        uri.query_pairs_mut()
+            .append_pair("secret", &base32(&self.secret))
+            .append_pair("issuer", issuer)
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &PERIOD_SECS.to_string());
+        uri.to_string()
+    }
+
+    // accept checks code against the periods around now, a matching code can't be accepted
+    // again
+    pub fn accept(&mut self, code: &str, now: DateTime<Utc>) -> bool {
+        let current = now.timestamp().div_euclid(PERIOD_SECS);
+        let matched = (current - SKEW_PERIODS..=current + SKEW_PERIODS)
+            .filter(|p| self.last_period.is_none_or(|last| *p > last))
+            .find(|p| code_at(&self.secret, *p) == code.trim());
+        match matched {
+            Some(period) => {
+                self.last_period = Some(period);
+                self.failed_checks = 0;
+                true
+            }
+            None => {
+                self.failed_checks += 1;
+                false
+            }
+        }
+    }
+}
+
+// code_at is the HOTP value of secret for a period, RFC 6238 with HMAC-SHA1
+pub fn code_at(secret: &[u8], period: i64) -> String {
+    // SHA1 is what authenticator apps implement, HOTP only relies on it as a PRF
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let digest = hmac::sign(&key, &period.to_be_bytes());
+    let digest = digest.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        truncated % 10_u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+// base32 encodes bytes without padding, the form secrets are shared with authenticator apps in
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0_u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    encoded
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct TotpEnrollResponse {
+    // base32 encoded, for entering into an authenticator app by hand
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct TotpCheckRequest {
+    pub number: String,
+    pub code: String,
+}
+
+// TotpEnrollRequest is the optional body of an enrollment, a number that is already enrolled
+// proves it holds the current secret before it is replaced
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default, PartialEq, Clone)]
+pub struct TotpEnrollRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TotpError {
+    NotEnrolled,
+    // the number is enrolled and no code of its current secret was submitted
+    Enrolled,
+    // the token is invalid, revoked or wasn't issued for a code or TOTP verification
+    InvalidToken(String),
+    Mismatch { remaining: u32 },
+    // too many wrong codes, the number can be verified again after until
+    Locked { until: DateTime<Utc> },
+    // the code matched but no token could be issued
+    Internal(String),
+}
+
+impl fmt::Display for TotpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TotpError::NotEnrolled => write!(f, "number has no TOTP enrollment"),
+            TotpError::Enrolled => write!(
+                f,
+                "number is already enrolled, submit a code of the current enrollment to replace it"
+            ),
+            TotpError::InvalidToken(e) => write!(f, "invalid token: {}", e),
+            TotpError::Mismatch { remaining } => {
+                write!(f, "invalid TOTP code, {} attempt(s) left", remaining)
+            }
+            TotpError::Locked { until } => write!(
+                f,
+                "too many invalid codes, retry after {}",
+                until.to_rfc3339()
+            ),
+            TotpError::Internal(e) => write!(f, "token could not be issued: {}", e),
+        }
+    }
+}
+
+// TotpStore keeps the enrollments of numbers, one per number
+pub trait TotpStore: Send + Sync {
+    // insert replaces any previous enrollment of the number
+    fn insert(&mut self, enrollment: TotpEnrollment);
+    fn get(&self, number: &str) -> Option<&TotpEnrollment>;
+    fn remove(&mut self, number: &str) -> Option<TotpEnrollment>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryTotpStore {
+    enrollments: HashMap<String, TotpEnrollment>,
+}
+
+impl InMemoryTotpStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TotpStore for InMemoryTotpStore {
+    fn insert(&mut self, enrollment: TotpEnrollment) {
+        self.enrollments
+            .insert(enrollment.number.clone(), enrollment);
+    }
+
+    fn get(&self, number: &str) -> Option<&TotpEnrollment> {
+        self.enrollments.get(number)
+    }
+
+    fn remove(&mut self, number: &str) -> Option<TotpEnrollment> {
+        self.enrollments.remove(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // RFC 6238 appendix B, truncated to 6 digits
+    #[test]
+    fn test_code_at() {
+        let secret = b"12345678901234567890";
+        assert_eq!(code_at(secret, 59 / PERIOD_SECS), "287082");
+        assert_eq!(code_at(secret, 1111111109 / PERIOD_SECS), "081804");
+        assert_eq!(code_at(secret, 20000000000 / PERIOD_SECS), "353130");
+        assert_eq!(base32(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32(b"f"), "MY");
+    }
+
+    #[test]
+    fn test_accept() {
+        let mut enrollment = TotpEnrollment::new("+15555550100");
+        let now = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let period = now.timestamp() / PERIOD_SECS;
+        let code = code_at(&enrollment.secret, period);
+
+        assert!(enrollment.accept(&code, now));
+        // codes are single use
+        assert!(!enrollment.accept(&code, now));
+        assert_eq!(enrollment.failed_checks, 1);
+        // the next period's code is accepted early for clock drift, older ones never again
+        let next = code_at(&enrollment.secret, period + 1);
+        assert!(enrollment.accept(&next, now));
+        assert_eq!(enrollment.failed_checks, 0);
+        let previous = code_at(&enrollment.secret, period - 1);
+        assert!(!enrollment.accept(&previous, now));
+
+        let uri = enrollment.provisioning_uri("telecom");
+        assert!(uri.starts_with("otpauth://totp/telecom:%2B15555550100?secret="));
+        assert!(uri.contains("&issuer=telecom&digits=6&period=30"));
+    }
+}
@@ -0,0 +1,208 @@
+use ::http::{HeaderMap, HeaderValue};
+use rand::Rng;
+use tracing::{info_span, Span};
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+// TraceContext is the W3C trace context of the span currently handling a request, span_id is the
+// id outbound calls report as their parent
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+    // the request id of the API call that started the work, logged and stored with everything
+    // done for it, retries and webhook deliveries included
+    pub correlation_id: Option<String>,
+}
+
+impl TraceContext {
+    // new_root starts a trace for requests that arrive without a usable traceparent
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: random_hex(16),
+            span_id: random_hex(8),
+            sampled: true,
+            tracestate: None,
+            correlation_id: None,
+        }
+    }
+
+    pub fn with_correlation_id(self, correlation_id: String) -> Self {
+        Self {
+            correlation_id: Some(correlation_id),
+            ..self
+        }
+    }
+
+    // from_headers continues the caller's trace in a new span, a malformed traceparent starts a
+    // new trace and drops tracestate along with it as the spec requires
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let parent = headers
+            .get(TRACEPARENT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+        match parent {
+            Some((trace_id, sampled)) => Self {
+                trace_id,
+                span_id: random_hex(8),
+                sampled,
+                tracestate: tracestate(headers),
+                correlation_id: None,
+            },
+            None => Self::new_root(),
+        }
+    }
+
+    // child is the context of a nested span in the same trace, such as one outbound call
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_hex(8),
+            ..self.clone()
+        }
+    }
+
+    // span is entered around the work done for the correlation id so every event logged in it
+    // carries the id, without one it is disabled
+    pub fn span(&self) -> Span {
+        match &self.correlation_id {
+            Some(id) => info_span!("request", correlation_id = %id),
+            None => Span::none(),
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { "01" } else { "00" }
+        )
+    }
+
+    // inject writes the headers an outbound call needs to appear as a child of this span
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        if let Ok(v) = HeaderValue::from_str(&self.traceparent()) {
+            headers.insert(TRACEPARENT_HEADER, v);
+        }
+        if let Some(v) = self
+            .tracestate
+            .as_deref()
+            .and_then(|s| HeaderValue::from_str(s).ok())
+        {
+            headers.insert(TRACESTATE_HEADER, v);
+        }
+    }
+}
+
+// parse_traceparent returns the trace id and sampled flag of a version 00 traceparent, later
+// versions are read the same way as long as the known fields parse
+fn parse_traceparent(value: &str) -> Option<(String, bool)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    if version.len() != 2 || version == "ff" || !is_lower_hex(version) {
+        return None;
+    }
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    if trace_id.len() != 32 || !is_lower_hex(trace_id) || is_zero(trace_id) {
+        return None;
+    }
+    if parent_id.len() != 16 || !is_lower_hex(parent_id) || is_zero(parent_id) {
+        return None;
+    }
+    if flags.len() != 2 || !is_lower_hex(flags) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), flags & 1 == 1))
+}
+
+// multiple tracestate headers are combined into one list
+fn tracestate(headers: &HeaderMap) -> Option<String> {
+    let members = headers
+        .get_all(TRACESTATE_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>();
+    match members.is_empty() {
+        true => None,
+        false => Some(members.join(",")),
+    }
+}
+
+fn is_lower_hex(s: &str) -> bool {
+    s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+fn is_zero(s: &str) -> bool {
+    s.chars().all(|c| c == '0')
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    // an all zero id would be invalid, the odds of drawing one are negligible
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_context() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT_HEADER,
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        headers.insert(
+            TRACESTATE_HEADER,
+            HeaderValue::from_static("congo=t61rcWkgMzE"),
+        );
+        let trace = TraceContext::from_headers(&headers);
+        assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(trace.span_id, "00f067aa0ba902b7");
+        assert!(trace.sampled);
+        assert_eq!(trace.tracestate.as_deref(), Some("congo=t61rcWkgMzE"));
+
+        let child = trace
+            .clone()
+            .with_correlation_id("cbf2c18e9c492100".to_string())
+            .child();
+        assert_eq!(child.trace_id, trace.trace_id);
+        assert_eq!(child.correlation_id.as_deref(), Some("cbf2c18e9c492100"));
+        assert_ne!(child.span_id, trace.span_id);
+        let mut outbound = HeaderMap::new();
+        child.inject(&mut outbound);
+        assert_eq!(
+            outbound[TRACEPARENT_HEADER],
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", child.span_id)
+        );
+        assert_eq!(outbound[TRACESTATE_HEADER], "congo=t61rcWkgMzE");
+
+        // invalid traceparents start a new trace
+        for invalid in &[
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            headers.insert(TRACEPARENT_HEADER, HeaderValue::from_static(invalid));
+            let trace = TraceContext::from_headers(&headers);
+            assert_ne!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_eq!(trace.tracestate, None);
+        }
+    }
+}
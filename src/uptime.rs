@@ -0,0 +1,195 @@
+use crate::alerting::{AlertKind, CarrierAlert};
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+// longest window uptime is reported over, incidents that ended before it are forgotten
+const MAX_WINDOW_SECS: u64 = 30 * 24 * 3600;
+
+// window reported over when the query names none
+const DEFAULT_WINDOW_SECS: u64 = 24 * 3600;
+
+// UptimeQuery is the window GET /rank/uptime reports over
+#[derive(Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct UptimeQuery {
+    // the last window seconds, a day when omitted
+    pub window: Option<u64>,
+}
+
+impl UptimeQuery {
+    pub fn window(&self) -> Result<Duration, Error> {
+        match self.window.unwrap_or(DEFAULT_WINDOW_SECS) {
+            w if w == 0 || w > MAX_WINDOW_SECS => Err(anyhow!(
+                "window must be between 1 and {} seconds",
+                MAX_WINDOW_SECS
+            )),
+            w => Ok(Duration::seconds(w as i64)),
+        }
+    }
+}
+
+// Incident is a span a carrier was degraded for, see alerting::AlertConfig
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct Incident {
+    pub start: DateTime<Utc>,
+    // omitted while the carrier is still degraded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<DateTime<Utc>>,
+    // share of the verifications within the alerting window delivered when it was degraded
+    pub success_rate: f64,
+}
+
+// UptimeReport is served at GET /rank/uptime
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct UptimeReport {
+    pub window_secs: u64,
+    // start of the part of the window the server was running for, uptime is judged over it
+    pub since: DateTime<Utc>,
+    pub carriers: Vec<CarrierUptime>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Clone)]
+pub struct CarrierUptime {
+    pub carrier: String,
+    // percentage of the window the carrier wasn't degraded for
+    pub uptime: f64,
+    pub downtime_secs: i64,
+    // incidents overlapping the window, oldest first
+    pub incidents: Vec<Incident>,
+}
+
+// UptimeTracker keeps the spans carriers were degraded for from the alerts the carrier monitor
+// raises on their verification outcomes
+#[derive(Debug)]
+pub struct UptimeTracker {
+    // carriers are only judged from when the server started
+    started: DateTime<Utc>,
+    incidents: HashMap<String, Vec<Incident>>,
+}
+
+impl UptimeTracker {
+    pub fn new(started: DateTime<Utc>) -> Self {
+        Self {
+            started,
+            incidents: HashMap::new(),
+        }
+    }
+
+    // record opens an incident for a carrier moving into degradation and ends it once it
+    // recovers
+    pub fn record(&mut self, alert: &CarrierAlert) {
+        let incidents = self.incidents.entry(alert.carrier.clone()).or_default();
+        let open = incidents.last_mut().filter(|i| i.end.is_none());
+        match (alert.kind, open) {
+            (AlertKind::Degraded, None) => incidents.push(Incident {
+                start: alert.time,
+                end: None,
+                success_rate: alert.success_rate,
+            }),
+            (AlertKind::Recovered, Some(incident)) => incident.end = Some(alert.time),
+            _ => (),
+        }
+        let oldest = alert.time - Duration::seconds(MAX_WINDOW_SECS as i64);
+        incidents.retain(|i| i.end.is_none_or(|end| end > oldest));
+    }
+
+    // report tells the uptime of carriers over the window up to now
+    pub fn report(
+        &self,
+        carriers: &[String],
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> UptimeReport {
+        let since = (now - window).max(self.started);
+        let observed = (now - since).num_milliseconds();
+        let carriers = carriers
+            .iter()
+            .map(|carrier| {
+                let incidents = self
+                    .incidents
+                    .get(carrier)
+                    .into_iter()
+                    .flatten()
+                    .filter(|i| i.end.is_none_or(|end| end > since))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let downtime = incidents
+                    .iter()
+                    .map(|i| {
+                        (i.end.unwrap_or(now).min(now) - i.start.max(since)).num_milliseconds()
+                    })
+                    .sum::<i64>();
+                let uptime = match observed {
+                    0 => 100.0,
+                    o => 100.0 * (1.0 - downtime as f64 / o as f64),
+                };
+                CarrierUptime {
+                    carrier: carrier.clone(),
+                    // to the thousandth of a percent, 99.999 is still told from 100
+                    uptime: (uptime * 1e3).round() / 1e3,
+                    downtime_secs: downtime / 1000,
+                    incidents,
+                }
+            })
+            .collect();
+        UptimeReport {
+            window_secs: window.num_seconds() as u64,
+            since,
+            carriers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uptime() {
+        let now = Utc::now();
+        let mut tracker = UptimeTracker::new(now - Duration::days(3));
+        let alert = |carrier: &str, kind, hours_ago| CarrierAlert {
+            carrier: carrier.to_string(),
+            kind,
+            success_rate: 0.25,
+            attempts: 20,
+            window_secs: 300,
+            time: now - Duration::hours(hours_ago),
+        };
+        // two hours down yesterday, still down for the last one
+        tracker.record(&alert("carrier_1", AlertKind::Degraded, 30));
+        tracker.record(&alert("carrier_1", AlertKind::Recovered, 20));
+        tracker.record(&alert("carrier_1", AlertKind::Degraded, 12));
+        tracker.record(&alert("carrier_1", AlertKind::Recovered, 10));
+        tracker.record(&alert("carrier_1", AlertKind::Degraded, 1));
+        // a recovery without a degradation opens nothing
+        tracker.record(&alert("carrier_2", AlertKind::Recovered, 5));
+
+        let carriers = vec!["carrier_1".to_string(), "carrier_2".to_string()];
+        let report = tracker.report(&carriers, Duration::days(1), now);
+        assert_eq!(report.since, now - Duration::days(1));
+        let carrier_1 = &report.carriers[0];
+        // the incident that started 30 hours ago overlaps the window by 4 hours
+        assert_eq!(carrier_1.incidents.len(), 3);
+        assert_eq!(carrier_1.incidents[2].end, None);
+        assert_eq!(carrier_1.downtime_secs, 7 * 3600);
+        assert_eq!(carrier_1.uptime, 70.833);
+        assert_eq!(report.carriers[1].uptime, 100.0);
+        assert!(report.carriers[1].incidents.is_empty());
+
+        // judged from when the server started
+        let week = tracker.report(&carriers, Duration::days(7), now);
+        assert_eq!(week.since, now - Duration::days(3));
+        assert_eq!(week.carriers[0].downtime_secs, 13 * 3600);
+
+        assert!(UptimeQuery { window: Some(0) }.window().is_err());
+        assert!(UptimeQuery {
+            window: Some(MAX_WINDOW_SECS + 1)
+        }
+        .window()
+        .is_err());
+        assert_eq!(UptimeQuery::default().window().unwrap(), Duration::days(1));
+    }
+}
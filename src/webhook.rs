@@ -0,0 +1,336 @@
+use crate::events::VerificationEvent;
+use crate::middleware::REQUEST_ID_HEADER;
+use crate::trace::TraceContext;
+use anyhow::{anyhow, Error};
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{StatusCode, Url};
+use serde::Serialize;
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn, Instrument};
+
+pub const SIGNATURE_HEADER: &str = "X-Telecom-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Telecom-Timestamp";
+
+// longest wait between two delivery attempts of the same notification
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    // shared secret clients use to verify the HMAC-SHA256 signature of notifications
+    pub secret: String,
+    // delivery attempts per notification before it is dropped
+    pub max_attempts: u32,
+    // wait before the first retry, doubled after every failed attempt
+    pub initial_backoff: Duration,
+    pub timeout: Duration,
+    // log notifications in place of delivering them, set by --dry-run
+    pub dry_run: bool,
+    // hosts client callback_urls may name, any public host when empty
+    pub allowed_hosts: Vec<String>,
+    // notifications delivered at once, each retrying one until it succeeds or is dropped
+    pub workers: usize,
+    // notifications waiting for a worker before new ones are dropped
+    pub queue: usize,
+}
+
+impl WebhookConfig {
+    pub fn new<T: ToString>(secret: T) -> Self {
+        Self {
+            secret: secret.to_string(),
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            dry_run: false,
+            allowed_hosts: Vec::new(),
+            workers: 4,
+            queue: 1024,
+        }
+    }
+}
+
+struct Notification {
+    url: Url,
+    body: Vec<u8>,
+    trace: TraceContext,
+}
+
+// WebhookDispatcher delivers terminal verification events to the callback_url given by clients,
+// deliveries run in the background so a slow client endpoint never delays verification
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    sender: mpsc::Sender<Notification>,
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl WebhookDispatcher {
+    // spawn starts the delivery workers on the current tokio runtime
+    pub fn spawn(config: WebhookConfig) -> Result<Self, Error> {
+        // redirects could lead to any address, only the resolved callback host is connected to
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()?;
+        let (sender, receiver) = mpsc::channel::<Notification>(config.queue.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..config.workers.max(1) {
+            let (client, config, receiver) = (client.clone(), config.clone(), receiver.clone());
+            tokio::spawn(async move {
+                loop {
+                    let notification = match receiver.lock().await.recv().await {
+                        Some(n) => n,
+                        None => return,
+                    };
+                    let span = notification.trace.span();
+                    deliver(&client, &config, notification)
+                        .instrument(span)
+                        .await;
+                }
+            });
+        }
+        Ok(Self {
+            sender,
+            allowed_hosts: Arc::new(config.allowed_hosts),
+        })
+    }
+
+    // callback_url parses a client's callback_url, which must also name an allowed host
+    pub fn callback_url(&self, url: &str) -> Result<Url, Error> {
+        let parsed = parse_callback_url(url)?;
+        let host = parsed.host_str().unwrap_or_default();
+        if !self.allowed_hosts.is_empty() && !self.allowed_hosts.iter().any(|h| h == host) {
+            return Err(anyhow!("callback_url host is not allowed: {}", host));
+        }
+        Ok(parsed)
+    }
+
+    // dispatch queues a notification, every delivery attempt is a child span of trace
+    pub fn dispatch(&self, url: Url, event: VerificationEvent, trace: TraceContext) {
+        self.dispatch_json(url, &event, trace)
+    }
+
+    // dispatch_json queues any JSON payload signed like verification events, e.g. operator alerts
+    pub fn dispatch_json<T: Serialize>(&self, url: Url, payload: &T, trace: TraceContext) {
+        let body = match serde_json::to_vec(payload) {
+            Ok(b) => b,
+            Err(e) => {
+                error!(error = %e, "webhook serialization error");
+                return;
+            }
+        };
+        match self.sender.try_send(Notification { url, body, trace }) {
+            Ok(()) => (),
+            Err(mpsc::error::TrySendError::Full(n)) => {
+                warn!(url = %n.url, "webhook queue is full, dropping notification")
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                error!("webhook dispatcher stopped, dropping notification")
+            }
+        }
+    }
+}
+
+// parse_callback_url accepts absolute https URLs whose host isn't a loopback, private, link-local
+// or unspecified address, hosts given by name are checked once they are resolved
+pub fn parse_callback_url(url: &str) -> Result<Url, Error> {
+    let parsed = Url::parse(url).map_err(|e| anyhow!("invalid callback_url: {}", e))?;
+    if parsed.scheme() != "https" {
+        return Err(anyhow!("invalid callback_url scheme: {}", parsed.scheme()));
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| anyhow!("callback_url has no host"))?;
+    // IPv6 hosts are bracketed
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    match ip.parse::<IpAddr>() {
+        Ok(ip) if !is_public(ip) => Err(anyhow!("callback_url address is not public: {}", ip)),
+        _ => Ok(parsed),
+    }
+}
+
+// is_public holds for addresses outside of the loopback, private, shared, link-local,
+// unspecified, broadcast and multicast ranges
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => !is_reserved_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    // 100.64.0.0/10 is shared by carrier-grade NATs
+    let shared = ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64;
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || shared)
+}
+
+fn is_reserved_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 unique local and fe80::/10 link-local
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+}
+
+// PublicResolver resolves callback hosts, refusing to connect to any that resolve to an address
+// that isn't public so callbacks can't reach the server's own network
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|a| !is_public(a.ip())) {
+                let e = anyhow!(
+                    "{} resolves to an address that is not public: {}",
+                    host,
+                    addr.ip()
+                );
+                return Err(e.into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// sign returns the hex encoded HMAC-SHA256 of "<timestamp>.<body>", including the timestamp lets
+// receivers reject replays of old notifications
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliver(client: &reqwest::Client, config: &WebhookConfig, notification: Notification) {
+    if config.dry_run {
+        info!(url = %notification.url, "webhook not delivered in dry run");
+        return;
+    }
+    let body = notification.body;
+    let mut backoff = config.initial_backoff;
+    for attempt in 1..=config.max_attempts {
+        let timestamp = chrono::offset::Utc::now().timestamp();
+        let mut trace_headers = reqwest::header::HeaderMap::new();
+        notification.trace.child().inject(&mut trace_headers);
+        // receivers can match deliveries to the request that caused them
+        if let Some(id) = notification
+            .trace
+            .correlation_id
+            .as_deref()
+            .and_then(|id| reqwest::header::HeaderValue::from_str(id).ok())
+        {
+            trace_headers.insert(REQUEST_ID_HEADER, id);
+        }
+        let result = client
+            .post(notification.url.clone())
+            .headers(trace_headers)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(&config.secret, timestamp, &body)),
+            )
+            .body(body.clone())
+            .send()
+            .await;
+
+        let retry = match result {
+            Ok(r) if r.status().is_success() => {
+                info!(url = %notification.url, attempts = attempt, "webhook delivered");
+                return;
+            }
+            Ok(r) => is_retryable(r.status()),
+            Err(_) => true,
+        };
+        if !retry || attempt == config.max_attempts {
+            warn!(url = %notification.url, attempts = attempt, "webhook delivery failed");
+            return;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// client errors other than throttling won't succeed on retry
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("secret", 1600000000, b"{}"),
+            sign("secret", 1600000000, b"{}")
+        );
+        assert_ne!(
+            sign("secret", 1600000000, b"{}"),
+            sign("secret", 1600000001, b"{}")
+        );
+        assert_ne!(
+            sign("secret", 1600000000, b"{}"),
+            sign("other", 1600000000, b"{}")
+        );
+        assert_eq!(sign("secret", 1600000000, b"{}").len(), 64);
+    }
+
+    #[test]
+    fn test_parse_callback_url() {
+        assert!(parse_callback_url("https://example.com/hook").is_ok());
+        assert!(parse_callback_url("https://93.184.215.14/hook").is_ok());
+        assert!(parse_callback_url("http://example.com/hook").is_err());
+        assert!(parse_callback_url("ftp://example.com/hook").is_err());
+        assert!(parse_callback_url("/hook").is_err());
+        for url in &[
+            "https://127.0.0.1/hook",
+            "https://10.1.2.3/hook",
+            "https://192.168.0.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/hook",
+            "https://0.0.0.0/hook",
+            "https://[::1]/hook",
+            "https://[fd00::1]/hook",
+            "https://[fe80::1]/hook",
+            "https://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(parse_callback_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_hosts() {
+        let mut config = WebhookConfig::new("secret");
+        config.allowed_hosts = vec!["hooks.example.com".to_string()];
+        let dispatcher = WebhookDispatcher::spawn(config).unwrap();
+        assert!(dispatcher
+            .callback_url("https://hooks.example.com/done")
+            .is_ok());
+        assert!(dispatcher.callback_url("https://example.com/done").is_err());
+
+        // names are checked once they resolve
+        let resolved = PublicResolver.resolve("localhost".parse().unwrap()).await;
+        assert!(resolved.is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use axum::response::Response;
+use axum::Router;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use telecom::admin::AdminTokens;
+use telecom::balancer::BalancerConfig;
+use telecom::escalation::EscalationConfig;
+use telecom::http::{self, AppState, HttpConfig, Routes};
+use telecom::otp::OtpConfig;
+use telecom::provider::{MockTelecomProvider, TelecomProvider};
+use telecom::repo::VerificationKeeper;
+use telecom::test_numbers::{TestNumber, TestNumbers};
+use telecom::totp;
+use telecom::{BalancerType, VerificationServer};
+use tower::ServiceExt;
+
+const TOKEN: &str = "0123456789abcdef0123";
+
+// server routes to a mock carrier that always reaches the number
+fn server() -> VerificationServer {
+    let carrier = MockTelecomProvider::new("carrier_1".to_string(), 100, 100)
+        .unwrap()
+        .with_seed(1);
+    let carriers = vec![Box::new(carrier) as Box<dyn TelecomProvider>];
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    VerificationServer::new(BalancerType::RoundRobin, carriers, Box::new(keeper)).with_seed(42)
+}
+
+fn state(server: VerificationServer) -> AppState {
+    let tokens = BTreeMap::from([("alice".to_string(), TOKEN.to_string())]);
+    AppState::new(Arc::new(server)).with_admin_tokens(AdminTokens::new(&tokens))
+}
+
+fn router(routes: Routes) -> Router {
+    http::router(state(server()), &HttpConfig::default(), routes)
+}
+
+async fn send(router: &Router, request: Request<Body>) -> Response {
+    router.clone().oneshot(request).await.unwrap()
+}
+
+fn get(uri: &str, token: Option<&str>) -> Request<Body> {
+    let mut request = Request::get(uri);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+async fn json(response: Response) -> Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_authentication() {
+    let admin = router(Routes::Admin);
+    for token in &[None, Some("wrong"), Some("0123456789abcdef012")] {
+        let refused = send(&admin, get("/admin/carriers", *token)).await;
+        assert_eq!(refused.status(), StatusCode::UNAUTHORIZED, "{:?}", token);
+        assert!(refused.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+    let listed = send(&admin, get("/admin/carriers", Some(TOKEN))).await;
+    assert_eq!(listed.status(), StatusCode::OK);
+
+    // changes are audited as the operator the token was issued to, whatever headers claim
+    let opt_out = Request::put("/admin/opt-outs/+14155550100")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .header("x-actor", "mallory")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(&admin, opt_out).await.status(), StatusCode::OK);
+    let audit = json(send(&admin, get("/admin/audit", Some(TOKEN))).await).await;
+    assert_eq!(audit["items"][0]["actor"], "alice (unix socket)");
+
+    // the verification API's listener only serves the admin API when asked to
+    let public = router(Routes::Public);
+    let missing = send(&public, get("/admin/carriers", Some(TOKEN))).await;
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    let all = router(Routes::All);
+    assert_eq!(
+        send(&all, get("/admin/carriers", None)).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn test_debug_state_is_admin_only() {
+    for routes in &[Routes::Admin, Routes::All] {
+        let router = router(*routes);
+        let refused = send(&router, get("/debug/state", None)).await;
+        assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+        let state = send(&router, get("/debug/state", Some(TOKEN))).await;
+        assert_eq!(state.status(), StatusCode::OK);
+    }
+    let public = router(Routes::Public);
+    let missing = send(&public, get("/debug/state", Some(TOKEN))).await;
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+}
+
+fn post_json(uri: &str, body: Value) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_listener_routes() {
+    let state = state(server());
+    let config = HttpConfig::default();
+    let public = http::router(state.clone(), &config, Routes::Public);
+    let admin = http::router(state, &config, Routes::Admin);
+    let operational = [
+        "/metrics",
+        "/slo",
+        "/health/score",
+        "/attempts",
+        "/events",
+        "/admin/carriers",
+    ];
+    for uri in &operational {
+        let missing = send(&public, get(uri, Some(TOKEN))).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND, "{}", uri);
+        let refused = send(&admin, get(uri, None)).await;
+        assert_eq!(refused.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+        let served = send(&admin, get(uri, Some(TOKEN))).await;
+        assert_eq!(served.status(), StatusCode::OK, "{}", uri);
+    }
+    for uri in &["/rank", "/rank/uptime", "/openapi.json"] {
+        assert_eq!(send(&public, get(uri, None)).await.status(), StatusCode::OK);
+        let missing = send(&admin, get(uri, Some(TOKEN))).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+
+    // attempts are listed without the numbers they were made for
+    let request = serde_json::json!({"number": "+14155550123", "time": 1781000000000_i64});
+    let attempt = send(&public, post_json("/", request)).await;
+    assert_eq!(attempt.status(), StatusCode::OK);
+    let attempts = json(send(&admin, get("/attempts", Some(TOKEN))).await).await;
+    assert_eq!(attempts["items"][0]["number"], "+141******23");
+
+    // repo gauges are read when scraped
+    let scraped = send(&admin, get("/metrics", Some(TOKEN))).await;
+    let body = to_bytes(scraped.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        body.lines().any(|l| l == "telecom_repo_attempts 1"),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn test_reuse_recent_needs_token() {
+    let numbers = TestNumbers::new(vec![
+        (
+            "+14155550100".to_string(),
+            TestNumber::Code("000000".into()),
+        ),
+        (
+            "+14155550101".to_string(),
+            TestNumber::Code("111111".into()),
+        ),
+    ]);
+    let server = server()
+        .with_test_numbers(numbers)
+        .with_otp_config(OtpConfig {
+            reuse_window: chrono::Duration::minutes(10),
+            ..OtpConfig::default()
+        });
+    let public = http::router(state(server), &HttpConfig::default(), Routes::Public);
+    let mut tokens = Vec::new();
+    for (number, code) in &[("+14155550100", "000000"), ("+14155550101", "111111")] {
+        let request = serde_json::json!({"number": number, "time": 1781000000000_i64});
+        let attempt = json(send(&public, post_json("/", request)).await).await;
+        let check = serde_json::json!({"attempt_id": attempt["attempt_id"], "code": code});
+        let checked = json(send(&public, post_json("/check", check)).await).await;
+        tokens.push(checked["token"].as_str().unwrap().to_string());
+    }
+
+    // knowing a recently verified number isn't enough, nor is a token for another number
+    let reuse = |token: Option<&str>| {
+        let mut request = serde_json::json!({
+            "number": "+14155550100",
+            "time": 1781000000000_i64,
+            "reuse_recent": true,
+        });
+        if let Some(token) = token {
+            request["reuse_token"] = token.into();
+        }
+        post_json("/", request)
+    };
+    for token in &[None, Some("not a token"), Some(tokens[1].as_str())] {
+        let refused = json(send(&public, reuse(*token)).await).await;
+        assert_eq!(refused.get("reused"), None, "{:?}", token);
+        assert_eq!(refused.get("token"), None, "{:?}", token);
+        assert!(refused["attempt_id"].is_string(), "{:?}", token);
+    }
+    let reused = json(send(&public, reuse(Some(&tokens[0]))).await).await;
+    assert_eq!(reused["reused"], true);
+    assert!(reused["token"].is_string());
+}
+
+fn enroll(token: &str, code: Option<&str>) -> Request<Body> {
+    let request = Request::post("/totp/enroll")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header(header::CONTENT_TYPE, "application/json");
+    let body = code.map(|c| serde_json::json!({ "code": c }).to_string());
+    request.body(body.unwrap_or_default().into()).unwrap()
+}
+
+#[tokio::test]
+async fn test_totp_enrollment() {
+    let numbers = TestNumbers::new(vec![(
+        "+14155550100".to_string(),
+        TestNumber::Code("000000".into()),
+    )]);
+    let server = server()
+        .with_test_numbers(numbers)
+        .with_otp_config(OtpConfig {
+            reuse_window: chrono::Duration::minutes(10),
+            ..OtpConfig::default()
+        });
+    let public = http::router(state(server), &HttpConfig::default(), Routes::Public);
+    let request = serde_json::json!({"number": "+14155550100", "time": 1781000000000_i64});
+    let attempt = json(send(&public, post_json("/", request)).await).await;
+    let check = serde_json::json!({"attempt_id": attempt["attempt_id"], "code": "000000"});
+    let checked = json(send(&public, post_json("/check", check)).await).await;
+    let token = checked["token"].as_str().unwrap().to_string();
+
+    // a reused token was issued without proving anything
+    let reuse = serde_json::json!({
+        "number": "+14155550100",
+        "time": 1781000000000_i64,
+        "reuse_recent": true,
+        "reuse_token": token,
+    });
+    let reused = json(send(&public, post_json("/", reuse)).await).await;
+    let reused = reused["token"].as_str().unwrap();
+    let refused = send(&public, enroll(reused, None)).await;
+    assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+
+    let enrolled = send(&public, enroll(&token, None)).await;
+    assert_eq!(enrolled.status(), StatusCode::OK);
+    let secret = json(enrolled).await["secret"].as_str().unwrap().to_string();
+
+    // the enrollment is only replaced along with a code of its secret
+    let conflict = send(&public, enroll(&token, None)).await;
+    assert_eq!(conflict.status(), StatusCode::CONFLICT);
+    let wrong = send(&public, enroll(&token, Some("not a code"))).await;
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+    let period = chrono::Utc::now().timestamp() / totp::PERIOD_SECS;
+    let code = totp::code_at(&decode_base32(&secret), period);
+    let replaced = send(&public, enroll(&token, Some(&code))).await;
+    assert_eq!(replaced.status(), StatusCode::OK);
+    assert_ne!(json(replaced).await["secret"], secret.as_str());
+}
+
+fn decode_base32(s: &str) -> Vec<u8> {
+    let (mut bytes, mut buffer, mut bits) = (Vec::new(), 0_u32, 0);
+    for c in s.bytes() {
+        let value = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567"
+            .iter()
+            .position(|a| *a == c)
+            .unwrap();
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    bytes
+}
+
+#[tokio::test]
+async fn test_delayed_step_is_scheduled() {
+    // SMS never reaches the number, the voice call after it is delayed
+    let carrier = MockTelecomProvider::new("carrier_1".to_string(), 0, 100)
+        .unwrap()
+        .with_seed(1);
+    let carriers = vec![Box::new(carrier) as Box<dyn TelecomProvider>];
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    let escalation = EscalationConfig {
+        default: "sms,voice:60".parse().unwrap(),
+        ..EscalationConfig::default()
+    };
+    let server = VerificationServer::new(BalancerType::RoundRobin, carriers, Box::new(keeper))
+        .with_seed(42)
+        .with_escalation(escalation)
+        .unwrap();
+    let public = http::router(state(server), &HttpConfig::default(), Routes::Public);
+
+    let started = std::time::Instant::now();
+    let request = serde_json::json!({"number": "+14155550100", "time": 1781000000000_i64});
+    let attempt = json(send(&public, post_json("/", request)).await).await;
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(attempt["retrying"], true);
+    let uri = format!("/verifications/{}", attempt["attempt_id"].as_str().unwrap());
+    let status = json(send(&public, get(&uri, None)).await).await;
+    assert_eq!(status["state"], "retrying");
+}
+
+// SlowCarrier reaches every number, but only after a while
+struct SlowCarrier(Duration);
+
+impl TelecomProvider for SlowCarrier {
+    fn send_sms(&self, _number: &str, _message: &str) -> bool {
+        std::thread::sleep(self.0);
+        true
+    }
+
+    fn send_voice(&self, number: &str, message: &str) -> bool {
+        self.send_sms(number, message)
+    }
+
+    fn get_name(&self) -> Arc<str> {
+        "slow".into()
+    }
+}
+
+#[tokio::test]
+async fn test_parallel_returns_first_delivery() {
+    let fast = MockTelecomProvider::new("fast".to_string(), 100, 100)
+        .unwrap()
+        .with_seed(1);
+    let carriers = vec![
+        Box::new(SlowCarrier(Duration::from_secs(3))) as Box<dyn TelecomProvider>,
+        Box::new(fast) as Box<dyn TelecomProvider>,
+    ];
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    let balancer = BalancerConfig {
+        failover_depth: 2,
+        ..BalancerConfig::new(BalancerType::RoundRobin)
+    };
+    let server = VerificationServer::new(BalancerType::RoundRobin, carriers, Box::new(keeper))
+        .with_seed(42)
+        .with_balancer(&balancer)
+        .unwrap();
+    let public = http::router(state(server), &HttpConfig::default(), Routes::Public);
+
+    let started = std::time::Instant::now();
+    let request = serde_json::json!({
+        "number": "+14155550100",
+        "time": 1781000000000_i64,
+        "parallel": true,
+    });
+    let attempt = json(send(&public, post_json("/", request)).await).await;
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let uri = format!("/verifications/{}", attempt["attempt_id"].as_str().unwrap());
+    let status = json(send(&public, get(&uri, None)).await).await;
+    assert_eq!(status["carrier"], "fast");
+}
+
+// ThreadCarrier reaches every number and remembers the thread it was called on
+struct ThreadCarrier(Arc<std::sync::Mutex<Option<String>>>);
+
+impl TelecomProvider for ThreadCarrier {
+    fn send_sms(&self, _number: &str, _message: &str) -> bool {
+        *self.0.lock().unwrap() = std::thread::current().name().map(str::to_string);
+        true
+    }
+
+    fn send_voice(&self, number: &str, message: &str) -> bool {
+        self.send_sms(number, message)
+    }
+
+    fn get_name(&self) -> Arc<str> {
+        "carrier_1".into()
+    }
+}
+
+#[tokio::test]
+async fn test_carriers_run_on_the_carrier_pool() {
+    let thread = Arc::new(std::sync::Mutex::new(None));
+    let carriers = vec![Box::new(ThreadCarrier(thread.clone())) as Box<dyn TelecomProvider>];
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    let server = VerificationServer::new(BalancerType::RoundRobin, carriers, Box::new(keeper))
+        .with_max_provider_calls(4, 4)
+        .unwrap();
+    let public = http::router(state(server), &HttpConfig::default(), Routes::Public);
+
+    let request = serde_json::json!({"number": "+14155550100", "time": 1781000000000_i64});
+    let attempt = json(send(&public, post_json("/", request)).await).await;
+    assert!(attempt["attempt_id"].is_string());
+    let thread = thread.lock().unwrap().clone().unwrap_or_default();
+    assert!(thread.starts_with("telecom-carrier"), "{}", thread);
+}
+
+#[tokio::test]
+async fn test_saturated_carrier_calls() {
+    let carriers =
+        vec![Box::new(SlowCarrier(Duration::from_millis(300))) as Box<dyn TelecomProvider>];
+    let keeper = VerificationKeeper::new([1, 2, 3, 4, 5]).unwrap();
+    let server = VerificationServer::new(BalancerType::RoundRobin, carriers, Box::new(keeper))
+        .with_max_provider_calls(1, 1)
+        .unwrap();
+    let server = Arc::new(server);
+    let public = http::router(
+        AppState::new(server.clone()),
+        &HttpConfig::default(),
+        Routes::Public,
+    );
+    let verify = |number: &str| {
+        let public = public.clone();
+        let request = serde_json::json!({"number": number, "time": 1781000000000_i64});
+        tokio::spawn(async move { send(&public, post_json("/", request)).await })
+    };
+
+    // one request takes the only call and another waits for it
+    let running = verify("+14155550100");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let waiting = verify("+14155550101");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(server.carrier_calls().in_flight(), 1);
+    assert_eq!(server.carrier_calls().queued(), 1);
+
+    // the next one is turned away
+    let refused = send(
+        &public,
+        post_json(
+            "/",
+            serde_json::json!({
+                "number": "+14155550102",
+                "time": 1781000000000_i64,
+            }),
+        ),
+    )
+    .await;
+    assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(refused.headers().contains_key(header::RETRY_AFTER));
+    assert!(json(refused).await["error"].is_string());
+
+    // a caller that gives up leaves the queue
+    waiting.abort();
+    let _ = waiting.await;
+    assert_eq!(server.carrier_calls().queued(), 0);
+    assert_eq!(running.await.unwrap().status(), StatusCode::OK);
+    assert_eq!(server.carrier_calls().in_flight(), 0);
+}
+
+#[tokio::test]
+async fn test_body_limits() {
+    let public = router(Routes::Public);
+    // sent with their length like any client does
+    let request = |content_type: &str, body: Vec<u8>| {
+        Request::post("/")
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let large = format!(r#"{{"number": "{}"}}"#, "1".repeat(64 * 1024)).into_bytes();
+    let response = send(&public, request("application/json", large)).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(json(response).await["error"].is_string());
+
+    // bodies without a length are cut off once they pass the limit
+    let chunks = (0..3).map(|_| Ok::<_, std::io::Error>(vec![b' '; 32 * 1024]));
+    let streamed = Request::post("/")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from_stream(tokio_stream::iter(chunks)))
+        .unwrap();
+    let response = send(&public, streamed).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(json(response).await["error"].is_string());
+
+    let text = b"number=+14155550100".to_vec();
+    let response = send(&public, request("text/plain", text)).await;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(json(response).await["error"].is_string());
+}
+
+#[tokio::test]
+async fn test_method_not_allowed() {
+    let public = router(Routes::Public);
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    for (method, uri, allow) in &[
+        ("DELETE", "/check", "POST"),
+        ("GET", "/", "POST"),
+        ("POST", "/verifications/1234", "GET,HEAD"),
+    ] {
+        let response = send(&public, request(method, uri)).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", uri);
+        assert_eq!(response.headers()[header::ALLOW], *allow, "{}", uri);
+        assert!(json(response).await["error"].is_string());
+    }
+}